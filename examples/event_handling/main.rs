@@ -1,29 +1,15 @@
 use std::io::stdin;
 
-use win_acapture_rs::{
-    event_args::AudioSessionEventArgs,
-    manager::{DeviceManager, SessionManager},
-    notifications::Notifications,
-    session_notification::SessionCreated,
-};
+use win_acapture_rs::{event_args::AudioSessionEventArgs, notifications::Notifications, session_notification::SessionCreated};
 
 /// Setup events for every session
 fn main() {
-    let mut notification_manager = Notifications::new();
-
-    // Set up session events
-    let sessions = SessionManager::get_sessions().unwrap();
-    for session in sessions {
-        notification_manager.register_session_event(&session, handle_event).unwrap();
-    }
-
-    // Set up session notification (NewSession) tied to devices
-    let devices = DeviceManager::get_playback_devices().unwrap();
-    for dev in devices {
-        notification_manager
-            .register_session_notification(dev, handle_notification)
-            .unwrap();
-    }
+    // Session events for every current session, NewSession notifications for every playback device
+    let _notifications = Notifications::builder()
+        .on_session_event(handle_event)
+        .on_new_session(handle_notification)
+        .build()
+        .unwrap();
 
     println!("Listening for events, press enter to exit");
     stdin().read_line(&mut String::new()).unwrap();
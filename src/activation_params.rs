@@ -3,7 +3,7 @@ use std::mem::ManuallyDrop;
 use windows::Win32::{
     Media::Audio::{
         AUDIOCLIENT_ACTIVATION_PARAMS, AUDIOCLIENT_ACTIVATION_TYPE_DEFAULT, AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK,
-        PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE,
+        PROCESS_LOOPBACK_MODE,
     },
     System::{
         Com::{
@@ -18,14 +18,14 @@ use windows::Win32::{
 pub(crate) struct SafeActivationParams(PROPVARIANT);
 
 impl SafeActivationParams {
-    pub fn new(pid: Option<u32>) -> Self {
+    /// Process loopback of the tree rooted at the pid with the given mode, or a regular activation for `None`
+    pub fn new(process: Option<(u32, PROCESS_LOOPBACK_MODE)>) -> Self {
         let params_ptr = unsafe { CoTaskMemAlloc(size_of::<AUDIOCLIENT_ACTIVATION_PARAMS>()) } as *mut AUDIOCLIENT_ACTIVATION_PARAMS;
         debug_assert!(!params_ptr.is_null(), "Failed allocating memory for activation params");
         let audioclient_activate_params: &mut AUDIOCLIENT_ACTIVATION_PARAMS = unsafe { &mut *params_ptr };
-        if let Some(pid) = pid {
+        if let Some((pid, mode)) = process {
             audioclient_activate_params.ActivationType = AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK;
-            audioclient_activate_params.Anonymous.ProcessLoopbackParams.ProcessLoopbackMode =
-                PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE;
+            audioclient_activate_params.Anonymous.ProcessLoopbackParams.ProcessLoopbackMode = mode;
             audioclient_activate_params.Anonymous.ProcessLoopbackParams.TargetProcessId = pid;
         } else {
             audioclient_activate_params.ActivationType = AUDIOCLIENT_ACTIVATION_TYPE_DEFAULT;
@@ -0,0 +1,75 @@
+//! Retrying audio client activation, see [`AudioClient::set_activation_retry`](crate::audio_client::AudioClient::set_activation_retry).
+//!
+//! Right after a device is plugged in, activating it can fail for a short while until the audio service finished
+//! setting up the endpoint. Those failures are retried, everything else fails right away.
+
+use std::time::Duration;
+
+use windows::Win32::{
+    Foundation::ERROR_NOT_FOUND,
+    Media::Audio::{
+        AUDCLNT_E_DEVICE_IN_USE, AUDCLNT_E_ENDPOINT_CREATE_FAILED, AUDCLNT_E_RESOURCES_INVALIDATED, AUDCLNT_E_SERVICE_NOT_RUNNING,
+    },
+};
+use windows_core::HRESULT;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActivationRetry {
+    attempts: u32,
+    delay: Duration,
+}
+
+impl Default for ActivationRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            delay: Duration::from_millis(100),
+        }
+    }
+}
+
+impl ActivationRetry {
+    /// `attempts` includes the first try, at least one attempt is always made
+    pub fn new(attempts: u32, delay: Duration) -> Self {
+        Self {
+            attempts: attempts.max(1),
+            delay,
+        }
+    }
+
+    /// Fails on the first error, the behaviour without a retry policy
+    pub fn never() -> Self {
+        Self::new(1, Duration::ZERO)
+    }
+
+    pub fn get_attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn get_delay(&self) -> Duration {
+        self.delay
+    }
+}
+
+/// True for activation failures that typically go away on their own shortly after a device arrived
+pub fn is_transient_activation_error(hresult: HRESULT) -> bool {
+    hresult == AUDCLNT_E_DEVICE_IN_USE
+        || hresult == AUDCLNT_E_ENDPOINT_CREATE_FAILED
+        || hresult == AUDCLNT_E_RESOURCES_INVALIDATED
+        || hresult == AUDCLNT_E_SERVICE_NOT_RUNNING
+        || hresult == ERROR_NOT_FOUND.to_hresult()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use windows::Win32::Foundation::E_ACCESSDENIED;
+
+    #[test]
+    fn transient_errors() {
+        assert!(is_transient_activation_error(AUDCLNT_E_DEVICE_IN_USE));
+        assert!(is_transient_activation_error(ERROR_NOT_FOUND.to_hresult()));
+        assert!(!is_transient_activation_error(E_ACCESSDENIED));
+        assert_eq!(ActivationRetry::new(0, Duration::ZERO).get_attempts(), 1);
+    }
+}
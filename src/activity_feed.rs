@@ -0,0 +1,280 @@
+//! Merged stream of what applications and devices are doing, see [`ActivityFeed`].
+//!
+//! Device notifications, new session notifications of every active playback and capture device, including devices
+//! added later, and the session events of every session are turned into owned, timestamped [`ActivityEvent`]s and
+//! sent through one channel, in the order they arrived. Sessions that exist when the feed is created are watched as
+//! well, but don't produce an event of their own.
+
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use thiserror::Error;
+
+use crate::device_query::{DataFlow, DeviceRole};
+use crate::device_state::DeviceState;
+use crate::event_args::{AudioSessionEventArgs, DeviceNotificationEventArgs, SessionDisconnectReason, SessionState};
+use crate::manager::{AudioError, Session};
+use crate::notifications::NotificationError;
+use crate::session_tracker::{SessionHandler, SessionTracker};
+use crate::stable_key::SessionKey;
+
+#[derive(Error, Debug)]
+pub enum ActivityFeedError {
+    #[error("Failed setting up notifications: {0}")]
+    NotificationError(#[source] NotificationError),
+    #[error("Failed enumerating sessions: {0}")]
+    SessionEnumError(#[source] AudioError),
+}
+
+/// The application behind a session event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppIdentity {
+    pid: u32,
+    process_name: Option<String>,
+    key: Option<SessionKey>,
+}
+
+impl AppIdentity {
+    fn from_session(session: &Session) -> Self {
+        Self {
+            pid: *session.get_pid(),
+            process_name: session.get_process_name().clone(),
+            key: session.get_stable_key().ok(),
+        }
+    }
+
+    pub fn get_pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn get_process_name(&self) -> Option<&str> {
+        self.process_name.as_deref()
+    }
+
+    /// Identity that survives restarts of the application, `None` for sessions without a parsable identifier
+    pub fn get_key(&self) -> Option<&SessionKey> {
+        self.key.as_ref()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Activity {
+    /// The application created a new session
+    SessionCreated(AppIdentity),
+    /// The session became active, the application started playing
+    StartedPlaying(AppIdentity),
+    /// The session became inactive, the application stopped playing
+    StoppedPlaying(AppIdentity),
+    /// The session expired, e.g. the application exited
+    SessionExpired(AppIdentity),
+    SessionDisconnected(AppIdentity, SessionDisconnectReason),
+    /// Master volume of the session, from `0.0` to `1.0`
+    VolumeChanged {
+        app: AppIdentity,
+        volume: f32,
+        muted: bool,
+    },
+    DisplayNameChanged {
+        app: AppIdentity,
+        display_name: String,
+    },
+    /// `device_id` is `None` if there is no default device left for the flow and role
+    DefaultDeviceChanged {
+        flow: DataFlow,
+        role: DeviceRole,
+        device_id: Option<String>,
+    },
+    DeviceAdded(String),
+    DeviceRemoved(String),
+    DeviceStateChanged(String, DeviceState),
+}
+
+impl Activity {
+    /// The application the activity belongs to, `None` for device activity
+    pub fn get_app(&self) -> Option<&AppIdentity> {
+        match self {
+            Activity::SessionCreated(app)
+            | Activity::StartedPlaying(app)
+            | Activity::StoppedPlaying(app)
+            | Activity::SessionExpired(app)
+            | Activity::SessionDisconnected(app, _)
+            | Activity::VolumeChanged { app, .. }
+            | Activity::DisplayNameChanged { app, .. } => Some(app),
+            Activity::DefaultDeviceChanged { .. }
+            | Activity::DeviceAdded(_)
+            | Activity::DeviceRemoved(_)
+            | Activity::DeviceStateChanged(..) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActivityEvent {
+    timestamp: SystemTime,
+    activity: Activity,
+}
+
+impl ActivityEvent {
+    fn now(activity: Activity) -> Self {
+        Self {
+            timestamp: SystemTime::now(),
+            activity,
+        }
+    }
+
+    /// When the notification arrived
+    pub fn get_timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    pub fn get_activity(&self) -> &Activity {
+        &self.activity
+    }
+}
+
+/// Watches every application and device and sends what happens to one channel, dropping it stops the notifications
+pub struct ActivityFeed {
+    _tracker: SessionTracker<Feed>,
+    receiver: Receiver<ActivityEvent>,
+}
+
+/// Turns the notifications of the tracker into activity
+struct Feed {
+    sender: Sender<ActivityEvent>,
+    /// Application of every watched session, by session name
+    apps: Mutex<HashMap<String, AppIdentity>>,
+}
+
+impl ActivityFeed {
+    pub fn new() -> Result<Self, ActivityFeedError> {
+        let (sender, receiver) = mpsc::channel();
+        let feed = Feed {
+            sender,
+            apps: Mutex::new(HashMap::new()),
+        };
+        let tracker = SessionTracker::start(feed).map_err(|err| match err {
+            NotificationError::FailedEnumeratingSessions(err) => ActivityFeedError::SessionEnumError(err),
+            err => ActivityFeedError::NotificationError(err),
+        })?;
+        Ok(Self {
+            _tracker: tracker,
+            receiver,
+        })
+    }
+
+    /// The merged events, in the order they arrived
+    pub fn events(&self) -> &Receiver<ActivityEvent> {
+        &self.receiver
+    }
+
+    // See drop implementation of the tracker for cleanup
+    pub fn stop(self) {}
+}
+
+impl Feed {
+    fn lock_apps(&self) -> MutexGuard<'_, HashMap<String, AppIdentity>> {
+        self.apps.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn send(&self, activity: Activity) {
+        let _ = self.sender.send(ActivityEvent::now(activity));
+    }
+}
+
+impl SessionHandler for Feed {
+    fn session_added(&self, session: &Session, created: bool) -> bool {
+        let app = AppIdentity::from_session(session);
+        // Sent before the events are registered, so it comes ahead of the session's own events
+        if created {
+            self.send(Activity::SessionCreated(app.clone()));
+        }
+        self.lock_apps().insert(session.get_name().clone(), app);
+        true
+    }
+
+    fn session_event(&self, name: &str, event: AudioSessionEventArgs) {
+        let Some(app) = self.lock_apps().get(name).cloned() else {
+            return;
+        };
+        if let Some(activity) = session_activity(&app, event) {
+            if matches!(activity, Activity::SessionExpired(_) | Activity::SessionDisconnected(..)) {
+                self.lock_apps().remove(name);
+            }
+            self.send(activity);
+        }
+    }
+
+    fn device_event(&self, event: DeviceNotificationEventArgs) {
+        if let Some(activity) = device_activity(event) {
+            self.send(activity);
+        }
+    }
+}
+
+fn session_activity(app: &AppIdentity, event: AudioSessionEventArgs) -> Option<Activity> {
+    let app = app.clone();
+    match event {
+        AudioSessionEventArgs::StateChanged(args) => Some(match args.get_state() {
+            SessionState::AudioSessionStateActive => Activity::StartedPlaying(app),
+            SessionState::AudioSessionStateInactive => Activity::StoppedPlaying(app),
+            SessionState::AudioSessionStateExpired => Activity::SessionExpired(app),
+        }),
+        AudioSessionEventArgs::SessionDisconnected(args) => Some(Activity::SessionDisconnected(app, args.get_reason())),
+        AudioSessionEventArgs::SimpleVolumeChanged(args) => Some(Activity::VolumeChanged {
+            app,
+            volume: args.get_volume(),
+            muted: args.is_muted(),
+        }),
+        AudioSessionEventArgs::DisplayNameChanged(args) => args
+            .get_display_name()
+            .ok()
+            .map(|display_name| Activity::DisplayNameChanged { app, display_name }),
+        AudioSessionEventArgs::IconPathChanged(_)
+        | AudioSessionEventArgs::ChannelVolumeChanged(_)
+        | AudioSessionEventArgs::GroupingParamChanged(_) => None,
+    }
+}
+
+fn device_activity(event: DeviceNotificationEventArgs) -> Option<Activity> {
+    match event {
+        DeviceNotificationEventArgs::DefaultDeviceChanged(args) => Some(Activity::DefaultDeviceChanged {
+            flow: args.get_flow(),
+            role: args.get_role(),
+            device_id: args.get_default_device().ok().filter(|id| !id.is_empty()),
+        }),
+        DeviceNotificationEventArgs::DeviceAdded(args) => args.get_device_id().ok().map(Activity::DeviceAdded),
+        DeviceNotificationEventArgs::DeviceRemoved(args) => args.get_device_id().ok().map(Activity::DeviceRemoved),
+        DeviceNotificationEventArgs::DeviceStateChanged(args) => {
+            let state = args.get_state();
+            args.get_device_id().ok().map(|id| Activity::DeviceStateChanged(id, state))
+        }
+        DeviceNotificationEventArgs::DevicePropertyValueChanged(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_client::AudioClient;
+    use std::time::Duration;
+
+    #[test]
+    fn own_playback_shows_up() {
+        let feed = ActivityFeed::new().unwrap();
+
+        // Playing audio creates or activates a session of this process
+        let (playback, _format) = AudioClient::new().start_playback_device(None, |_| 0, |_| {}).unwrap();
+        let _playback = playback.start().unwrap();
+        let pid = std::process::id();
+        let event = loop {
+            let event = feed.events().recv_timeout(Duration::from_secs(5)).unwrap();
+            let own = event.get_activity().get_app().is_some_and(|app| app.get_pid() == pid);
+            if own && matches!(event.get_activity(), Activity::SessionCreated(_) | Activity::StartedPlaying(_)) {
+                break event;
+            }
+        };
+        assert!(event.get_timestamp() <= SystemTime::now());
+    }
+}
@@ -0,0 +1,219 @@
+//! Automatic gain control for capture streams, see [`Agc`].
+//!
+//! The level of every packet is metered as RMS. The gain moves towards the one that brings that level to the target,
+//! quickly (attack) when the input gets louder and slowly (release) when it gets quieter, and is ramped across the
+//! packet so the change doesn't click. Below the noise floor the gain is held, so pauses don't pump up the background
+//! noise. The gain applied to a packet never exceeds one over its peak, so a loud attack is limited right away instead
+//! of clipping until the smoothed gain catches up.
+
+use std::time::Duration;
+
+use crate::audio_stream::CapturePacket;
+use crate::convert::{bytes_to_f32, f32_to_bytes, is_convertible};
+use crate::sample_format::SampleFormat;
+
+const DEFAULT_TARGET_LEVEL_DB: f32 = -18.0;
+const DEFAULT_MAX_GAIN_DB: f32 = 30.0;
+const DEFAULT_NOISE_FLOOR_DB: f32 = -60.0;
+const DEFAULT_ATTACK: Duration = Duration::from_millis(20);
+const DEFAULT_RELEASE: Duration = Duration::from_millis(500);
+
+fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+fn linear_to_db(linear: f32) -> f32 {
+    20.0 * linear.max(f32::MIN_POSITIVE).log10()
+}
+
+/// Evens out the level of captured audio, create it with [`Agc::new`] and run packets through [`Agc::process`] or
+/// [`Agc::wrap`]
+pub struct Agc {
+    format: SampleFormat,
+    target_level: f32,
+    max_gain: f32,
+    noise_floor: f32,
+    attack: Duration,
+    release: Duration,
+    gain: f32,
+    level: f32,
+    samples: Vec<f32>,
+    bytes: Vec<u8>,
+}
+
+impl Agc {
+    /// AGC for packets in `format`, packets in formats [`is_convertible`] rejects pass through untouched
+    ///
+    /// Defaults to a target of -18 dBFS, at most 30 dB of gain, a noise floor of -60 dBFS, 20 ms attack and 500 ms
+    /// release.
+    pub fn new(format: &SampleFormat) -> Self {
+        Self {
+            format: format.clone(),
+            target_level: db_to_linear(DEFAULT_TARGET_LEVEL_DB),
+            max_gain: db_to_linear(DEFAULT_MAX_GAIN_DB),
+            noise_floor: db_to_linear(DEFAULT_NOISE_FLOOR_DB),
+            attack: DEFAULT_ATTACK,
+            release: DEFAULT_RELEASE,
+            gain: 1.0,
+            level: 0.0,
+            samples: Vec::new(),
+            bytes: Vec::new(),
+        }
+    }
+
+    /// RMS level the output is brought to, in dBFS
+    pub fn target_level_db(mut self, target_level_db: f32) -> Self {
+        self.target_level = db_to_linear(target_level_db);
+        self
+    }
+
+    /// Upper bound of the gain in dB, the AGC never attenuates below 0 dB gain unless the input is above the target
+    pub fn max_gain_db(mut self, max_gain_db: f32) -> Self {
+        self.max_gain = db_to_linear(max_gain_db);
+        self
+    }
+
+    /// Input below this RMS level in dBFS holds the gain instead of raising it
+    pub fn noise_floor_db(mut self, noise_floor_db: f32) -> Self {
+        self.noise_floor = db_to_linear(noise_floor_db);
+        self
+    }
+
+    /// Time constant for lowering the gain when the input gets louder
+    pub fn attack(mut self, attack: Duration) -> Self {
+        self.attack = attack;
+        self
+    }
+
+    /// Time constant for raising the gain when the input gets quieter
+    pub fn release(mut self, release: Duration) -> Self {
+        self.release = release;
+        self
+    }
+
+    /// Gain applied to the end of the last packet, in dB
+    pub fn get_gain_db(&self) -> f32 {
+        linear_to_db(self.gain)
+    }
+
+    /// RMS level of the last packet before the gain, in dBFS
+    pub fn get_level_db(&self) -> f32 {
+        linear_to_db(self.level)
+    }
+
+    /// Applies the gain to interleaved samples in the AGC's format
+    pub fn process(&mut self, data: &mut [u8]) {
+        if !is_convertible(&self.format) || data.is_empty() {
+            return;
+        }
+        self.samples.clear();
+        bytes_to_f32(&self.format, data, &mut self.samples);
+        if self.samples.is_empty() {
+            return;
+        }
+
+        let (sum, peak) = self
+            .samples
+            .iter()
+            .fold((0.0f32, 0.0f32), |(sum, peak), s| (sum + s * s, peak.max(s.abs())));
+        self.level = (sum / self.samples.len() as f32).sqrt();
+
+        let channels = self.format.get_channel().max(1) as usize;
+        let frames = self.samples.len() / channels;
+        let duration = frames as f32 / self.format.get_n_samples_per_sec().max(1) as f32;
+        // Both ends of the ramp stay below the limit, so every frame in between does too
+        let limit = if peak > 0.0 { 1.0 / peak } else { f32::INFINITY };
+        let start_gain = self.gain.min(limit);
+        if self.level >= self.noise_floor {
+            let wanted = (self.target_level / self.level).min(self.max_gain).min(limit);
+            let time_constant = if wanted < self.gain { self.attack } else { self.release };
+            let coefficient = if time_constant.is_zero() {
+                1.0
+            } else {
+                1.0 - (-duration / time_constant.as_secs_f32()).exp()
+            };
+            self.gain += (wanted - self.gain) * coefficient;
+        }
+        self.gain = self.gain.min(limit);
+
+        let step = (self.gain - start_gain) / frames.max(1) as f32;
+        for (frame, samples) in self.samples.chunks_mut(channels).enumerate() {
+            let gain = start_gain + step * (frame + 1) as f32;
+            samples.iter_mut().for_each(|s| *s *= gain);
+        }
+        self.bytes.clear();
+        f32_to_bytes(&self.format, &self.samples, &mut self.bytes);
+        data.copy_from_slice(&self.bytes[..data.len()]);
+    }
+
+    /// Wraps a capture data callback, every packet passes through the AGC before it reaches `data_callback`
+    ///
+    /// The AGC has to be created with the format the stream delivers, e.g. the client's format or the device's mix
+    /// format from [`Device::get_mix_format`](crate::manager::Device::get_mix_format).
+    pub fn wrap<D>(mut self, mut data_callback: D) -> impl FnMut(CapturePacket) + Send + 'static
+    where
+        D: FnMut(CapturePacket) + Send + 'static,
+    {
+        let mut buffer = Vec::new();
+        move |packet| {
+            buffer.clear();
+            buffer.extend_from_slice(packet.data());
+            self.process(&mut buffer);
+            data_callback(packet.with_data(&buffer));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample_format::FormatTag;
+
+    fn format() -> SampleFormat {
+        SampleFormat::new(FormatTag::WaveFormatIeeeFloat, 1, 1000, 32)
+    }
+
+    fn packet(amplitude: f32, frames: usize) -> Vec<u8> {
+        let samples: Vec<f32> = (0..frames).map(|i| if i % 2 == 0 { amplitude } else { -amplitude }).collect();
+        let mut bytes = Vec::new();
+        f32_to_bytes(&format(), &samples, &mut bytes);
+        bytes
+    }
+
+    /// RMS and peak of a processed packet, in dBFS and linear
+    fn measure(data: &[u8]) -> (f32, f32) {
+        let mut samples = Vec::new();
+        bytes_to_f32(&format(), data, &mut samples);
+        let sum: f32 = samples.iter().map(|s| s * s).sum();
+        let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        (linear_to_db((sum / samples.len() as f32).sqrt()), peak)
+    }
+
+    #[test]
+    fn quiet_input_raised_towards_target() {
+        let mut agc = Agc::new(&format()).target_level_db(-20.0).max_gain_db(12.0);
+        // -40 dBFS square wave, a second of audio per packet
+        let mut quiet = packet(0.01, 1000);
+        agc.process(&mut quiet);
+        let first_gain = agc.get_gain_db();
+        assert!(first_gain > 0.0 && first_gain < 12.0);
+        for _ in 0..20 {
+            quiet = packet(0.01, 1000);
+            agc.process(&mut quiet);
+        }
+        assert!((agc.get_level_db() + 40.0).abs() < 0.1);
+        // The target needs 20 dB, the gain stops at the maximum and the output is raised by it
+        assert!((agc.get_gain_db() - 12.0).abs() < 0.1);
+        assert!((measure(&quiet).0 + 28.0).abs() < 0.1);
+
+        // Silence holds the gain
+        agc.process(&mut packet(0.0, 1000));
+        assert!((agc.get_gain_db() - 12.0).abs() < 0.1);
+
+        // A loud attack is limited from its first frame, not only once the gain caught up
+        let mut loud = packet(0.9, 1000);
+        agc.process(&mut loud);
+        assert!(measure(&loud).1 <= 1.0 + 1e-6);
+        assert!(agc.get_gain_db() <= linear_to_db(1.0 / 0.9) + 0.01);
+    }
+}
@@ -0,0 +1,273 @@
+//! Sessions grouped by their grouping param, the way the volume mixer shows them, see [`AppGroups`].
+//!
+//! An application that opens several streams, e.g. a browser with one process per tab, gives their sessions the same
+//! grouping param and the volume mixer shows one slider for all of them. Changing the volume or mute state of a single
+//! session leaves the others of the group alone, [`AppGroup`] changes all of them together. Membership follows the
+//! sessions: new sessions join their group, sessions that change their grouping param move, expired ones leave.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use log::warn;
+use thiserror::Error;
+use windows_core::GUID;
+
+use crate::event_args::{AudioSessionEventArgs, SessionState};
+use crate::manager::{AgileSession, AudioError, Session};
+use crate::notifications::NotificationError;
+use crate::session_tracker::{SessionHandler, SessionTracker};
+
+#[derive(Error, Debug)]
+pub enum AppGroupError {
+    #[error("Failed setting up session notifications: {0}")]
+    NotificationError(#[source] NotificationError),
+    #[error("Failed enumerating sessions: {0}")]
+    SessionEnumError(#[source] AudioError),
+    #[error("Failed muting session: {0}")]
+    FailedSettingMute(#[source] AudioError),
+    #[error("Failed reading session volume: {0}")]
+    FailedGettingVolume(#[source] AudioError),
+    #[error("Failed setting session volume: {0}")]
+    FailedSettingVolume(#[source] AudioError),
+    #[error("Group has no sessions left")]
+    EmptyGroup,
+}
+
+/// The sessions sharing one grouping param when the group was taken from [`AppGroups`]
+#[derive(Debug, Clone)]
+pub struct AppGroup {
+    grouping_param: GUID,
+    sessions: Vec<Session>,
+    /// Order of the oldest session
+    order: u64,
+}
+
+impl AppGroup {
+    /// Group of `members` oldest first, `None` if none of them can be resolved
+    fn from_members(grouping_param: GUID, mut members: Vec<&GroupedSession>) -> Option<Self> {
+        members.sort_by_key(|grouped| grouped.order);
+        let order = members.first()?.order;
+        let sessions: Vec<Session> = members.into_iter().filter_map(|grouped| resolve(&grouped.session)).collect();
+        (!sessions.is_empty()).then_some(Self {
+            grouping_param,
+            sessions,
+            order,
+        })
+    }
+
+    pub fn get_grouping_param(&self) -> GUID {
+        self.grouping_param
+    }
+
+    /// The sessions in the order [`AppGroups`] first saw them, oldest first
+    pub fn get_sessions(&self) -> &[Session] {
+        &self.sessions
+    }
+
+    /// Volume of the oldest session of the group, see [`AppGroup::get_sessions`] for the order
+    pub fn get_volume(&self) -> Result<f32, AppGroupError> {
+        let session = self.sessions.first().ok_or(AppGroupError::EmptyGroup)?;
+        session.get_volume().map_err(AppGroupError::FailedGettingVolume)
+    }
+
+    /// Whether every session of the group is muted
+    pub fn is_muted(&self) -> Result<bool, AppGroupError> {
+        for session in &self.sessions {
+            if !session.get_muted().map_err(AppGroupError::FailedGettingVolume)? {
+                return Ok(false);
+            }
+        }
+        Ok(!self.sessions.is_empty())
+    }
+
+    /// Sets the volume of every session from `0.0` to `1.0`
+    pub fn set_volume(&self, volume: f32) -> Result<(), AppGroupError> {
+        for session in &self.sessions {
+            session.set_volume(volume).map_err(AppGroupError::FailedSettingVolume)?;
+        }
+        Ok(())
+    }
+
+    /// Mutes or unmutes every session
+    pub fn set_muted(&self, muted: bool) -> Result<(), AppGroupError> {
+        for session in &self.sessions {
+            session.set_muted(muted).map_err(AppGroupError::FailedSettingMute)?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the sessions of every device grouped by grouping param, dropping it stops the notifications
+///
+/// Sessions without a grouping param aren't part of any group until they get one.
+pub struct AppGroups {
+    tracker: SessionTracker<Groups>,
+}
+
+/// Every watched session by name, updated by the tracker
+struct Groups {
+    sessions: Mutex<HashMap<String, GroupedSession>>,
+    /// Order of the next session that is seen
+    next_order: AtomicU64,
+}
+
+struct GroupedSession {
+    session: AgileSession,
+    grouping_param: GUID,
+    /// When the session was first seen, sessions of a group are listed in this order
+    order: u64,
+}
+
+impl AppGroups {
+    pub fn new() -> Result<Self, AppGroupError> {
+        let groups = Groups {
+            sessions: Mutex::new(HashMap::new()),
+            next_order: AtomicU64::new(0),
+        };
+        let tracker = SessionTracker::start(groups).map_err(|err| match err {
+            NotificationError::FailedEnumeratingSessions(err) => AppGroupError::SessionEnumError(err),
+            err => AppGroupError::NotificationError(err),
+        })?;
+        Ok(Self { tracker })
+    }
+
+    /// Every group with at least one session, ordered by their oldest session
+    pub fn get_groups(&self) -> Vec<AppGroup> {
+        let sessions = self.tracker.handler().lock_sessions();
+        let mut groups: HashMap<GUID, Vec<&GroupedSession>> = HashMap::new();
+        for grouped in sessions.values().filter(|grouped| grouped.grouping_param != GUID::zeroed()) {
+            groups.entry(grouped.grouping_param).or_default().push(grouped);
+        }
+        let mut groups: Vec<AppGroup> = groups
+            .into_iter()
+            .filter_map(|(grouping_param, members)| AppGroup::from_members(grouping_param, members))
+            .collect();
+        groups.sort_by_key(|group| group.order);
+        groups
+    }
+
+    pub fn get_group(&self, grouping_param: &GUID) -> Option<AppGroup> {
+        if *grouping_param == GUID::zeroed() {
+            return None;
+        }
+        let sessions = self.tracker.handler().lock_sessions();
+        let members = sessions
+            .values()
+            .filter(|grouped| grouped.grouping_param == *grouping_param)
+            .collect();
+        AppGroup::from_members(*grouping_param, members)
+    }
+
+    /// The group `session` is in right now, `None` if it has no grouping param or isn't known
+    pub fn get_group_of(&self, session: &Session) -> Option<AppGroup> {
+        let grouping_param = self.tracker.handler().lock_sessions().get(session.get_name())?.grouping_param;
+        self.get_group(&grouping_param)
+    }
+
+    // See drop implementation of the tracker for cleanup
+    pub fn stop(self) {}
+}
+
+/// The session for the calling thread, sessions that can't be resolved are left out
+fn resolve(session: &AgileSession) -> Option<Session> {
+    session
+        .resolve()
+        .inspect_err(|err| warn!("Failed resolving session {}: {}", session.get_name(), err))
+        .ok()
+}
+
+impl Groups {
+    fn lock_sessions(&self) -> MutexGuard<'_, HashMap<String, GroupedSession>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl SessionHandler for Groups {
+    fn session_added(&self, session: &Session, _created: bool) -> bool {
+        let name = session.get_name().clone();
+        let grouping_param = match session.get_grouping_param() {
+            Ok(grouping_param) => grouping_param,
+            Err(err) => {
+                warn!("Failed getting grouping param of {}: {}", name, err);
+                GUID::zeroed()
+            }
+        };
+        let session = match AgileSession::new(session) {
+            Ok(session) => session,
+            Err(err) => {
+                warn!("Failed watching session {}: {}", name, err);
+                return false;
+            }
+        };
+        let order = self.next_order.fetch_add(1, Ordering::Relaxed);
+        self.lock_sessions().insert(
+            name,
+            GroupedSession {
+                session,
+                grouping_param,
+                order,
+            },
+        );
+        true
+    }
+
+    fn session_event(&self, name: &str, event: AudioSessionEventArgs) {
+        let mut sessions = self.lock_sessions();
+        match event {
+            AudioSessionEventArgs::GroupingParamChanged(args) => {
+                if let Some(grouped) = sessions.get_mut(name) {
+                    grouped.grouping_param = args.get_grouping_param().unwrap_or(GUID::zeroed());
+                }
+            }
+            AudioSessionEventArgs::StateChanged(args) if matches!(args.get_state(), SessionState::AudioSessionStateExpired) => {
+                sessions.remove(name);
+            }
+            AudioSessionEventArgs::SessionDisconnected(_) => {
+                sessions.remove(name);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::audio_client::AudioClient;
+    use crate::manager::SessionManager;
+
+    #[test]
+    fn follow_grouping_param() {
+        let (playback, _format) = AudioClient::new().start_playback_device(None, |_| 0, |_| {}).unwrap();
+        let _playback = playback.start().unwrap();
+        let session = SessionManager::get_sessions()
+            .unwrap()
+            .into_iter()
+            .find(|session| *session.get_pid() == std::process::id())
+            .unwrap();
+        let groups = AppGroups::new().unwrap();
+        let original = session.get_grouping_param().unwrap();
+
+        let grouping_param = GUID::new().unwrap();
+        session.set_grouping_param(&grouping_param).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let group = loop {
+            if let Some(group) = groups.get_group(&grouping_param) {
+                break group;
+            }
+            assert!(Instant::now() < deadline, "grouping param change not seen");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(group.get_sessions(), std::slice::from_ref(&session));
+        assert_eq!(groups.get_group_of(&session).unwrap().get_grouping_param(), grouping_param);
+
+        let muted = session.get_muted().unwrap();
+        group.set_muted(true).unwrap();
+        assert!(group.is_muted().unwrap());
+        group.set_muted(muted).unwrap();
+        session.set_grouping_param(&original).unwrap();
+    }
+}
@@ -0,0 +1,334 @@
+//! Async front-end for capture and playback streams, see [`AsyncCaptureStream`] and [`AsyncPlaybackSink`].
+//!
+//! The streams still run on their own thread, the front-end only hands packets between that thread and a task through a
+//! bounded queue. Neither side waits on the other: a capture stream drops the oldest packets when the task falls behind,
+//! a playback stream plays silence when the task can't keep the queue filled.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+use futures_core::Stream;
+use futures_sink::Sink;
+
+use crate::audio_client::AudioClientError;
+use crate::audio_stream::{AudioStream, AudioStreamConfig, CapturePacket, OwnedCapturePacket, RenderRequest};
+use crate::sample_format::SampleFormat;
+
+/// Packets an [`AsyncCaptureStream`] holds before it drops the oldest
+const CAPTURE_QUEUE_LEN: usize = 256;
+/// Milliseconds of audio an [`AsyncPlaybackSink`] accepts before `poll_ready` waits for the stream to catch up
+const PLAYBACK_QUEUE_MS: u32 = 200;
+
+#[derive(Default)]
+struct CaptureState {
+    packets: VecDeque<OwnedCapturePacket>,
+    error: Option<AudioClientError>,
+    dropped: u64,
+    waker: Option<Waker>,
+}
+
+/// Queue between the capture callback and an [`AsyncCaptureStream`]
+#[derive(Clone, Default)]
+pub(crate) struct CaptureQueue {
+    state: Arc<Mutex<CaptureState>>,
+}
+
+impl CaptureQueue {
+    fn lock(&self) -> MutexGuard<'_, CaptureState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn data_callback(&self) -> impl FnMut(CapturePacket) + Send + use<> {
+        let queue = self.clone();
+        move |packet| {
+            let mut state = queue.lock();
+            if state.packets.len() == CAPTURE_QUEUE_LEN {
+                state.packets.pop_front();
+                state.dropped += 1;
+            }
+            state.packets.push_back(packet.to_owned());
+            let waker = state.waker.take();
+            drop(state);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+
+    pub(crate) fn error_callback(&self) -> impl FnMut(AudioClientError) + Send + use<> {
+        let queue = self.clone();
+        move |err| {
+            let mut state = queue.lock();
+            state.error.get_or_insert(err);
+            let waker = state.waker.take();
+            drop(state);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Captured packets as a [`Stream`], see [`AudioClient::capture_stream_async`](crate::audio_client::AudioClient::capture_stream_async)
+///
+/// A stream error is yielded after the packets captured before it, and ends the stream. Dropping the stream stops the
+/// capture.
+pub struct AsyncCaptureStream {
+    queue: CaptureQueue,
+    stream: AudioStream,
+    format: SampleFormat,
+    ended: bool,
+}
+
+impl AsyncCaptureStream {
+    pub(crate) fn start(config: AudioStreamConfig, queue: CaptureQueue) -> Result<Self, AudioClientError> {
+        let format = config.format().clone();
+        Ok(Self {
+            queue,
+            stream: config.start()?,
+            format,
+            ended: false,
+        })
+    }
+
+    /// Format of the packet data
+    pub fn format(&self) -> &SampleFormat {
+        &self.format
+    }
+
+    /// The running stream, e.g. for [`AudioStream::set_muted`]
+    pub fn audio_stream(&self) -> &AudioStream {
+        &self.stream
+    }
+
+    /// Number of packets dropped because they weren't polled in time
+    pub fn dropped_packets(&self) -> u64 {
+        self.queue.lock().dropped
+    }
+}
+
+impl Stream for AsyncCaptureStream {
+    type Item = Result<OwnedCapturePacket, AudioClientError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.ended {
+            return Poll::Ready(None);
+        }
+        let mut state = this.queue.lock();
+        if let Some(packet) = state.packets.pop_front() {
+            return Poll::Ready(Some(Ok(packet)));
+        }
+        if let Some(err) = state.error.take() {
+            drop(state);
+            this.ended = true;
+            return Poll::Ready(Some(Err(err)));
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[derive(Default)]
+struct PlaybackState {
+    data: VecDeque<u8>,
+    error: Option<AudioClientError>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+/// Queue between an [`AsyncPlaybackSink`] and the playback callback
+#[derive(Clone, Default)]
+pub(crate) struct PlaybackQueue {
+    state: Arc<Mutex<PlaybackState>>,
+}
+
+impl PlaybackQueue {
+    fn lock(&self) -> MutexGuard<'_, PlaybackState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn data_callback(&self, format: &SampleFormat) -> impl FnMut(RenderRequest) -> u32 + Send + use<> {
+        let queue = self.clone();
+        let block_align = format.block_align().max(1) as usize;
+        move |mut request| {
+            let mut state = queue.lock();
+            let frames = (state.data.len() / block_align).min(request.frames() as usize);
+            let len = frames * block_align;
+            for (dst, src) in request.buffer()[..len].iter_mut().zip(state.data.drain(..len)) {
+                *dst = src;
+            }
+            if state.closed && state.data.len() < block_align {
+                request.finish();
+            }
+            let waker = if len > 0 { state.waker.take() } else { None };
+            drop(state);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+            frames as u32
+        }
+    }
+
+    pub(crate) fn error_callback(&self) -> impl FnMut(AudioClientError) + Send + use<> {
+        let queue = self.clone();
+        move |err| {
+            let mut state = queue.lock();
+            state.error.get_or_insert(err);
+            let waker = state.waker.take();
+            drop(state);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Plays interleaved sample bytes sent into it, see
+/// [`AudioClient::playback_sink_async`](crate::audio_client::AudioClient::playback_sink_async)
+///
+/// Items can hold any number of bytes, a partial frame waits for the rest of its bytes. `poll_flush` resolves once the
+/// stream took everything sent so far, `poll_close` additionally ends the stream after it. Dropping the sink stops the
+/// playback right away.
+pub struct AsyncPlaybackSink {
+    queue: PlaybackQueue,
+    stream: AudioStream,
+    format: SampleFormat,
+    capacity: usize,
+}
+
+impl AsyncPlaybackSink {
+    pub(crate) fn start(config: AudioStreamConfig, format: SampleFormat, queue: PlaybackQueue) -> Result<Self, AudioClientError> {
+        let capacity = (format.get_n_samples_per_sec() * PLAYBACK_QUEUE_MS / 1000) as usize * format.block_align() as usize;
+        Ok(Self {
+            queue,
+            stream: config.start()?,
+            format,
+            capacity,
+        })
+    }
+
+    /// Format the sent bytes have to be in
+    pub fn format(&self) -> &SampleFormat {
+        &self.format
+    }
+
+    pub fn audio_stream(&self) -> &AudioStream {
+        &self.stream
+    }
+
+    /// Waits until the queue holds at most `max_len` bytes, `0` waits for it to run empty
+    fn poll_drained(&self, cx: &mut Context<'_>, max_len: usize) -> Poll<Result<(), AudioClientError>> {
+        let mut state = self.queue.lock();
+        if let Some(err) = state.error.clone() {
+            return Poll::Ready(Err(err));
+        }
+        if state.data.len() <= max_len {
+            return Poll::Ready(Ok(()));
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Sink<Vec<u8>> for AsyncPlaybackSink {
+    type Error = AudioClientError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_drained(cx, self.capacity.saturating_sub(1))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        let mut state = self.queue.lock();
+        if let Some(err) = state.error.clone() {
+            return Err(err);
+        }
+        state.data.extend(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // A trailing partial frame is never played, it doesn't hold up the flush
+        self.poll_drained(cx, self.format.block_align().max(1) as usize - 1)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.queue.lock().closed = true;
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::Wake;
+    use std::thread::{self, Thread};
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::audio_client::AudioClient;
+    use crate::capture_target::CaptureTarget;
+
+    struct ThreadWaker {
+        thread: Thread,
+        woken: AtomicBool,
+    }
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.woken.store(true, Ordering::Release);
+            self.thread.unpark();
+        }
+    }
+
+    /// Polls `future` on the test thread, without pulling in an executor
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let waker = Arc::new(ThreadWaker {
+            thread: thread::current(),
+            woken: AtomicBool::new(false),
+        });
+        let context_waker = Waker::from(waker.clone());
+        let mut cx = Context::from_waker(&context_waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            while !waker.woken.swap(false, Ordering::Acquire) {
+                thread::park_timeout(Duration::from_millis(100));
+            }
+        }
+    }
+
+    #[test]
+    fn capture_and_playback() {
+        let mut capture = AudioClient::new().capture_stream_async(&CaptureTarget::DefaultLoopback).unwrap();
+        let mut sink = AudioClient::new().playback_sink_async(None, None).unwrap();
+        let silence = vec![0u8; sink.format().block_align() as usize * 4800];
+
+        // Three items of 100 ms don't fit into the queue at once, the last one waits for the stream
+        let started = Instant::now();
+        let mut sent = 0;
+        let played = block_on(std::future::poll_fn(|cx| {
+            while sent < 3 {
+                match Pin::new(&mut sink).poll_ready(cx) {
+                    Poll::Ready(ready) => ready.unwrap(),
+                    Poll::Pending => return Poll::Pending,
+                }
+                Pin::new(&mut sink).start_send(silence.clone()).unwrap();
+                sent += 1;
+            }
+            Pin::new(&mut sink).poll_close(cx)
+        }));
+        assert!(played.is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(150));
+
+        let packet = block_on(std::future::poll_fn(|cx| Pin::new(&mut capture).poll_next(cx)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(packet.data().len() % capture.format().block_align() as usize, 0);
+    }
+}
@@ -1,18 +1,51 @@
-use crate::audio_stream::CapturePacket;
+use crate::activation_retry::{ActivationRetry, is_transient_activation_error};
+#[cfg(feature = "async")]
+use crate::async_stream::{AsyncCaptureStream, AsyncPlaybackSink, CaptureQueue, PlaybackQueue};
+use crate::audio_stream::{CapturePacket, CurrentStream, PlaybackControl, RenderRequest, SamplePacket, StreamId};
+use crate::block_processor::{BlockInput, BlockProcessor, InputQueue};
+use crate::capture_options::{CaptureOptions, ChannelFallback, OUTPUT_SAMPLE_RATES, RateMismatchPolicy};
+use crate::capture_reader::{CaptureReader, CaptureRing};
+use crate::capture_registry::{ActiveCaptures, DuplicateCapturePolicy, FanOut, ProcessCapture, StartingCapture};
+use crate::capture_target::CaptureTarget;
+use crate::convert::{Sample, borrow_samples, borrow_samples_mut, is_convertible, is_native};
+#[cfg(feature = "notifications")]
+use crate::default_loopback::{FollowingLoopback, FollowingLoopbackError};
+use crate::device_query::DataFlow;
+use crate::device_state::DeviceState;
+use crate::engine_period;
+use crate::hooks::{Hooks, global_hooks};
 use crate::manager::DeviceEnumError;
-use crate::{activation_params::SafeActivationParams, audio_stream::AudioStreamConfig, sample_format::SampleFormat};
+use crate::manager::{DeviceManager, FormatSupport};
+use crate::playback_options::{OutputSwitched, PlaybackOptions};
+use crate::preflight::{Preflight, PreflightIssue};
+#[cfg(feature = "notifications")]
+use crate::session_capture::{SessionCaptureError, SessionCaptureManager, SessionFilter, SessionPacket};
+use crate::stream_category::StreamCategory;
+use crate::stream_recovery::ReopenFn;
+use crate::{
+    activation_params::SafeActivationParams,
+    audio_stream::AudioStreamConfig,
+    sample_format::{SampleFormat, WaveFormat},
+};
 use crate::{com::com_initialized, manager::Device};
-use log::error;
-use std::{fmt::Display, ops::Deref, sync::Arc};
+use log::{debug, error, warn};
+use std::{
+    collections::VecDeque,
+    io,
+    ops::Deref,
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
 use thiserror::Error;
 use windows::Win32::System::Com::StringFromIID;
 use windows::{
     Win32::{
-        Foundation::{self, CloseHandle, HANDLE, WAIT_EVENT, WAIT_FAILED, WIN32_ERROR},
+        Foundation::{self, CloseHandle, E_ACCESSDENIED, HANDLE, WAIT_EVENT, WAIT_FAILED, WIN32_ERROR},
         Media::Audio::*,
         System::{
             Com::{self, CoTaskMemFree, StructuredStorage::PROPVARIANT},
-            Threading::{CreateEventW, INFINITE, SetEvent, WaitForSingleObject},
+            Threading::{CreateEventW, INFINITE, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION, SetEvent, WaitForSingleObject},
         },
     },
     core::{GUID, HRESULT, IUnknown, Interface},
@@ -21,29 +54,169 @@ use windows_core::{PWSTR, implement};
 
 #[derive(Error, Debug, Clone)]
 pub enum AudioClientError {
-    FailedToCreateStopEvent(windows_core::Error),
-    FailedToSetupEventHandle(windows_core::Error),
-    FailedToStartAudioClient(windows_core::Error),
+    #[error("Failed creating stop event: {0}")]
+    FailedToCreateStopEvent(#[source] windows_core::Error),
+    #[error("Failed setting up event handle: {0}")]
+    FailedToSetupEventHandle(#[source] windows_core::Error),
+    #[error("Failed starting audio client: {0}")]
+    FailedToStartAudioClient(#[source] windows_core::Error),
+    #[error("Wait failed: {0:?}")]
     WaitFailed(WIN32_ERROR),
-    FailedGettingBuffer(windows_core::Error),
-    FailedReleasingBuffer(windows_core::Error),
-    FailedStoppingAudioClient(windows_core::Error),
-    FailedResettingAudioClient(windows_core::Error),
+    #[error("Failed getting buffer: {0}")]
+    FailedGettingBuffer(#[source] windows_core::Error),
+    #[error("Failed releasing buffer: {0}")]
+    FailedReleasingBuffer(#[source] windows_core::Error),
+    #[error("Failed stopping audio client: {0}")]
+    FailedStoppingAudioClient(#[source] windows_core::Error),
+    #[error("Failed resetting audio client: {0}")]
+    FailedResettingAudioClient(#[source] windows_core::Error),
+    #[error("Device is not an input device")]
     NotInputDevice,
+    #[error("Device is not a playback device")]
     NotPlaybackDevice,
+    #[error("Recording already started")]
     RecordingAlreadyStarted,
+    #[error("Failed getting activation result")]
     FailedGettingActivationResult,
-    EventCreationError(windows_core::Error),
-    DeviceEnumError(DeviceEnumError),
-    FailedToGetMixFormat(windows_core::Error),
+    #[error("Failed creating event: {0}")]
+    EventCreationError(#[source] windows_core::Error),
+    #[error("Device enumeration error: {0}")]
+    DeviceEnumError(#[source] DeviceEnumError),
+    #[error("Failed getting mix format: {0}")]
+    FailedToGetMixFormat(#[source] windows_core::Error),
+    #[error("Failed creating thread")]
     FailedToCreateThread,
+    #[error("Stream already started")]
     StreamAlreadyStarted,
-    FailedToGetAudioClock(windows_core::Error),
+    #[error("Failed getting audio clock: {0}")]
+    FailedToGetAudioClock(#[source] windows_core::Error),
+    #[error("Failed getting stream latency: {0}")]
+    FailedGettingLatency(#[source] windows_core::Error),
+    #[error("Unsupported sample format: {0}")]
+    UnsupportedFormat(SampleFormat),
+    #[error("Can't convert from {0} to {1}")]
+    UnsupportedConversion(SampleFormat, SampleFormat),
+    #[error("Requested {0}Hz but the source runs at {1}Hz")]
+    SampleRateMismatch(u32, u32),
+    #[error("Data callback panicked")]
+    CallbackPanicked,
+    /// Raised by streams with [`DeadlineMode::abort_after`](crate::deadline::DeadlineMode::abort_after)
+    #[error("Callback missed its deadline {0} times in a row")]
+    DeadlinesMissed(u32),
+    #[error("Failed setting client properties: {0}")]
+    FailedSettingClientProperties(#[source] windows_core::Error),
+    #[error("Failed getting service: {0}")]
+    FailedGettingService(#[source] windows_core::Error),
+    #[error("Failed registering for events of the stream's session: {0}")]
+    FailedWatchingSession(#[source] windows_core::Error),
+    #[error("Failed adjusting sample rate: {0}")]
+    FailedAdjustingSampleRate(#[source] windows_core::Error),
+    #[error("Failed activating audio client: {0}")]
+    ActivationFailure(#[source] windows_core::Error),
+    #[error("Activation still failing after {0} attempts: {1}")]
+    TransientActivationFailure(u32, #[source] windows_core::Error),
+    #[error("Process {0} is already being captured")]
+    DuplicateCapture(u32),
+    #[error("Not a capture stream")]
+    NotCaptureStream,
+    #[error("Failed setting session properties: {0}")]
+    FailedSettingSessionProperties(#[source] windows_core::Error),
+    #[error("Buffer duration of {0:?} outside of the supported {1:?} to {2:?}")]
+    BufferDurationOutOfRange(Duration, Duration, Duration),
+    #[error("Block size must be at least one frame")]
+    EmptyBlockSize,
+    #[error("Output sample rate of {0}Hz outside of the supported {1}Hz to {2}Hz")]
+    OutputSampleRateOutOfRange(u32, u32, u32),
+    /// What the error callback of a stream gets, see [`AudioClientError::stream_id`]
+    #[error("Stream {0} failed: {1}")]
+    InStream(StreamId, #[source] Box<AudioClientError>),
+}
+
+impl AudioClientError {
+    /// The underlying windows error, if the error originates from a failed windows call
+    pub fn windows_error(&self) -> Option<&windows_core::Error> {
+        match self {
+            AudioClientError::FailedToCreateStopEvent(err)
+            | AudioClientError::FailedToSetupEventHandle(err)
+            | AudioClientError::FailedToStartAudioClient(err)
+            | AudioClientError::FailedGettingBuffer(err)
+            | AudioClientError::FailedReleasingBuffer(err)
+            | AudioClientError::FailedStoppingAudioClient(err)
+            | AudioClientError::FailedResettingAudioClient(err)
+            | AudioClientError::EventCreationError(err)
+            | AudioClientError::FailedToGetMixFormat(err)
+            | AudioClientError::FailedToGetAudioClock(err)
+            | AudioClientError::FailedGettingLatency(err)
+            | AudioClientError::FailedSettingClientProperties(err)
+            | AudioClientError::FailedSettingSessionProperties(err)
+            | AudioClientError::FailedGettingService(err)
+            | AudioClientError::FailedWatchingSession(err)
+            | AudioClientError::FailedAdjustingSampleRate(err)
+            | AudioClientError::ActivationFailure(err)
+            | AudioClientError::TransientActivationFailure(_, err) => Some(err),
+            AudioClientError::DeviceEnumError(err) => err.windows_error(),
+            AudioClientError::InStream(_, err) => err.windows_error(),
+            _ => None,
+        }
+    }
+
+    /// The stream the error was raised on, set for the errors passed to the error callback of a stream
+    pub fn stream_id(&self) -> Option<StreamId> {
+        match self {
+            AudioClientError::InStream(id, _) => Some(*id),
+            _ => None,
+        }
+    }
+
+    /// The error without the stream it was raised on, to match on what went wrong
+    pub fn without_stream(&self) -> &AudioClientError {
+        match self {
+            AudioClientError::InStream(_, err) => err.without_stream(),
+            err => err,
+        }
+    }
+
+    /// The HRESULT of the failed windows call, e.g. to check for `AUDCLNT_E_DEVICE_INVALIDATED`
+    pub fn hresult(&self) -> Option<HRESULT> {
+        match self.without_stream() {
+            AudioClientError::WaitFailed(err) => Some(err.to_hresult()),
+            _ => self.windows_error().map(|err| err.code()),
+        }
+    }
+}
+
+/// The [`io::ErrorKind`] closest to a failed windows call, `Other` for everything audio specific
+pub(crate) fn io_error_kind(hresult: Option<HRESULT>) -> io::ErrorKind {
+    match hresult {
+        Some(AUDCLNT_E_DEVICE_INVALIDATED | AUDCLNT_E_SERVICE_NOT_RUNNING) => io::ErrorKind::NotConnected,
+        Some(AUDCLNT_E_DEVICE_IN_USE) => io::ErrorKind::ResourceBusy,
+        Some(AUDCLNT_E_UNSUPPORTED_FORMAT) => io::ErrorKind::Unsupported,
+        Some(E_ACCESSDENIED) => io::ErrorKind::PermissionDenied,
+        Some(Foundation::E_OUTOFMEMORY) => io::ErrorKind::OutOfMemory,
+        Some(hresult) if hresult == Foundation::ERROR_NOT_FOUND.to_hresult() => io::ErrorKind::NotFound,
+        _ => io::ErrorKind::Other,
+    }
 }
 
-impl Display for AudioClientError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Recording error: {:?}", self)
+/// Lets stream errors pass through code built on [`io::Read`] and [`io::Write`], the original error stays reachable
+/// through [`io::Error::get_ref`]
+impl From<AudioClientError> for io::Error {
+    fn from(err: AudioClientError) -> Self {
+        let kind = match err.without_stream() {
+            AudioClientError::UnsupportedFormat(_)
+            | AudioClientError::UnsupportedConversion(..)
+            | AudioClientError::SampleRateMismatch(..) => io::ErrorKind::Unsupported,
+            AudioClientError::NotInputDevice
+            | AudioClientError::NotPlaybackDevice
+            | AudioClientError::NotCaptureStream
+            | AudioClientError::BufferDurationOutOfRange(..)
+            | AudioClientError::OutputSampleRateOutOfRange(..)
+            | AudioClientError::EmptyBlockSize => io::ErrorKind::InvalidInput,
+            AudioClientError::DuplicateCapture(_) => io::ErrorKind::ResourceBusy,
+            AudioClientError::DeadlinesMissed(_) => io::ErrorKind::TimedOut,
+            _ => io_error_kind(err.hresult()),
+        };
+        io::Error::new(kind, err)
     }
 }
 
@@ -77,7 +250,7 @@ impl Drop for PWSTRWrapper {
 pub struct WaveFormatWrapper(*mut WAVEFORMATEX);
 
 impl WaveFormatWrapper {
-    fn from_ptr(ptr: *mut WAVEFORMATEX) -> Self {
+    pub(crate) fn from_ptr(ptr: *mut WAVEFORMATEX) -> Self {
         Self(ptr)
     }
 }
@@ -99,14 +272,37 @@ impl Drop for WaveFormatWrapper {
 }
 
 const BUFFER_DURATION_MS: u32 = 20;
+/// Longest buffer a shared mode stream can be initialized with
+const MAX_BUFFER_DURATION: Duration = Duration::from_secs(2);
 
+#[derive(Clone)]
 pub struct AudioClient {
     format: Option<SampleFormat>,
+    capture_options: CaptureOptions,
+    playback_options: PlaybackOptions,
+    category: Option<StreamCategory>,
+    rate_adjust: bool,
+    activation_retry: ActivationRetry,
+    duplicate_capture_policy: DuplicateCapturePolicy,
+    low_latency: bool,
+    buffer_duration: Option<Duration>,
+    hooks: Option<Arc<dyn Hooks>>,
 }
 
 impl AudioClient {
     pub fn new() -> Self {
-        Self { format: None }
+        Self {
+            format: None,
+            capture_options: CaptureOptions::default(),
+            playback_options: PlaybackOptions::default(),
+            category: None,
+            rate_adjust: false,
+            activation_retry: ActivationRetry::default(),
+            duplicate_capture_policy: DuplicateCapturePolicy::default(),
+            low_latency: false,
+            buffer_duration: None,
+            hooks: None,
+        }
     }
 
     pub fn set_format(&mut self, format: SampleFormat) -> Result<(), AudioClientError> {
@@ -118,9 +314,237 @@ impl AudioClient {
         self.format.clone()
     }
 
+    /// Options applied to every capture stream started by this client
+    pub fn set_capture_options(&mut self, options: CaptureOptions) {
+        self.capture_options = options;
+    }
+
+    pub fn get_capture_options(&self) -> &CaptureOptions {
+        &self.capture_options
+    }
+
+    /// Shorthand for setting [`CaptureOptions::output_sample_rate`] on the current capture options
+    pub fn set_output_sample_rate(&mut self, sample_rate: u32) {
+        self.capture_options = self.capture_options.clone().output_sample_rate(sample_rate);
+    }
+
+    /// Options applied to every playback stream started by this client
+    pub fn set_playback_options(&mut self, options: PlaybackOptions) {
+        self.playback_options = options;
+    }
+
+    pub fn get_playback_options(&self) -> &PlaybackOptions {
+        &self.playback_options
+    }
+
+    /// Category reported to the audio engine for every stream started by this client
+    pub fn set_stream_category(&mut self, category: StreamCategory) {
+        self.category = Some(category);
+    }
+
+    pub fn get_stream_category(&self) -> Option<StreamCategory> {
+        self.category
+    }
+
+    /// Initializes streams with `AUDCLNT_STREAMFLAGS_RATEADJUST`, needed by [`AudioStream::set_sample_rate`](crate::audio_stream::AudioStream::set_sample_rate)
+    pub fn set_rate_adjust(&mut self, rate_adjust: bool) {
+        self.rate_adjust = rate_adjust;
+    }
+
+    pub fn get_rate_adjust(&self) -> bool {
+        self.rate_adjust
+    }
+
+    /// How often device and process loopback activation is retried when it fails with a transient error, e.g.
+    /// right after the device was plugged in
+    pub fn set_activation_retry(&mut self, retry: ActivationRetry) {
+        self.activation_retry = retry;
+    }
+
+    pub fn get_activation_retry(&self) -> ActivationRetry {
+        self.activation_retry
+    }
+
+    /// What starting a process capture does while this process already captures the same process
+    pub fn set_duplicate_capture_policy(&mut self, policy: DuplicateCapturePolicy) {
+        self.duplicate_capture_policy = policy;
+    }
+
+    pub fn get_duplicate_capture_policy(&self) -> DuplicateCapturePolicy {
+        self.duplicate_capture_policy
+    }
+
+    /// Size of the buffer of every stream started by this client. By default capture streams get 20 ms and playback
+    /// streams the smallest buffer the engine allows. A longer buffer survives longer stalls of the callback at the cost
+    /// of latency.
+    ///
+    /// Fails above the 2 s shared mode streams allow, durations below the minimum period of the device make starting a
+    /// stream fail with [`AudioClientError::BufferDurationOutOfRange`]. Low latency streams size their buffer from the
+    /// engine period instead.
+    pub fn set_buffer_duration(&mut self, duration: Duration) -> Result<(), AudioClientError> {
+        if duration.is_zero() || duration > MAX_BUFFER_DURATION {
+            return Err(AudioClientError::BufferDurationOutOfRange(
+                duration,
+                Duration::ZERO,
+                MAX_BUFFER_DURATION,
+            ));
+        }
+        self.buffer_duration = Some(duration);
+        Ok(())
+    }
+
+    pub fn get_buffer_duration(&self) -> Option<Duration> {
+        self.buffer_duration
+    }
+
+    /// Runs device streams at the smallest engine period the device supports for the format, see
+    /// [`Device::get_engine_periods`]. Devices without `IAudioClient3` support, formats the engine doesn't run at and
+    /// loopback streams keep the default period.
+    pub fn with_low_latency(mut self, low_latency: bool) -> Self {
+        self.low_latency = low_latency;
+        self
+    }
+
+    pub fn get_low_latency(&self) -> bool {
+        self.low_latency
+    }
+
+    /// Hooks for the streams started by this client, instead of the global ones, see [`crate::hooks`]
+    pub fn set_hooks(&mut self, hooks: impl Hooks) {
+        self.hooks = Some(Arc::new(hooks));
+    }
+
+    fn hooks(&self) -> Option<Arc<dyn Hooks>> {
+        self.hooks.clone().or_else(global_hooks)
+    }
+
+    /// Start recording every process of a UWP or MSIX package, e.g. `Microsoft.ZuneMusic_8wekyb3d8bbwe`
+    /// The processes are found through their audio sessions, so processes the package starts later are captured too.
+    /// Every process gets its own stream in the client's format, see [`SessionCaptureManager`].
+    #[cfg(feature = "notifications")]
+    pub fn start_recording_package<D, E>(
+        self,
+        package_family_name: &str,
+        data_callback: D,
+        error_callback: E,
+    ) -> Result<SessionCaptureManager, SessionCaptureError>
+    where
+        D: Fn(SessionPacket) + Send + Sync + 'static,
+        E: Fn(u32, AudioClientError) + Send + Sync + 'static,
+    {
+        SessionCaptureManager::start(
+            SessionFilter::PackageFamilyName(package_family_name.to_string()),
+            self.format.unwrap_or_default(),
+            data_callback,
+            error_callback,
+        )
+    }
+
     /// Start recording audio from a process
-    pub fn start_recording_process<D, E>(
-        mut self,
+    /// Fails with [`AudioClientError::DuplicateCapture`] if the process is already captured, unless the
+    /// [`DuplicateCapturePolicy`] allows duplicates
+    pub fn start_recording_process<D, E>(self, pid: u32, data_callback: D, error_callback: E) -> Result<AudioStreamConfig, AudioClientError>
+    where
+        D: FnMut(CapturePacket) + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        let mut captures = ActiveCaptures::lock(pid);
+        if self.duplicate_capture_policy != DuplicateCapturePolicy::AllowDuplicates && captures.find(pid).is_some() {
+            return Err(AudioClientError::DuplicateCapture(pid));
+        }
+        self.create_process_capture(captures.start(pid), data_callback, error_callback)
+    }
+
+    /// Same as [`AudioClient::start_recording_process`], but with [`DuplicateCapturePolicy::ReuseExisting`] the callbacks
+    /// subscribe to a running capture of the process instead of failing
+    pub fn start_recording_process_shared<D, E>(
+        self,
+        pid: u32,
+        data_callback: D,
+        error_callback: E,
+    ) -> Result<ProcessCapture, AudioClientError>
+    where
+        D: FnMut(CapturePacket) + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        let mut captures = ActiveCaptures::lock(pid);
+        match (self.duplicate_capture_policy, captures.find(pid)) {
+            (DuplicateCapturePolicy::ReuseExisting, Some(capture)) => {
+                Ok(ProcessCapture::Shared(capture.subscribe(data_callback, error_callback)))
+            }
+            (DuplicateCapturePolicy::Error, Some(_)) => Err(AudioClientError::DuplicateCapture(pid)),
+            _ => self
+                .create_process_capture(captures.start(pid), data_callback, error_callback)
+                .map(|config| ProcessCapture::Started(Box::new(config))),
+        }
+    }
+
+    /// Creates a process loopback stream and registers it, other threads capturing the same process wait until `starting`
+    /// is dropped at the end
+    fn create_process_capture<D, E>(
+        self,
+        starting: StartingCapture,
+        data_callback: D,
+        error_callback: E,
+    ) -> Result<AudioStreamConfig, AudioClientError>
+    where
+        D: FnMut(CapturePacket) + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        let pid = starting.pid();
+        let (audio_client, out_format, deliver_as) =
+            self.initialize_process_loopback(pid, PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE)?;
+        let delivered_format = deliver_as.clone().unwrap_or_else(|| out_format.clone());
+        let registration = starting.register(delivered_format, self.duplicate_capture_policy);
+        let reopen = self.process_reopener(pid, PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE, &out_format);
+        AudioStreamConfig::create_capture_stream(
+            data_callback,
+            error_callback,
+            audio_client,
+            Some(out_format),
+            deliver_as,
+            self.hooks(),
+            self.capture_options.get_monitor(),
+        )
+        .map(|config| config.with_reopen(reopen).with_registration(registration))
+    }
+
+    /// Opens the client a capture stream switches to when it moves to process `pid`, see [`AudioStream::switch_target`]
+    /// A registered capture is checked for duplicates like [`AudioClient::start_recording_process`] with the policy of the
+    /// client that started it and moves its registration to `pid`. Any other stream is registered for `pid` delivering
+    /// `delivered_format`, with [`DuplicateCapturePolicy::AllowDuplicates`].
+    pub(crate) fn switch_process_capture(
+        &self,
+        registration: &OnceLock<Arc<FanOut>>,
+        pid: u32,
+        format: &SampleFormat,
+        delivered_format: &SampleFormat,
+    ) -> Result<IAudioClient, AudioClientError> {
+        let mut captures = ActiveCaptures::lock(pid);
+        if let Some(registered) = registration.get()
+            && registered.get_policy() != DuplicateCapturePolicy::AllowDuplicates
+            && captures.find(pid).is_some_and(|capture| !Arc::ptr_eq(&capture, registered))
+        {
+            return Err(AudioClientError::DuplicateCapture(pid));
+        }
+        let starting = captures.start(pid);
+        let audio_client = self.open_capture_target(&CaptureTarget::Process(pid), format)?;
+        match registration.get() {
+            Some(registered) => registered.set_pid(pid),
+            None => {
+                let _ = registration.set(starting.register(delivered_format.clone(), DuplicateCapturePolicy::AllowDuplicates));
+            }
+        }
+        Ok(audio_client)
+    }
+
+    /// Start recording the audio of every process except the process tree rooted at `pid`
+    /// Unlike [`AudioClient::start_recording_process`], these captures aren't checked for duplicates
+    ///
+    /// Passing [`std::process::id`] records the system audio without the application's own output.
+    #[doc(alias = "start_recording_all_except_process")]
+    pub fn start_recording_process_excluding<D, E>(
+        self,
         pid: u32,
         data_callback: D,
         error_callback: E,
@@ -129,27 +553,273 @@ impl AudioClient {
         D: FnMut(CapturePacket) + Send + 'static,
         E: FnMut(AudioClientError) + Send + 'static,
     {
+        let (audio_client, out_format, deliver_as) =
+            self.initialize_process_loopback(pid, PROCESS_LOOPBACK_MODE_EXCLUDE_TARGET_PROCESS_TREE)?;
+        let reopen = self.process_reopener(pid, PROCESS_LOOPBACK_MODE_EXCLUDE_TARGET_PROCESS_TREE, &out_format);
+        AudioStreamConfig::create_capture_stream(
+            data_callback,
+            error_callback,
+            audio_client,
+            Some(out_format),
+            deliver_as,
+            self.hooks(),
+            self.capture_options.get_monitor(),
+        )
+        .map(|config| config.with_reopen(reopen))
+    }
+
+    /// Starts capturing `target` with `options`, for callers that keep what to capture as data instead of picking a method
+    /// `options` replace the capture options of the client, the format applies the same as with the method for the
+    /// target, e.g. [`AudioClient::start_recording_device`] for [`CaptureTarget::InputDevice`].
+    pub fn start_capture<D, E>(
+        mut self,
+        target: &CaptureTarget,
+        options: CaptureOptions,
+        data_callback: D,
+        error_callback: E,
+    ) -> Result<AudioStreamConfig, AudioClientError>
+    where
+        D: FnMut(CapturePacket) + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        self.capture_options = options;
+        self.capture(target, data_callback, error_callback)
+    }
+
+    /// Starts capturing `target` with the capture options of the client
+    pub(crate) fn capture<D, E>(
+        self,
+        target: &CaptureTarget,
+        data_callback: D,
+        error_callback: E,
+    ) -> Result<AudioStreamConfig, AudioClientError>
+    where
+        D: FnMut(CapturePacket) + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        match target {
+            CaptureTarget::InputDevice(_) | CaptureTarget::DefaultInput => {
+                self.start_recording_device(target.device(), data_callback, error_callback)
+            }
+            CaptureTarget::LoopbackDevice(_) | CaptureTarget::DefaultLoopback => {
+                self.start_recording_loopback_device(target.device(), data_callback, error_callback)
+            }
+            CaptureTarget::Process(pid) => self.start_recording_process(*pid, data_callback, error_callback),
+            CaptureTarget::ProcessTreeExclude(pid) => self.start_recording_process_excluding(*pid, data_callback, error_callback),
+        }
+    }
+
+    /// Starts capturing `target` into a [`Stream`](futures_core::Stream) of packets, for async code instead of a callback
+    /// The format and capture options of the client apply the same as with the method for the target.
+    #[cfg(feature = "async")]
+    pub fn capture_stream_async(self, target: &CaptureTarget) -> Result<AsyncCaptureStream, AudioClientError> {
+        let queue = CaptureQueue::default();
+        let config = self.capture(target, queue.data_callback(), queue.error_callback())?;
+        AsyncCaptureStream::start(config, queue)
+    }
+
+    /// Starts capturing `target` into a [`CaptureReader`], for code that consumes PCM through [`io::Read`]
+    /// The reader buffers up to `capacity` of audio and drops the oldest frames beyond that. The format and options of the
+    /// client apply the same as with the method for the target.
+    pub fn capture_reader(self, target: &CaptureTarget, capacity: Duration) -> Result<CaptureReader, AudioClientError> {
+        let ring = CaptureRing::default();
+        let config = self.capture(target, ring.data_callback(), ring.error_callback())?;
+        CaptureReader::start(config, ring, capacity)
+    }
+
+    /// Same as [`AudioClient::start_capture`], but the callback gets the packets decoded to samples of type `T`, e.g.
+    /// `start_capture_typed::<f32, _, _>(...)`. Fails with [`AudioClientError::UnsupportedFormat`] if the stream
+    /// format can't be decoded, see [`is_convertible`].
+    ///
+    /// If the stream format stores `T` as is, see [`is_native`], the samples are borrowed from the device buffer instead
+    /// of decoded. Loopback streams capture in the float mix format, for them `f32` costs no conversion.
+    pub fn start_capture_typed<T, D, E>(
+        self,
+        target: &CaptureTarget,
+        data_callback: D,
+        error_callback: E,
+    ) -> Result<AudioStreamConfig, AudioClientError>
+    where
+        T: Sample,
+        D: FnMut(SamplePacket<T>) + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        let format = Arc::new(OnceLock::new());
+        let config = self.capture(target, typed_callback(format.clone(), data_callback), error_callback)?;
+        set_typed_format::<T>(config, &format)
+    }
+
+    /// Same as [`AudioClient::start_capture_typed`], but fails with [`AudioClientError::UnsupportedFormat`] unless the
+    /// stream format stores `T` as is, so every packet is borrowed from the device buffer. Set the client format to
+    /// the one of `T`, see [`native_format`](crate::convert::native_format), for targets that don't capture in it already.
+    pub fn start_capture_native<T, D, E>(
+        self,
+        target: &CaptureTarget,
+        data_callback: D,
+        error_callback: E,
+    ) -> Result<AudioStreamConfig, AudioClientError>
+    where
+        T: Sample,
+        D: FnMut(SamplePacket<T>) + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        let format = Arc::new(OnceLock::new());
+        let config = self.capture(target, typed_callback(format.clone(), data_callback), error_callback)?;
+        if !is_native::<T>(config.format()) {
+            return Err(AudioClientError::UnsupportedFormat(config.format().clone()));
+        }
+        set_typed_format::<T>(config, &format)
+    }
+
+    /// Same as [`AudioClient::start_recording_loopback_device`], with packets decoded like
+    /// [`AudioClient::start_capture_typed`]
+    pub fn start_recording_loopback_device_typed<T, D, E>(
+        self,
+        dev: Option<&Device>,
+        data_callback: D,
+        error_callback: E,
+    ) -> Result<AudioStreamConfig, AudioClientError>
+    where
+        T: Sample,
+        D: FnMut(SamplePacket<T>) + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        let format = Arc::new(OnceLock::new());
+        let config = self.start_recording_loopback_device(dev, typed_callback(format.clone(), data_callback), error_callback)?;
+        set_typed_format::<T>(config, &format)
+    }
+
+    /// Activates and initializes a client for `target` that captures in `format`, the audio engine converts the target to
+    /// it. Used to move a running stream to another target.
+    pub(crate) fn open_capture_target(&self, target: &CaptureTarget, format: &SampleFormat) -> Result<IAudioClient, AudioClientError> {
+        const CONVERT: u32 = AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM | AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY;
+        com_initialized();
+        let wave_format = WaveFormat::from(format.clone());
+        let (audio_client, flags) = match target {
+            CaptureTarget::InputDevice(_) | CaptureTarget::DefaultInput => {
+                if target.device().is_some_and(|dev| dev.is_playback) {
+                    return Err(AudioClientError::NotInputDevice);
+                }
+                let audio_client = self.activate_device_or_default(target.device(), &DEVINTERFACE_AUDIO_CAPTURE)?;
+                (audio_client, AUDCLNT_STREAMFLAGS_EVENTCALLBACK | CONVERT)
+            }
+            CaptureTarget::LoopbackDevice(_) | CaptureTarget::DefaultLoopback => {
+                if target.device().is_some_and(|dev| !dev.is_playback) {
+                    return Err(AudioClientError::NotPlaybackDevice);
+                }
+                let audio_client = self.activate_device_or_default(target.device(), &DEVINTERFACE_AUDIO_RENDER)?;
+                (
+                    audio_client,
+                    AUDCLNT_STREAMFLAGS_EVENTCALLBACK | AUDCLNT_STREAMFLAGS_LOOPBACK | CONVERT,
+                )
+            }
+            CaptureTarget::Process(_) | CaptureTarget::ProcessTreeExclude(_) => {
+                let activate_params = SafeActivationParams::new(target.process_loopback());
+                let audio_client = self
+                    .with_activation_retry(|| self.get_audio_client(VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK, Some(activate_params.prop())))?;
+                // Process loopback takes any PCM format
+                (audio_client, AUDCLNT_STREAMFLAGS_EVENTCALLBACK | AUDCLNT_STREAMFLAGS_LOOPBACK)
+            }
+        };
+        self.initialize_client(audio_client, wave_format.as_ptr(), flags, BUFFER_DURATION_MS)
+    }
+
+    /// Opens `dev` again the way a stream was opened, for [`StreamRecovery`](crate::stream_recovery::StreamRecovery).
+    /// The device can come back with another mix format, the conversion flags keep the stream in `format`.
+    fn device_reopener(
+        &self,
+        dev: Option<&Device>,
+        interface: windows_core::GUID,
+        format: &SampleFormat,
+        flags: u32,
+        buffer_duration_ms: u32,
+    ) -> ReopenFn {
+        let (client, dev, format) = (self.clone(), dev.cloned(), format.clone());
+        Box::new(move || {
+            com_initialized();
+            let audio_client = client.activate_device_or_default(dev.as_ref(), &interface)?;
+            let wave_format = WaveFormat::from(format.clone());
+            let flags = flags | AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM | AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY;
+            client.initialize_client(audio_client, wave_format.as_ptr(), flags, buffer_duration_ms)
+        })
+    }
+
+    /// Opens the process loopback client of a stream again, fails if the capture format changed with the render mix rate
+    fn process_reopener(&self, pid: u32, mode: PROCESS_LOOPBACK_MODE, format: &SampleFormat) -> ReopenFn {
+        let (client, format) = (self.clone(), format.clone());
+        Box::new(move || {
+            let (audio_client, out_format, _) = client.initialize_process_loopback(pid, mode)?;
+            if out_format != format {
+                return Err(AudioClientError::UnsupportedFormat(out_format));
+            }
+            Ok(audio_client)
+        })
+    }
+
+    /// Activates and initializes a process loopback client, returns it with the format to capture with and the format to
+    /// deliver to the callback
+    fn initialize_process_loopback(
+        &self,
+        pid: u32,
+        mode: PROCESS_LOOPBACK_MODE,
+    ) -> Result<(IAudioClient, SampleFormat, Option<SampleFormat>), AudioClientError> {
         com_initialized();
-        let activate_params = SafeActivationParams::new(Some(pid));
+        let activate_params = SafeActivationParams::new(Some((pid, mode)));
 
-        let audio_client = self.get_audio_client(VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK, Some(activate_params.prop()))?;
-        let capture_format = self.format.clone().unwrap_or_default().into();
+        let audio_client =
+            self.with_activation_retry(|| self.get_audio_client(VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK, Some(activate_params.prop())))?;
+        let requested_format = self.format.clone().unwrap_or_default();
+        let (out_format, deliver_as) = self.process_capture_format(&requested_format)?;
+        let deliver_as = self.with_output_rate(deliver_as, &out_format)?;
+        let capture_format = WaveFormat::from(out_format.clone());
 
         let audio_client = self.initialize_client(
             audio_client,
-            &capture_format,
+            capture_format.as_ptr(),
             AUDCLNT_STREAMFLAGS_LOOPBACK | AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
             BUFFER_DURATION_MS,
         )?;
+        Ok((audio_client, out_format, deliver_as))
+    }
+
+    /// Checks the requested process loopback format against the render mix rate, returns the format to capture with and
+    /// the format to deliver to the callback
+    fn process_capture_format(&self, requested: &SampleFormat) -> Result<(SampleFormat, Option<SampleFormat>), AudioClientError> {
+        let mix_rate = DeviceManager::get_default_playback_device()
+            .ok()
+            .and_then(|dev| dev.get_mix_format().ok())
+            .map(|mix_format| mix_format.get_n_samples_per_sec());
+        let mix_rate = match mix_rate {
+            Some(mix_rate) if mix_rate != requested.get_n_samples_per_sec() => mix_rate,
+            _ => return Ok((requested.clone(), self.deliver_as())),
+        };
 
-        let out_format = SampleFormat::from_wave_format_ex(&capture_format);
-        AudioStreamConfig::create_capture_stream(data_callback, error_callback, audio_client, Some(out_format))
+        match self.capture_options.get_rate_mismatch() {
+            RateMismatchPolicy::Warn => {
+                warn!(
+                    "Process loopback requested at {}Hz but the render mix rate is {}Hz, the audio will be pitch shifted",
+                    requested.get_n_samples_per_sec(),
+                    mix_rate
+                );
+                Ok((requested.clone(), self.deliver_as()))
+            }
+            RateMismatchPolicy::Error => Err(AudioClientError::SampleRateMismatch(requested.get_n_samples_per_sec(), mix_rate)),
+            RateMismatchPolicy::Resample => {
+                let capture_format = SampleFormat::new(
+                    requested.get_format_tag().clone(),
+                    requested.get_channel(),
+                    mix_rate,
+                    requested.get_w_bits_per_sample(),
+                );
+                Ok((capture_format, Some(self.deliver_as().unwrap_or_else(|| requested.clone()))))
+            }
+        }
     }
 
     /// Start recording audio from an input device
     /// If `dev` is `None`, the default input device will be used
     pub fn start_recording_device<D, E>(
-        mut self,
+        self,
         dev: Option<&Device>,
         data_callback: D,
         error_callback: E,
@@ -166,20 +836,112 @@ impl AudioClient {
         com_initialized();
 
         let audio_client = self.activate_device_or_default(dev, &DEVINTERFACE_AUDIO_CAPTURE)?;
-        let format = match self.format.clone() {
-            Some(format) => &mut format.into() as *mut WAVEFORMATEX,
-            None => unsafe { audio_client.GetMixFormat() }.map_err(AudioClientError::FailedToGetMixFormat)?,
+        let Some(requested) = self.format.clone() else {
+            let mix_format = unsafe { audio_client.GetMixFormat() }.map_err(AudioClientError::FailedToGetMixFormat)?;
+            let mix_format = WaveFormatWrapper::from_ptr(mix_format);
+            let audio_client = self.initialize_client(audio_client, *mix_format, AUDCLNT_STREAMFLAGS_EVENTCALLBACK, BUFFER_DURATION_MS)?;
+            let mix_format = SampleFormat::from_wave_format_ex(*mix_format);
+            let deliver_as = self.with_output_rate(self.deliver_as(), &mix_format)?;
+            let reopen = self.device_reopener(
+                dev,
+                DEVINTERFACE_AUDIO_CAPTURE,
+                &mix_format,
+                AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+                BUFFER_DURATION_MS,
+            );
+            return AudioStreamConfig::create_capture_stream(
+                data_callback,
+                error_callback,
+                audio_client,
+                Some(mix_format.clone()),
+                deliver_as,
+                self.hooks(),
+                self.capture_options.get_monitor(),
+            )
+            .map(|config| config.with_reopen(reopen));
         };
 
-        let audio_client = self.initialize_client(audio_client, format, AUDCLNT_STREAMFLAGS_EVENTCALLBACK, BUFFER_DURATION_MS)?;
+        let fallback = self.capture_options.get_channel_fallback();
+        let negotiated = match fallback {
+            // Initialize reports the rejection
+            ChannelFallback::Error => requested.clone(),
+            _ => Self::closest_supported_format(&audio_client, &requested).unwrap_or_else(|| requested.clone()),
+        };
+        if negotiated != requested {
+            warn!("Device rejected {}, capturing {} instead", requested, negotiated);
+        }
 
-        AudioStreamConfig::create_capture_stream(data_callback, error_callback, audio_client, self.format.clone())
+        // Owned for the duration of Initialize, which reads the format through the pointer
+        let wave_format = WaveFormat::from(negotiated.clone());
+        let audio_client = self.initialize_client(
+            audio_client,
+            wave_format.as_ptr(),
+            AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+            BUFFER_DURATION_MS,
+        )?;
+        let deliver_as = match fallback {
+            ChannelFallback::Remap if negotiated != requested => Some(self.deliver_as().unwrap_or(requested)),
+            _ => self.deliver_as(),
+        };
+        let deliver_as = self.with_output_rate(deliver_as, &negotiated)?;
+        let reopen = self.device_reopener(
+            dev,
+            DEVINTERFACE_AUDIO_CAPTURE,
+            &negotiated,
+            AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+            BUFFER_DURATION_MS,
+        );
+        AudioStreamConfig::create_capture_stream(
+            data_callback,
+            error_callback,
+            audio_client,
+            Some(negotiated),
+            deliver_as,
+            self.hooks(),
+            self.capture_options.get_monitor(),
+        )
+        .map(|config| config.with_reopen(reopen))
+    }
+
+    /// Start recording audio from the default communications input device, e.g. the headset microphone of a VoIP setup
+    ///
+    /// The device is looked up once, the stream keeps recording from it when the user picks another default
+    /// communications device.
+    pub fn start_recording_default_communications_input<D, E>(
+        self,
+        data_callback: D,
+        error_callback: E,
+    ) -> Result<AudioStreamConfig, AudioClientError>
+    where
+        D: FnMut(CapturePacket) + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        let dev = DeviceManager::get_default_communications_device(DataFlow::Capture).map_err(AudioClientError::DeviceEnumError)?;
+        self.start_recording_device(Some(&dev), data_callback, error_callback)
+    }
+
+    /// `None` if the device supports `format` in shared mode, otherwise the closest format it suggests, or its mix
+    /// format when the driver doesn't suggest one
+    fn closest_supported_format(audio_client: &IAudioClient, format: &SampleFormat) -> Option<SampleFormat> {
+        let wave_format = WaveFormat::from(format.clone());
+        let mut closest_match: *mut WAVEFORMATEX = std::ptr::null_mut();
+        let hr = unsafe { audio_client.IsFormatSupported(AUDCLNT_SHAREMODE_SHARED, wave_format.as_ptr(), Some(&mut closest_match)) };
+        let closest_match = WaveFormatWrapper::from_ptr(closest_match);
+        if hr == Foundation::S_OK {
+            return None;
+        }
+        if hr == Foundation::S_FALSE && !closest_match.is_null() {
+            return Some(SampleFormat::from_wave_format_ex(*closest_match));
+        }
+        let mix_format = unsafe { audio_client.GetMixFormat() }.ok()?;
+        let mix_format = WaveFormatWrapper::from_ptr(mix_format);
+        Some(SampleFormat::from_wave_format_ex(*mix_format))
     }
 
     /// Start recording audio from a loopback device
     /// If `dev` is `None`, the default loopback device will be used
     pub fn start_recording_loopback_device<D, E>(
-        mut self,
+        self,
         dev: Option<&Device>,
         data_callback: D,
         error_callback: E,
@@ -197,26 +959,215 @@ impl AudioClient {
 
         let audio_client = self.activate_device_or_default(dev, &DEVINTERFACE_AUDIO_RENDER)?;
         let capture_format = unsafe { audio_client.GetMixFormat() }.map_err(AudioClientError::FailedToGetMixFormat)?;
+        let capture_format = WaveFormatWrapper::from_ptr(capture_format);
         let audio_client = self.initialize_client(
             audio_client,
-            capture_format,
+            *capture_format,
             AUDCLNT_STREAMFLAGS_EVENTCALLBACK | AUDCLNT_STREAMFLAGS_LOOPBACK,
             BUFFER_DURATION_MS,
         )?;
 
-        AudioStreamConfig::create_capture_stream(data_callback, error_callback, audio_client, Some(self.format.unwrap_or_default()))
+        // Loopback always captures in the mix format
+        let out_format = SampleFormat::from_wave_format_ex(*capture_format);
+        let deliver_as = self.with_output_rate(self.deliver_as(), &out_format)?;
+        let reopen = self.device_reopener(
+            dev,
+            DEVINTERFACE_AUDIO_RENDER,
+            &out_format,
+            AUDCLNT_STREAMFLAGS_EVENTCALLBACK | AUDCLNT_STREAMFLAGS_LOOPBACK,
+            BUFFER_DURATION_MS,
+        );
+        AudioStreamConfig::create_capture_stream(
+            data_callback,
+            error_callback,
+            audio_client,
+            Some(out_format),
+            deliver_as,
+            self.hooks(),
+            self.capture_options.get_monitor(),
+        )
+        .map(|config| config.with_reopen(reopen))
+    }
+
+    /// Start recording audio played on the default playback device, moving to the new default device whenever the
+    /// user switches outputs, see [`FollowingLoopback`]
+    #[cfg(feature = "notifications")]
+    pub fn start_recording_default_loopback_following<D, E>(
+        self,
+        data_callback: D,
+        error_callback: E,
+    ) -> Result<FollowingLoopback, FollowingLoopbackError>
+    where
+        D: FnMut(CapturePacket) + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        FollowingLoopback::start(self, data_callback, error_callback)
     }
 
     /// Start playback on the given device
     /// If `dev` is `None`, the default playback device will be used
+    ///
+    /// `data_callback` fills a [`RenderRequest`] per period and returns how many frames it wrote, `0` plays silence
     pub fn start_playback_device<D, E>(
-        mut self,
+        self,
         dev: Option<&Device>,
         data_callback: D,
         error_callback: E,
     ) -> Result<(AudioStreamConfig, SampleFormat), AudioClientError>
     where
-        D: FnMut(&mut [u8]) -> bool + Send + 'static,
+        D: FnMut(RenderRequest) -> u32 + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        self.start_playback_with(dev, None, |_| Ok(data_callback), error_callback)
+    }
+
+    /// Start playback of `format` on the given device, the audio engine converts it to the mix format
+    /// If `dev` is `None`, the default playback device will be used
+    pub fn start_playback_device_with_format<D, E>(
+        self,
+        dev: Option<&Device>,
+        format: &SampleFormat,
+        data_callback: D,
+        error_callback: E,
+    ) -> Result<AudioStreamConfig, AudioClientError>
+    where
+        D: FnMut(RenderRequest) -> u32 + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        self.start_playback_with(dev, Some(format), |_| Ok(data_callback), error_callback)
+            .map(|(stream, _)| stream)
+    }
+
+    /// Same as [`AudioClient::start_playback_device`], but the callback fills interleaved samples of type `T` sized to the
+    /// frames of the period, e.g. `start_playback_device_typed::<f32, _, _>(...)`. The samples start out silent.
+    ///
+    /// The crate encodes them in the mix format, which is float for most devices, then `f32` is written to the device
+    /// buffer as is. Fails with [`AudioClientError::UnsupportedFormat`] if the mix format can't be encoded, see
+    /// [`is_convertible`].
+    pub fn start_playback_device_typed<T, D, E>(
+        self,
+        dev: Option<&Device>,
+        data_callback: D,
+        error_callback: E,
+    ) -> Result<(AudioStreamConfig, SampleFormat), AudioClientError>
+    where
+        T: Sample,
+        D: FnMut(&mut [T]) -> PlaybackControl + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        self.start_playback_with(dev, None, |format| typed_render_callback(format, data_callback), error_callback)
+    }
+
+    /// Same as [`AudioClient::start_playback_device_typed`] with samples in `format`, the audio engine converts it to the
+    /// mix format
+    pub fn start_playback_device_typed_with_format<T, D, E>(
+        self,
+        dev: Option<&Device>,
+        format: &SampleFormat,
+        data_callback: D,
+        error_callback: E,
+    ) -> Result<AudioStreamConfig, AudioClientError>
+    where
+        T: Sample,
+        D: FnMut(&mut [T]) -> PlaybackControl + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        self.start_playback_with(
+            dev,
+            Some(format),
+            |format| typed_render_callback(format, data_callback),
+            error_callback,
+        )
+        .map(|(stream, _)| stream)
+    }
+
+    /// Starts playback into a [`Sink`](futures_sink::Sink) of sample bytes, for async code instead of a callback
+    /// If `dev` is `None`, the default playback device will be used. Without a `format` the sink takes the mix format,
+    /// see [`AsyncPlaybackSink::format`], otherwise the audio engine converts like with
+    /// [`AudioClient::start_playback_device_with_format`].
+    #[cfg(feature = "async")]
+    pub fn playback_sink_async(self, dev: Option<&Device>, format: Option<&SampleFormat>) -> Result<AsyncPlaybackSink, AudioClientError> {
+        let queue = PlaybackQueue::default();
+        let (config, format) = self.start_playback_with(dev, format, |format| Ok(queue.data_callback(format)), queue.error_callback())?;
+        AsyncPlaybackSink::start(config, format, queue)
+    }
+
+    /// Start an ASIO style processing stream on the given playback device
+    /// If `output` is `None`, the default playback device will be used
+    ///
+    /// `process` is always called with exactly `block_frames` frames of interleaved `f32` samples, no matter how many
+    /// frames WASAPI requests per period. The input slice holds the audio captured from `input` in its mix format channel
+    /// layout (empty without an input device), the output slice uses the channel layout of the returned format.
+    /// The crate converts from and to the device formats, the block size adds `block_frames` of latency. Fails with
+    /// [`AudioClientError::EmptyBlockSize`] if `block_frames` is `0`.
+    pub fn start_block_processing<P, E>(
+        self,
+        output: Option<&Device>,
+        input: Option<&Device>,
+        block_frames: usize,
+        process: P,
+        error_callback: E,
+    ) -> Result<(AudioStreamConfig, SampleFormat), AudioClientError>
+    where
+        P: FnMut(&[f32], &mut [f32]) + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        if block_frames == 0 {
+            return Err(AudioClientError::EmptyBlockSize);
+        }
+        let error_callback = Arc::new(Mutex::new(error_callback));
+        let block_input = match input {
+            Some(dev) => {
+                let queue: InputQueue = Arc::new(Mutex::new(VecDeque::new()));
+                let capture_queue = queue.clone();
+                let capture_error_callback = error_callback.clone();
+                let stream = AudioClient::new().start_recording_device(
+                    Some(dev),
+                    move |packet| capture_queue.lock().unwrap_or_else(|e| e.into_inner()).extend(packet.data()),
+                    move |err| (capture_error_callback.lock().unwrap_or_else(|e| e.into_inner()))(err),
+                )?;
+                let format = stream.format().clone();
+                if !is_convertible(&format) {
+                    return Err(AudioClientError::UnsupportedFormat(format));
+                }
+                Some(BlockInput {
+                    format,
+                    queue,
+                    stream: Some(stream),
+                })
+            }
+            None => None,
+        };
+
+        self.start_playback_with(
+            output,
+            None,
+            move |format| {
+                if !is_convertible(format) {
+                    return Err(AudioClientError::UnsupportedFormat(format.clone()));
+                }
+                let mut processor = BlockProcessor::new(process, block_frames, format.clone(), block_input);
+                Ok(move |mut request: RenderRequest| {
+                    processor.fill(request.buffer());
+                    request.frames()
+                })
+            },
+            move |err| (error_callback.lock().unwrap_or_else(|e| e.into_inner()))(err),
+        )
+    }
+
+    /// Shared playback setup, `make_callback` receives the stream format before the stream is created
+    /// Without a `format` the stream uses the device mix format
+    fn start_playback_with<F, D, E>(
+        self,
+        dev: Option<&Device>,
+        format: Option<&SampleFormat>,
+        make_callback: F,
+        error_callback: E,
+    ) -> Result<(AudioStreamConfig, SampleFormat), AudioClientError>
+    where
+        F: FnOnce(&SampleFormat) -> Result<D, AudioClientError>,
+        D: FnMut(RenderRequest) -> u32 + Send + 'static,
         E: FnMut(AudioClientError) + Send + 'static,
     {
         if let Some(dev) = dev
@@ -227,40 +1178,255 @@ impl AudioClient {
         com_initialized();
 
         let audio_client = self.activate_device_or_default(dev, &DEVINTERFACE_AUDIO_RENDER)?;
-        let format = unsafe { audio_client.GetMixFormat() }.map_err(AudioClientError::FailedToGetMixFormat)?;
-        let format = WaveFormatWrapper::from_ptr(format);
-        let audio_client = self.initialize_client(audio_client, *format, AUDCLNT_STREAMFLAGS_EVENTCALLBACK, 0)?;
+        let (audio_client, device_format) = match format {
+            Some(format) => {
+                let wave_format = WaveFormat::from(format.clone());
+                let flags =
+                    AUDCLNT_STREAMFLAGS_EVENTCALLBACK | AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM | AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY;
+                (
+                    self.initialize_client(audio_client, wave_format.as_ptr(), flags, 0)?,
+                    format.clone(),
+                )
+            }
+            None => {
+                let format = unsafe { audio_client.GetMixFormat() }.map_err(AudioClientError::FailedToGetMixFormat)?;
+                let format = WaveFormatWrapper::from_ptr(format);
+                let audio_client = self.initialize_client(audio_client, *format, AUDCLNT_STREAMFLAGS_EVENTCALLBACK, 0)?;
+                (audio_client, SampleFormat::from_wave_format_ex(format.0))
+            }
+        };
+        let data_callback = make_callback(&device_format)?;
+        let hooks = self.hooks();
+        let reopen = self.device_reopener(dev, DEVINTERFACE_AUDIO_RENDER, &device_format, AUDCLNT_STREAMFLAGS_EVENTCALLBACK, 0);
+        let failover = (!self.playback_options.get_failover().is_empty()).then(|| PlaybackFailover {
+            current: dev.cloned(),
+            format: device_format.clone(),
+            client: self,
+        });
 
-        AudioStreamConfig::create_playback_stream(data_callback, error_callback, audio_client, self.format.unwrap_or_default())
-            .map(|stream| (stream, SampleFormat::from_wave_format_ex(format.0)))
+        AudioStreamConfig::create_playback_stream(data_callback, error_callback, audio_client, device_format.clone(), failover, hooks)
+            .map(|stream| (stream.with_reopen(reopen), device_format))
     }
 
-    fn activate_device_or_default(&self, dev: Option<&Device>, default_iid: &windows_core::GUID) -> Result<IAudioClient, AudioClientError> {
-        match dev {
-            Some(dev) => {
-                unsafe { dev.inner.Activate::<IAudioClient>(Com::CLSCTX_ALL, None) }.map_err(AudioClientError::FailedToStartAudioClient)
+    /// Checks whether capturing `target` would succeed, without starting a stream
+    /// Does everything short of starting the stream: resolving the device, activating and initializing an audio client
+    /// with the client's format. Only unexpected failures are returned as errors, everything else ends up in the report.
+    pub fn preflight(&self, target: &CaptureTarget) -> Result<Preflight, AudioClientError> {
+        com_initialized();
+        let mut report = Preflight::default();
+        match target {
+            CaptureTarget::Process(pid) | CaptureTarget::ProcessTreeExclude(pid) => {
+                match unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, *pid) } {
+                    Ok(process) => drop(EventHandleWrapper(process)),
+                    Err(_) => {
+                        report.issues.push(PreflightIssue::ProcessNotFound);
+                        return Ok(report);
+                    }
+                }
+                let activate_params = SafeActivationParams::new(target.process_loopback());
+                let audio_client = match self.get_audio_client(VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK, Some(activate_params.prop())) {
+                    Ok(audio_client) => audio_client,
+                    Err(err) => {
+                        report
+                            .issues
+                            .push(PreflightIssue::ProcessLoopbackUnavailable(err.hresult().unwrap_or_default()));
+                        return Ok(report);
+                    }
+                };
+                let capture_format = WaveFormat::from(self.format.clone().unwrap_or_default());
+                self.preflight_initialize(
+                    &mut report,
+                    audio_client,
+                    capture_format.as_ptr(),
+                    AUDCLNT_STREAMFLAGS_LOOPBACK | AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+                );
+            }
+            CaptureTarget::InputDevice(_)
+            | CaptureTarget::LoopbackDevice(_)
+            | CaptureTarget::DefaultInput
+            | CaptureTarget::DefaultLoopback => {
+                let loopback = target.is_loopback_device();
+                let dev = match target.device() {
+                    Some(dev) => dev.clone(),
+                    None => {
+                        let default_dev = if loopback {
+                            DeviceManager::get_default_playback_device()
+                        } else {
+                            DeviceManager::get_default_input_device()
+                        };
+                        match default_dev {
+                            Ok(dev) => dev,
+                            Err(_) => {
+                                report.issues.push(PreflightIssue::NoDefaultDevice);
+                                return Ok(report);
+                            }
+                        }
+                    }
+                };
+                if dev.is_playback != loopback {
+                    report.issues.push(PreflightIssue::WrongDeviceDirection);
+                    return Ok(report);
+                }
+                if let Ok(state) = dev.get_state() {
+                    report.device_state = Some(state.clone());
+                    if state != DeviceState::Active {
+                        report.issues.push(PreflightIssue::DeviceNotActive(state));
+                        return Ok(report);
+                    }
+                }
+
+                let audio_client = match unsafe { dev.inner.Activate::<IAudioClient>(Com::CLSCTX_ALL, None) } {
+                    Ok(audio_client) => audio_client,
+                    Err(err) => {
+                        report.issues.push(Self::preflight_issue(err.code()));
+                        return Ok(report);
+                    }
+                };
+                let mix_format = unsafe { audio_client.GetMixFormat() }.map_err(AudioClientError::FailedToGetMixFormat)?;
+                let mix_format = WaveFormatWrapper::from_ptr(mix_format);
+                let user_format: Option<WaveFormat> = self.format.clone().map(Into::into);
+                let format = match (&self.format, &user_format, loopback) {
+                    (Some(format), Some(user_format), false) => {
+                        let support = dev.format_supported(format).ok();
+                        report.format_support = support.clone();
+                        if matches!(support, Some(FormatSupport::Unsupported | FormatSupport::ClosestMatch(_))) {
+                            report.issues.push(PreflightIssue::FormatUnsupported);
+                            return Ok(report);
+                        }
+                        user_format.as_ptr()
+                    }
+                    _ => *mix_format as *const WAVEFORMATEX,
+                };
+                let flags = if loopback {
+                    AUDCLNT_STREAMFLAGS_EVENTCALLBACK | AUDCLNT_STREAMFLAGS_LOOPBACK
+                } else {
+                    AUDCLNT_STREAMFLAGS_EVENTCALLBACK
+                };
+                self.preflight_initialize(&mut report, audio_client, format, flags);
             }
+        }
+        Ok(report)
+    }
+
+    fn preflight_initialize(&self, report: &mut Preflight, audio_client: IAudioClient, format: *const WAVEFORMATEX, flags: u32) {
+        if let Err(err) = self.initialize_client(audio_client, format, flags, BUFFER_DURATION_MS) {
+            report.issues.push(Self::preflight_issue(err.hresult().unwrap_or_default()));
+        }
+    }
+
+    fn preflight_issue(hresult: HRESULT) -> PreflightIssue {
+        if hresult == E_ACCESSDENIED {
+            PreflightIssue::AccessDenied
+        } else if hresult == AUDCLNT_E_UNSUPPORTED_FORMAT {
+            PreflightIssue::FormatUnsupported
+        } else {
+            PreflightIssue::ClientInitializationFailed(hresult)
+        }
+    }
+
+    fn deliver_as(&self) -> Option<SampleFormat> {
+        self.capture_options.get_deliver_as().cloned()
+    }
+
+    /// `deliver_as` at the [`CaptureOptions::output_sample_rate`], or `source` at that rate if nothing else is converted
+    fn with_output_rate(&self, deliver_as: Option<SampleFormat>, source: &SampleFormat) -> Result<Option<SampleFormat>, AudioClientError> {
+        let Some(sample_rate) = self.capture_options.get_output_sample_rate() else {
+            return Ok(deliver_as);
+        };
+        if !OUTPUT_SAMPLE_RATES.contains(&sample_rate) {
+            return Err(AudioClientError::OutputSampleRateOutOfRange(
+                sample_rate,
+                *OUTPUT_SAMPLE_RATES.start(),
+                *OUTPUT_SAMPLE_RATES.end(),
+            ));
+        }
+        Ok(Some(deliver_as.unwrap_or_else(|| source.clone()).with_sample_rate(sample_rate)))
+    }
+
+    fn activate_device_or_default(&self, dev: Option<&Device>, default_iid: &windows_core::GUID) -> Result<IAudioClient, AudioClientError> {
+        self.with_activation_retry(|| match dev {
+            Some(dev) => unsafe { dev.inner.Activate::<IAudioClient>(Com::CLSCTX_ALL, None) }.map_err(AudioClientError::ActivationFailure),
             None => {
                 let audio_render_guid = unsafe { StringFromIID(default_iid).expect("can only fail on OOM") };
                 let audio_render_guid = PWSTRWrapper(audio_render_guid);
                 self.get_audio_client(audio_render_guid.0, None)
             }
+        })
+    }
+
+    /// Repeats `activate` while it fails with a transient [`AudioClientError::ActivationFailure`]
+    fn with_activation_retry<F>(&self, mut activate: F) -> Result<IAudioClient, AudioClientError>
+    where
+        F: FnMut() -> Result<IAudioClient, AudioClientError>,
+    {
+        let mut attempt = 1;
+        loop {
+            match activate() {
+                Err(AudioClientError::ActivationFailure(err)) if is_transient_activation_error(err.code()) => {
+                    if attempt >= self.activation_retry.get_attempts() {
+                        return Err(AudioClientError::TransientActivationFailure(attempt, err));
+                    }
+                    debug!("Activation attempt {} failed with {}, retrying", attempt, err);
+                    thread::sleep(self.activation_retry.get_delay());
+                    attempt += 1;
+                }
+                res => return res,
+            }
         }
     }
 
     fn initialize_client(
-        &mut self,
+        &self,
         audio_client: IAudioClient,
         format: *const WAVEFORMATEX,
         flags: u32,
         buffer_duration_ms: u32,
     ) -> Result<IAudioClient, AudioClientError> {
-        const REFTIME_MS: i64 = 10_000;
+        if let Some(category) = self.category {
+            let properties = AudioClientProperties {
+                cbSize: size_of::<AudioClientProperties>() as u32,
+                bIsOffload: false.into(),
+                eCategory: category.into(),
+                Options: AUDCLNT_STREAMOPTIONS_NONE,
+            };
+            let audio_client2 = audio_client
+                .cast::<IAudioClient2>()
+                .map_err(AudioClientError::FailedSettingClientProperties)?;
+            unsafe { audio_client2.SetClientProperties(&properties) }.map_err(AudioClientError::FailedSettingClientProperties)?;
+        }
+        let flags = if self.rate_adjust {
+            flags | AUDCLNT_STREAMFLAGS_RATEADJUST
+        } else {
+            flags
+        };
+        if self.low_latency && flags & AUDCLNT_STREAMFLAGS_LOOPBACK == 0 {
+            match engine_period::initialize_low_latency(&audio_client, format, flags) {
+                Ok(periods) => {
+                    debug!("Initialized stream at an engine period of {} frames", periods.get_min());
+                    return Ok(audio_client);
+                }
+                // The client stays uninitialized, the default period still works
+                Err(err) => debug!("Low latency stream not available, using the default period: {}", err),
+            }
+        }
+        let buffer_duration = self.buffer_duration.unwrap_or(Duration::from_millis(buffer_duration_ms as u64));
+        // Process loopback clients don't report a period, the engine picks the buffer size for them anyway
+        let mut min_period = 0i64;
+        if self.buffer_duration.is_some() && unsafe { audio_client.GetDevicePeriod(None, Some(&mut min_period)) }.is_ok() {
+            let min_period = Duration::from_nanos(min_period.max(0) as u64 * 100);
+            if buffer_duration < min_period {
+                return Err(AudioClientError::BufferDurationOutOfRange(
+                    buffer_duration,
+                    min_period,
+                    MAX_BUFFER_DURATION,
+                ));
+            }
+        }
         unsafe {
             audio_client.Initialize(
                 AUDCLNT_SHAREMODE_SHARED,
                 flags,
-                REFTIME_MS * buffer_duration_ms as i64,
+                (buffer_duration.as_nanos() / 100) as i64,
                 0,
                 format,
                 None,
@@ -279,12 +1445,12 @@ impl AudioClient {
     where
         P: windows_core::Param<windows_core::PCWSTR>,
     {
-        let activate_event = unsafe { CreateEventW(None, false, false, None) }.expect("Failed to create event handle");
+        let activate_event = unsafe { CreateEventW(None, false, false, None) }.map_err(AudioClientError::EventCreationError)?;
         let activate_event = Arc::new(EventHandleWrapper(activate_event));
         let handler: IActivateAudioInterfaceCompletionHandler = ActivateHandler::new(activate_event.clone()).into();
         let res =
             unsafe { ActivateAudioInterfaceAsync(device_interface_path, &IAudioClient::IID as *const GUID, activate_params, &handler) }
-                .expect("ActivateAudioInterfaceAsync failed");
+                .map_err(AudioClientError::ActivationFailure)?;
 
         unsafe { get_wait_error(WaitForSingleObject(**activate_event, INFINITE))? };
 
@@ -297,6 +1463,7 @@ impl AudioClient {
             )
         }
         .map_err(AudioClientError::FailedToStartAudioClient)?;
+        activate_result.ok().map_err(AudioClientError::ActivationFailure)?;
 
         let audio_client = activated_interface
             .ok_or(AudioClientError::FailedGettingActivationResult)?
@@ -306,10 +1473,125 @@ impl AudioClient {
     }
 }
 
+/// Format of a typed stream, and whether its packets are borrowed as `T` instead of decoded
+type TypedFormat = (SampleFormat, bool);
+
+/// Capture callback decoding the packets for a typed data callback, once the stream format is set in `format`
+fn typed_callback<T, D>(format: Arc<OnceLock<TypedFormat>>, mut data_callback: D) -> impl FnMut(CapturePacket) + Send + 'static
+where
+    T: Sample,
+    D: FnMut(SamplePacket<T>) + Send + 'static,
+{
+    let mut samples = Vec::new();
+    move |packet| {
+        // Set before the config is handed out, so before the stream can start
+        let Some((format, native)) = format.get() else {
+            return;
+        };
+        if *native && let Some(borrowed) = borrow_samples::<T>(packet.data()) {
+            return data_callback(SamplePacket::new(borrowed, format.get_channel(), &packet));
+        }
+        samples.clear();
+        T::decode(format, packet.data(), &mut samples);
+        data_callback(SamplePacket::new(&samples, format.get_channel(), &packet))
+    }
+}
+
+/// Hands the format of a stream to its [`typed_callback`]
+fn set_typed_format<T: Sample>(config: AudioStreamConfig, format: &OnceLock<TypedFormat>) -> Result<AudioStreamConfig, AudioClientError> {
+    if !is_convertible(config.format()) {
+        return Err(AudioClientError::UnsupportedFormat(config.format().clone()));
+    }
+    let _ = format.set((config.format().clone(), is_native::<T>(config.format())));
+    Ok(config)
+}
+
+/// Playback callback encoding the samples of a typed data callback in `format`
+fn typed_render_callback<T, D>(
+    format: &SampleFormat,
+    mut data_callback: D,
+) -> Result<impl FnMut(RenderRequest) -> u32 + Send + 'static + use<T, D>, AudioClientError>
+where
+    T: Sample,
+    D: FnMut(&mut [T]) -> PlaybackControl + Send + 'static,
+{
+    if !is_convertible(format) {
+        return Err(AudioClientError::UnsupportedFormat(format.clone()));
+    }
+    let format = format.clone();
+    let native = is_native::<T>(&format);
+    let channels = format.get_channel() as usize;
+    let (mut samples, mut encoded) = (Vec::new(), Vec::new());
+    Ok(move |mut request: RenderRequest| {
+        let frames = request.frames();
+        if native {
+            let buffer = request.buffer();
+            // Zero bytes are silence in every native format
+            buffer.fill(0);
+            if let Some(borrowed) = borrow_samples_mut::<T>(buffer) {
+                let control = data_callback(borrowed);
+                return control.apply(&mut request, frames);
+            }
+        }
+        samples.clear();
+        samples.resize(frames as usize * channels, T::default());
+        let written = data_callback(&mut samples).apply(&mut request, frames);
+        encoded.clear();
+        T::encode(&format, &samples[..written as usize * channels], &mut encoded);
+        request.buffer()[..encoded.len()].copy_from_slice(&encoded);
+        written
+    })
+}
+
+/// Moves a playback stream to another device of [`PlaybackOptions::failover`] once its device is gone
+pub(crate) struct PlaybackFailover {
+    client: AudioClient,
+    current: Option<Device>,
+    format: SampleFormat,
+}
+
+impl PlaybackFailover {
+    /// Opens the first usable failover device, or the default playback device, with the format of the stream
+    pub(crate) fn switch(&mut self) -> Result<IAudioClient, AudioClientError> {
+        let candidates: Vec<Device> = self
+            .client
+            .playback_options
+            .get_failover()
+            .iter()
+            .filter(|dev| Some(*dev) != self.current.as_ref() && dev.get_state().is_ok_and(|state| state == DeviceState::Active))
+            .cloned()
+            .collect();
+        let default_dev = DeviceManager::get_default_playback_device().map_err(AudioClientError::DeviceEnumError);
+
+        let mut last_err = None;
+        for dev in candidates.into_iter().map(Ok).chain(std::iter::once(default_dev)) {
+            match dev.and_then(|dev| self.open(&dev).map(|audio_client| (audio_client, dev))) {
+                Ok((audio_client, dev)) => {
+                    let from = self.current.replace(dev.clone());
+                    self.client.playback_options.output_switched(OutputSwitched::new(from, dev));
+                    return Ok(audio_client);
+                }
+                Err(err) => {
+                    warn!("Stream {} failed switching playback output: {}", CurrentStream, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("the default device is always tried"))
+    }
+
+    fn open(&self, dev: &Device) -> Result<IAudioClient, AudioClientError> {
+        let audio_client = self.client.activate_device_or_default(Some(dev), &DEVINTERFACE_AUDIO_RENDER)?;
+        let wave_format = WaveFormat::from(self.format.clone());
+        let flags = AUDCLNT_STREAMFLAGS_EVENTCALLBACK | AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM | AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY;
+        self.client.initialize_client(audio_client, wave_format.as_ptr(), flags, 0)
+    }
+}
+
 pub(crate) fn get_wait_error(wait_event: WAIT_EVENT) -> Result<u32, AudioClientError> {
     if wait_event == WAIT_FAILED {
         let err = unsafe { Foundation::GetLastError() };
-        error!("Wait failed: {:?}", err);
+        error!("Stream {} wait failed: {:?}", CurrentStream, err);
         return Err(AudioClientError::WaitFailed(err));
     }
     Ok(wait_event.0)
@@ -338,15 +1620,21 @@ impl IActivateAudioInterfaceCompletionHandler_Impl for ActivateHandler_Impl {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::audio_stream::{PollStatus, ThreadAffinity};
+    use crate::deadline::DeadlineMode;
+    use crate::glitch_recorder::{GlitchKind, GlitchRecorder};
+    use crate::sample_format::FormatTag;
+    use crate::stream_instant::StreamInstant;
     use std::sync::mpsc::channel;
     use std::time::Duration;
+    use windows::Win32::System::Threading::{GetCurrentThread, GetCurrentThreadId, SetThreadAffinityMask};
 
     #[test]
     fn playback() {
         let client = AudioClient::new();
         let (err_sender, err_recv) = channel();
         let (audio_stream, _format) = client
-            .start_playback_device(None, |_data| false, move |err| err_sender.send(err).unwrap())
+            .start_playback_device(None, |_| 0, move |err| err_sender.send(err).unwrap())
             .unwrap();
         audio_stream.start().unwrap();
 
@@ -355,10 +1643,580 @@ mod tests {
         }
     }
 
+    #[test]
+    fn block_processing() {
+        let client = AudioClient::new();
+        let (block_sender, block_recv) = channel();
+        let (audio_stream, format) = client
+            .start_block_processing(
+                None,
+                None,
+                256,
+                move |input, output| block_sender.send((input.len(), output.len())).unwrap(),
+                |_err| {},
+            )
+            .unwrap();
+        let _audio_stream = audio_stream.start().unwrap();
+
+        let (input_len, output_len) = block_recv.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(input_len, 0);
+        assert_eq!(output_len, 256 * format.get_channel() as usize);
+
+        let res = AudioClient::new().start_block_processing(None, None, 0, |_, _| {}, |_err| {});
+        assert!(matches!(res, Err(AudioClientError::EmptyBlockSize)));
+    }
+
+    #[test]
+    fn preflight() {
+        let client = AudioClient::new();
+        assert!(client.preflight(&CaptureTarget::DefaultLoopback).unwrap().can_capture());
+        assert!(client.preflight(&CaptureTarget::Process(std::process::id())).unwrap().can_capture());
+
+        let report = client.preflight(&CaptureTarget::Process(u32::MAX)).unwrap();
+        assert_eq!(report.issues, vec![PreflightIssue::ProcessNotFound]);
+
+        let playback_dev = DeviceManager::get_default_playback_device().unwrap();
+        let report = client.preflight(&CaptureTarget::InputDevice(playback_dev)).unwrap();
+        assert_eq!(report.issues, vec![PreflightIssue::WrongDeviceDirection]);
+    }
+
+    #[test]
+    fn default_communications_input() {
+        let device = DeviceManager::get_default_communications_device(DataFlow::Capture).unwrap();
+        assert!(!device.is_playback);
+        let (packet_send, packet_recv) = channel();
+        let audio_stream = AudioClient::new()
+            .start_recording_default_communications_input(move |packet| packet_send.send(packet.data().len()).unwrap(), |_err| {})
+            .unwrap();
+        let _audio_stream = audio_stream.start().unwrap();
+        packet_recv.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn output_sample_rate() {
+        let mix_rate = DeviceManager::get_default_playback_device()
+            .unwrap()
+            .get_mix_format()
+            .unwrap()
+            .get_n_samples_per_sec();
+        let sample_rate = if mix_rate == 44100 { 48000 } else { 44100 };
+        let mut client = AudioClient::new();
+        client.set_output_sample_rate(sample_rate);
+        let stream = client.start_recording_loopback_device(None, |_packet| {}, |_err| {});
+        if cfg!(feature = "resampler") {
+            let stream = stream.unwrap();
+            assert_eq!(stream.format().get_n_samples_per_sec(), sample_rate);
+            assert_eq!(stream.source_format().get_n_samples_per_sec(), mix_rate);
+        } else {
+            assert!(matches!(stream, Err(AudioClientError::UnsupportedConversion(..))));
+        }
+    }
+
+    #[test]
+    fn output_sample_rate_out_of_range() {
+        for sample_rate in [0, *OUTPUT_SAMPLE_RATES.end() + 1] {
+            let mut client = AudioClient::new();
+            client.set_output_sample_rate(sample_rate);
+            let stream = client.start_recording_loopback_device(None, |_packet| {}, |_err| {});
+            assert!(
+                matches!(stream, Err(AudioClientError::OutputSampleRateOutOfRange(rate, ..)) if rate == sample_rate),
+                "{:?}",
+                stream.err()
+            );
+        }
+    }
+
+    #[test]
+    fn typed_capture() {
+        let (playback_stream, _format) = AudioClient::new()
+            .start_playback_device(None, |request| request.frames(), |_err| {})
+            .unwrap();
+        let _playback_stream = playback_stream.start().unwrap();
+
+        let (packet_send, packet_recv) = channel();
+        let audio_stream = AudioClient::new()
+            .start_recording_loopback_device_typed::<i16, _, _>(
+                None,
+                move |packet| {
+                    packet_send
+                        .send((packet.samples().len(), packet.frames(), packet.channels()))
+                        .unwrap()
+                },
+                |_err| {},
+            )
+            .unwrap();
+        let channels = audio_stream.format().get_channel();
+        let _audio_stream = audio_stream.start().unwrap();
+        let (samples, frames, packet_channels) = packet_recv.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(packet_channels, channels);
+        assert_eq!(samples, frames * channels as usize);
+    }
+
+    #[test]
+    fn native_capture() {
+        // Loopback captures in the float mix format
+        let audio_stream = AudioClient::new()
+            .start_capture_native::<f32, _, _>(&CaptureTarget::DefaultLoopback, |_| {}, |_err| {})
+            .unwrap();
+        assert!(is_native::<f32>(audio_stream.format()));
+
+        let res = AudioClient::new().start_capture_native::<i16, _, _>(&CaptureTarget::DefaultLoopback, |_| {}, |_err| {});
+        assert!(matches!(res, Err(AudioClientError::UnsupportedFormat(_))));
+    }
+
+    #[test]
+    fn start_capture_targets() {
+        for target in [
+            CaptureTarget::DefaultLoopback,
+            CaptureTarget::Process(std::process::id()),
+            CaptureTarget::ProcessTreeExclude(std::process::id()),
+        ] {
+            let mut client = AudioClient::new();
+            client.set_duplicate_capture_policy(DuplicateCapturePolicy::AllowDuplicates);
+            let stream = client.start_capture(&target, CaptureOptions::new(), |_| {}, |_| {}).unwrap();
+            drop(stream.start().unwrap());
+        }
+    }
+
+    #[test]
+    fn switch_capture_target() {
+        let (playback_stream, _format) = AudioClient::new()
+            .start_playback_device(None, |request| request.frames(), |_err| {})
+            .unwrap();
+        let _playback_stream = playback_stream.start().unwrap();
+
+        let (packet_send, packet_recv) = channel();
+        let audio_stream = AudioClient::new()
+            .start_recording_loopback_device(None, move |packet| packet_send.send(packet.data().len()).unwrap(), |_err| {})
+            .unwrap();
+        let block_align = audio_stream.format().block_align() as usize;
+        let audio_stream = audio_stream.start().unwrap();
+        packet_recv.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        audio_stream.switch_target(&CaptureTarget::Process(std::process::id())).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        while packet_recv.try_recv().is_ok() {}
+        // Packets of the new target arrive in the format of the stream
+        let len = packet_recv.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(len % block_align, 0);
+    }
+
+    #[test]
+    fn capture_markers() {
+        let (playback_stream, _format) = AudioClient::new()
+            .start_playback_device(None, |request| request.frames(), |_err| {})
+            .unwrap();
+        let _playback_stream = playback_stream.start().unwrap();
+
+        let (marker_send, marker_recv) = channel();
+        let audio_stream = AudioClient::new()
+            .start_recording_loopback_device(
+                None,
+                move |packet| {
+                    for marker in packet.markers() {
+                        marker_send.send((marker.clone(), packet.data().len())).unwrap();
+                    }
+                },
+                |_err| {},
+            )
+            .unwrap();
+        let block_align = audio_stream.format().block_align() as usize;
+        let audio_stream = audio_stream.start().unwrap();
+
+        audio_stream.insert_marker("cue").unwrap();
+        let (marker, len) = marker_recv.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(marker.label(), "cue");
+        assert!((marker.offset() as usize) < len / block_align);
+    }
+
+    #[test]
+    fn capture_callback_panic() {
+        let (playback_stream, _format) = AudioClient::new().start_playback_device(None, |_| 0, |_err| {}).unwrap();
+        let _playback_stream = playback_stream.start().unwrap();
+
+        let (err_sender, err_recv) = channel();
+        let capture_stream = AudioClient::new()
+            .start_recording_loopback_device(None, |_packet| panic!("callback panic"), move |err| err_sender.send(err).unwrap())
+            .unwrap();
+        let capture_stream = capture_stream.start().unwrap();
+
+        let err = err_recv.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(matches!(err.without_stream(), AudioClientError::CallbackPanicked), "{:?}", err);
+        assert_eq!(err.stream_id(), Some(capture_stream.id()));
+    }
+
+    #[test]
+    fn capture_stop_and_drain() {
+        let (playback_stream, _format) = AudioClient::new()
+            .start_playback_device(None, |request| request.frames(), |_err| {})
+            .unwrap();
+        let _playback_stream = playback_stream.start().unwrap();
+
+        let (packet_sender, packet_recv) = channel();
+        let capture_stream = AudioClient::new()
+            .start_recording_loopback_device(None, move |packet| packet_sender.send(packet.data().len()).unwrap(), |_err| {})
+            .unwrap();
+        let capture_stream = capture_stream.start().unwrap();
+        packet_recv.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        capture_stream.stop_and_drain(Duration::from_millis(100));
+        // The callback is dropped with the stream thread, so the channel disconnects once the drain is done
+        while packet_recv.recv_timeout(Duration::from_secs(1)).is_ok() {}
+    }
+
+    #[test]
+    fn playback_callback_panic() {
+        let (err_sender, err_recv) = channel();
+        let (audio_stream, _format) = AudioClient::new()
+            .start_playback_device(None, |_| panic!("callback panic"), move |err| err_sender.send(err).unwrap())
+            .unwrap();
+        let _audio_stream = audio_stream.start().unwrap();
+
+        let err = err_recv.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(matches!(err.without_stream(), AudioClientError::CallbackPanicked), "{:?}", err);
+    }
+
+    #[test]
+    fn stream_service() {
+        let (audio_stream, _format) = AudioClient::new()
+            .start_playback_device(None, |request| request.frames(), |_err| {})
+            .unwrap();
+        let clock = unsafe { audio_stream.service::<IAudioClock>() }.unwrap();
+        assert!(unsafe { clock.GetFrequency() }.unwrap() > 0);
+
+        let audio_stream = audio_stream.start().unwrap();
+        unsafe { audio_stream.service::<IAudioStreamVolume>() }.unwrap();
+    }
+
+    #[test]
+    fn stream_position_and_latency() {
+        let (audio_stream, _format) = AudioClient::new()
+            .start_playback_device(None, |request| request.frames(), |_err| {})
+            .unwrap();
+        let audio_stream = audio_stream.start().unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        let position = audio_stream.position().unwrap();
+        assert!(position.frequency() > 0);
+        assert!(position.duration() > Duration::ZERO && position.duration() < Duration::from_secs(1));
+        let age = StreamInstant::now().duration_since(position.timestamp()).unwrap();
+        assert!(age < Duration::from_millis(100));
+        assert!(audio_stream.latency().unwrap() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn stream_position_after_switch() {
+        let (playback_stream, _format) = AudioClient::new()
+            .start_playback_device(None, |request| request.frames(), |_err| {})
+            .unwrap();
+        let _playback_stream = playback_stream.start().unwrap();
+        let audio_stream = AudioClient::new()
+            .start_recording_loopback_device(None, |_| {}, |_err| {})
+            .unwrap()
+            .start()
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        let before = audio_stream.position().unwrap();
+
+        audio_stream.switch_target(&CaptureTarget::Process(std::process::id())).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        // The position comes from the client of the new target, which started after the switch
+        let after = audio_stream.position().unwrap();
+        assert!(after.duration() < before.duration());
+        assert!(audio_stream.latency().is_ok());
+    }
+
+    #[test]
+    fn buffer_duration() {
+        let mut client = AudioClient::new();
+        assert!(client.set_buffer_duration(Duration::ZERO).is_err());
+        assert!(client.set_buffer_duration(Duration::from_secs(3)).is_err());
+
+        client.set_buffer_duration(Duration::from_millis(200)).unwrap();
+        let config = client.clone().start_recording_loopback_device(None, |_| {}, |_| {}).unwrap();
+        let format = config.format().clone();
+        let min_bytes = format.get_n_samples_per_sec() as usize / 10 * format.block_align() as usize;
+        assert!(config.max_packet_bytes().unwrap() >= min_bytes);
+
+        // Shorter than any device period
+        client.set_buffer_duration(Duration::from_micros(10)).unwrap();
+        let res = client.start_recording_loopback_device(None, |_| {}, |_| {});
+        assert!(matches!(res, Err(AudioClientError::BufferDurationOutOfRange(..))));
+    }
+
+    #[test]
+    fn stream_thread_affinity() {
+        let (mask_send, mask_recv) = channel();
+        let (audio_stream, _format) = AudioClient::new()
+            .start_playback_device(
+                None,
+                move |request| {
+                    // Setting the same mask again returns the one the thread had, without changing it
+                    let previous = unsafe { SetThreadAffinityMask(GetCurrentThread(), 1) };
+                    let _ = mask_send.send(previous);
+                    request.frames()
+                },
+                |_err| {},
+            )
+            .unwrap();
+        let audio_stream = audio_stream.with_affinity(ThreadAffinity::Mask(1)).start().unwrap();
+        assert_ne!(audio_stream.thread_id(), unsafe { GetCurrentThreadId() });
+        assert_eq!(mask_recv.recv_timeout(Duration::from_secs(1)).unwrap(), 1);
+    }
+
+    #[test]
+    fn muted_capture() {
+        let (packet_send, packet_recv) = channel();
+        let audio_stream = AudioClient::new()
+            .start_recording_device(
+                None,
+                move |packet| {
+                    let silent = packet.data().iter().all(|byte| *byte == 0);
+                    let _ = packet_send.send((packet.is_muted(), silent));
+                },
+                |_err| {},
+            )
+            .unwrap()
+            .start()
+            .unwrap();
+        audio_stream.set_muted(true);
+        assert!(audio_stream.is_muted());
+        // Skip packets read before the flag was set
+        let muted = packet_recv.iter().find(|(muted, _)| *muted).unwrap();
+        assert_eq!(muted, (true, true));
+    }
+
+    #[test]
+    fn max_packet_bytes() {
+        let largest = Arc::new(Mutex::new(0));
+        let largest_packet = largest.clone();
+        let config = AudioClient::new()
+            .start_recording_device(
+                None,
+                move |packet| {
+                    let mut largest = largest_packet.lock().unwrap();
+                    *largest = (*largest).max(packet.data().len());
+                },
+                |_err| {},
+            )
+            .unwrap();
+        let limit = config.max_packet_bytes().unwrap();
+        assert!(limit > 0);
+        assert_eq!(limit % config.format().block_align() as usize, 0);
+        let audio_stream = config.start().unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        drop(audio_stream);
+        assert!(*largest.lock().unwrap() <= limit);
+    }
+
+    #[test]
+    fn partial_render_fill() {
+        let (audio_stream, _format) = AudioClient::new()
+            .start_playback_device(None, |request| request.frames() / 2, |_err| {})
+            .unwrap();
+        let audio_stream = audio_stream.with_glitch_recorder(GlitchRecorder::default()).start().unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        let report = audio_stream.glitch_report().unwrap();
+        assert!(
+            report
+                .get_glitches()
+                .iter()
+                .any(|glitch| matches!(glitch.get_kind(), GlitchKind::PartialFill { .. }))
+        );
+    }
+
+    #[test]
+    fn deadline_abort() {
+        let (err_sender, err_recv) = channel();
+        let (audio_stream, _format) = AudioClient::new()
+            .start_playback_device(
+                None,
+                |_| {
+                    std::thread::sleep(Duration::from_millis(15));
+                    0
+                },
+                move |err| err_sender.send(err).unwrap(),
+            )
+            .unwrap();
+        let mode = DeadlineMode::new().period(Duration::from_millis(5)).abort_after(3);
+        let audio_stream = audio_stream.with_deadline_mode(mode).start().unwrap();
+
+        let err = err_recv.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(matches!(err.without_stream(), AudioClientError::DeadlinesMissed(3)), "{:?}", err);
+        let stats = audio_stream.deadline_stats().unwrap();
+        assert_eq!(stats.get_misses(), 3);
+        assert_eq!(stats.get_period(), Duration::from_millis(5));
+    }
+
+    #[test]
+    fn playback_complete() {
+        let mut periods = 0;
+        let (audio_stream, _format) = AudioClient::new()
+            .start_playback_device(
+                None,
+                move |mut request| {
+                    periods += 1;
+                    if periods == 3 {
+                        request.finish();
+                    }
+                    request.frames()
+                },
+                |_err| {},
+            )
+            .unwrap();
+        let (complete_send, complete_recv) = channel();
+        let _audio_stream = audio_stream
+            .on_complete(move |complete| complete_send.send(complete).unwrap())
+            .start()
+            .unwrap();
+        let complete = complete_recv.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert!(complete.get_frames() > 0);
+    }
+
+    #[test]
+    fn typed_playback() {
+        let mut periods = 0;
+        let (audio_stream, format) = AudioClient::new()
+            .start_playback_device_typed::<i16, _, _>(
+                None,
+                move |samples| {
+                    samples.fill(i16::MAX / 8);
+                    periods += 1;
+                    match periods {
+                        3 => PlaybackControl::Finish(1),
+                        _ => PlaybackControl::Continue,
+                    }
+                },
+                |_err| {},
+            )
+            .unwrap();
+        assert!(is_convertible(&format));
+        let (complete_send, complete_recv) = channel();
+        let _audio_stream = audio_stream
+            .on_complete(move |complete| complete_send.send(complete).unwrap())
+            .start()
+            .unwrap();
+        let complete = complete_recv.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert!(complete.get_frames() > 1);
+    }
+
+    #[test]
+    fn stream_summary() {
+        let (audio_stream, _format) = AudioClient::new()
+            .start_playback_device(None, |request| request.frames(), |_err| {})
+            .unwrap();
+        let (summary_send, summary_recv) = channel();
+        let audio_stream = audio_stream
+            .on_summary(move |summary| summary_send.send(summary).unwrap())
+            .start()
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        let summary = audio_stream.stop_with_summary().unwrap();
+        assert!(summary.get_frames() > 0 && summary.get_callbacks() > 0);
+        assert!(summary.get_duration() > Duration::ZERO);
+        assert_eq!(summary_recv.try_recv().unwrap(), summary);
+    }
+
+    #[test]
+    fn adjust_sample_rate() {
+        let mut audio_client = AudioClient::new();
+        audio_client.set_rate_adjust(true);
+        let (audio_stream, format) = audio_client
+            .start_playback_device(None, |request| request.frames(), |_err| {})
+            .unwrap();
+        let audio_stream = audio_stream.start().unwrap();
+        let rate = format.get_n_samples_per_sec() as f32;
+        audio_stream.set_sample_rate(rate * 1.001).unwrap();
+        audio_stream.set_sample_rate(rate).unwrap();
+    }
+
+    #[test]
+    fn capture_channel_fallback() {
+        let requested = SampleFormat::new(FormatTag::WaveFormatIeeeFloat, 8, 48000, 32);
+        for (fallback, delivered_channels) in [(ChannelFallback::Device, None), (ChannelFallback::Remap, Some(8))] {
+            let mut audio_client = AudioClient::new();
+            audio_client.set_format(requested.clone()).unwrap();
+            audio_client.set_capture_options(CaptureOptions::new().channel_fallback(fallback));
+            let config = audio_client.start_recording_device(None, |_packet| {}, |_err| {}).unwrap();
+            let source_channels = config.source_format().get_channel();
+            assert_eq!(config.format().get_channel(), delivered_channels.unwrap_or(source_channels));
+            let _stream = config.start().unwrap();
+        }
+    }
+
+    #[test]
+    fn capture_closest_match() {
+        // 11025 Hz mono 16-bit is rarely the shared mode format of a capture device
+        let requested = SampleFormat::new(FormatTag::WaveFormatPcm, 1, 11025, 16);
+        let mut audio_client = AudioClient::new();
+        audio_client.set_format(requested.clone()).unwrap();
+        let config = audio_client.start_recording_device(None, |_packet| {}, |_err| {}).unwrap();
+        let device = DeviceManager::get_default_input_device().unwrap();
+        assert_eq!(device.format_supported(config.source_format()).unwrap(), FormatSupport::Supported);
+        assert_eq!(config.format(), config.source_format());
+        let _stream = config.start().unwrap();
+    }
+
+    #[test]
+    fn playback_runner() {
+        let (audio_stream, _format) = AudioClient::new()
+            .start_playback_device(None, |request| request.frames(), |_err| {})
+            .unwrap();
+        let mut runner = audio_stream.into_runner();
+        let stopper = runner.stopper();
+
+        let status = runner.poll_once(Duration::from_secs(1)).unwrap();
+        assert_eq!(status, PollStatus::Processed);
+        stopper.stop();
+        runner.run_until_stopped().unwrap();
+        assert_eq!(runner.poll_once(Duration::ZERO).unwrap(), PollStatus::Stopped);
+    }
+
+    #[test]
+    fn playback_failover_switch() {
+        let default_dev = DeviceManager::get_default_playback_device().unwrap();
+        let (switched_send, switched_recv) = channel();
+        let switched_send = Mutex::new(switched_send);
+        let mut client = AudioClient::new();
+        client.set_playback_options(
+            PlaybackOptions::new()
+                .failover(&DeviceManager::get_playback_devices().unwrap())
+                .on_output_switched(move |switched| switched_send.lock().unwrap().send(switched).unwrap()),
+        );
+        let mut failover = PlaybackFailover {
+            client,
+            current: Some(default_dev.clone()),
+            format: default_dev.get_mix_format().unwrap(),
+        };
+
+        // Without a lost device the switch still moves to the next device, or back to the default one
+        failover.switch().unwrap();
+        let switched = switched_recv.try_recv().unwrap();
+        assert_eq!(switched.get_from(), Some(&default_dev));
+        assert_eq!(failover.current.as_ref(), Some(switched.get_to()));
+    }
+
+    #[test]
+    fn process_capture_rate_mismatch() {
+        let mix_rate = DeviceManager::get_default_playback_device()
+            .unwrap()
+            .get_mix_format()
+            .unwrap()
+            .get_n_samples_per_sec();
+        let other_rate = if mix_rate == 44100 { 48000 } else { 44100 };
+        let mut client = AudioClient::new();
+        client
+            .set_format(SampleFormat::new(FormatTag::WaveFormatIeeeFloat, 2, other_rate, 32))
+            .unwrap();
+        client.set_capture_options(CaptureOptions::new().rate_mismatch(RateMismatchPolicy::Error));
+        let res = client.start_recording_process(std::process::id(), |_data| {}, |_err| {});
+        assert!(matches!(res, Err(AudioClientError::SampleRateMismatch(requested, mix)) if requested == other_rate && mix == mix_rate));
+    }
+
     #[test]
     fn process_capture() {
         let rendering_client = AudioClient::new();
-        let (audio_stream_config, _format) = rendering_client.start_playback_device(None, |_data| false, |_err| {}).unwrap();
+        let (audio_stream_config, _format) = rendering_client.start_playback_device(None, |_| 0, |_err| {}).unwrap();
         audio_stream_config.start().unwrap();
 
         let client = AudioClient::new();
@@ -1,42 +1,207 @@
+use std::cell::Cell;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, mpsc};
 use std::thread::{self};
+use std::time::{Duration, Instant};
 
+use log::warn;
+
+use crate::clock;
+use crate::com::ComSend;
+use crate::companion_session::CompanionSession;
+use crate::deadline::{DeadlineLog, DeadlineMode, DeadlineStats, DeadlineTracker};
+use crate::diagnostics::{ObjectKind, Tracked};
+#[cfg(feature = "notifications")]
+use crate::event_args::AudioSessionEventArgs;
+use crate::glitch_recorder::{GlitchLog, GlitchRecorder, GlitchReport, GlitchTracker};
+use crate::hooks::{self, Hooks, StreamDirection, StreamInfo};
+use crate::level_monitor::{self, LevelMonitor};
+use crate::packet_interval::{self, IntervalDeviation, IntervalMonitor};
+#[cfg(feature = "notifications")]
+use crate::session_watch::SessionWatch;
 use crate::stream_instant::StreamInstant;
+use crate::stream_recovery::{Recoverer, RecoveryEvent, ReopenFn, StreamRecovery};
+use crate::stream_summary::{StreamCounters, StreamSummary, SummarySink, SummarySlot};
 use crate::{
-    audio_client::{AudioClientError, EventHandleWrapper, get_wait_error},
-    sample_format::SampleFormat,
+    audio_client::{AudioClient, AudioClientError, EventHandleWrapper, PlaybackFailover, WaveFormatWrapper, get_wait_error},
+    capture_registry::FanOut,
+    capture_target::CaptureTarget,
+    convert::PacketConverter,
+    sample_format::{FormatTag, SampleFormat},
 };
 use windows::Win32::{
-    Foundation::{HANDLE, WAIT_OBJECT_0},
-    Media::Audio::{AUDCLNT_BUFFERFLAGS_SILENT, IAudioCaptureClient, IAudioClient, IAudioRenderClient},
+    Foundation::{HANDLE, WAIT_OBJECT_0, WAIT_TIMEOUT},
+    Media::Audio::{
+        AUDCLNT_BUFFERFLAGS_SILENT, AUDCLNT_E_DEVICE_INVALIDATED, IAudioCaptureClient, IAudioClient, IAudioClock, IAudioClockAdjustment,
+        IAudioRenderClient,
+    },
     System::Threading::{
-        CreateEventA, CreateEventW, GetCurrentThread, INFINITE, SetEvent, SetThreadPriority, THREAD_PRIORITY_TIME_CRITICAL,
-        WaitForMultipleObjectsEx,
+        CreateEventA, CreateEventW, GetCurrentThread, GetCurrentThreadId, INFINITE, SetEvent, SetThreadAffinityMask, SetThreadPriority,
+        SetThreadSelectedCpuSets, THREAD_PRIORITY_TIME_CRITICAL, WaitForMultipleObjectsEx,
     },
 };
+use windows::core::Interface;
+
+/// How long a playback stream with failover waits for a buffer event before checking its device
+const FAILOVER_CHECK_MS: u32 = 200;
+
+/// Client the stream loop runs on right now, updated by the runner when the loop moves to another client
+type CurrentClient = Arc<Mutex<ComSend<IAudioClient>>>;
 
 pub(crate) struct StreamRunContext<T> {
     audio_client: IAudioClient,
     stream_client: T,
-    stop_handle: HANDLE,
     format: SampleFormat,
 }
 unsafe impl<T> Send for StreamRunContext<T> {}
 
+impl<T: Interface> StreamRunContext<T> {
+    /// Moves to a client opened again with the same format, e.g. after the old one was invalidated
+    fn replace_client(&mut self, audio_client: IAudioClient) -> Result<(), AudioClientError> {
+        self.stream_client = unsafe { audio_client.GetService::<T>() }.map_err(AudioClientError::FailedToStartAudioClient)?;
+        self.audio_client = audio_client;
+        Ok(())
+    }
+}
+
+/// Identifies a stream across its config, the running stream and the callbacks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StreamId(u64);
+
+static NEXT_STREAM_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT_STREAM: Cell<Option<StreamId>> = const { Cell::new(None) };
+}
+
+impl StreamId {
+    fn next() -> Self {
+        Self(NEXT_STREAM_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The stream whose thread is calling, i.e. inside the data and error callbacks of a stream
+    /// Lets a callback shared by several streams tell which stream a packet or error belongs to
+    pub fn current() -> Option<StreamId> {
+        CURRENT_STREAM.with(|current| current.get())
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// The stream of the calling thread for log messages, see [`StreamId::current`]
+pub(crate) struct CurrentStream;
+
+impl fmt::Display for CurrentStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match StreamId::current() {
+            Some(id) => id.fmt(f),
+            None => f.write_str("#?"),
+        }
+    }
+}
+
+type ErrorFn = Box<dyn FnMut(AudioClientError) + Send + 'static>;
+
 pub struct AudioStreamConfig {
-    stream_fn: Box<dyn FnOnce() + Send + 'static>,
+    stream_loop: Box<dyn StreamLoop>,
+    error_callback: ErrorFn,
     stop_handle: HANDLE,
     format: SampleFormat,
-    thread_name: String,
+    source_format: SampleFormat,
+    thread_name: &'static str,
+    id: StreamId,
+    label: Option<String>,
+    glitch_log: Option<Arc<GlitchLog>>,
+    deadline_log: Option<Arc<DeadlineLog>>,
+    /// Only capture streams can be muted or switched
+    capture: Option<Arc<CaptureControl>>,
+    tracked: Tracked,
+    direction: StreamDirection,
+    hooks: Option<Arc<dyn Hooks>>,
+    /// Boxed to keep the config small, few streams set one
+    affinity: Option<Box<ThreadAffinity>>,
+    #[cfg(feature = "notifications")]
+    watch_session: bool,
+    companion: Option<Box<CompanionSession>>,
+    /// Set by the methods that know how to open the device again
+    recovery: Option<Box<Recoverer>>,
+    interval_monitor: Option<Box<IntervalMonitor>>,
+    summary: SummarySink,
 }
 
 unsafe impl Send for AudioStreamConfig {}
 
+/// Processors the stream thread runs on, see [`AudioStreamConfig::with_affinity`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ThreadAffinity {
+    /// Hard affinity mask in the processor group of the process, bit `n` allows logical processor `n`
+    Mask(usize),
+    /// Soft preference for the CPU sets with the given ids, as listed by `GetSystemCpuSetInformation`. Unlike a mask
+    /// it leaves the scheduler room when the preferred processors are busy.
+    CpuSets(Vec<u32>),
+}
+
+impl ThreadAffinity {
+    fn apply(&self) -> windows::core::Result<()> {
+        unsafe {
+            match self {
+                ThreadAffinity::Mask(mask) => match SetThreadAffinityMask(GetCurrentThread(), *mask) {
+                    0 => Err(windows::core::Error::from_win32()),
+                    _ => Ok(()),
+                },
+                ThreadAffinity::CpuSets(ids) => SetThreadSelectedCpuSets(GetCurrentThread(), ids).ok(),
+            }
+        }
+    }
+}
+
 pub struct CapturePacket<'a> {
     data: &'a [u8],
     timestamp: StreamInstant,
+    muted: bool,
+    markers: &'a [Marker],
 }
 
 impl<'a> CapturePacket<'a> {
+    /// Same packet with other data, e.g. after a conversion
+    pub(crate) fn with_data<'b>(&self, data: &'b [u8]) -> CapturePacket<'b>
+    where
+        'a: 'b,
+    {
+        CapturePacket {
+            data,
+            timestamp: self.timestamp,
+            muted: self.muted,
+            markers: self.markers,
+        }
+    }
+
+    /// Same packet with the markers moved, after a conversion changed the number of frames
+    fn with_markers<'b>(&self, markers: &'b [Marker]) -> CapturePacket<'b>
+    where
+        'a: 'b,
+    {
+        CapturePacket {
+            markers,
+            ..self.with_data(self.data)
+        }
+    }
+
+    /// Shorthand for [`StreamId::current`], always set inside a capture callback
+    pub fn stream_id(&self) -> Option<StreamId> {
+        StreamId::current()
+    }
+
     pub fn data(&self) -> &'a [u8] {
         self.data
     }
@@ -44,11 +209,368 @@ impl<'a> CapturePacket<'a> {
     pub fn timestamp(&self) -> &StreamInstant {
         &self.timestamp
     }
+
+    /// Whether the stream was muted with [`AudioStream::set_muted`], the data is silence then
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// Markers inserted with [`AudioStream::insert_marker`] that fall into this packet, usually none
+    pub fn markers(&self) -> &'a [Marker] {
+        self.markers
+    }
+
+    /// Copies the packet, e.g. to send it to another thread
+    pub fn to_owned(&self) -> OwnedCapturePacket {
+        OwnedCapturePacket {
+            data: self.data.to_vec(),
+            timestamp: self.timestamp,
+            muted: self.muted,
+            markers: self.markers.to_vec(),
+        }
+    }
+}
+
+/// A [`CapturePacket`] copied out of the stream buffer, to keep it past the callback, see [`CapturePacket::to_owned`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnedCapturePacket {
+    data: Vec<u8>,
+    timestamp: StreamInstant,
+    muted: bool,
+    markers: Vec<Marker>,
+}
+
+impl OwnedCapturePacket {
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn timestamp(&self) -> &StreamInstant {
+        &self.timestamp
+    }
+
+    /// See [`CapturePacket::is_muted`]
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// See [`CapturePacket::markers`]
+    pub fn markers(&self) -> &[Marker] {
+        &self.markers
+    }
+}
+
+/// A captured packet decoded to samples of type `T`, see
+/// [`AudioClient::start_capture_typed`](crate::audio_client::AudioClient::start_capture_typed)
+pub struct SamplePacket<'a, T> {
+    samples: &'a [T],
+    channels: u16,
+    timestamp: StreamInstant,
+    muted: bool,
+    markers: &'a [Marker],
+}
+
+impl<'a, T> SamplePacket<'a, T> {
+    pub(crate) fn new(samples: &'a [T], channels: u16, packet: &CapturePacket<'a>) -> Self {
+        Self {
+            samples,
+            channels,
+            timestamp: packet.timestamp,
+            muted: packet.muted,
+            markers: packet.markers,
+        }
+    }
+
+    /// Interleaved samples, [`SamplePacket::channels`] per frame
+    pub fn samples(&self) -> &'a [T] {
+        self.samples
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    pub fn timestamp(&self) -> &StreamInstant {
+        &self.timestamp
+    }
+
+    /// See [`CapturePacket::is_muted`]
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// See [`CapturePacket::markers`]
+    pub fn markers(&self) -> &'a [Marker] {
+        self.markers
+    }
+}
+
+/// A position in a capture stream, see [`AudioStream::insert_marker`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Marker {
+    label: String,
+    timestamp: StreamInstant,
+    offset: u32,
+}
+
+impl Marker {
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// When the marker was inserted, on the clock of the packet timestamps
+    pub fn timestamp(&self) -> &StreamInstant {
+        &self.timestamp
+    }
+
+    /// Frame of the packet data the marker falls on
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+}
+
+/// One period of a playback stream to fill, handed to the playback data callback
+///
+/// The callback returns how many frames it wrote from the start of the buffer. The frames after that are zeroed, and
+/// recorded as [`GlitchKind::PartialFill`](crate::glitch_recorder::GlitchKind::PartialFill) when a glitch recorder is
+/// attached. Writing no frames at all releases the period as silence. A source that ran out calls
+/// [`RenderRequest::finish`] with its last frames.
+pub struct RenderRequest<'a> {
+    buffer: &'a mut [u8],
+    frames: u32,
+    format: &'a SampleFormat,
+    finished: &'a mut bool,
+}
+
+impl<'a> RenderRequest<'a> {
+    /// Shorthand for [`StreamId::current`], always set inside a playback callback
+    pub fn stream_id(&self) -> Option<StreamId> {
+        StreamId::current()
+    }
+
+    /// Interleaved samples in the stream format, `frames() * format().block_align()` bytes
+    pub fn buffer(&mut self) -> &mut [u8] {
+        self.buffer
+    }
+
+    /// Frames the device wants for this period
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    pub fn format(&self) -> &SampleFormat {
+        self.format
+    }
+
+    /// Marks the frames written in this period as the end of the stream. The callback isn't called again, the stream
+    /// plays silence from then on and calls the [`AudioStreamConfig::on_complete`] hook once the last frame played out.
+    pub fn finish(&mut self) {
+        *self.finished = true;
+    }
+}
+
+/// What a typed playback callback wrote, see
+/// [`AudioClient::start_playback_device_typed`](crate::audio_client::AudioClient::start_playback_device_typed)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackControl {
+    /// Every frame of the buffer was written
+    Continue,
+    /// Only this many frames from the start of the buffer were written, like a [`RenderRequest`] callback returning less
+    /// than [`RenderRequest::frames`]
+    Partial(u32),
+    /// This many frames were written and are the last ones of the stream, see [`RenderRequest::finish`]
+    Finish(u32),
+}
+
+impl PlaybackControl {
+    /// Frames written of a period of `frames`, marking `request` finished for [`PlaybackControl::Finish`]
+    pub(crate) fn apply(self, request: &mut RenderRequest, frames: u32) -> u32 {
+        match self {
+            PlaybackControl::Continue => frames,
+            PlaybackControl::Partial(written) => written.min(frames),
+            PlaybackControl::Finish(written) => {
+                request.finish();
+                written.min(frames)
+            }
+        }
+    }
+}
+
+/// Handed to the [`AudioStreamConfig::on_complete`] hook once a finished playback stream played out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlaybackComplete {
+    frames: u64,
+}
+
+impl PlaybackComplete {
+    /// Frames the data callback wrote over the whole stream
+    pub fn get_frames(&self) -> u64 {
+        self.frames
+    }
+}
+
+type CompleteFn = Box<dyn FnOnce(PlaybackComplete) + Send + 'static>;
+
+/// Device position of a stream, see [`AudioStream::position`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamPosition {
+    position: u64,
+    frequency: u64,
+    timestamp: StreamInstant,
+}
+
+impl StreamPosition {
+    /// Raw `IAudioClock` position, in units of [`StreamPosition::frequency`] per second
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Units per second of the position, the sample rate for most devices but e.g. bytes per second for some
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    /// Audio played or captured by the device since the stream started
+    pub fn duration(&self) -> Duration {
+        let nanos = self.position as u128 * 1_000_000_000 / self.frequency.max(1) as u128;
+        Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+    }
+
+    /// When the device was at the position, in the timeline of capture packet timestamps
+    pub fn timestamp(&self) -> &StreamInstant {
+        &self.timestamp
+    }
+}
+
+/// Holds back the `Start()` call of several stream threads until all of them are ready
+#[derive(Default)]
+pub(crate) struct StartGate {
+    state: Mutex<GateState>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct GateState {
+    arrived: usize,
+    /// First error of a thread that failed preparing its client
+    setup_error: Option<AudioClientError>,
+    open: Option<bool>,
+    started: usize,
+    /// First error of a thread whose `Start()` failed
+    start_error: Option<AudioClientError>,
+}
+
+impl StartGate {
+    fn lock(&self) -> MutexGuard<'_, GateState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Called by a stream thread once its client is ready or failed to set up, returns false if the group was cancelled
+    pub(crate) fn arrive_and_wait(&self, setup: Result<(), &AudioClientError>) -> bool {
+        let mut state = self.lock();
+        state.arrived += 1;
+        if let Err(err) = setup {
+            state.setup_error.get_or_insert_with(|| err.clone());
+        }
+        self.cond.notify_all();
+        let state = self.cond.wait_while(state, |s| s.open.is_none()).unwrap_or_else(|e| e.into_inner());
+        state.open == Some(true)
+    }
+
+    /// Waits for `count` threads to arrive, fails with the first setup error
+    pub(crate) fn wait_arrived(&self, count: usize) -> Result<(), AudioClientError> {
+        let state = self.lock();
+        let mut state = self
+            .cond
+            .wait_while(state, |s| s.arrived < count)
+            .unwrap_or_else(|e| e.into_inner());
+        state.setup_error.take().map_or(Ok(()), Err)
+    }
+
+    /// Called by a stream thread after its `Start()` call
+    pub(crate) fn report_started(&self, started: Result<(), &AudioClientError>) {
+        let mut state = self.lock();
+        state.started += 1;
+        if let Err(err) = started {
+            state.start_error.get_or_insert_with(|| err.clone());
+        }
+        self.cond.notify_all();
+    }
+
+    /// Waits for `count` threads to call `Start()`, fails with the first error
+    pub(crate) fn wait_started(&self, count: usize) -> Result<(), AudioClientError> {
+        let state = self.lock();
+        let mut state = self
+            .cond
+            .wait_while(state, |s| s.started < count)
+            .unwrap_or_else(|e| e.into_inner());
+        state.start_error.take().map_or(Ok(()), Err)
+    }
+
+    /// Releases every waiting thread, `start` decides whether they go on to start their clients
+    pub(crate) fn open(&self, start: bool) {
+        let mut state = self.lock();
+        state.open = Some(start);
+        self.cond.notify_all();
+    }
+}
+
+/// Set before signalling the stop event to deliver the packets left in the buffer until the deadline
+type DrainDeadline = Arc<Mutex<Option<Instant>>>;
+
+/// State a capture loop shares with the handles of its stream
+pub(crate) struct CaptureControl {
+    muted: AtomicBool,
+    /// Format the loop reads from the client, a new target is opened in it
+    format: SampleFormat,
+    /// Client of the target passed to [`AudioStream::switch_target`], taken over on the next buffer event
+    next_client: Mutex<Option<StreamRunContext<IAudioCaptureClient>>>,
+    /// Markers waiting for the packet they fall into
+    markers: Mutex<Vec<(String, StreamInstant)>>,
+    /// Format the callback gets, which a registered capture hands to its subscribers
+    delivered_format: SampleFormat,
+    /// Registry entry of a process capture, moved along by [`AudioStream::switch_target`]
+    registration: OnceLock<Arc<FanOut>>,
+}
+
+impl CaptureControl {
+    fn registration(&self) -> Option<&Arc<FanOut>> {
+        self.registration.get()
+    }
+}
+
+impl Drop for CaptureControl {
+    fn drop(&mut self) {
+        if let Some(registration) = self.registration() {
+            registration.stop();
+        }
+    }
 }
 
 pub struct AudioStream {
     thread: Option<thread::JoinHandle<()>>,
+    thread_id: u32,
+    audio_client: CurrentClient,
     stop_handle: HANDLE,
+    drain_until: DrainDeadline,
+    id: StreamId,
+    label: Option<String>,
+    glitch_log: Option<Arc<GlitchLog>>,
+    deadline_log: Option<Arc<DeadlineLog>>,
+    capture: Option<Arc<CaptureControl>>,
+    summary: SummarySlot,
+    /// Stopped after the stream, when the fields are dropped
+    _companion: Option<Box<AudioStream>>,
+    #[cfg(feature = "notifications")]
+    session_watch: Option<Box<SessionWatch>>,
 }
 
 unsafe impl Send for AudioStream {}
@@ -56,9 +578,12 @@ unsafe impl Send for AudioStream {}
 impl AudioStreamConfig {
     pub(crate) fn create_capture_stream<D, E>(
         data_callback: D,
-        mut error_callback: E,
+        error_callback: E,
         audio_client: IAudioClient,
         format: Option<SampleFormat>,
+        deliver_as: Option<SampleFormat>,
+        hooks: Option<Arc<dyn Hooks>>,
+        monitor: Option<LevelMonitor>,
     ) -> Result<AudioStreamConfig, AudioClientError>
     where
         D: FnMut(CapturePacket) + Send + 'static,
@@ -72,203 +597,1321 @@ impl AudioStreamConfig {
             Some(format) => format,
             None => {
                 let mix_format = unsafe { audio_client.GetMixFormat() }.map_err(AudioClientError::FailedToGetMixFormat)?;
-                SampleFormat::from_wave_format_ex(mix_format)
+                let mix_format = WaveFormatWrapper::from_ptr(mix_format);
+                SampleFormat::from_wave_format_ex(*mix_format)
             }
         };
 
         let run_context = StreamRunContext {
             audio_client,
             stream_client: capture_client,
-            stop_handle: stop_handle.clone(),
             format: format.clone(),
         };
+        let id = StreamId::next();
+        let delivered = deliver_as.as_ref().unwrap_or(&format);
+        let control = Arc::new(CaptureControl {
+            muted: AtomicBool::new(false),
+            format: format.clone(),
+            next_client: Mutex::new(None),
+            markers: Mutex::new(Vec::new()),
+            delivered_format: delivered.clone(),
+            registration: OnceLock::new(),
+        });
 
-        let capture_fn = move || {
-            let res = Self::capture_audio(run_context, data_callback);
-            if let Err(err) = res {
-                error_callback(err);
+        // Once the stream is registered, its subscribers get everything the callbacks get
+        let (data_control, error_control) = (control.clone(), control.clone());
+        let (mut data_callback, mut error_callback) = (data_callback, error_callback);
+        let data_callback = move |packet: CapturePacket| {
+            if let Some(registration) = data_control.registration() {
+                registration.deliver(&packet);
+            }
+            data_callback(packet);
+        };
+        let error_callback = move |err: AudioClientError| {
+            if let Some(registration) = error_control.registration() {
+                registration.error(&err);
+            }
+            error_callback(err);
+        };
+        let data_callback = level_monitor::observe_levels(monitor, delivered, data_callback);
+        let data_callback = hooks::observe_capture(hooks.clone(), id, delivered.block_align() as usize, data_callback);
+        let error_callback = hooks::observe_errors(hooks.clone(), id, error_callback);
+        let (stream_loop, delivered_format): (Box<dyn StreamLoop>, _) = match deliver_as.filter(|deliver_as| *deliver_as != format) {
+            Some(deliver_as) => {
+                let mut converter = PacketConverter::new(format.clone(), deliver_as.clone())
+                    .ok_or_else(|| AudioClientError::UnsupportedConversion(format.clone(), deliver_as.clone()))?;
+                let mut data_callback = data_callback;
+                let (source_block_align, delivered_block_align) = (format.block_align() as usize, deliver_as.block_align() as usize);
+                let convert_callback = move |packet: CapturePacket| {
+                    let data = converter.convert(packet.data);
+                    if packet.markers.is_empty() {
+                        return data_callback(packet.with_data(data));
+                    }
+                    // Rate conversion changes the number of frames, the markers keep their relative position
+                    let (frames, converted_frames) = (packet.data.len() / source_block_align, data.len() / delivered_block_align);
+                    let markers: Vec<Marker> = packet
+                        .markers
+                        .iter()
+                        .map(|marker| Marker {
+                            offset: (marker.offset as usize * converted_frames / frames.max(1)).min(converted_frames.saturating_sub(1))
+                                as u32,
+                            ..marker.clone()
+                        })
+                        .collect();
+                    data_callback(packet.with_data(data).with_markers(&markers))
+                };
+                (
+                    Box::new(CaptureLoop::new(run_context, convert_callback, control.clone())),
+                    deliver_as,
+                )
             }
+            None => (
+                Box::new(CaptureLoop::new(run_context, data_callback, control.clone())),
+                format.clone(),
+            ),
         };
 
         Ok(AudioStreamConfig {
-            stream_fn: Box::new(capture_fn),
+            stream_loop,
+            error_callback: Box::new(error_callback),
             stop_handle,
-            format: format.clone(),
-            thread_name: "capture".to_string(),
+            format: delivered_format,
+            source_format: format,
+            thread_name: "capture",
+            id,
+            label: None,
+            glitch_log: None,
+            deadline_log: None,
+            capture: Some(control),
+            tracked: Tracked::new(ObjectKind::AudioClient),
+            direction: StreamDirection::Capture,
+            hooks,
+            affinity: None,
+            #[cfg(feature = "notifications")]
+            watch_session: false,
+            companion: None,
+            recovery: None,
+            interval_monitor: None,
+            summary: SummarySink::new(None),
         })
     }
 
     pub(crate) fn create_playback_stream<D, E>(
         data_callback: D,
-        mut error_callback: E,
+        error_callback: E,
         audio_client: IAudioClient,
         format: SampleFormat,
+        failover: Option<PlaybackFailover>,
+        hooks: Option<Arc<dyn Hooks>>,
     ) -> Result<AudioStreamConfig, AudioClientError>
     where
-        D: FnMut(&mut [u8]) -> bool + Send + 'static,
+        D: FnMut(RenderRequest) -> u32 + Send + 'static,
         E: FnMut(AudioClientError) + Send + 'static,
     {
         let render_client =
             unsafe { audio_client.GetService::<IAudioRenderClient>() }.map_err(AudioClientError::FailedToStartAudioClient)?;
+        let buffer_size = unsafe { audio_client.GetBufferSize() }.map_err(AudioClientError::FailedToStartAudioClient)?;
         let stop_handle = unsafe { CreateEventW(None, false, false, None) }.map_err(AudioClientError::EventCreationError)?;
 
         let run_context = StreamRunContext {
             audio_client,
             stream_client: render_client,
-            stop_handle: stop_handle.clone(),
             format: format.clone(),
         };
-
-        let capture_fn = move || {
-            let res = Self::playback_audio(run_context, data_callback);
-            if let Err(err) = res {
-                error_callback(err);
-            }
-        };
+        let id = StreamId::next();
+        let data_callback = hooks::observe_playback(hooks.clone(), id, data_callback);
+        let error_callback = hooks::observe_errors(hooks.clone(), id, error_callback);
 
         Ok(AudioStreamConfig {
-            stream_fn: Box::new(capture_fn),
+            stream_loop: Box::new(PlaybackLoop {
+                run_context,
+                data_callback,
+                buffer_size,
+                failover,
+                glitches: None,
+                deadlines: None,
+                frames_written: 0,
+                last_padding: 0,
+                remaining: None,
+                on_complete: None,
+                counters: StreamCounters::new(format.get_n_samples_per_sec()),
+            }),
+            error_callback: Box::new(error_callback),
             stop_handle,
-            format,
-            thread_name: "playback".to_string(),
+            format: format.clone(),
+            source_format: format,
+            thread_name: "playback",
+            id,
+            label: None,
+            glitch_log: None,
+            deadline_log: None,
+            capture: None,
+            tracked: Tracked::new(ObjectKind::AudioClient),
+            direction: StreamDirection::Playback,
+            hooks,
+            affinity: None,
+            #[cfg(feature = "notifications")]
+            watch_session: false,
+            companion: None,
+            recovery: None,
+            interval_monitor: None,
+            summary: SummarySink::new(None),
         })
     }
 
     pub fn start(self) -> Result<AudioStream, AudioClientError> {
-        let thr = thread::Builder::new()
-            .name(self.thread_name)
-            .spawn(self.stream_fn)
+        self.start_gated(None)
+    }
+
+    /// Runs the stream on a thread spawned from `builder`, letting the caller pick the name and stack size
+    pub fn start_on(self, builder: thread::Builder) -> Result<AudioStream, AudioClientError> {
+        self.spawn(builder, None)
+    }
+
+    /// Spawns the stream thread, with a gate the thread prepares the client and waits for the gate before calling `Start()`
+    pub(crate) fn start_gated(self, start_gate: Option<Arc<StartGate>>) -> Result<AudioStream, AudioClientError> {
+        // The id and label end up in the thread name, so they show up in debuggers and logs
+        let thread_name = match &self.label {
+            Some(label) => format!("{} {} {}", self.thread_name, self.id, label),
+            None => format!("{} {}", self.thread_name, self.id),
+        };
+        self.spawn(thread::Builder::new().name(thread_name), start_gate)
+    }
+
+    fn spawn(mut self, builder: thread::Builder, start_gate: Option<Arc<StartGate>>) -> Result<AudioStream, AudioClientError> {
+        let (glitch_log, deadline_log) = (self.glitch_log.clone(), self.deadline_log.clone());
+        let (capture, affinity) = (self.capture.clone(), self.affinity.clone());
+        let companion = self.companion.take().and_then(|companion| match companion.start() {
+            Ok(stream) => Some(Box::new(stream)),
+            Err(err) => {
+                warn!("Failed starting companion session {}: {}", companion.get_display_name(), err);
+                None
+            }
+        });
+        #[cfg(feature = "notifications")]
+        let session_watch = self
+            .watch_session
+            .then(|| SessionWatch::start(self.stream_loop.audio_client()))
+            .and_then(|session_watch| match session_watch {
+                Ok(session_watch) => Some(Box::new(session_watch)),
+                Err(err) => {
+                    warn!("Failed watching the session of stream {}: {}", self.id, err);
+                    None
+                }
+            });
+        let (mut runner, mut error_callback) = self.into_parts();
+        let summary = runner.summary.slot();
+        let audio_client = runner.current_client.clone();
+        let (id, label, stop_handle, drain_until) = (runner.id, runner.label.clone(), runner.stop_handle, runner.drain_until.clone());
+        let (thread_id_sender, thread_id_receiver) = mpsc::sync_channel(1);
+        let thr = builder
+            .spawn(move || {
+                let _ = thread_id_sender.send(unsafe { GetCurrentThreadId() });
+                CURRENT_STREAM.with(|current| current.set(Some(id)));
+                set_thread_priority();
+                if let Some(affinity) = affinity
+                    && let Err(err) = affinity.apply()
+                {
+                    warn!("Failed setting the affinity of stream {} to {:?}: {}", id, affinity, err);
+                }
+                if let Err(err) = runner.run_gated(start_gate) {
+                    error_callback(AudioClientError::InStream(id, Box::new(err)));
+                }
+            })
             .map_err(|_| AudioClientError::FailedToCreateThread)?;
+        let thread_id = thread_id_receiver.recv().map_err(|_| AudioClientError::FailedToCreateThread)?;
         Ok(AudioStream {
             thread: Some(thr),
-            stop_handle: self.stop_handle,
+            thread_id,
+            audio_client,
+            stop_handle,
+            drain_until,
+            id,
+            label,
+            glitch_log,
+            deadline_log,
+            capture,
+            summary,
+            _companion: companion,
+            #[cfg(feature = "notifications")]
+            session_watch,
         })
     }
 
-    pub fn format(&self) -> &SampleFormat {
-        &self.format
+    /// Hands the stream loop to the caller instead of spawning a thread, see [`StreamRunner`]
+    /// The error callback isn't used, errors are returned from the runner methods instead
+    pub fn into_runner(self) -> StreamRunner {
+        self.into_parts().0
+    }
+
+    fn into_parts(self) -> (StreamRunner, ErrorFn) {
+        let hooks = self.hooks.map(|hooks| {
+            let info = StreamInfo {
+                id: self.id,
+                direction: self.direction,
+                format: self.format,
+                label: self.label.clone(),
+            };
+            (hooks, info)
+        });
+        let audio_client = self.stream_loop.audio_client().clone();
+        let runner = StreamRunner {
+            stream_loop: self.stream_loop,
+            stop_handle: self.stop_handle,
+            drain_until: Arc::default(),
+            h_event: None,
+            finished: false,
+            id: self.id,
+            label: self.label,
+            _tracked: self.tracked,
+            hooks,
+            reported_start: false,
+            recovery: self.recovery,
+            interval_monitor: self.interval_monitor,
+            summary: self.summary,
+            current_client: Arc::new(Mutex::new(ComSend(audio_client))),
+        };
+        (runner, self.error_callback)
+    }
+
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    /// Attaches a user label to the stream, shown next to the id in the stream thread name
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Pins the stream thread to the given processors, for applications that keep audio work on isolated cores
+    /// Failing to apply it is logged and the stream runs without. Streams run with [`AudioStreamConfig::into_runner`]
+    /// keep the affinity of the calling thread.
+    pub fn with_affinity(mut self, affinity: ThreadAffinity) -> Self {
+        self.affinity = Some(Box::new(affinity));
+        self
+    }
+
+    /// Lists the stream in the volume mixer under the given name while it runs, for capture streams of applications
+    /// that don't play audio, see [`CompanionSession`]. Failing to start the companion is logged and the stream runs
+    /// without. Streams run with [`AudioStreamConfig::into_runner`] don't start it.
+    pub fn with_companion_session(mut self, companion: CompanionSession) -> Self {
+        self.companion = Some(Box::new(companion));
+        self
+    }
+
+    /// Delivers the events of the stream's own audio session to [`AudioStream::session_events`], so the application
+    /// notices when the user mutes it, changes its volume in the mixer or the session is disconnected. Failing to
+    /// register is logged and the stream runs without. Streams run with [`AudioStreamConfig::into_runner`] don't watch.
+    #[cfg(feature = "notifications")]
+    pub fn watch_session(mut self, watch: bool) -> Self {
+        self.watch_session = watch;
+        self
     }
 
-    fn capture_audio<D>(run_context: StreamRunContext<IAudioCaptureClient>, mut data_callback: D) -> Result<(), AudioClientError>
+    /// Opens the device again with the same format when it is invalidated, e.g. removed, switched to another format in
+    /// the sound settings or restarted with the audio service, instead of failing the stream. `callback` gets the
+    /// progress on the stream thread. Only streams opened on a device or process know how to reopen it, for others the
+    /// policy is logged and ignored. The client behind [`AudioStream::service`] stays the original one.
+    pub fn with_recovery<F>(mut self, recovery: StreamRecovery, callback: F) -> Self
     where
-        D: FnMut(CapturePacket),
+        F: FnMut(RecoveryEvent) + Send + 'static,
     {
-        Self::set_thread_priority();
-        let (audio_client, capture_client) = (run_context.audio_client, run_context.stream_client);
+        match &mut self.recovery {
+            Some(recoverer) => recoverer.set_policy(recovery, Box::new(callback)),
+            None => warn!("Stream {} can't reopen its device, ignoring {:?}", self.id, recovery),
+        }
+        self
+    }
 
-        let block_align = run_context.format.block_align() as usize;
+    /// Keeps the registry entry of a process capture for [`AudioStream::switch_target`]
+    pub(crate) fn with_registration(self, registration: Arc<FanOut>) -> Self {
+        if let Some(control) = &self.capture {
+            let _ = control.registration.set(registration);
+        }
+        self
+    }
 
-        let mut buffer: *mut u8 = std::ptr::null_mut();
-        let mut flags: u32 = 0;
-        let mut pu64qpcposition: u64 = 0;
+    /// Lets [`AudioStreamConfig::with_recovery`] open the device again through `reopen`
+    pub(crate) fn with_reopen(mut self, reopen: ReopenFn) -> Self {
+        self.recovery = Some(Box::new(Recoverer::new(reopen)));
+        self
+    }
+
+    /// Called on the stream thread once a playback stream whose data callback called [`RenderRequest::finish`] played
+    /// its last frame, judged by the padding of the device buffer. Never called for capture streams, or if the stream
+    /// is stopped before.
+    pub fn on_complete<F>(mut self, callback: F) -> Self
+    where
+        F: FnOnce(PlaybackComplete) + Send + 'static,
+    {
+        self.stream_loop.on_complete(Box::new(callback));
+        self
+    }
+
+    /// Called once the stream stopped, for whatever reason, with its [`StreamSummary`]. Runs on the stream thread
+    /// before it exits, the summary is also kept for [`AudioStream::summary`].
+    pub fn on_summary<F>(mut self, callback: F) -> Self
+    where
+        F: FnOnce(StreamSummary) + Send + 'static,
+    {
+        self.summary = SummarySink::new(Some(Box::new(callback)));
+        self
+    }
+
+    /// Keeps a bounded log of discontinuities, silent packets, device position jumps and callback overruns, read it
+    /// with [`AudioStream::glitch_report`]
+    pub fn with_glitch_recorder(mut self, recorder: GlitchRecorder) -> Self {
+        let glitch_log = Arc::new(GlitchLog::new(recorder));
+        let sample_rate = self.source_format.get_n_samples_per_sec();
+        self.stream_loop.track_glitches(GlitchTracker::new(glitch_log.clone(), sample_rate));
+        self.glitch_log = Some(glitch_log);
+        self
+    }
+
+    /// Measures every callback against the device period and counts the misses, read them with
+    /// [`AudioStream::deadline_stats`]. With [`DeadlineMode::abort_after`] the stream fails once the callback keeps
+    /// missing its deadline, instead of running late.
+    pub fn with_deadline_mode(mut self, mode: DeadlineMode) -> Self {
+        let mut default_period = 0i64;
+        let device_period = match unsafe { self.stream_loop.audio_client().GetDevicePeriod(Some(&mut default_period), None) } {
+            Ok(()) => Duration::from_nanos(default_period as u64 * 100),
+            // The default period of shared mode streams on every device seen so far
+            Err(_) => Duration::from_millis(10),
+        };
+        let tracker = DeadlineTracker::new(mode, device_period);
+        self.deadline_log = Some(tracker.log());
+        self.stream_loop.track_deadlines(tracker);
+        self
+    }
+
+    /// Interval the buffer events should come at, which is also the usual duration of a packet: the engine period of
+    /// the stream, limited to its buffer duration
+    pub fn expected_packet_interval(&self) -> Duration {
+        packet_interval::expected_interval(self.stream_loop.audio_client(), &self.source_format)
+    }
+
+    /// Measures the intervals between buffer events in windows of 100 events and calls `callback` on the stream thread
+    /// for every window whose mean is off from [`AudioStreamConfig::expected_packet_interval`] by more than `tolerance`,
+    /// a fraction of the expected interval. Deviating windows are logged too.
+    pub fn with_interval_monitor<F>(mut self, tolerance: f64, callback: F) -> Self
+    where
+        F: FnMut(IntervalDeviation) + Send + 'static,
+    {
+        let expected = self.expected_packet_interval();
+        self.interval_monitor = Some(Box::new(IntervalMonitor::new(expected, tolerance, 100, Box::new(callback))));
+        self
+    }
+
+    /// Format of the data handed to the callbacks
+    pub fn format(&self) -> &SampleFormat {
+        &self.format
+    }
+
+    /// Format used by the device or session, differs from [`AudioStreamConfig::format`] when the crate converts packets
+    pub fn source_format(&self) -> &SampleFormat {
+        &self.source_format
+    }
 
-        let h_event = unsafe { CreateEventA(None, false, false, None) }.map_err(|h| AudioClientError::FailedToCreateStopEvent(h))?;
-        let h_event = EventHandleWrapper(h_event);
-        let handles = [*h_event, run_context.stop_handle];
-        unsafe { audio_client.SetEventHandle(*h_event) }.map_err(|h| AudioClientError::FailedToSetupEventHandle(h))?;
-        unsafe { audio_client.Start() }.map_err(|h| AudioClientError::FailedToStartAudioClient(h))?;
+    pub fn is_converted(&self) -> bool {
+        self.format != self.source_format
+    }
+
+    /// Largest packet in bytes the data callback can get, the whole device buffer in the delivered format
+    ///
+    /// With rate conversion the frame count is scaled to the delivered rate and rounded up, plus one frame the
+    /// resampler may carry over from the previous packet.
+    pub fn max_packet_bytes(&self) -> Result<usize, AudioClientError> {
+        let buffer_frames =
+            unsafe { self.stream_loop.audio_client().GetBufferSize() }.map_err(AudioClientError::FailedGettingBuffer)? as u64;
+        let (source_rate, rate) = (
+            self.source_format.get_n_samples_per_sec() as u64,
+            self.format.get_n_samples_per_sec() as u64,
+        );
+        let frames = match source_rate == rate || source_rate == 0 {
+            true => buffer_frames,
+            false => (buffer_frames * rate).div_ceil(source_rate) + 1,
+        };
+        Ok(frames as usize * self.format.block_align() as usize)
+    }
+
+    /// Gets a service the crate doesn't wrap, e.g. `IAudioClockAdjustment`, from the client of this stream
+    ///
+    /// # Safety
+    /// The stream thread drives the client and its capture or render client. The returned interface must not be used to
+    /// start, stop or reset the client or to get and release buffers, and must not outlive the stream's COM apartment.
+    pub unsafe fn service<T: Interface>(&self) -> Result<T, AudioClientError> {
+        get_service(self.stream_loop.audio_client())
+    }
+}
+
+/// Outcome of a single [`StreamRunner::poll_once`] call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PollStatus {
+    /// A buffer event was handled
+    Processed,
+    /// Nothing happened before the timeout
+    TimedOut,
+    /// The stream was stopped, further polls return this again
+    Stopped,
+}
+
+/// A stream driven by a thread owned by the caller, created with [`AudioStreamConfig::into_runner`]
+///
+/// The client is started on the first poll and stopped once a stop is requested through a [`StreamStopper`] or the runner
+/// is dropped. Unlike the crate spawned threads, the runner doesn't change the priority of the calling thread.
+pub struct StreamRunner {
+    stream_loop: Box<dyn StreamLoop>,
+    stop_handle: HANDLE,
+    drain_until: DrainDeadline,
+    h_event: Option<EventHandleWrapper>,
+    finished: bool,
+    id: StreamId,
+    label: Option<String>,
+    _tracked: Tracked,
+    hooks: Option<(Arc<dyn Hooks>, StreamInfo)>,
+    /// The start hook only runs for the first start, not for restarts after a recovery
+    reported_start: bool,
+    recovery: Option<Box<Recoverer>>,
+    interval_monitor: Option<Box<IntervalMonitor>>,
+    summary: SummarySink,
+    current_client: CurrentClient,
+}
+
+unsafe impl Send for StreamRunner {}
+
+impl StreamRunner {
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Returns a handle that stops the runner from any thread
+    pub fn stopper(&self) -> StreamStopper {
+        StreamStopper {
+            stop_handle: self.stop_handle,
+        }
+    }
+
+    /// Waits up to `timeout` for the next buffer event and handles it
+    pub fn poll_once(&mut self, timeout: Duration) -> Result<PollStatus, AudioClientError> {
+        let timeout = u32::try_from(timeout.as_millis()).unwrap_or(INFINITE - 1).min(INFINITE - 1);
+        self.poll(timeout)
+    }
+
+    /// Handles buffer events on the calling thread until the stream is stopped
+    pub fn run_until_stopped(&mut self) -> Result<(), AudioClientError> {
+        let timeout = self.stream_loop.idle_timeout().unwrap_or(INFINITE);
+        while self.poll(timeout)? != PollStatus::Stopped {}
+        Ok(())
+    }
 
-        while let Ok(mut frames_available) = unsafe { capture_client.GetNextPacketSize() } {
-            let wait_res = unsafe { get_wait_error(WaitForMultipleObjectsEx(&handles, false, INFINITE, false))? };
+    fn run_gated(&mut self, start_gate: Option<Arc<StartGate>>) -> Result<(), AudioClientError> {
+        if !self.start(start_gate)? {
+            return Ok(());
+        }
+        self.run_until_stopped()
+    }
 
-            // Stop event was called
-            if wait_res == WAIT_OBJECT_0.0 + 1 {
-                break;
+    /// Returns false if the gate was cancelled before the client was started
+    fn start(&mut self, start_gate: Option<Arc<StartGate>>) -> Result<bool, AudioClientError> {
+        match start_client(self.stream_loop.audio_client(), start_gate)? {
+            Some(h_event) => {
+                self.h_event = Some(h_event);
+                if let Some((hooks, info)) = &self.hooks
+                    && !self.reported_start
+                {
+                    hooks.on_stream_start(info);
+                }
+                self.reported_start = true;
+                Ok(true)
             }
+            None => {
+                self.finished = true;
+                Ok(false)
+            }
+        }
+    }
 
-            if frames_available == 0 {
-                continue;
+    fn poll(&mut self, timeout: u32) -> Result<PollStatus, AudioClientError> {
+        if self.finished || (self.h_event.is_none() && !self.start(None)?) {
+            return Ok(PollStatus::Stopped);
+        }
+        let Some(h_event) = &self.h_event else {
+            return Ok(PollStatus::Stopped);
+        };
+        let handles = [**h_event, self.stop_handle];
+        let wait_res = unsafe { get_wait_error(WaitForMultipleObjectsEx(&handles, false, timeout, false))? };
+        if wait_res == WAIT_TIMEOUT.0 && self.stream_loop.idle_timeout().is_none() {
+            return Ok(PollStatus::TimedOut);
+        }
+        // Stop event was called
+        if wait_res == WAIT_OBJECT_0.0 + 1 {
+            self.finished = true;
+            self.h_event = None;
+            let drain_until = self.drain_until.lock().unwrap_or_else(|e| e.into_inner()).take();
+            if let Some(deadline) = drain_until {
+                // Nothing new arrives once stopped, what is already buffered stays readable until the reset
+                unsafe { self.stream_loop.audio_client().Stop() }.map_err(AudioClientError::FailedStoppingAudioClient)?;
+                self.in_stream(|stream_loop| stream_loop.drain(deadline))?;
             }
-            unsafe {
-                capture_client.GetBuffer(
-                    &mut buffer,
-                    &mut frames_available as *mut _,
-                    &mut flags as *mut _,
-                    None,
-                    Some(&mut pu64qpcposition as *mut _),
-                )
+            self.summarize();
+            stop_client(self.stream_loop.audio_client())?;
+            self.report_stop();
+            return Ok(PollStatus::Stopped);
+        }
+
+        let moved = match self.in_stream(|stream_loop| stream_loop.process()) {
+            Ok(()) => self.in_stream(|stream_loop| stream_loop.switch_client())?,
+            Err(err) => match self.in_stream(|stream_loop| stream_loop.recover(err)) {
+                Ok(()) => true,
+                Err(err) => {
+                    if !self.reopen(err)? {
+                        // Stopped while waiting for the device, the old client is gone and isn't stopped
+                        self.summarize();
+                        self.finished = true;
+                        self.h_event = None;
+                        self.report_stop();
+                        return Ok(PollStatus::Stopped);
+                    }
+                    true
+                }
+            },
+        };
+        if let Some(monitor) = &mut self.interval_monitor {
+            match moved {
+                true => monitor.reset(),
+                false if wait_res == WAIT_OBJECT_0.0 => monitor.buffer_event(Instant::now()),
+                false => {}
             }
-            .map_err(AudioClientError::FailedGettingBuffer)?;
-            debug_assert!(!buffer.is_null());
-            let now = convert_instant(pu64qpcposition);
+        }
+        if moved {
+            *self.current_client.lock().unwrap_or_else(|e| e.into_inner()) = ComSend(self.stream_loop.audio_client().clone());
+            // The loop moved to a new client, which needs its own buffer event
+            self.h_event = None;
+            self.start(None)?;
+        }
+        if wait_res == WAIT_TIMEOUT.0 {
+            return Ok(PollStatus::TimedOut);
+        }
+        Ok(PollStatus::Processed)
+    }
 
-            let buf_slice = unsafe { std::slice::from_raw_parts(buffer, frames_available as usize * block_align) };
-            data_callback(CapturePacket {
-                data: buf_slice,
-                timestamp: now,
-            });
+    /// Opens the device again after `err` if the stream has a [`StreamRecovery`], returns false if the stream was stopped
+    /// in the meantime
+    fn reopen(&mut self, err: AudioClientError) -> Result<bool, AudioClientError> {
+        let Some(recovery) = &mut self.recovery else {
+            return Err(err);
+        };
+        let Some(audio_client) = recovery.recover(err, self.stop_handle)? else {
+            return Ok(false);
+        };
+        self.in_stream(|stream_loop| stream_loop.replace_client(audio_client))?;
+        Ok(true)
+    }
+
+    /// The summary once the runner stopped
+    pub fn summary(&self) -> Option<StreamSummary> {
+        self.summary.slot().lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn summarize(&mut self) {
+        self.summary.deliver(self.stream_loop.counters(), self.stream_loop.audio_client());
+    }
+
+    fn report_stop(&self) {
+        if let Some((hooks, info)) = &self.hooks {
+            hooks.on_stream_stop(info);
+        }
+    }
 
-            unsafe { capture_client.ReleaseBuffer(frames_available) }.map_err(AudioClientError::FailedReleasingBuffer)?;
+    /// Callbacks on a caller owned thread see this stream as the current one too
+    fn in_stream<R>(&mut self, f: impl FnOnce(&mut dyn StreamLoop) -> R) -> R {
+        let previous = CURRENT_STREAM.replace(Some(self.id));
+        let res = f(self.stream_loop.as_mut());
+        CURRENT_STREAM.set(previous);
+        res
+    }
+}
+
+impl Drop for StreamRunner {
+    fn drop(&mut self) {
+        // Also covers streams that ended with an error
+        self.summarize();
+        if self.h_event.is_some() {
+            let _ = stop_client(self.stream_loop.audio_client());
+            self.report_stop();
         }
+    }
+}
+
+/// Stops a [`StreamRunner`] from another thread, the runner returns [`PollStatus::Stopped`] on its next poll
+#[derive(Clone)]
+pub struct StreamStopper {
+    stop_handle: HANDLE,
+}
+
+unsafe impl Send for StreamStopper {}
+unsafe impl Sync for StreamStopper {}
+
+impl StreamStopper {
+    pub fn stop(&self) {
         unsafe {
-            audio_client.Stop().map_err(AudioClientError::FailedStoppingAudioClient)?;
-            audio_client.Reset().map_err(AudioClientError::FailedResettingAudioClient)?;
+            let _ = SetEvent(self.stop_handle);
         }
+    }
+}
+
+/// The per event work of a stream, driven either by a crate spawned thread or by a [`StreamRunner`]
+trait StreamLoop: Send {
+    fn audio_client(&self) -> &IAudioClient;
+
+    fn counters(&self) -> &StreamCounters;
+
+    /// Handles one buffer event
+    fn process(&mut self) -> Result<(), AudioClientError>;
+
+    /// Delivers what is left in the buffer of the stopped client, until empty or the deadline passes
+    fn drain(&mut self, _deadline: Instant) -> Result<(), AudioClientError> {
         Ok(())
     }
 
-    fn playback_audio<D>(run_context: StreamRunContext<IAudioRenderClient>, mut data_callback: D) -> Result<(), AudioClientError>
-    where
-        D: FnMut(&mut [u8]) -> bool,
-    {
-        Self::set_thread_priority();
-        let (audio_client, render_client) = (run_context.audio_client, run_context.stream_client);
+    fn track_glitches(&mut self, tracker: GlitchTracker);
 
-        let buffer_size = unsafe { audio_client.GetBufferSize() }.map_err(AudioClientError::FailedToStartAudioClient)?;
-        let h_event = unsafe { CreateEventA(None, false, false, None) }.map_err(|h| AudioClientError::FailedToCreateStopEvent(h))?;
-        let h_event = EventHandleWrapper(h_event);
-        let handles = [*h_event, run_context.stop_handle];
+    fn track_deadlines(&mut self, tracker: DeadlineTracker);
+
+    /// Only playback loops have an end of stream
+    fn on_complete(&mut self, _callback: CompleteFn) {}
+
+    /// Moves to a client prepared by another thread, returns true if it did and the runner has to start the new client
+    fn switch_client(&mut self) -> Result<bool, AudioClientError> {
+        Ok(false)
+    }
+
+    /// Moves to a new client after `process` failed, the runner starts the new client
+    fn recover(&mut self, err: AudioClientError) -> Result<(), AudioClientError> {
+        Err(err)
+    }
+
+    /// Moves to `audio_client`, opened with the format of the stream by a [`StreamRecovery`]
+    fn replace_client(&mut self, audio_client: IAudioClient) -> Result<(), AudioClientError>;
+
+    /// Loops with a timeout also process when no buffer event arrives within it, so a lost device is noticed even
+    /// if its events stop
+    fn idle_timeout(&self) -> Option<u32> {
+        None
+    }
+}
+
+struct CaptureLoop<D> {
+    run_context: StreamRunContext<IAudioCaptureClient>,
+    data_callback: D,
+    block_align: usize,
+    glitches: Option<GlitchTracker>,
+    deadlines: Option<DeadlineTracker>,
+    control: Arc<CaptureControl>,
+    /// Silence handed out instead of the packets while muted
+    silence: Vec<u8>,
+    /// Markers of the current packet
+    markers: Vec<Marker>,
+    counters: StreamCounters,
+}
+
+impl<D> CaptureLoop<D> {
+    fn new(run_context: StreamRunContext<IAudioCaptureClient>, data_callback: D, control: Arc<CaptureControl>) -> Self {
         let block_align = run_context.format.block_align() as usize;
+        let counters = StreamCounters::new(run_context.format.get_n_samples_per_sec());
+        Self {
+            run_context,
+            data_callback,
+            block_align,
+            glitches: None,
+            deadlines: None,
+            control,
+            silence: Vec::new(),
+            markers: Vec::new(),
+            counters,
+        }
+    }
+}
 
-        unsafe { audio_client.SetEventHandle(*h_event) }.map_err(|h| AudioClientError::FailedToSetupEventHandle(h))?;
-        unsafe { audio_client.Start() }.map_err(|h| AudioClientError::FailedToStartAudioClient(h))?;
+impl<D> StreamLoop for CaptureLoop<D>
+where
+    D: FnMut(CapturePacket) + Send,
+{
+    fn audio_client(&self) -> &IAudioClient {
+        &self.run_context.audio_client
+    }
 
-        loop {
-            let wait_res = unsafe { get_wait_error(WaitForMultipleObjectsEx(&handles, false, INFINITE, false))? };
-            // Stop event was called
-            if wait_res == WAIT_OBJECT_0.0 + 1 {
-                break;
-            }
-            let padding = unsafe { audio_client.GetCurrentPadding() }.map_err(AudioClientError::FailedGettingBuffer)?;
-            let available_frames = buffer_size - padding;
-            if available_frames == 0 {
-                continue;
+    fn counters(&self) -> &StreamCounters {
+        &self.counters
+    }
+
+    fn process(&mut self) -> Result<(), AudioClientError> {
+        // Drain every packet that arrived since the last event
+        while self.read_packet()? {}
+        Ok(())
+    }
+
+    fn drain(&mut self, deadline: Instant) -> Result<(), AudioClientError> {
+        while Instant::now() < deadline && self.read_packet()? {}
+        Ok(())
+    }
+
+    fn track_glitches(&mut self, tracker: GlitchTracker) {
+        self.glitches = Some(tracker);
+    }
+
+    fn track_deadlines(&mut self, tracker: DeadlineTracker) {
+        self.deadlines = Some(tracker);
+    }
+
+    fn replace_client(&mut self, audio_client: IAudioClient) -> Result<(), AudioClientError> {
+        let previous = self.run_context.audio_client.clone();
+        self.run_context.replace_client(audio_client)?;
+        self.counters.restart(&previous);
+        Ok(())
+    }
+
+    fn switch_client(&mut self) -> Result<bool, AudioClientError> {
+        let Some(next) = self.control.next_client.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+            return Ok(false);
+        };
+        // What the old target captured up to now is still delivered
+        while self.read_packet()? {}
+        stop_client(&self.run_context.audio_client)?;
+        let previous = std::mem::replace(&mut self.run_context, next);
+        if let Some(glitches) = &mut self.glitches {
+            glitches.restart();
+        }
+        self.counters.restart(&previous.audio_client);
+        Ok(true)
+    }
+}
+
+impl<D> CaptureLoop<D>
+where
+    D: FnMut(CapturePacket),
+{
+    /// Delivers the next packet, returns false if none is available
+    fn read_packet(&mut self) -> Result<bool, AudioClientError> {
+        let capture_client = &self.run_context.stream_client;
+        let mut buffer: *mut u8 = std::ptr::null_mut();
+        let mut flags: u32 = 0;
+        let mut pu64deviceposition: u64 = 0;
+        let mut pu64qpcposition: u64 = 0;
+
+        let mut frames_available = unsafe { capture_client.GetNextPacketSize() }.map_err(AudioClientError::FailedGettingBuffer)?;
+        if frames_available == 0 {
+            return Ok(false);
+        }
+        unsafe {
+            capture_client.GetBuffer(
+                &mut buffer,
+                &mut frames_available as *mut _,
+                &mut flags as *mut _,
+                Some(&mut pu64deviceposition as *mut _),
+                Some(&mut pu64qpcposition as *mut _),
+            )
+        }
+        .map_err(AudioClientError::FailedGettingBuffer)?;
+        let captured = CaptureBuffer {
+            capture_client,
+            frames: frames_available,
+            released: false,
+        };
+        debug_assert!(!buffer.is_null());
+        // The `qpc_position` is in 100 nanosecond units. source: `https://learn.microsoft.com/en-us/windows/win32/api/audioclient/nf-audioclient-iaudiocaptureclient-getbuffer`
+        let now = clock::hns_to_instant(pu64qpcposition);
+        if let Some(glitches) = &mut self.glitches {
+            glitches.packet(flags, pu64deviceposition, frames_available, now);
+        }
+
+        let mut buf_slice = unsafe { std::slice::from_raw_parts(buffer, frames_available as usize * self.block_align) };
+        let muted = self.control.muted.load(Ordering::Relaxed);
+        if muted {
+            // The packet is still taken and released so WASAPI's buffer doesn't overrun
+            let format = &self.run_context.format;
+            // 8 bit PCM is unsigned, silence sits in the middle
+            let is_unsigned = format.get_format_tag() != &FormatTag::WaveFormatIeeeFloat && format.get_w_bits_per_sample() == 8;
+            let zero = if is_unsigned { 0x80 } else { 0 };
+            self.silence.clear();
+            self.silence.resize(buf_slice.len(), zero);
+            buf_slice = &self.silence;
+        }
+        let sample_rate = self.run_context.format.get_n_samples_per_sec();
+        take_markers(&self.control, &mut self.markers, now, frames_available, sample_rate);
+        let callback_start = Instant::now();
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            (self.data_callback)(CapturePacket {
+                data: buf_slice,
+                timestamp: now,
+                muted,
+                markers: &self.markers,
+            })
+        }))
+        .map_err(|_| AudioClientError::CallbackPanicked)?;
+        let elapsed = callback_start.elapsed();
+        if let Some(glitches) = &mut self.glitches {
+            glitches.callback(elapsed, frames_available, now);
+        }
+        self.counters.captured(flags, pu64deviceposition, frames_available, elapsed);
+
+        captured.release()?;
+        if let Some(deadlines) = &mut self.deadlines {
+            deadlines.callback(elapsed)?;
+        }
+        Ok(true)
+    }
+}
+
+/// Moves the markers inserted before the end of the packet starting at `start` into `markers`
+fn take_markers(control: &CaptureControl, markers: &mut Vec<Marker>, start: StreamInstant, frames: u32, sample_rate: u32) {
+    markers.clear();
+    let mut pending = control.markers.lock().unwrap_or_else(|e| e.into_inner());
+    if pending.is_empty() {
+        return;
+    }
+    let sample_rate = sample_rate as u128;
+    let end = start.add(Duration::from_nanos((frames as u128 * 1_000_000_000 / sample_rate.max(1)) as u64));
+    pending.retain(|(label, timestamp)| {
+        if end.is_some_and(|end| *timestamp >= end) {
+            return true;
+        }
+        // Markers from before the packet, e.g. inserted before the first one, go on its first frame
+        let since_start = timestamp.duration_since(&start).unwrap_or_default();
+        let offset = (since_start.as_nanos() * sample_rate / 1_000_000_000).min(frames.saturating_sub(1) as u128);
+        markers.push(Marker {
+            label: label.clone(),
+            timestamp: *timestamp,
+            offset: offset as u32,
+        });
+        false
+    });
+}
+
+struct PlaybackLoop<D> {
+    run_context: StreamRunContext<IAudioRenderClient>,
+    data_callback: D,
+    buffer_size: u32,
+    failover: Option<PlaybackFailover>,
+    glitches: Option<GlitchTracker>,
+    deadlines: Option<DeadlineTracker>,
+    frames_written: u64,
+    /// Padding right after the last release, the drop to the current padding is what played since
+    last_padding: u32,
+    /// Frames of the stream still queued in the device buffer, set once the callback finished
+    remaining: Option<u32>,
+    on_complete: Option<CompleteFn>,
+    counters: StreamCounters,
+}
+
+impl<D> StreamLoop for PlaybackLoop<D>
+where
+    D: FnMut(RenderRequest) -> u32 + Send,
+{
+    fn audio_client(&self) -> &IAudioClient {
+        &self.run_context.audio_client
+    }
+
+    fn counters(&self) -> &StreamCounters {
+        &self.counters
+    }
+
+    fn process(&mut self) -> Result<(), AudioClientError> {
+        let (audio_client, render_client) = (&self.run_context.audio_client, &self.run_context.stream_client);
+        let block_align = self.run_context.format.block_align() as usize;
+
+        let padding = unsafe { audio_client.GetCurrentPadding() }.map_err(AudioClientError::FailedGettingBuffer)?;
+        if let Some(remaining) = &mut self.remaining {
+            *remaining = remaining.saturating_sub(self.last_padding.saturating_sub(padding));
+            if *remaining == 0
+                && let Some(on_complete) = self.on_complete.take()
+            {
+                let complete = PlaybackComplete {
+                    frames: self.frames_written,
+                };
+                panic::catch_unwind(AssertUnwindSafe(|| on_complete(complete))).map_err(|_| AudioClientError::CallbackPanicked)?;
             }
+        }
+        self.last_padding = padding;
+        let available_frames = self.buffer_size - padding;
+        if available_frames == 0 {
+            return Ok(());
+        }
 
-            let buffer = unsafe { render_client.GetBuffer(available_frames) }.map_err(AudioClientError::FailedGettingBuffer)?;
-            let buffer = unsafe { std::slice::from_raw_parts_mut(buffer, available_frames as usize * block_align) };
-            let is_active = data_callback(buffer);
-            let flags = if is_active { 0u32 } else { AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 };
-            unsafe { render_client.ReleaseBuffer(available_frames, flags) }.map_err(AudioClientError::FailedReleasingBuffer)?;
+        let buffer = unsafe { render_client.GetBuffer(available_frames) }.map_err(AudioClientError::FailedGettingBuffer)?;
+        let mut rendered = RenderBuffer {
+            render_client,
+            frames: available_frames,
+            flags: AUDCLNT_BUFFERFLAGS_SILENT.0 as u32,
+            released: false,
+        };
+        self.last_padding = padding + available_frames;
+        if self.remaining.is_some() {
+            // Past the end of the stream, the buffer is released as silence
+            return rendered.release();
+        }
+        let buffer = unsafe { std::slice::from_raw_parts_mut(buffer, available_frames as usize * block_align) };
+        let callback_start = Instant::now();
+        let mut finished = false;
+        let written = panic::catch_unwind(AssertUnwindSafe(|| {
+            (self.data_callback)(RenderRequest {
+                buffer: &mut *buffer,
+                frames: available_frames,
+                format: &self.run_context.format,
+                finished: &mut finished,
+            })
+        }))
+        .map_err(|_| AudioClientError::CallbackPanicked)?
+        .min(available_frames);
+        // An empty buffer before the end of the stream played silence
+        let underrun = padding == 0 && self.frames_written > 0;
+        self.frames_written += written as u64;
+        if finished {
+            self.remaining = Some(padding + written);
+        }
+        let now = StreamInstant::now();
+        let elapsed = callback_start.elapsed();
+        self.counters.rendered(written, underrun, elapsed);
+        if let Some(glitches) = &mut self.glitches {
+            glitches.callback(elapsed, available_frames, now);
+        }
+        if written > 0 {
+            rendered.flags = 0;
+            if written < available_frames {
+                buffer[written as usize * block_align..].fill(0);
+                if let Some(glitches) = self.glitches.as_mut().filter(|_| !finished) {
+                    glitches.partial_fill(written, available_frames, now);
+                }
+            }
         }
+        rendered.release()?;
+        match &mut self.deadlines {
+            Some(deadlines) => deadlines.callback(elapsed),
+            None => Ok(()),
+        }
+    }
+
+    fn track_glitches(&mut self, tracker: GlitchTracker) {
+        self.glitches = Some(tracker);
+    }
+
+    fn track_deadlines(&mut self, tracker: DeadlineTracker) {
+        self.deadlines = Some(tracker);
+    }
+
+    fn on_complete(&mut self, callback: CompleteFn) {
+        self.on_complete = Some(callback);
+    }
 
+    fn recover(&mut self, err: AudioClientError) -> Result<(), AudioClientError> {
+        let Some(failover) = self
+            .failover
+            .as_mut()
+            .filter(|_| err.hresult() == Some(AUDCLNT_E_DEVICE_INVALIDATED))
+        else {
+            return Err(err);
+        };
+        warn!("Stream {} lost its playback device, switching output", CurrentStream);
+        let audio_client = failover.switch()?;
+        self.replace_client(audio_client)
+    }
+
+    fn replace_client(&mut self, audio_client: IAudioClient) -> Result<(), AudioClientError> {
+        self.buffer_size = unsafe { audio_client.GetBufferSize() }.map_err(AudioClientError::FailedToStartAudioClient)?;
+        let previous = self.run_context.audio_client.clone();
+        self.run_context.replace_client(audio_client)?;
+        self.counters.restart(&previous);
+        // Whatever was queued on the lost device is gone, a finished stream is complete on the new one right away
+        self.last_padding = 0;
+        if let Some(remaining) = &mut self.remaining {
+            *remaining = 0;
+        }
         Ok(())
     }
 
-    fn set_thread_priority() {
-        unsafe {
-            let curr_thr = GetCurrentThread();
-            let _ = SetThreadPriority(curr_thr, THREAD_PRIORITY_TIME_CRITICAL);
+    fn idle_timeout(&self) -> Option<u32> {
+        self.failover.as_ref().map(|_| FAILOVER_CHECK_MS)
+    }
+}
+
+/// Sets up the buffer event and starts the client, returns `None` if the gate was cancelled before starting
+fn start_client(audio_client: &IAudioClient, start_gate: Option<Arc<StartGate>>) -> Result<Option<EventHandleWrapper>, AudioClientError> {
+    let h_event = create_buffer_event(audio_client);
+    // Every gated thread has to arrive, even when the setup failed, so the group learns about it and cancels the start
+    if let Some(start_gate) = &start_gate
+        && !start_gate.arrive_and_wait(h_event.as_ref().map(|_| ()))
+    {
+        return Ok(None);
+    }
+    let h_event = h_event?;
+    let started = unsafe { audio_client.Start() }.map_err(AudioClientError::FailedToStartAudioClient);
+    if let Some(start_gate) = &start_gate {
+        start_gate.report_started(started.as_ref().copied());
+    }
+    started?;
+    Ok(Some(h_event))
+}
+
+fn get_service<T: Interface>(audio_client: &IAudioClient) -> Result<T, AudioClientError> {
+    unsafe { audio_client.GetService::<T>() }.map_err(AudioClientError::FailedGettingService)
+}
+
+fn stop_client(audio_client: &IAudioClient) -> Result<(), AudioClientError> {
+    unsafe {
+        audio_client.Stop().map_err(AudioClientError::FailedStoppingAudioClient)?;
+        audio_client.Reset().map_err(AudioClientError::FailedResettingAudioClient)?;
+    }
+    Ok(())
+}
+
+fn create_buffer_event(audio_client: &IAudioClient) -> Result<EventHandleWrapper, AudioClientError> {
+    let h_event = unsafe { CreateEventA(None, false, false, None) }.map_err(AudioClientError::FailedToCreateStopEvent)?;
+    let h_event = EventHandleWrapper(h_event);
+    unsafe { audio_client.SetEventHandle(*h_event) }.map_err(AudioClientError::FailedToSetupEventHandle)?;
+    Ok(h_event)
+}
+
+fn set_thread_priority() {
+    unsafe {
+        let curr_thr = GetCurrentThread();
+        let _ = SetThreadPriority(curr_thr, THREAD_PRIORITY_TIME_CRITICAL);
+    }
+}
+
+/// A buffer taken with `GetBuffer`, released even if the callback panics or a later call fails
+struct CaptureBuffer<'a> {
+    capture_client: &'a IAudioCaptureClient,
+    frames: u32,
+    released: bool,
+}
+
+impl CaptureBuffer<'_> {
+    fn release(mut self) -> Result<(), AudioClientError> {
+        self.released = true;
+        unsafe { self.capture_client.ReleaseBuffer(self.frames) }.map_err(AudioClientError::FailedReleasingBuffer)
+    }
+}
+
+impl Drop for CaptureBuffer<'_> {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = unsafe { self.capture_client.ReleaseBuffer(self.frames) };
         }
     }
 }
 
-fn convert_instant(buffer_qpc_position: u64) -> StreamInstant {
-    // The `qpc_position` is in 100 nanosecond units. Convert it to nanoseconds. source: `https://learn.microsoft.com/en-us/windows/win32/api/audioclient/nf-audioclient-iaudiocaptureclient-getbuffer`
-    let qpc_nanos = buffer_qpc_position as i128 * 100;
-    StreamInstant::from_nanos_i128(qpc_nanos).expect("performance counter out of range of `StreamInstant` representation")
+/// Render counterpart of [`CaptureBuffer`], a buffer that isn't released explicitly is played as silence
+struct RenderBuffer<'a> {
+    render_client: &'a IAudioRenderClient,
+    frames: u32,
+    flags: u32,
+    released: bool,
+}
+
+impl RenderBuffer<'_> {
+    fn release(mut self) -> Result<(), AudioClientError> {
+        self.released = true;
+        unsafe { self.render_client.ReleaseBuffer(self.frames, self.flags) }.map_err(AudioClientError::FailedReleasingBuffer)
+    }
+}
+
+impl Drop for RenderBuffer<'_> {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = unsafe { self.render_client.ReleaseBuffer(self.frames, AUDCLNT_BUFFERFLAGS_SILENT.0 as u32) };
+        }
+    }
 }
 
 impl AudioStream {
     // See drop implementation for cleanup
     pub fn stop_recording(self) {}
-}
 
-impl Drop for AudioStream {
-    fn drop(&mut self) {
+    /// Lets the stream run until the process exits, for services that never stop their streams
+    pub fn detach(mut self) {
+        // Dropping the join handle detaches the thread, the stop event is never signalled
+        drop(self.thread.take());
+        std::mem::forget(self);
+    }
+
+    /// Stops the stream after delivering the packets still buffered by WASAPI, so the last tens of milliseconds of a
+    /// recording aren't lost. Packets left after `timeout` are discarded. Blocks until the stream thread exited.
+    pub fn stop_and_drain(self, timeout: Duration) {
+        *self.drain_until.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now() + timeout);
+    }
+
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Win32 id of the stream thread, e.g. for `OpenThread` or for scheduling tools that work with thread ids
+    pub fn thread_id(&self) -> u32 {
+        self.thread_id
+    }
+
+    /// Mutes a capture stream without stopping it, e.g. for push to talk. While muted the stream keeps reading from
+    /// WASAPI but hands silence flagged with [`CapturePacket::is_muted`] to the callback. Has no effect on playback
+    /// streams, and doesn't touch the mute state of the device.
+    pub fn set_muted(&self, muted: bool) {
+        if let Some(control) = &self.capture {
+            control.muted.store(muted, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.capture.as_ref().is_some_and(|control| control.muted.load(Ordering::Relaxed))
+    }
+
+    /// Marks the current moment of a capture stream, e.g. where the user asked to keep a highlight
+    ///
+    /// The marker is handed to the data callback with the packet that contains the moment, in
+    /// [`CapturePacket::markers`], along with the frame it falls on. Markers are placed by the packet timestamps, so they
+    /// are as accurate as those. A marker inserted after the stream stopped is never delivered.
+    pub fn insert_marker(&self, label: impl Into<String>) -> Result<(), AudioClientError> {
+        let control = self.capture.as_ref().ok_or(AudioClientError::NotCaptureStream)?;
+        let marker = (label.into(), StreamInstant::now());
+        control.markers.lock().unwrap_or_else(|e| e.into_inner()).push(marker);
+        Ok(())
+    }
+
+    /// Moves a capture stream to another target without restarting it, e.g. when the user picks another source in the
+    /// middle of a recording
+    ///
+    /// The client of the new target is opened on the calling thread, in the format the stream reads, with the audio
+    /// engine converting the target to it, so the callback keeps getting the same format. On its next buffer event the
+    /// stream thread delivers what the old target still had buffered and starts the new one, leaving a gap of about a
+    /// period. Timestamps stay on the same clock.
+    ///
+    /// A process capture moves its registration, see [`DuplicateCapturePolicy`](crate::capture_registry::DuplicateCapturePolicy),
+    /// along: switching to another process fails with [`AudioClientError::DuplicateCapture`] like starting a capture of
+    /// it would, switching to any other target ends the registration. Subscribers keep getting the packets of the stream.
+    /// Any other stream switched to a process is registered from then on, without a duplicate check.
+    pub fn switch_target(&self, target: &CaptureTarget) -> Result<(), AudioClientError> {
+        let control = self.capture.as_ref().ok_or(AudioClientError::NotCaptureStream)?;
+        let client = AudioClient::new();
+        let audio_client = match target {
+            CaptureTarget::Process(pid) => {
+                client.switch_process_capture(&control.registration, *pid, &control.format, &control.delivered_format)?
+            }
+            _ => {
+                let audio_client = client.open_capture_target(target, &control.format)?;
+                if let Some(registration) = control.registration() {
+                    registration.set_pid(0);
+                }
+                audio_client
+            }
+        };
+        let stream_client =
+            unsafe { audio_client.GetService::<IAudioCaptureClient>() }.map_err(AudioClientError::FailedToStartAudioClient)?;
+        let run_context = StreamRunContext {
+            audio_client,
+            stream_client,
+            format: control.format.clone(),
+        };
+        // A switch the stream didn't get to yet is replaced
+        *control.next_client.lock().unwrap_or_else(|e| e.into_inner()) = Some(run_context);
+        Ok(())
+    }
+
+    /// Glitches recorded so far, `None` if the stream was started without
+    /// [`AudioStreamConfig::with_glitch_recorder`]
+    pub fn glitch_report(&self) -> Option<GlitchReport> {
+        self.glitch_log.as_ref().map(|glitch_log| glitch_log.report())
+    }
+
+    /// Events of the stream's session, `None` unless [`AudioStreamConfig::watch_session`] was set and registering
+    /// succeeded. Events queue up until received, including those the application caused itself.
+    #[cfg(feature = "notifications")]
+    pub fn session_events(&self) -> Option<&mpsc::Receiver<AudioSessionEventArgs>> {
+        self.session_watch.as_ref().map(|session_watch| session_watch.events())
+    }
+
+    /// Summary of the stream once it stopped on its own, e.g. after an error, see [`AudioStreamConfig::on_summary`]
+    pub fn summary(&self) -> Option<StreamSummary> {
+        self.summary.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Stops the stream, waits for the stream thread to exit and returns the summary of the whole stream. `None` only
+    /// for detached streams and threads that panicked.
+    pub fn stop_with_summary(mut self) -> Option<StreamSummary> {
+        self.signal_stop();
+        let _ = self.thread.take().map(|thr| thr.join());
+        self.summary.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+
+    /// Deadline accounting so far, `None` if the stream was started without [`AudioStreamConfig::with_deadline_mode`]
+    pub fn deadline_stats(&self) -> Option<DeadlineStats> {
+        self.deadline_log.as_ref().map(|deadline_log| deadline_log.stats())
+    }
+
+    /// Gets a service the crate doesn't wrap from the client of this stream, see [`AudioStreamConfig::service`]
+    ///
+    /// # Safety
+    /// Same contract as [`AudioStreamConfig::service`], the stream thread is running while the interface is used.
+    pub unsafe fn service<T: Interface>(&self) -> Result<T, AudioClientError> {
+        get_service(&self.current_client())
+    }
+
+    /// Changes the rate the stream runs at to correct drift against another clock, without a software resampler
+    /// The client has to be initialized with [`AudioClient::set_rate_adjust`](crate::audio_client::AudioClient::set_rate_adjust).
+    /// Adjusts the client the stream thread runs on right now. A client opened by a failover or recovery gets the flag
+    /// too, but starts at the nominal rate, so the rate has to be set again.
+    pub fn set_sample_rate(&self, sample_rate: f32) -> Result<(), AudioClientError> {
+        let clock_adjustment: IAudioClockAdjustment = get_service(&self.current_client())?;
+        unsafe { clock_adjustment.SetSampleRate(sample_rate) }.map_err(AudioClientError::FailedAdjustingSampleRate)
+    }
+
+    /// Where the device is in the stream right now, with the performance counter time it was read at, for syncing
+    /// the audio to video or another clock. After a failover, recovery or [`AudioStream::switch_target`] the position
+    /// is the one of the new client, which starts over at zero.
+    pub fn position(&self) -> Result<StreamPosition, AudioClientError> {
+        let clock: IAudioClock = get_service(&self.current_client())?;
+        let frequency = unsafe { clock.GetFrequency() }.map_err(AudioClientError::FailedToGetAudioClock)?;
+        let (mut position, mut qpc_position) = (0, 0);
+        unsafe { clock.GetPosition(&mut position, Some(&mut qpc_position)) }.map_err(AudioClientError::FailedToGetAudioClock)?;
+        Ok(StreamPosition {
+            position,
+            frequency,
+            timestamp: clock::hns_to_instant(qpc_position),
+        })
+    }
+
+    /// Latency the audio engine adds on top of the buffer, as reported by `IAudioClient::GetStreamLatency`. Zero for
+    /// process loopback streams and some virtual devices.
+    pub fn latency(&self) -> Result<Duration, AudioClientError> {
+        let latency = unsafe { self.current_client().GetStreamLatency() }.map_err(AudioClientError::FailedGettingLatency)?;
+        Ok(Duration::from_nanos(latency.max(0) as u64 * 100))
+    }
+
+    /// Client the stream thread runs on right now, which changes after a failover, recovery or switch
+    fn current_client(&self) -> IAudioClient {
+        self.audio_client.lock().unwrap_or_else(|e| e.into_inner()).get().clone()
+    }
+
+    /// Asks the stream thread to stop without waiting for it
+    pub(crate) fn signal_stop(&self) {
         unsafe {
             let _ = SetEvent(self.stop_handle);
         }
+    }
+}
+
+impl Drop for AudioStream {
+    fn drop(&mut self) {
+        self.signal_stop();
         let _ = self.thread.take().map(|thr| thr.join());
     }
 }
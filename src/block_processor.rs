@@ -0,0 +1,107 @@
+//! Fixed block size processing on top of the WASAPI render buffer, see [`AudioClient::start_block_processing`](crate::audio_client::AudioClient::start_block_processing).
+//!
+//! WASAPI asks for a varying number of frames every period. The processor always calls the user with exactly
+//! `block_frames` frames and keeps the leftover of the last block for the next buffer.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::audio_stream::{AudioStream, AudioStreamConfig};
+use crate::convert::{bytes_to_f32, f32_to_bytes};
+use crate::sample_format::SampleFormat;
+
+/// Blocks of input that may queue up before the oldest input is discarded to bound latency
+const MAX_QUEUED_INPUT_BLOCKS: usize = 4;
+
+pub(crate) type InputQueue = Arc<Mutex<VecDeque<u8>>>;
+
+pub(crate) struct BlockInput {
+    pub(crate) format: SampleFormat,
+    pub(crate) queue: InputQueue,
+    /// Started on the first processed block, stopped when the processor is dropped
+    pub(crate) stream: Option<AudioStreamConfig>,
+}
+
+pub(crate) struct BlockProcessor<P> {
+    process: P,
+    block_frames: usize,
+    output_format: SampleFormat,
+    input: Option<BlockInput>,
+    _input_stream: Option<AudioStream>,
+    input_bytes: Vec<u8>,
+    input_block: Vec<f32>,
+    output_block: Vec<f32>,
+    pending: Vec<u8>,
+    pending_offset: usize,
+}
+
+impl<P> BlockProcessor<P>
+where
+    P: FnMut(&[f32], &mut [f32]),
+{
+    pub(crate) fn new(process: P, block_frames: usize, output_format: SampleFormat, input: Option<BlockInput>) -> Self {
+        let output_channels = output_format.get_channel() as usize;
+        Self {
+            process,
+            block_frames,
+            output_format,
+            input,
+            _input_stream: None,
+            input_bytes: Vec::new(),
+            input_block: Vec::new(),
+            output_block: vec![0.0; block_frames * output_channels],
+            pending: Vec::new(),
+            pending_offset: 0,
+        }
+    }
+
+    /// Fills a render buffer, running as many blocks as needed
+    pub(crate) fn fill(&mut self, buffer: &mut [u8]) {
+        let mut written = 0;
+        while written < buffer.len() {
+            if self.pending_offset == self.pending.len() {
+                self.run_block();
+            }
+            let len = (self.pending.len() - self.pending_offset).min(buffer.len() - written);
+            buffer[written..written + len].copy_from_slice(&self.pending[self.pending_offset..self.pending_offset + len]);
+            self.pending_offset += len;
+            written += len;
+        }
+    }
+
+    fn run_block(&mut self) {
+        self.read_input();
+        self.output_block.fill(0.0);
+        (self.process)(&self.input_block, &mut self.output_block);
+        self.pending.clear();
+        self.pending_offset = 0;
+        f32_to_bytes(&self.output_format, &self.output_block, &mut self.pending);
+    }
+
+    /// Takes one block of input, zero filling whatever the capture side didn't deliver in time
+    fn read_input(&mut self) {
+        self.input_block.clear();
+        let Some(input) = &mut self.input else {
+            return;
+        };
+        if let Some(stream) = input.stream.take() {
+            self._input_stream = stream.start().ok();
+        }
+
+        let block_align = input.format.block_align() as usize;
+        let block_len = self.block_frames * block_align;
+        self.input_bytes.clear();
+        {
+            let mut queue = input.queue.lock().unwrap_or_else(|e| e.into_inner());
+            let max_queued = block_len * MAX_QUEUED_INPUT_BLOCKS;
+            if queue.len() > max_queued {
+                let excess = ((queue.len() - max_queued).div_ceil(block_align) * block_align).min(queue.len());
+                queue.drain(..excess);
+            }
+            let len = queue.len().min(block_len);
+            self.input_bytes.extend(queue.drain(..len));
+        }
+        self.input_bytes.resize(block_len, 0);
+        bytes_to_f32(&input.format, &self.input_bytes, &mut self.input_block);
+    }
+}
@@ -0,0 +1,426 @@
+//! Flat C interface for using the crate from C, C++ or C# as a DLL.
+//!
+//! Build the DLL with `cargo rustc --release --features capi --crate-type cdylib` and generate the header with
+//! `cbindgen --config cbindgen.toml --output win_acapture_rs.h`.
+//!
+//! Every function returns a [`WacResult`], the message of the last failure on the calling thread is available through
+//! [`wac_last_error`]. Strings are NUL terminated UTF-8. Capture callbacks run on the stream thread, `user_data` is
+//! handed to them untouched and has to stay valid until the stream is stopped.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, c_char, c_void};
+use std::fmt::Display;
+
+use crate::audio_client::{AudioClient, AudioClientError};
+use crate::audio_stream::AudioStream;
+use crate::manager::{AudioSessionState, Device, DeviceManager, SessionManager, SessionStateFilter};
+use crate::sample_format::SampleFormat;
+
+/// Size of the string buffers in [`WacDeviceInfo`] and [`WacSessionInfo`], longer strings are truncated
+pub const WAC_STRING_LEN: usize = 512;
+
+thread_local!(static LAST_ERROR: RefCell<String> = const { RefCell::new(String::new()) });
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WacResult {
+    Ok = 0,
+    /// A required pointer was null or a string wasn't valid UTF-8
+    InvalidArgument = 1,
+    /// The caller buffer was too small, the required count was still written
+    BufferTooSmall = 2,
+    NotFound = 3,
+    /// See [`wac_last_error`]
+    Failed = 4,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WacFormat {
+    /// `WAVE_FORMAT_PCM` (1) or `WAVE_FORMAT_IEEE_FLOAT` (3)
+    pub format_tag: u16,
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits_per_sample: u16,
+}
+
+impl From<&SampleFormat> for WacFormat {
+    fn from(format: &SampleFormat) -> Self {
+        Self {
+            format_tag: format.get_format_tag().to_wave_format_tag(),
+            channels: format.get_channel(),
+            sample_rate: format.get_n_samples_per_sec(),
+            bits_per_sample: format.get_w_bits_per_sample(),
+        }
+    }
+}
+
+impl From<WacFormat> for SampleFormat {
+    fn from(format: WacFormat) -> Self {
+        SampleFormat::new(
+            format.format_tag.into(),
+            format.channels,
+            format.sample_rate,
+            format.bits_per_sample,
+        )
+    }
+}
+
+#[repr(C)]
+pub struct WacDeviceInfo {
+    pub id: [c_char; WAC_STRING_LEN],
+    pub friendly_name: [c_char; WAC_STRING_LEN],
+    pub is_playback: bool,
+    /// Default device of its data flow for any role
+    pub is_default: bool,
+}
+
+#[repr(C)]
+pub struct WacSessionInfo {
+    pub pid: u32,
+    /// Executable path, empty if it couldn't be determined
+    pub process_name: [c_char; WAC_STRING_LEN],
+    pub display_name: [c_char; WAC_STRING_LEN],
+    pub is_active: bool,
+}
+
+/// Called with every captured packet of interleaved samples in the stream format
+pub type WacCaptureCallback = Option<unsafe extern "C" fn(user_data: *mut c_void, data: *const u8, len: usize)>;
+/// Called when the stream fails, `hresult` is `0` for errors that don't come from a windows call
+pub type WacErrorCallback = Option<unsafe extern "C" fn(user_data: *mut c_void, hresult: i32)>;
+
+/// Capture settings, see [`wac_client_create`]
+pub struct WacClient {
+    client: AudioClient,
+}
+
+/// A running capture, see [`wac_stream_stop`]
+pub struct WacStream {
+    _stream: AudioStream,
+    format: SampleFormat,
+}
+
+/// The caller promises `user_data` may be used from the stream thread
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+impl UserData {
+    // A method instead of the field, so closures capture the whole `Send` wrapper
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+fn fail(result: WacResult, err: impl Display) -> WacResult {
+    LAST_ERROR.with(|last| *last.borrow_mut() = err.to_string());
+    result
+}
+
+/// Copies `value` into a fixed size buffer, cutting it at a character boundary if it doesn't fit
+fn copy_str(value: &str, buffer: &mut [c_char; WAC_STRING_LEN]) {
+    let mut len = value.len().min(WAC_STRING_LEN - 1);
+    while !value.is_char_boundary(len) {
+        len -= 1;
+    }
+    for (dst, src) in buffer.iter_mut().zip(value.as_bytes()[..len].iter()) {
+        *dst = *src as c_char;
+    }
+    buffer[len] = 0;
+}
+
+/// Writes as many entries as fit into `out` and the total number into `out_count`
+unsafe fn write_entries<T>(entries: Vec<T>, out: *mut T, capacity: usize, out_count: *mut usize) -> WacResult {
+    unsafe { *out_count = entries.len() };
+    let fits = entries.len() <= capacity;
+    if !out.is_null() {
+        for (index, entry) in entries.into_iter().take(capacity).enumerate() {
+            unsafe { out.add(index).write(entry) };
+        }
+    }
+    if fits { WacResult::Ok } else { WacResult::BufferTooSmall }
+}
+
+/// Copies the message of the last failed call on this thread into `buffer`, returns the length of the message
+///
+/// # Safety
+/// `buffer` has to be null or point to `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wac_last_error(buffer: *mut c_char, len: usize) -> usize {
+    LAST_ERROR.with(|last| {
+        let last = last.borrow();
+        if !buffer.is_null() && len > 0 {
+            let copied = last.len().min(len - 1);
+            unsafe {
+                std::ptr::copy_nonoverlapping(last.as_ptr() as *const c_char, buffer, copied);
+                *buffer.add(copied) = 0;
+            }
+        }
+        last.len()
+    })
+}
+
+/// Creates a client capturing in the default format, free it with [`wac_client_destroy`]
+#[unsafe(no_mangle)]
+pub extern "C" fn wac_client_create() -> *mut WacClient {
+    Box::into_raw(Box::new(WacClient {
+        client: AudioClient::new(),
+    }))
+}
+
+/// # Safety
+/// `client` has to come from [`wac_client_create`] and must not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wac_client_destroy(client: *mut WacClient) {
+    if !client.is_null() {
+        drop(unsafe { Box::from_raw(client) });
+    }
+}
+
+/// Sets the format process captures deliver
+///
+/// # Safety
+/// `client` has to come from [`wac_client_create`], `format` has to point to a valid [`WacFormat`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wac_client_set_format(client: *mut WacClient, format: *const WacFormat) -> WacResult {
+    let (Some(client), Some(format)) = (unsafe { client.as_mut() }, unsafe { format.as_ref() }) else {
+        return WacResult::InvalidArgument;
+    };
+    match client.client.set_format((*format).into()) {
+        Ok(()) => WacResult::Ok,
+        Err(err) => fail(WacResult::Failed, err),
+    }
+}
+
+/// Captures what plays on the render device `device_id`, or on the default render device if `device_id` is null
+///
+/// # Safety
+/// `client` has to come from [`wac_client_create`], `device_id` has to be null or a NUL terminated string and
+/// `out_stream` has to point to writable memory. `user_data` has to stay valid until the stream is stopped.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wac_client_start_loopback(
+    client: *const WacClient,
+    device_id: *const c_char,
+    callback: WacCaptureCallback,
+    error_callback: WacErrorCallback,
+    user_data: *mut c_void,
+    out_stream: *mut *mut WacStream,
+) -> WacResult {
+    let Some(client) = (unsafe { client.as_ref() }) else {
+        return WacResult::InvalidArgument;
+    };
+    let device = match unsafe { find_device(device_id) } {
+        Ok(device) => device,
+        Err(result) => return result,
+    };
+    unsafe {
+        start_stream(out_stream, callback, error_callback, user_data, |data, err| {
+            client.client.clone().start_recording_loopback_device(device.as_ref(), data, err)
+        })
+    }
+}
+
+/// Captures the audio of the process `pid` and its children
+///
+/// # Safety
+/// `client` has to come from [`wac_client_create`] and `out_stream` has to point to writable memory. `user_data` has
+/// to stay valid until the stream is stopped.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wac_client_start_process_capture(
+    client: *const WacClient,
+    pid: u32,
+    callback: WacCaptureCallback,
+    error_callback: WacErrorCallback,
+    user_data: *mut c_void,
+    out_stream: *mut *mut WacStream,
+) -> WacResult {
+    let Some(client) = (unsafe { client.as_ref() }) else {
+        return WacResult::InvalidArgument;
+    };
+    unsafe {
+        start_stream(out_stream, callback, error_callback, user_data, |data, err| {
+            client.client.clone().start_recording_process(pid, data, err)
+        })
+    }
+}
+
+type DataFn = Box<dyn FnMut(crate::audio_stream::CapturePacket) + Send>;
+type ErrorFn = Box<dyn FnMut(AudioClientError) + Send>;
+
+unsafe fn start_stream<F>(
+    out_stream: *mut *mut WacStream,
+    callback: WacCaptureCallback,
+    error_callback: WacErrorCallback,
+    user_data: *mut c_void,
+    start: F,
+) -> WacResult
+where
+    F: FnOnce(DataFn, ErrorFn) -> Result<crate::audio_stream::AudioStreamConfig, AudioClientError>,
+{
+    let Some(callback) = callback else {
+        return WacResult::InvalidArgument;
+    };
+    if out_stream.is_null() {
+        return WacResult::InvalidArgument;
+    }
+    let (data_user, error_user) = (UserData(user_data), UserData(user_data));
+    let data: DataFn = Box::new(move |packet| {
+        let data = packet.data();
+        unsafe { callback(data_user.get(), data.as_ptr(), data.len()) };
+    });
+    let error: ErrorFn = Box::new(move |err| {
+        if let Some(error_callback) = error_callback {
+            unsafe { error_callback(error_user.get(), err.hresult().map_or(0, |hr| hr.0)) };
+        }
+    });
+    let started = start(data, error).and_then(|config| {
+        let format = config.format().clone();
+        config.start().map(|stream| (stream, format))
+    });
+    match started {
+        Ok((stream, format)) => {
+            unsafe { *out_stream = Box::into_raw(Box::new(WacStream { _stream: stream, format })) };
+            WacResult::Ok
+        }
+        Err(err) => fail(WacResult::Failed, err),
+    }
+}
+
+unsafe fn find_device(device_id: *const c_char) -> Result<Option<Device>, WacResult> {
+    if device_id.is_null() {
+        return Ok(None);
+    }
+    let device_id = unsafe { CStr::from_ptr(device_id) }
+        .to_str()
+        .map_err(|err| fail(WacResult::InvalidArgument, err))?;
+    let devices = DeviceManager::get_devices_annotated().map_err(|err| fail(WacResult::Failed, err))?;
+    devices
+        .into_iter()
+        .find(|info| info.get_id() == device_id)
+        .map(|info| Some(info.get_device().clone()))
+        .ok_or_else(|| fail(WacResult::NotFound, format!("No device with id {}", device_id)))
+}
+
+/// Writes the format the stream delivers to `out_format`
+///
+/// # Safety
+/// `stream` has to come from one of the start functions and `out_format` has to point to writable memory.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wac_stream_get_format(stream: *const WacStream, out_format: *mut WacFormat) -> WacResult {
+    let Some(stream) = (unsafe { stream.as_ref() }) else {
+        return WacResult::InvalidArgument;
+    };
+    if out_format.is_null() {
+        return WacResult::InvalidArgument;
+    }
+    unsafe { *out_format = (&stream.format).into() };
+    WacResult::Ok
+}
+
+/// Stops the stream and frees it, no callback runs after this returns
+///
+/// # Safety
+/// `stream` has to come from one of the start functions and must not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wac_stream_stop(stream: *mut WacStream) {
+    if !stream.is_null() {
+        drop(unsafe { Box::from_raw(stream) });
+    }
+}
+
+/// Fills `out` with up to `capacity` active render and capture devices and writes the number of devices to
+/// `out_count`. Pass a null `out` to only query the count.
+///
+/// # Safety
+/// `out` has to be null or point to `capacity` writable entries, `out_count` has to point to writable memory.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wac_enumerate_devices(out: *mut WacDeviceInfo, capacity: usize, out_count: *mut usize) -> WacResult {
+    if out_count.is_null() {
+        return WacResult::InvalidArgument;
+    }
+    let devices = match DeviceManager::get_devices_annotated() {
+        Ok(devices) => devices,
+        Err(err) => return fail(WacResult::Failed, err),
+    };
+    let entries = devices
+        .iter()
+        .map(|info| {
+            let mut entry = WacDeviceInfo {
+                id: [0; WAC_STRING_LEN],
+                friendly_name: [0; WAC_STRING_LEN],
+                is_playback: info.is_playback(),
+                is_default: info.is_default(),
+            };
+            copy_str(info.get_id(), &mut entry.id);
+            copy_str(info.get_friendly_name(), &mut entry.friendly_name);
+            entry
+        })
+        .collect();
+    unsafe { write_entries(entries, out, capacity, out_count) }
+}
+
+/// Fills `out` with up to `capacity` sessions that aren't expired and writes the number of sessions to `out_count`.
+/// Pass a null `out` to only query the count.
+///
+/// # Safety
+/// `out` has to be null or point to `capacity` writable entries, `out_count` has to point to writable memory.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wac_enumerate_sessions(out: *mut WacSessionInfo, capacity: usize, out_count: *mut usize) -> WacResult {
+    if out_count.is_null() {
+        return WacResult::InvalidArgument;
+    }
+    let sessions = match SessionManager::get_sessions_filtered(SessionStateFilter::ActiveAndInactive) {
+        Ok(sessions) => sessions,
+        Err(err) => return fail(WacResult::Failed, err),
+    };
+    let entries = sessions
+        .iter()
+        .map(|session| {
+            let mut entry = WacSessionInfo {
+                pid: *session.get_pid(),
+                process_name: [0; WAC_STRING_LEN],
+                display_name: [0; WAC_STRING_LEN],
+                is_active: session
+                    .get_state()
+                    .is_ok_and(|state| state == AudioSessionState::AudioSessionStateActive),
+            };
+            copy_str(session.get_process_name().as_deref().unwrap_or_default(), &mut entry.process_name);
+            copy_str(&session.get_display_name().unwrap_or_default(), &mut entry.display_name);
+            entry
+        })
+        .collect();
+    unsafe { write_entries(entries, out, capacity, out_count) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enumerate_into_caller_buffer() {
+        let mut count = 0;
+        assert_ne!(
+            unsafe { wac_enumerate_devices(std::ptr::null_mut(), 0, &mut count) },
+            WacResult::Failed
+        );
+        let mut devices: Vec<WacDeviceInfo> = (0..count)
+            .map(|_| WacDeviceInfo {
+                id: [0; WAC_STRING_LEN],
+                friendly_name: [0; WAC_STRING_LEN],
+                is_playback: false,
+                is_default: false,
+            })
+            .collect();
+        assert_eq!(
+            unsafe { wac_enumerate_devices(devices.as_mut_ptr(), count, &mut count) },
+            WacResult::Ok
+        );
+        assert!(devices.iter().all(|device| device.id[0] != 0));
+
+        // Two byte characters, the cut can't split the last one
+        let mut buffer = [1; WAC_STRING_LEN];
+        copy_str(&"é".repeat(WAC_STRING_LEN), &mut buffer);
+        assert_eq!(buffer[WAC_STRING_LEN - 2], 0);
+        assert_ne!(buffer[WAC_STRING_LEN - 3], 0);
+    }
+}
@@ -0,0 +1,142 @@
+//! Options applied to capture streams started by an [`AudioClient`](crate::audio_client::AudioClient).
+
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::level_monitor::{LevelMonitor, Levels};
+use crate::sample_format::SampleFormat;
+
+/// Rates [`CaptureOptions::output_sample_rate`] accepts, the range WASAPI formats can describe
+pub const OUTPUT_SAMPLE_RATES: RangeInclusive<u32> = 1_000..=384_000;
+
+/// What process loopback capture does when the requested sample rate differs from the render mix rate
+///
+/// The process loopback device accepts any format, but it doesn't convert the rate: capturing 44.1 kHz from a
+/// 48 kHz mix produces pitch shifted audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateMismatchPolicy {
+    /// Log a warning and capture with the requested rate anyway
+    #[default]
+    Warn,
+    /// Fail with [`AudioClientError::SampleRateMismatch`](crate::audio_client::AudioClientError::SampleRateMismatch)
+    Error,
+    /// Capture at the mix rate and resample to the requested rate, requires the `resampler` feature
+    Resample,
+}
+
+/// What device capture does when the device doesn't support the requested format in shared mode
+///
+/// The format is checked with `IsFormatSupported` before initializing, a rejected one is replaced by the closest match
+/// the device suggests, or by its mix format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChannelFallback {
+    /// Fail with the error of the rejected `Initialize`
+    Error,
+    /// Capture with the closest match, the callback gets the device's format
+    #[default]
+    Device,
+    /// Capture with the closest match and convert to the requested format in software, a different sample rate
+    /// requires the `resampler` feature
+    Remap,
+}
+
+#[derive(Clone, Default)]
+pub struct CaptureOptions {
+    deliver_as: Option<SampleFormat>,
+    output_sample_rate: Option<u32>,
+    rate_mismatch: RateMismatchPolicy,
+    channel_fallback: ChannelFallback,
+    monitor: Option<LevelMonitor>,
+}
+
+impl fmt::Debug for CaptureOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CaptureOptions")
+            .field("deliver_as", &self.deliver_as)
+            .field("output_sample_rate", &self.output_sample_rate)
+            .field("rate_mismatch", &self.rate_mismatch)
+            .field("channel_fallback", &self.channel_fallback)
+            .field("monitor", &self.get_monitor_interval())
+            .finish()
+    }
+}
+
+impl CaptureOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Convert every packet to `format` before it reaches the data callback
+    ///
+    /// Converts the sample type and channel count, so the callback only ever sees one format no matter what the
+    /// device or session uses. Converting the sample rate requires the `resampler` feature, otherwise starting a
+    /// stream with a different rate fails with [`AudioClientError::UnsupportedConversion`](crate::audio_client::AudioClientError::UnsupportedConversion).
+    /// The stream config reports both formats, see [`AudioStreamConfig::source_format`](crate::audio_stream::AudioStreamConfig::source_format).
+    pub fn deliver_as(mut self, format: SampleFormat) -> Self {
+        self.deliver_as = Some(format);
+        self
+    }
+
+    pub fn get_deliver_as(&self) -> Option<&SampleFormat> {
+        self.deliver_as.as_ref()
+    }
+
+    /// Resample every packet to `sample_rate` before it reaches the data callback, e.g. to feed an encoder that only
+    /// takes 48 kHz from a device mixing at 44.1 kHz
+    ///
+    /// Keeps the sample type and channels of the source, or of [`CaptureOptions::deliver_as`] whose rate it replaces.
+    /// Requires the `resampler` feature like any rate conversion. A rate outside of [`OUTPUT_SAMPLE_RATES`] fails the
+    /// start of the stream with [`AudioClientError::OutputSampleRateOutOfRange`](crate::audio_client::AudioClientError::OutputSampleRateOutOfRange).
+    pub fn output_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.output_sample_rate = Some(sample_rate);
+        self
+    }
+
+    pub fn get_output_sample_rate(&self) -> Option<u32> {
+        self.output_sample_rate
+    }
+
+    /// How process loopback handles a requested rate that differs from the default render device's mix rate
+    pub fn rate_mismatch(mut self, policy: RateMismatchPolicy) -> Self {
+        self.rate_mismatch = policy;
+        self
+    }
+
+    pub fn get_rate_mismatch(&self) -> RateMismatchPolicy {
+        self.rate_mismatch
+    }
+    /// How device capture handles a requested format the device doesn't support
+    /// The negotiated format is reported by [`AudioStreamConfig::source_format`](crate::audio_stream::AudioStreamConfig::source_format)
+    pub fn channel_fallback(mut self, fallback: ChannelFallback) -> Self {
+        self.channel_fallback = fallback;
+        self
+    }
+
+    pub fn get_channel_fallback(&self) -> ChannelFallback {
+        self.channel_fallback
+    }
+
+    /// Calls `callback` on the stream thread with the peak and RMS levels of the captured audio, once every `interval`
+    /// of captured audio, besides the data callback. Meant for level meters, see [`Levels`]. Packets in a format that
+    /// can't be decoded, see [`is_convertible`](crate::convert::is_convertible), aren't metered.
+    pub fn monitor<F>(mut self, interval: Duration, callback: F) -> Self
+    where
+        F: Fn(Levels) + Send + Sync + 'static,
+    {
+        self.monitor = Some(LevelMonitor {
+            interval,
+            callback: Arc::new(callback),
+        });
+        self
+    }
+
+    pub fn get_monitor_interval(&self) -> Option<Duration> {
+        self.monitor.as_ref().map(|monitor| monitor.interval)
+    }
+
+    pub(crate) fn get_monitor(&self) -> Option<LevelMonitor> {
+        self.monitor.clone()
+    }
+}
@@ -0,0 +1,248 @@
+//! Captured PCM as an [`io::Read`], see [`CaptureReader`].
+//!
+//! The capture callback copies every packet into a ring buffer holding a fixed duration of audio, reads take the bytes
+//! out in the order they were captured. The callback never waits for the reader: once the ring is full the oldest whole
+//! frames are dropped and counted, so a reader that falls behind loses audio instead of stalling the device.
+
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::audio_client::AudioClientError;
+use crate::audio_stream::{AudioStream, AudioStreamConfig, CapturePacket};
+use crate::sample_format::SampleFormat;
+
+#[derive(Default)]
+struct RingState {
+    data: VecDeque<u8>,
+    capacity: usize,
+    block_align: usize,
+    error: Option<AudioClientError>,
+    failed: bool,
+    dropped_bytes: u64,
+}
+
+impl RingState {
+    /// Appends a packet, dropping the oldest whole frames beyond the capacity
+    fn push(&mut self, data: &[u8]) {
+        // Whole frames only, a reader in the middle of a frame stays aligned
+        let block_align = self.block_align.max(1);
+        // A packet larger than the whole ring only keeps its newest frames
+        let skipped = data
+            .len()
+            .saturating_sub(self.capacity)
+            .next_multiple_of(block_align)
+            .min(data.len());
+        let data = &data[skipped..];
+        let excess = (self.data.len() + data.len()).saturating_sub(self.capacity);
+        let dropped = excess.next_multiple_of(block_align).min(self.data.len());
+        self.data.drain(..dropped);
+        self.dropped_bytes += (skipped + dropped) as u64;
+        self.data.extend(data);
+    }
+}
+
+/// Ring between the capture callback and a [`CaptureReader`]
+#[derive(Clone, Default)]
+pub(crate) struct CaptureRing {
+    state: Arc<(Mutex<RingState>, Condvar)>,
+}
+
+impl CaptureRing {
+    fn lock(&self) -> MutexGuard<'_, RingState> {
+        self.state.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn data_callback(&self) -> impl FnMut(CapturePacket) + Send + use<> {
+        let ring = self.clone();
+        move |packet| {
+            ring.lock().push(packet.data());
+            ring.state.1.notify_all();
+        }
+    }
+
+    pub(crate) fn error_callback(&self) -> impl FnMut(AudioClientError) + Send + use<> {
+        let ring = self.clone();
+        move |err| {
+            ring.lock().error.get_or_insert(err);
+            ring.state.1.notify_all();
+        }
+    }
+
+    /// Waits up to `timeout` for data, then takes as much of it as fits into `buf`
+    fn read(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.lock();
+        loop {
+            if !state.data.is_empty() {
+                let len = state.data.len().min(buf.len());
+                for (dst, src) in buf.iter_mut().zip(state.data.drain(..len)) {
+                    *dst = src;
+                }
+                return Ok(len);
+            }
+            if let Some(err) = state.error.take() {
+                state.failed = true;
+                return Err(err.into());
+            }
+            if state.failed {
+                return Ok(0);
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "No captured data within the read timeout"));
+                    }
+                    self.state.1.wait_timeout(state, timeout).unwrap_or_else(|e| e.into_inner()).0
+                }
+                None => self.state.1.wait(state).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+}
+
+/// Captured PCM as a byte stream, see [`AudioClient::capture_reader`](crate::audio_client::AudioClient::capture_reader)
+///
+/// Reads block until captured data is available, and return the interleaved bytes of [`CaptureReader::format`]. A stream
+/// error is returned once the data captured before it is read, converted into an [`io::Error`], later reads return
+/// end of file. Dropping the reader stops the capture.
+pub struct CaptureReader {
+    ring: CaptureRing,
+    stream: AudioStream,
+    format: SampleFormat,
+    read_timeout: Option<Duration>,
+}
+
+impl CaptureReader {
+    pub(crate) fn start(config: AudioStreamConfig, ring: CaptureRing, capacity: Duration) -> Result<Self, AudioClientError> {
+        let format = config.format().clone();
+        {
+            let mut state = ring.lock();
+            state.block_align = (format.block_align() as usize).max(1);
+            let frames = (capacity.as_secs_f64() * format.get_n_samples_per_sec() as f64).ceil() as usize;
+            state.capacity = frames.max(1) * state.block_align;
+        }
+        Ok(Self {
+            ring,
+            stream: config.start()?,
+            format,
+            read_timeout: None,
+        })
+    }
+
+    /// Format of the bytes read
+    pub fn format(&self) -> &SampleFormat {
+        &self.format
+    }
+
+    /// The running stream, e.g. for [`AudioStream::set_muted`]
+    pub fn audio_stream(&self) -> &AudioStream {
+        &self.stream
+    }
+
+    /// Bytes dropped because they weren't read in time, always whole frames
+    pub fn dropped_bytes(&self) -> u64 {
+        self.ring.lock().dropped_bytes
+    }
+
+    /// Bytes captured and not read yet
+    pub fn available(&self) -> usize {
+        self.ring.lock().data.len()
+    }
+
+    /// Limits how long a read waits for data, reads then fail with [`io::ErrorKind::TimedOut`]. Loopback captures
+    /// get no data while nothing plays, without a timeout a read waits for the next sound.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    // See drop implementation of the stream for cleanup
+    pub fn stop(self) {}
+}
+
+impl Read for CaptureReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.ring.read(buf, self.read_timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use windows::Win32::Media::Audio::AUDCLNT_E_DEVICE_INVALIDATED;
+
+    use super::*;
+    use crate::audio_client::AudioClient;
+    use crate::capture_target::CaptureTarget;
+
+    #[test]
+    fn read_loopback() {
+        // Silence played on the default device keeps the loopback capture delivering
+        let (playback, _) = AudioClient::new()
+            .start_playback_device(
+                None,
+                |mut request| {
+                    request.buffer().fill(0);
+                    request.frames()
+                },
+                |_| {},
+            )
+            .unwrap();
+        let _playback = playback.start().unwrap();
+
+        let mut reader = AudioClient::new()
+            .capture_reader(&CaptureTarget::DefaultLoopback, Duration::from_millis(500))
+            .unwrap();
+        reader.set_read_timeout(Some(Duration::from_secs(2)));
+        let mut frame = vec![0; reader.format().block_align() as usize];
+        reader.read_exact(&mut frame).unwrap();
+    }
+
+    #[test]
+    fn drop_oldest_frames() {
+        let mut state = RingState {
+            capacity: 4,
+            block_align: 2,
+            ..Default::default()
+        };
+        state.push(&[1, 2, 3, 4]);
+        state.push(&[5, 6]);
+        assert_eq!(state.data, [3, 4, 5, 6]);
+        // Larger than the whole ring
+        state.push(&[7, 8, 9, 10, 11, 12]);
+        assert_eq!(state.data, [9, 10, 11, 12]);
+        assert_eq!(state.dropped_bytes, 8);
+    }
+
+    #[test]
+    fn error_after_data() {
+        let ring = CaptureRing::default();
+        {
+            let mut state = ring.lock();
+            state.capacity = 8;
+            state.data.extend([1, 2, 3]);
+        }
+        let mut buf = [0; 2];
+        assert_eq!(ring.read(&mut buf, Some(Duration::from_millis(1))).unwrap(), 2);
+        assert_eq!(ring.read(&mut buf, Some(Duration::from_millis(1))).unwrap(), 1);
+        let err = ring.read(&mut buf, Some(Duration::from_millis(1))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // A stream error comes after the buffered data, then the reader is at its end
+        ring.lock().data.push_back(4);
+        (ring.error_callback())(AudioClientError::FailedGettingBuffer(AUDCLNT_E_DEVICE_INVALIDATED.into()));
+        assert_eq!(ring.read(&mut buf, None).unwrap(), 1);
+        let err = ring.read(&mut buf, None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        assert!(err.get_ref().unwrap().is::<AudioClientError>());
+        assert_eq!(ring.read(&mut buf, None).unwrap(), 0);
+    }
+}
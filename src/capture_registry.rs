@@ -0,0 +1,327 @@
+//! In-process registry of the running process loopback captures, see [`DuplicateCapturePolicy`].
+//!
+//! Two process loopback streams on the same process don't share anything in WASAPI and may deliver diverging data. Every
+//! process capture started through [`AudioClient`](crate::audio_client::AudioClient) is registered here until its stream
+//! is dropped, and can hand its packets to further subscribers on its own stream thread. A stream switched to a process
+//! with [`AudioStream::switch_target`](crate::audio_stream::AudioStream::switch_target) is registered from then on.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+
+use crate::audio_client::AudioClientError;
+use crate::audio_stream::{AudioStreamConfig, CapturePacket};
+use crate::sample_format::SampleFormat;
+
+static ACTIVE_CAPTURES: Mutex<Registry> = Mutex::new(Registry {
+    captures: Vec::new(),
+    starting: Vec::new(),
+});
+/// Notified whenever a capture finished starting
+static STARTED: Condvar = Condvar::new();
+
+struct Registry {
+    captures: Vec<Weak<FanOut>>,
+    /// Processes whose capture is being activated, without the registry locked
+    starting: Vec<u32>,
+}
+
+fn lock_registry() -> MutexGuard<'static, Registry> {
+    ACTIVE_CAPTURES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// What [`AudioClient`](crate::audio_client::AudioClient) does when a process is captured again while a capture of it
+/// is still running in this process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateCapturePolicy {
+    /// Start another stream, the behavior of WASAPI
+    #[default]
+    AllowDuplicates,
+    /// [`AudioClient::start_recording_process_shared`](crate::audio_client::AudioClient::start_recording_process_shared)
+    /// subscribes to the running capture instead of starting a stream,
+    /// [`AudioClient::start_recording_process`](crate::audio_client::AudioClient::start_recording_process) fails
+    ReuseExisting,
+    /// Fail with [`AudioClientError::DuplicateCapture`]
+    Error,
+}
+
+/// Result of [`AudioClient::start_recording_process_shared`](crate::audio_client::AudioClient::start_recording_process_shared)
+pub enum ProcessCapture {
+    /// No capture of the process was running, a new stream has to be started
+    Started(Box<AudioStreamConfig>),
+    /// The packets of the running capture are handed to the callbacks
+    Shared(CaptureSubscription),
+}
+
+type SubscriberDataFn = Box<dyn FnMut(CapturePacket) + Send + 'static>;
+type SubscriberErrorFn = Box<dyn FnMut(AudioClientError) + Send + 'static>;
+
+struct Subscriber {
+    id: u64,
+    /// `None` once unsubscribed
+    callbacks: Mutex<Option<SubscriberCallbacks>>,
+}
+
+struct SubscriberCallbacks {
+    data_callback: SubscriberDataFn,
+    error_callback: SubscriberErrorFn,
+}
+
+impl Subscriber {
+    fn lock(&self) -> MutexGuard<'_, Option<SubscriberCallbacks>> {
+        self.callbacks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Shared between the stream callbacks of a process capture, its subscriptions and the registry
+pub(crate) struct FanOut {
+    /// 0 once the stream switched to a target that isn't a process, see
+    /// [`AudioStream::switch_target`](crate::audio_stream::AudioStream::switch_target)
+    pid: AtomicU32,
+    /// Policy of the client that started the capture, checked again when the stream switches to another process
+    policy: DuplicateCapturePolicy,
+    format: SampleFormat,
+    /// Cleared once the stream dropped its callbacks
+    running: AtomicBool,
+    next_id: AtomicU64,
+    subscribers: Mutex<Vec<Arc<Subscriber>>>,
+}
+
+impl FanOut {
+    fn lock(&self) -> MutexGuard<'_, Vec<Arc<Subscriber>>> {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The subscribers right now, their callbacks are called without the list locked
+    fn subscribers(&self) -> Vec<Arc<Subscriber>> {
+        self.lock().clone()
+    }
+
+    pub(crate) fn get_policy(&self) -> DuplicateCapturePolicy {
+        self.policy
+    }
+
+    /// Registers the capture for `pid` instead, 0 for a target that isn't a process
+    pub(crate) fn set_pid(&self, pid: u32) {
+        self.pid.store(pid, Ordering::Relaxed);
+    }
+
+    /// Marks the capture as stopped once its stream is gone
+    pub(crate) fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+
+    pub(crate) fn deliver(&self, packet: &CapturePacket) {
+        for subscriber in self.subscribers() {
+            if let Some(callbacks) = &mut *subscriber.lock() {
+                (callbacks.data_callback)(packet.with_data(packet.data()));
+            }
+        }
+    }
+
+    pub(crate) fn error(&self, err: &AudioClientError) {
+        for subscriber in self.subscribers() {
+            if let Some(callbacks) = &mut *subscriber.lock() {
+                (callbacks.error_callback)(err.clone());
+            }
+        }
+    }
+
+    pub(crate) fn subscribe<D, E>(self: &Arc<Self>, data_callback: D, error_callback: E) -> CaptureSubscription
+    where
+        D: FnMut(CapturePacket) + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.lock().push(Arc::new(Subscriber {
+            id,
+            callbacks: Mutex::new(Some(SubscriberCallbacks {
+                data_callback: Box::new(data_callback),
+                error_callback: Box::new(error_callback),
+            })),
+        }));
+        CaptureSubscription { fan_out: self.clone(), id }
+    }
+}
+
+/// Receives the packets of another capture of the same process, dropping it unsubscribes
+///
+/// The callbacks run on the thread of the capture's stream, in the format of that stream. Once the stream is dropped
+/// no further packets arrive.
+pub struct CaptureSubscription {
+    fan_out: Arc<FanOut>,
+    id: u64,
+}
+
+impl CaptureSubscription {
+    /// Process the shared stream captures, 0 once it switched to a target that isn't a process
+    pub fn pid(&self) -> u32 {
+        self.fan_out.pid.load(Ordering::Relaxed)
+    }
+
+    /// Format of the shared stream, may differ from the format of the client that subscribed
+    pub fn format(&self) -> &SampleFormat {
+        &self.fan_out.format
+    }
+
+    /// Whether the shared stream still exists
+    pub fn is_running(&self) -> bool {
+        self.fan_out.running.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for CaptureSubscription {
+    fn drop(&mut self) {
+        let removed = {
+            let mut subscribers = self.fan_out.lock();
+            let index = subscribers.iter().position(|subscriber| subscriber.id == self.id);
+            index.map(|index| subscribers.remove(index))
+        };
+        // Waits for a callback that is running right now, none is called afterwards
+        if let Some(subscriber) = removed {
+            subscriber.lock().take();
+        }
+    }
+}
+
+/// Locked registry, looked up before a process capture is created
+pub(crate) struct ActiveCaptures(MutexGuard<'static, Registry>);
+
+impl ActiveCaptures {
+    /// Waits for a capture of `pid` that is being started by another thread, so it is found once it runs
+    pub(crate) fn lock(pid: u32) -> Self {
+        let captures = STARTED
+            .wait_while(lock_registry(), |registry| registry.starting.contains(&pid))
+            .unwrap_or_else(|e| e.into_inner());
+        Self(captures)
+    }
+
+    /// The running capture of `pid`
+    pub(crate) fn find(&mut self, pid: u32) -> Option<Arc<FanOut>> {
+        self.0
+            .captures
+            .retain(|capture| capture.upgrade().is_some_and(|capture| capture.running.load(Ordering::Relaxed)));
+        self.0
+            .captures
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|capture| capture.pid.load(Ordering::Relaxed) == pid)
+    }
+
+    /// Marks a capture of `pid` as starting and unlocks the registry, callers of [`ActiveCaptures::lock`] for the same
+    /// process wait until the returned value is dropped
+    pub(crate) fn start(mut self, pid: u32) -> StartingCapture {
+        self.0.starting.push(pid);
+        StartingCapture { pid }
+    }
+}
+
+/// A capture being started without the registry locked, see [`ActiveCaptures::start`]
+pub(crate) struct StartingCapture {
+    pid: u32,
+}
+
+impl Drop for StartingCapture {
+    fn drop(&mut self) {
+        let mut registry = lock_registry();
+        if let Some(index) = registry.starting.iter().position(|pid| *pid == self.pid) {
+            registry.starting.remove(index);
+        }
+        drop(registry);
+        STARTED.notify_all();
+    }
+}
+
+impl StartingCapture {
+    pub(crate) fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Registers the capture delivering `format`, its stream passes everything on to the subscribers once it holds the
+    /// returned entry, see [`AudioStreamConfig::with_registration`]
+    pub(crate) fn register(&self, format: SampleFormat, policy: DuplicateCapturePolicy) -> Arc<FanOut> {
+        let fan_out = Arc::new(FanOut {
+            pid: AtomicU32::new(self.pid),
+            policy,
+            format,
+            running: AtomicBool::new(true),
+            next_id: AtomicU64::new(0),
+            subscribers: Mutex::new(Vec::new()),
+        });
+        lock_registry().captures.push(Arc::downgrade(&fan_out));
+        fan_out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_client::AudioClient;
+    use crate::capture_target::CaptureTarget;
+
+    #[test]
+    fn duplicate_process_capture() {
+        let pid = std::process::id();
+        let mut client = AudioClient::new();
+        client.set_duplicate_capture_policy(DuplicateCapturePolicy::ReuseExisting);
+        let stream = client.clone().start_recording_process(pid, |_| {}, |_| {}).unwrap();
+        assert!(matches!(
+            client.clone().start_recording_process(pid, |_| {}, |_| {}),
+            Err(AudioClientError::DuplicateCapture(_))
+        ));
+
+        let Ok(ProcessCapture::Shared(subscription)) = client.clone().start_recording_process_shared(pid, |_| {}, |_| {}) else {
+            panic!("expected a subscription to the running capture");
+        };
+        assert_eq!(subscription.format(), stream.format());
+        drop(stream);
+        assert!(!subscription.is_running());
+        assert!(matches!(
+            client.start_recording_process_shared(pid, |_| {}, |_| {}),
+            Ok(ProcessCapture::Started(_))
+        ));
+    }
+
+    #[test]
+    fn switched_process_capture() {
+        let pid = std::process::id();
+        let mut client = AudioClient::new();
+        client.set_duplicate_capture_policy(DuplicateCapturePolicy::Error);
+        let stream = client
+            .clone()
+            .start_recording_process(pid, |_| {}, |_| {})
+            .unwrap()
+            .start()
+            .unwrap();
+
+        // The stream no longer captures the process after switching away
+        stream.switch_target(&CaptureTarget::DefaultLoopback).unwrap();
+        let other = client.clone().start_recording_process(pid, |_| {}, |_| {}).unwrap();
+        assert!(matches!(
+            stream.switch_target(&CaptureTarget::Process(pid)),
+            Err(AudioClientError::DuplicateCapture(_))
+        ));
+
+        drop(other);
+        stream.switch_target(&CaptureTarget::Process(pid)).unwrap();
+        assert!(matches!(
+            client.clone().start_recording_process(pid, |_| {}, |_| {}),
+            Err(AudioClientError::DuplicateCapture(_))
+        ));
+        drop(stream);
+
+        // A stream that didn't start as a process capture is registered once it switches to one
+        let stream = client
+            .clone()
+            .start_recording_loopback_device(None, |_| {}, |_| {})
+            .unwrap()
+            .start()
+            .unwrap();
+        stream.switch_target(&CaptureTarget::Process(pid)).unwrap();
+        assert!(matches!(
+            client.clone().start_recording_process(pid, |_| {}, |_| {}),
+            Err(AudioClientError::DuplicateCapture(_))
+        ));
+        drop(stream);
+        client.start_recording_process(pid, |_| {}, |_| {}).unwrap();
+    }
+}
@@ -0,0 +1,48 @@
+use windows::Win32::Media::Audio::{
+    PROCESS_LOOPBACK_MODE, PROCESS_LOOPBACK_MODE_EXCLUDE_TARGET_PROCESS_TREE, PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE,
+};
+
+use crate::manager::Device;
+
+/// Source of a capture stream, see [`AudioClient::start_capture`](crate::audio_client::AudioClient::start_capture)
+#[derive(Debug, Clone, PartialEq)]
+pub enum CaptureTarget {
+    /// Input device
+    InputDevice(Device),
+    /// Everything played on a playback device
+    LoopbackDevice(Device),
+    /// Default input device
+    DefaultInput,
+    /// Everything played on the default playback device
+    DefaultLoopback,
+    /// Audio played by the process tree rooted at the given pid
+    Process(u32),
+    /// Audio played by every process except the process tree rooted at the given pid, e.g. everything but the own
+    /// application. Only a single tree can be excluded, see [`ExcludeCapture`](crate::exclude_capture::ExcludeCapture)
+    /// for several processes
+    ProcessTreeExclude(u32),
+}
+
+impl CaptureTarget {
+    /// Pid and mode of the process loopback activation, `None` for device targets
+    pub(crate) fn process_loopback(&self) -> Option<(u32, PROCESS_LOOPBACK_MODE)> {
+        match self {
+            CaptureTarget::Process(pid) => Some((*pid, PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE)),
+            CaptureTarget::ProcessTreeExclude(pid) => Some((*pid, PROCESS_LOOPBACK_MODE_EXCLUDE_TARGET_PROCESS_TREE)),
+            _ => None,
+        }
+    }
+
+    /// Device of a device target, `None` for the default devices and process targets
+    pub(crate) fn device(&self) -> Option<&Device> {
+        match self {
+            CaptureTarget::InputDevice(dev) | CaptureTarget::LoopbackDevice(dev) => Some(dev),
+            _ => None,
+        }
+    }
+
+    /// Whether the target captures what a playback device plays
+    pub(crate) fn is_loopback_device(&self) -> bool {
+        matches!(self, CaptureTarget::LoopbackDevice(_) | CaptureTarget::DefaultLoopback)
+    }
+}
@@ -0,0 +1,90 @@
+//! The performance counter clock stream timestamps are taken from.
+//!
+//! Capture packet timestamps and [`StreamInstant::now`] are the raw `QueryPerformanceCounter` ticks converted to
+//! nanoseconds. Other APIs stamping their data with the same counter, e.g. Media Foundation or Desktop Duplication,
+//! hand out raw ticks or 100 nanosecond units instead, these functions convert between all of them without drift.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use windows::Win32::System::Performance::{QueryPerformanceCounter, QueryPerformanceFrequency};
+
+use crate::stream_instant::StreamInstant;
+
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+
+/// Ticks per second of the performance counter, fixed at boot
+pub fn qpc_frequency() -> u64 {
+    static FREQUENCY: OnceLock<u64> = OnceLock::new();
+    *FREQUENCY.get_or_init(|| {
+        let mut frequency = 0i64;
+        // Can't fail on Windows XP and later
+        let _ = unsafe { QueryPerformanceFrequency(&mut frequency) };
+        frequency.max(1) as u64
+    })
+}
+
+/// Current value of the performance counter in raw ticks
+pub fn qpc_now() -> u64 {
+    let mut counter = 0i64;
+    let _ = unsafe { QueryPerformanceCounter(&mut counter) };
+    counter as u64
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as i128 * NANOS_PER_SEC / qpc_frequency() as i128;
+    Duration::from_nanos(nanos.min(u64::MAX as i128) as u64)
+}
+
+/// Rounds down to whole ticks
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = duration.as_nanos() * qpc_frequency() as u128 / NANOS_PER_SEC as u128;
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// The instant a counter value was taken at, in the timeline of capture packet timestamps
+pub fn ticks_to_instant(ticks: u64) -> StreamInstant {
+    StreamInstant::from_nanos_i128(ticks as i128 * NANOS_PER_SEC / qpc_frequency() as i128)
+        .expect("performance counter out of range of `StreamInstant` representation")
+}
+
+/// Counter value at `instant`, `None` for instants before the counter started
+pub fn instant_to_ticks(instant: StreamInstant) -> Option<u64> {
+    let ticks = instant.as_nanos() * qpc_frequency() as i128 / NANOS_PER_SEC;
+    u64::try_from(ticks).ok()
+}
+
+/// The instant of a counter value in 100 nanosecond units, e.g. the position `IAudioCaptureClient::GetBuffer` reports
+pub fn hns_to_instant(hns: u64) -> StreamInstant {
+    StreamInstant::from_nanos_i128(hns as i128 * 100).expect("performance counter out of range of `StreamInstant` representation")
+}
+
+/// Counter value at `instant` in 100 nanosecond units, `None` for instants before the counter started
+pub fn instant_to_hns(instant: StreamInstant) -> Option<u64> {
+    u64::try_from(instant.as_nanos() / 100).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        assert!(qpc_frequency() > 1);
+        let ticks = qpc_now();
+        let instant = ticks_to_instant(ticks);
+        let after = StreamInstant::now();
+        assert!(after.duration_since(&instant).unwrap() < Duration::from_millis(100));
+
+        // A tick is at most a microsecond on current hardware, so conversions round trip within one
+        let back = instant_to_ticks(instant).unwrap();
+        assert!(ticks - back <= 1);
+        let hns = instant_to_hns(instant).unwrap();
+        assert!(instant.duration_since(&hns_to_instant(hns)).unwrap() < Duration::from_nanos(100));
+
+        let second = duration_to_ticks(Duration::from_secs(1));
+        assert_eq!(second, qpc_frequency());
+        assert_eq!(ticks_to_duration(second), Duration::from_secs(1));
+        assert_eq!(instant_to_ticks(StreamInstant::new(-1, 0)), None);
+    }
+}
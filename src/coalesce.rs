@@ -0,0 +1,166 @@
+//! Thinning out bursts of notifications before they reach a callback, see [`Coalescing`].
+//!
+//! Dragging a volume slider makes some drivers raise dozens of volume events, a UI only needs the value the slider
+//! settled on. Events that make older ones of the same kind obsolete are coalesced, every other event is delivered
+//! as it comes, after the coalesced events that arrived before it.
+
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::event_args::{AudioSessionEventArgs, EndpointVolumeChangedArgs};
+
+/// How a registration coalesces its events, see
+/// [`Notifications::register_session_event_coalesced`](crate::notifications::Notifications::register_session_event_coalesced)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coalescing {
+    /// Hold an event until no newer one of its kind arrived for the duration, then deliver it
+    Debounce(Duration),
+    /// Deliver right away, but drop the events that queued up while the callback was busy except the newest of each kind
+    LatestOnly,
+}
+
+/// Events that can be coalesced, the newest event of a key replaces the older ones
+pub trait Coalesce: Send + 'static {
+    /// `None` for events that are never dropped, e.g. state changes
+    fn coalesce_key(&self) -> Option<u32>;
+}
+
+impl Coalesce for AudioSessionEventArgs {
+    fn coalesce_key(&self) -> Option<u32> {
+        match self {
+            AudioSessionEventArgs::DisplayNameChanged(_) => Some(0),
+            AudioSessionEventArgs::IconPathChanged(_) => Some(1),
+            AudioSessionEventArgs::SimpleVolumeChanged(_) => Some(2),
+            AudioSessionEventArgs::ChannelVolumeChanged(_) => Some(3),
+            AudioSessionEventArgs::GroupingParamChanged(_) => Some(4),
+            AudioSessionEventArgs::StateChanged(_) | AudioSessionEventArgs::SessionDisconnected(_) => None,
+        }
+    }
+}
+
+impl Coalesce for EndpointVolumeChangedArgs {
+    fn coalesce_key(&self) -> Option<u32> {
+        Some(0)
+    }
+}
+
+/// Events waiting for delivery in arrival order, at most one per key
+struct Pending<A> {
+    events: Vec<(Option<u32>, A, Instant)>,
+}
+
+impl<A: Coalesce> Pending<A> {
+    fn push(&mut self, event: A, deliver_at: Instant) {
+        let key = event.coalesce_key();
+        if key.is_some() {
+            self.events.retain(|(pending_key, _, _)| *pending_key != key);
+        }
+        self.events.push((key, event, deliver_at));
+    }
+
+    /// Delivers the events due at `now`, and every event before the last due one so the order is kept
+    fn deliver_due(&mut self, now: Instant, callback_fn: &dyn Fn(A)) {
+        let Some(last_due) = self.events.iter().rposition(|(_, _, deliver_at)| *deliver_at <= now) else {
+            return;
+        };
+        for (_, event, _) in self.events.drain(..=last_due) {
+            callback_fn(event);
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.events.iter().map(|(_, _, deliver_at)| *deliver_at).min()
+    }
+}
+
+/// Wraps `callback_fn` so its events are coalesced on a thread of the registration
+/// The thread exits once the returned callback is dropped, delivering what is still pending
+pub(crate) fn coalesced<A: Coalesce>(
+    coalescing: Coalescing,
+    callback_fn: Box<dyn Fn(A) + Send + 'static>,
+) -> Box<dyn Fn(A) + Send + 'static> {
+    let (send, recv) = mpsc::channel::<A>();
+    thread::Builder::new()
+        .name("notification coalescing".to_string())
+        .spawn(move || {
+            let mut pending = Pending { events: Vec::new() };
+            match coalescing {
+                Coalescing::Debounce(delay) => loop {
+                    let event = match pending.next_deadline() {
+                        Some(deadline) => recv.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                        None => recv.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+                    match event {
+                        // Events that are never dropped don't wait either
+                        Ok(event) if event.coalesce_key().is_none() => pending.push(event, Instant::now()),
+                        Ok(event) => pending.push(event, Instant::now() + delay),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                    pending.deliver_due(Instant::now(), &callback_fn);
+                },
+                Coalescing::LatestOnly => {
+                    while let Ok(event) = recv.recv() {
+                        let now = Instant::now();
+                        pending.push(event, now);
+                        while let Ok(event) = recv.try_recv() {
+                            pending.push(event, now);
+                        }
+                        pending.deliver_due(now, &callback_fn);
+                    }
+                }
+            }
+            for (_, event, _) in pending.events.drain(..) {
+                callback_fn(event);
+            }
+        })
+        .expect("Failed spawning notification coalescing thread");
+    Box::new(move |event| {
+        let _ = send.send(event);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Event {
+        Volume(u32),
+        State,
+    }
+
+    impl Coalesce for Event {
+        fn coalesce_key(&self) -> Option<u32> {
+            match self {
+                Event::Volume(_) => Some(0),
+                Event::State => None,
+            }
+        }
+    }
+
+    #[test]
+    fn debounce() {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let sink = delivered.clone();
+        let callback_fn = coalesced(
+            Coalescing::Debounce(Duration::from_millis(50)),
+            Box::new(move |event| sink.lock().unwrap().push(event)),
+        );
+        for volume in 0..10 {
+            callback_fn(Event::Volume(volume));
+        }
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(*delivered.lock().unwrap(), [Event::Volume(9)]);
+
+        // A state change flushes the volume that arrived before it
+        callback_fn(Event::Volume(1));
+        callback_fn(Event::Volume(2));
+        callback_fn(Event::State);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(*delivered.lock().unwrap(), [Event::Volume(9), Event::Volume(2), Event::State]);
+    }
+}
@@ -2,9 +2,17 @@
 //! Handles COM initialization and cleanup.
 
 use std::marker::PhantomData;
+#[cfg(feature = "notifications")]
+use std::sync::mpsc;
+use std::thread;
+#[cfg(feature = "notifications")]
+use std::thread::JoinHandle;
 
 use windows::Win32::Foundation::RPC_E_CHANGED_MODE;
-use windows::Win32::System::Com::{CoInitializeEx, CoUninitialize, COINIT_APARTMENTTHREADED};
+use windows::Win32::System::Com::{
+    APTTYPE, APTTYPE_MAINSTA, APTTYPE_STA, APTTYPEQUALIFIER, COINIT_APARTMENTTHREADED, COINIT_MULTITHREADED, CoGetApartmentType,
+    CoInitializeEx, CoUninitialize,
+};
 
 thread_local!(static COM_INITIALIZED: ComInitialized = {
     unsafe {
@@ -55,3 +63,146 @@ impl Drop for ComInitialized {
 pub fn com_initialized() {
     COM_INITIALIZED.with(|_| {});
 }
+
+/// Returns true if the current thread is in a single-threaded apartment, e.g. a GUI thread or a plugin host thread
+pub fn is_sta_thread() -> bool {
+    let mut apt_type = APTTYPE::default();
+    let mut qualifier = APTTYPEQUALIFIER::default();
+    match unsafe { CoGetApartmentType(&mut apt_type, &mut qualifier) } {
+        Ok(()) => apt_type == APTTYPE_STA || apt_type == APTTYPE_MAINSTA,
+        Err(_) => false,
+    }
+}
+
+/// Lets COM interface pointers move to the [`MtaWorker`].
+/// The audio endpoint and session objects are free-threaded, so using them from the MTA is fine.
+pub(crate) struct ComSend<T>(pub(crate) T);
+
+unsafe impl<T> Send for ComSend<T> {}
+
+impl<T> ComSend<T> {
+    pub(crate) fn get(&self) -> &T {
+        &self.0
+    }
+}
+
+/// Joins the multithreaded apartment for the lifetime of the guard, for worker threads that don't pump messages
+struct MtaInitialized(windows::core::HRESULT);
+
+impl MtaInitialized {
+    fn new() -> Self {
+        Self(unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) })
+    }
+}
+
+impl Drop for MtaInitialized {
+    fn drop(&mut self) {
+        if self.0.is_ok() {
+            unsafe { CoUninitialize() };
+        }
+    }
+}
+
+/// Runs `f` for every item on up to `threads` threads in the multithreaded apartment, keeping the order of the items
+/// Items and results may hold COM interfaces, so they are only moved between threads for free-threaded objects. The
+/// workers don't pump messages, so they must not live in a single-threaded apartment.
+pub(crate) fn map_parallel<T, R, F>(items: Vec<T>, threads: usize, f: F) -> Vec<R>
+where
+    F: Fn(&T) -> R + Sync,
+{
+    if threads <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let chunk_len = items.len().div_ceil(threads);
+    let f = &f;
+    thread::scope(|scope| {
+        let workers: Vec<_> = items
+            .chunks(chunk_len)
+            .map(|chunk| {
+                let chunk = ComSend(chunk);
+                scope.spawn(move || {
+                    let _mta = MtaInitialized::new();
+                    ComSend(chunk.get().iter().map(f).collect::<Vec<R>>())
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("COM worker thread panicked").0)
+            .collect()
+    })
+}
+
+#[cfg(feature = "notifications")]
+type MtaJob = Box<dyn FnOnce() + Send + 'static>;
+
+#[cfg(feature = "notifications")]
+/// Thread living in the multithreaded apartment, runs COM calls on behalf of STA threads that don't pump messages
+pub(crate) struct MtaWorker {
+    sender: Option<mpsc::Sender<MtaJob>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[cfg(feature = "notifications")]
+impl MtaWorker {
+    pub(crate) fn spawn() -> Result<Self, windows::core::Error> {
+        let (sender, recv) = mpsc::channel::<MtaJob>();
+        let (ready_send, ready_recv) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let result = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) };
+            let _ = ready_send.send(result);
+            if result.is_err() {
+                return;
+            }
+            for job in recv {
+                job();
+            }
+            unsafe { CoUninitialize() };
+        });
+        ready_recv.recv().expect("MTA worker crashed").ok()?;
+        Ok(Self {
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    /// Runs `job` on the worker and blocks until it returns
+    pub(crate) fn run<R, F>(&self, job: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce() -> R + Send + 'static,
+    {
+        let (result_send, result_recv) = mpsc::channel();
+        self.sender
+            .as_ref()
+            .expect("MTA worker stopped")
+            .send(Box::new(move || {
+                let _ = result_send.send(job());
+            }))
+            .expect("MTA worker crashed");
+        result_recv.recv().expect("MTA worker crashed")
+    }
+}
+
+#[cfg(feature = "notifications")]
+impl Drop for MtaWorker {
+    fn drop(&mut self) {
+        // Closing the channel ends the worker loop
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parallel_workers_in_mta() {
+        com_initialized();
+        let results = map_parallel(vec![1, 2, 3, 4], 2, |item| (*item * 2, is_sta_thread()));
+        assert_eq!(results, vec![(2, false), (4, false), (6, false), (8, false)]);
+    }
+}
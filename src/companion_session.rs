@@ -0,0 +1,105 @@
+//! A silent render session that makes a capture only application show up in the volume mixer, see [`CompanionSession`].
+
+use log::warn;
+use windows::Win32::Media::Audio::IAudioSessionControl;
+use windows_core::HSTRING;
+
+use crate::audio_client::{AudioClient, AudioClientError};
+use crate::audio_stream::AudioStream;
+use crate::event_context::EventContext;
+use crate::manager::Device;
+
+/// Name and icon of the mixer entry of a capture stream, see
+/// [`AudioStreamConfig::with_companion_session`](crate::audio_stream::AudioStreamConfig::with_companion_session)
+///
+/// Windows only lists sessions of render streams in the volume mixer. The companion is a render stream playing silence
+/// for as long as the capture stream runs, so users see the application by name, e.g. to turn down its monitoring.
+/// It joins the default session of the process, an application that also plays audio renames that session.
+#[derive(Debug, Clone)]
+pub struct CompanionSession {
+    display_name: String,
+    icon_path: Option<String>,
+    device: Option<Device>,
+}
+
+impl CompanionSession {
+    pub fn new(display_name: impl Into<String>) -> Self {
+        Self {
+            display_name: display_name.into(),
+            icon_path: None,
+            device: None,
+        }
+    }
+
+    /// Icon shown next to the name, in the `path,index` format of the mixer, e.g. `C:\app\app.exe,0`
+    pub fn icon_path(mut self, icon_path: impl Into<String>) -> Self {
+        self.icon_path = Some(icon_path.into());
+        self
+    }
+
+    /// Playback device whose mixer lists the session, the default playback device if not set
+    pub fn device(mut self, device: Device) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    pub fn get_display_name(&self) -> &str {
+        &self.display_name
+    }
+
+    pub fn get_icon_path(&self) -> Option<&str> {
+        self.icon_path.as_deref()
+    }
+
+    pub fn get_device(&self) -> Option<&Device> {
+        self.device.as_ref()
+    }
+
+    /// Starts the silent stream and names its session
+    pub(crate) fn start(&self) -> Result<AudioStream, AudioClientError> {
+        let display_name = self.display_name.clone();
+        let (config, _format) = AudioClient::new().start_playback_device(
+            self.device.as_ref(),
+            |_request| 0,
+            move |err| warn!("Companion session {} failed: {}", display_name, err),
+        )?;
+        let session: IAudioSessionControl = unsafe { config.service() }?;
+        let context = EventContext::process_default();
+        unsafe { session.SetDisplayName(&HSTRING::from(&self.display_name), context.as_ptr()) }
+            .map_err(AudioClientError::FailedSettingSessionProperties)?;
+        if let Some(icon_path) = &self.icon_path {
+            unsafe { session.SetIconPath(&HSTRING::from(icon_path), context.as_ptr()) }
+                .map_err(AudioClientError::FailedSettingSessionProperties)?;
+        }
+        config.with_label("companion session").start()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::manager::SessionManager;
+
+    #[test]
+    fn companion_session() {
+        let (packet_send, packet_recv) = channel();
+        let capture_stream = AudioClient::new()
+            .start_recording_device(None, move |packet| packet_send.send(packet.data().len()).unwrap(), |_err| {})
+            .unwrap()
+            .with_companion_session(CompanionSession::new("Companion test"))
+            .start()
+            .unwrap();
+        packet_recv.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        let named = |name: &str| {
+            SessionManager::get_sessions().unwrap().iter().any(|session| {
+                *session.get_pid() == std::process::id() && session.get_display_name().is_ok_and(|display_name| display_name == name)
+            })
+        };
+        assert!(named("Companion test"));
+        drop(capture_stream);
+    }
+}
@@ -0,0 +1,363 @@
+//! Conversion between raw interleaved buffers in a [`SampleFormat`] and normalized `f32` samples.
+
+use std::any::TypeId;
+
+#[cfg(feature = "resampler")]
+use crate::resampler::Resampler;
+use crate::sample_format::{FormatTag, SampleFormat};
+
+/// True if samples in this format can be converted from and to `f32`
+pub fn is_convertible(format: &SampleFormat) -> bool {
+    matches!(
+        (format.get_format_tag(), format.get_w_bits_per_sample()),
+        (FormatTag::WaveFormatIeeeFloat, 32) | (FormatTag::WaveFormatIeeeFloat, 64) | (FormatTag::WaveFormatPcm, 8 | 16 | 24 | 32)
+    )
+}
+
+/// Appends the samples of `data` to `out` as `f32` in the range `-1.0..=1.0`
+/// Unconvertible formats produce silence
+pub fn bytes_to_f32(format: &SampleFormat, data: &[u8], out: &mut Vec<f32>) {
+    let bytes_per_sample = (format.get_w_bits_per_sample() / 8) as usize;
+    if bytes_per_sample == 0 {
+        return;
+    }
+    let samples = data.chunks_exact(bytes_per_sample);
+    match (format.get_format_tag(), format.get_w_bits_per_sample()) {
+        (FormatTag::WaveFormatIeeeFloat, 32) => out.extend(samples.map(|s| f32::from_le_bytes([s[0], s[1], s[2], s[3]]))),
+        (FormatTag::WaveFormatIeeeFloat, 64) => out.extend(samples.map(|s| f64::from_le_bytes(s.try_into().unwrap()) as f32)),
+        (FormatTag::WaveFormatPcm, 8) => out.extend(samples.map(|s| (s[0] as f32 - 128.0) / 128.0)),
+        (FormatTag::WaveFormatPcm, 16) => out.extend(samples.map(|s| i16::from_le_bytes([s[0], s[1]]) as f32 / 32768.0)),
+        (FormatTag::WaveFormatPcm, 24) => out.extend(samples.map(|s| i32::from_le_bytes([0, s[0], s[1], s[2]]) as f32 / 2147483648.0)),
+        (FormatTag::WaveFormatPcm, 32) => out.extend(samples.map(|s| i32::from_le_bytes([s[0], s[1], s[2], s[3]]) as f32 / 2147483648.0)),
+        _ => out.extend(samples.map(|_| 0.0)),
+    }
+}
+
+/// A sample type captured data can be delivered as and playback data written as, see
+/// [`AudioClient::start_capture_typed`](crate::audio_client::AudioClient::start_capture_typed) and
+/// [`AudioClient::start_playback_device_typed`](crate::audio_client::AudioClient::start_playback_device_typed)
+///
+/// The default value is silence.
+pub trait Sample: Copy + Default + Send + 'static {
+    /// Appends the samples of `data` to `out`, keeping the channels interleaved
+    /// Unconvertible formats produce silence
+    fn decode(format: &SampleFormat, data: &[u8], out: &mut Vec<Self>);
+
+    /// Appends `samples` to `out` encoded in `format`
+    /// Unconvertible formats produce silence
+    fn encode(format: &SampleFormat, samples: &[Self], out: &mut Vec<u8>);
+}
+
+/// `f32` in the range `-1.0..=1.0`
+impl Sample for f32 {
+    fn decode(format: &SampleFormat, data: &[u8], out: &mut Vec<Self>) {
+        bytes_to_f32(format, data, out);
+    }
+
+    fn encode(format: &SampleFormat, samples: &[Self], out: &mut Vec<u8>) {
+        f32_to_bytes(format, samples, out);
+    }
+}
+
+/// Full scale `i16`, wider integer formats lose their low bits
+impl Sample for i16 {
+    fn decode(format: &SampleFormat, data: &[u8], out: &mut Vec<Self>) {
+        decode_i32(format, data, |sample| (sample >> 16) as i16, out);
+    }
+
+    fn encode(format: &SampleFormat, samples: &[Self], out: &mut Vec<u8>) {
+        encode_i32(format, samples.iter().map(|&sample| (sample as i32) << 16), out);
+    }
+}
+
+/// Full scale `i32`, narrower integer formats are shifted up so they keep their level
+impl Sample for i32 {
+    fn decode(format: &SampleFormat, data: &[u8], out: &mut Vec<Self>) {
+        decode_i32(format, data, |sample| sample, out);
+    }
+
+    fn encode(format: &SampleFormat, samples: &[Self], out: &mut Vec<u8>) {
+        encode_i32(format, samples.iter().copied(), out);
+    }
+}
+
+/// Format tag and bits per sample that store `T` as is, `None` for types that are always decoded
+pub fn native_format<T: Sample>() -> Option<(FormatTag, u16)> {
+    // Resolved at compile time for every `T`
+    let id = TypeId::of::<T>();
+    if id == TypeId::of::<f32>() {
+        Some((FormatTag::WaveFormatIeeeFloat, 32))
+    } else if id == TypeId::of::<i16>() {
+        Some((FormatTag::WaveFormatPcm, 16))
+    } else if id == TypeId::of::<i32>() {
+        Some((FormatTag::WaveFormatPcm, 32))
+    } else {
+        None
+    }
+}
+
+/// True if data in `format` can be handed out as `T` without decoding
+pub fn is_native<T: Sample>(format: &SampleFormat) -> bool {
+    native_format::<T>().is_some_and(|(tag, bits)| *format.get_format_tag() == tag && format.get_w_bits_per_sample() == bits)
+}
+
+/// The samples of `data` without copying, for data in a format [`is_native`] for `T`. `None` for other types and if
+/// `data` isn't aligned for `T`.
+pub(crate) fn borrow_samples<T: Sample>(data: &[u8]) -> Option<&[T]> {
+    native_format::<T>()?;
+    // SAFETY: `T` is `f32`, `i16` or `i32`, which are valid for every bit pattern
+    let (prefix, samples, suffix) = unsafe { data.align_to::<T>() };
+    (prefix.is_empty() && suffix.is_empty()).then_some(samples)
+}
+
+/// The samples of `data` to write in place, like [`borrow_samples`]
+pub(crate) fn borrow_samples_mut<T: Sample>(data: &mut [u8]) -> Option<&mut [T]> {
+    native_format::<T>()?;
+    // SAFETY: `T` is `f32`, `i16` or `i32`, which are valid for every bit pattern
+    let (prefix, samples, suffix) = unsafe { data.align_to_mut::<T>() };
+    (prefix.is_empty() && suffix.is_empty()).then_some(samples)
+}
+
+/// Decodes to full scale `i32` and narrows with `map`, integer formats are converted without going through `f32`
+fn decode_i32<T>(format: &SampleFormat, data: &[u8], map: impl Fn(i32) -> T, out: &mut Vec<T>) {
+    let bytes_per_sample = (format.get_w_bits_per_sample() / 8) as usize;
+    if bytes_per_sample == 0 {
+        return;
+    }
+    let from_float = |sample: f64| (sample.clamp(-1.0, 1.0) * 2147483647.0) as i32;
+    let samples = data.chunks_exact(bytes_per_sample);
+    match (format.get_format_tag(), format.get_w_bits_per_sample()) {
+        (FormatTag::WaveFormatIeeeFloat, 32) => {
+            out.extend(samples.map(|s| map(from_float(f32::from_le_bytes([s[0], s[1], s[2], s[3]]) as f64))))
+        }
+        (FormatTag::WaveFormatIeeeFloat, 64) => out.extend(samples.map(|s| map(from_float(f64::from_le_bytes(s.try_into().unwrap()))))),
+        (FormatTag::WaveFormatPcm, 8) => out.extend(samples.map(|s| map((s[0] as i32 - 128) << 24))),
+        (FormatTag::WaveFormatPcm, 16) => out.extend(samples.map(|s| map((i16::from_le_bytes([s[0], s[1]]) as i32) << 16))),
+        (FormatTag::WaveFormatPcm, 24) => out.extend(samples.map(|s| map(i32::from_le_bytes([0, s[0], s[1], s[2]])))),
+        (FormatTag::WaveFormatPcm, 32) => out.extend(samples.map(|s| map(i32::from_le_bytes([s[0], s[1], s[2], s[3]])))),
+        _ => out.extend(samples.map(|_| map(0))),
+    }
+}
+
+/// Encodes full scale `i32` samples, the counterpart of [`decode_i32`]
+fn encode_i32(format: &SampleFormat, samples: impl ExactSizeIterator<Item = i32>, out: &mut Vec<u8>) {
+    let bytes_per_sample = (format.get_w_bits_per_sample() / 8) as usize;
+    out.reserve(samples.len() * bytes_per_sample);
+    for sample in samples {
+        match (format.get_format_tag(), format.get_w_bits_per_sample()) {
+            (FormatTag::WaveFormatIeeeFloat, 32) => out.extend_from_slice(&((sample as f64 / 2147483648.0) as f32).to_le_bytes()),
+            (FormatTag::WaveFormatIeeeFloat, 64) => out.extend_from_slice(&(sample as f64 / 2147483648.0).to_le_bytes()),
+            (FormatTag::WaveFormatPcm, 8) => out.push(((sample >> 24) + 128) as u8),
+            (FormatTag::WaveFormatPcm, 16) => out.extend_from_slice(&((sample >> 16) as i16).to_le_bytes()),
+            (FormatTag::WaveFormatPcm, 24) => out.extend_from_slice(&sample.to_le_bytes()[1..]),
+            (FormatTag::WaveFormatPcm, 32) => out.extend_from_slice(&sample.to_le_bytes()),
+            _ => out.extend(std::iter::repeat_n(0, bytes_per_sample)),
+        }
+    }
+}
+
+/// Appends `samples` to `out` encoded in `format`, values outside of `-1.0..=1.0` are clipped
+/// Unconvertible formats produce silence
+pub fn f32_to_bytes(format: &SampleFormat, samples: &[f32], out: &mut Vec<u8>) {
+    let bytes_per_sample = (format.get_w_bits_per_sample() / 8) as usize;
+    out.reserve(samples.len() * bytes_per_sample);
+    for &sample in samples {
+        let clipped = sample.clamp(-1.0, 1.0);
+        match (format.get_format_tag(), format.get_w_bits_per_sample()) {
+            (FormatTag::WaveFormatIeeeFloat, 32) => out.extend_from_slice(&sample.to_le_bytes()),
+            (FormatTag::WaveFormatIeeeFloat, 64) => out.extend_from_slice(&(sample as f64).to_le_bytes()),
+            (FormatTag::WaveFormatPcm, 8) => out.push((clipped * 127.0 + 128.0) as u8),
+            (FormatTag::WaveFormatPcm, 16) => out.extend_from_slice(&((clipped * 32767.0) as i16).to_le_bytes()),
+            (FormatTag::WaveFormatPcm, 24) => out.extend_from_slice(&((clipped * 8388607.0) as i32).to_le_bytes()[..3]),
+            (FormatTag::WaveFormatPcm, 32) => out.extend_from_slice(&((clipped as f64 * 2147483647.0) as i32).to_le_bytes()),
+            _ => out.extend(std::iter::repeat_n(0, bytes_per_sample)),
+        }
+    }
+}
+
+/// Appends interleaved `samples` with `from` channels to `out` with `to` channels
+/// Mono is spread to every channel, mixing down to mono averages all channels, otherwise channels are kept by
+/// position: extra channels are dropped and missing ones are silent
+pub fn remap_channels(samples: &[f32], from: u16, to: u16, out: &mut Vec<f32>) {
+    let (from, to) = (from as usize, to as usize);
+    if from == 0 || to == 0 {
+        return;
+    }
+    if from == to {
+        out.extend_from_slice(samples);
+        return;
+    }
+    out.reserve(samples.len() / from * to);
+    for frame in samples.chunks_exact(from) {
+        if from == 1 {
+            out.extend(std::iter::repeat_n(frame[0], to));
+        } else if to == 1 {
+            out.push(frame.iter().sum::<f32>() / from as f32);
+        } else {
+            out.extend((0..to).map(|channel| frame.get(channel).copied().unwrap_or(0.0)));
+        }
+    }
+}
+
+/// Converts captured packets from the device format to the format requested with
+/// [`CaptureOptions::deliver_as`](crate::capture_options::CaptureOptions::deliver_as)
+pub(crate) struct PacketConverter {
+    from: SampleFormat,
+    to: SampleFormat,
+    samples: Vec<f32>,
+    remapped: Vec<f32>,
+    #[cfg(feature = "resampler")]
+    resampler: Option<(Resampler, Vec<f32>)>,
+    out: Vec<u8>,
+}
+
+impl PacketConverter {
+    /// Returns `None` if the conversion isn't possible, rate conversion needs the `resampler` feature
+    pub(crate) fn new(from: SampleFormat, to: SampleFormat) -> Option<Self> {
+        if !is_convertible(&from) || !is_convertible(&to) {
+            return None;
+        }
+        let same_rate = from.get_n_samples_per_sec() == to.get_n_samples_per_sec();
+        #[cfg(not(feature = "resampler"))]
+        if !same_rate {
+            return None;
+        }
+        Some(Self {
+            #[cfg(feature = "resampler")]
+            resampler: (!same_rate).then(|| {
+                let resampler = Resampler::new(to.get_channel(), from.get_n_samples_per_sec(), to.get_n_samples_per_sec());
+                (resampler, Vec::new())
+            }),
+            from,
+            to,
+            samples: Vec::new(),
+            remapped: Vec::new(),
+            out: Vec::new(),
+        })
+    }
+
+    pub(crate) fn convert(&mut self, data: &[u8]) -> &[u8] {
+        self.samples.clear();
+        self.remapped.clear();
+        self.out.clear();
+        bytes_to_f32(&self.from, data, &mut self.samples);
+        remap_channels(&self.samples, self.from.get_channel(), self.to.get_channel(), &mut self.remapped);
+        #[cfg(feature = "resampler")]
+        if let Some((resampler, resampled)) = &mut self.resampler {
+            resampled.clear();
+            resampler.process(&self.remapped, resampled);
+            f32_to_bytes(&self.to, resampled, &mut self.out);
+            return &self.out;
+        }
+        f32_to_bytes(&self.to, &self.remapped, &mut self.out);
+        &self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let samples = [0.0, 0.5, -0.5, 1.0, -1.0];
+        for (tag, bits) in [
+            (FormatTag::WaveFormatIeeeFloat, 32),
+            (FormatTag::WaveFormatPcm, 16),
+            (FormatTag::WaveFormatPcm, 24),
+            (FormatTag::WaveFormatPcm, 32),
+        ] {
+            let format = SampleFormat::new(tag, 1, 48000, bits);
+            let mut bytes = Vec::new();
+            f32_to_bytes(&format, &samples, &mut bytes);
+            assert_eq!(bytes.len(), samples.len() * bits as usize / 8);
+            let mut decoded = Vec::new();
+            bytes_to_f32(&format, &bytes, &mut decoded);
+            for (a, b) in samples.iter().zip(decoded) {
+                assert!((a - b).abs() < 0.001, "{} {} {:?}", a, b, format);
+            }
+        }
+    }
+
+    #[test]
+    fn typed_samples() {
+        let samples = [0.0, 0.5, -1.0];
+        for (tag, bits) in [
+            (FormatTag::WaveFormatIeeeFloat, 32),
+            (FormatTag::WaveFormatPcm, 16),
+            (FormatTag::WaveFormatPcm, 24),
+        ] {
+            let format = SampleFormat::new(tag, 1, 48000, bits);
+            let mut bytes = Vec::new();
+            f32_to_bytes(&format, &samples, &mut bytes);
+            let mut decoded = Vec::new();
+            i16::decode(&format, &bytes, &mut decoded);
+            assert_eq!(decoded.len(), samples.len());
+            assert!((decoded[1] as i32 - 16383).abs() <= 1, "{:?} {:?}", decoded, format);
+            assert!(decoded[2] <= i16::MIN + 1, "{:?} {:?}", decoded, format);
+            let mut decoded = Vec::new();
+            i32::decode(&format, &bytes, &mut decoded);
+            assert!((decoded[1] as f64 / 2147483648.0 - 0.5).abs() < 0.001, "{:?} {:?}", decoded, format);
+        }
+    }
+
+    #[test]
+    fn encode_typed_samples() {
+        for (tag, bits) in [
+            (FormatTag::WaveFormatIeeeFloat, 32),
+            (FormatTag::WaveFormatIeeeFloat, 64),
+            (FormatTag::WaveFormatPcm, 8),
+            (FormatTag::WaveFormatPcm, 16),
+            (FormatTag::WaveFormatPcm, 24),
+            (FormatTag::WaveFormatPcm, 32),
+        ] {
+            let format = SampleFormat::new(tag, 1, 48000, bits);
+            let mut bytes = Vec::new();
+            i16::encode(&format, &[0, 16384, i16::MIN], &mut bytes);
+            assert_eq!(bytes.len(), 3 * bits as usize / 8);
+            let mut decoded = Vec::new();
+            bytes_to_f32(&format, &bytes, &mut decoded);
+            for (expected, sample) in [0.0, 0.5, -1.0].iter().zip(decoded) {
+                assert!((expected - sample).abs() < 0.01, "{} {} {:?}", expected, sample, format);
+            }
+        }
+
+        let mut samples = [0.0f32; 2];
+        let bytes: &mut [u8] = unsafe { std::slice::from_raw_parts_mut(samples.as_mut_ptr().cast(), size_of_val(&samples)) };
+        borrow_samples_mut::<f32>(bytes).unwrap()[1] = 0.5;
+        assert_eq!(samples, [0.0, 0.5]);
+    }
+
+    #[test]
+    fn borrowed_samples() {
+        let format = SampleFormat::new(FormatTag::WaveFormatPcm, 2, 48000, 16);
+        assert!(is_native::<i16>(&format));
+        assert!(!is_native::<f32>(&format) && !is_native::<i32>(&format));
+
+        let samples = [1i16, -2, 3, i16::MIN];
+        let bytes: &[u8] = unsafe { std::slice::from_raw_parts(samples.as_ptr().cast(), size_of_val(&samples)) };
+        assert_eq!(borrow_samples::<i16>(bytes), Some(&samples[..]));
+        // Misaligned data is decoded instead
+        assert!(borrow_samples::<i16>(&bytes[1..7]).is_none());
+    }
+
+    #[test]
+    fn packet_conversion() {
+        let from = SampleFormat::new(FormatTag::WaveFormatIeeeFloat, 2, 48000, 32);
+        let to = SampleFormat::new(FormatTag::WaveFormatPcm, 1, 48000, 16);
+        let mut converter = PacketConverter::new(from.clone(), to.clone()).unwrap();
+        let mut data = Vec::new();
+        f32_to_bytes(&from, &[0.5, 0.0, -0.5, -0.5], &mut data);
+        let mut decoded = Vec::new();
+        bytes_to_f32(&to, converter.convert(&data), &mut decoded);
+        assert_eq!(decoded.len(), 2);
+        assert!(
+            (decoded[0] - 0.25).abs() < 0.001 && (decoded[1] + 0.5).abs() < 0.001,
+            "{:?}",
+            decoded
+        );
+
+        let other_rate = SampleFormat::new(FormatTag::WaveFormatPcm, 1, 44100, 16);
+        assert_eq!(PacketConverter::new(from, other_rate).is_some(), cfg!(feature = "resampler"));
+    }
+}
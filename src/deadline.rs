@@ -0,0 +1,165 @@
+//! Deadline accounting for streams that promise a latency, see [`DeadlineMode`].
+//!
+//! Every buffer event leaves the callback one device period until the next one. A callback that takes longer is a
+//! miss: the next period is late to the device, which shows up as an xrun (a dropout on playback, a lost packet on
+//! capture) as soon as the buffer can't absorb it anymore. Misses are counted per stream, and a stream can be made to
+//! fail after too many of them in a row instead of silently running late.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::audio_client::AudioClientError;
+
+/// Turns on deadline accounting for a stream, see
+/// [`AudioStreamConfig::with_deadline_mode`](crate::audio_stream::AudioStreamConfig::with_deadline_mode)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeadlineMode {
+    period: Option<Duration>,
+    abort_after: Option<u32>,
+}
+
+impl DeadlineMode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Deadline of every callback, the default period of the device if not set
+    pub fn period(mut self, period: Duration) -> Self {
+        self.period = Some(period);
+        self
+    }
+
+    /// Fails the stream with [`AudioClientError::DeadlinesMissed`] after `misses` consecutive misses
+    pub fn abort_after(mut self, misses: u32) -> Self {
+        self.abort_after = Some(misses.max(1));
+        self
+    }
+
+    pub fn get_period(&self) -> Option<Duration> {
+        self.period
+    }
+
+    pub fn get_abort_after(&self) -> Option<u32> {
+        self.abort_after
+    }
+}
+
+/// Deadline accounting of a stream so far, see [`AudioStream::deadline_stats`](crate::audio_stream::AudioStream::deadline_stats)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeadlineStats {
+    period: Duration,
+    callbacks: u64,
+    misses: u64,
+    consecutive_misses: u32,
+    max_consecutive_misses: u32,
+    worst: Duration,
+}
+
+impl DeadlineStats {
+    /// Deadline every callback was measured against
+    pub fn get_period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn get_callbacks(&self) -> u64 {
+        self.callbacks
+    }
+
+    /// Callbacks that took longer than the period
+    pub fn get_misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Misses since the last callback that made its deadline
+    pub fn get_consecutive_misses(&self) -> u32 {
+        self.consecutive_misses
+    }
+
+    pub fn get_max_consecutive_misses(&self) -> u32 {
+        self.max_consecutive_misses
+    }
+
+    /// Longest callback so far
+    pub fn get_worst(&self) -> Duration {
+        self.worst
+    }
+}
+
+/// Shared between the stream thread updating it and the stream handle reading it
+pub(crate) struct DeadlineLog {
+    stats: Mutex<DeadlineStats>,
+}
+
+impl DeadlineLog {
+    pub(crate) fn stats(&self) -> DeadlineStats {
+        *self.stats.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Measures the callbacks of one stream against its period
+pub(crate) struct DeadlineTracker {
+    log: Arc<DeadlineLog>,
+    abort_after: Option<u32>,
+}
+
+impl DeadlineTracker {
+    /// `device_period` is used unless the mode sets its own period
+    pub(crate) fn new(mode: DeadlineMode, device_period: Duration) -> Self {
+        let stats = DeadlineStats {
+            period: mode.period.unwrap_or(device_period),
+            ..Default::default()
+        };
+        Self {
+            log: Arc::new(DeadlineLog { stats: Mutex::new(stats) }),
+            abort_after: mode.abort_after,
+        }
+    }
+
+    pub(crate) fn log(&self) -> Arc<DeadlineLog> {
+        self.log.clone()
+    }
+
+    /// Accounts a callback that took `elapsed`, fails once the consecutive misses reach the abort threshold
+    pub(crate) fn callback(&mut self, elapsed: Duration) -> Result<(), AudioClientError> {
+        let mut stats = self.log.stats.lock().unwrap_or_else(|e| e.into_inner());
+        stats.callbacks += 1;
+        stats.worst = stats.worst.max(elapsed);
+        if elapsed <= stats.period {
+            stats.consecutive_misses = 0;
+            return Ok(());
+        }
+        stats.misses += 1;
+        stats.consecutive_misses += 1;
+        stats.max_consecutive_misses = stats.max_consecutive_misses.max(stats.consecutive_misses);
+        match self.abort_after {
+            Some(abort_after) if stats.consecutive_misses >= abort_after => {
+                Err(AudioClientError::DeadlinesMissed(stats.consecutive_misses))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_misses() {
+        let mode = DeadlineMode::new().abort_after(3);
+        let mut tracker = DeadlineTracker::new(mode, Duration::from_millis(10));
+        let (made, missed) = (Duration::from_millis(2), Duration::from_millis(12));
+        for elapsed in [made, missed, missed, made, missed, missed] {
+            tracker.callback(elapsed).unwrap();
+        }
+        let stats = tracker.log().stats();
+        assert_eq!(stats.get_callbacks(), 6);
+        assert_eq!(stats.get_misses(), 4);
+        assert_eq!(stats.get_consecutive_misses(), 2);
+        assert_eq!(stats.get_max_consecutive_misses(), 2);
+        assert_eq!(stats.get_worst(), missed);
+
+        let err = tracker.callback(missed).unwrap_err();
+        assert!(matches!(err.without_stream(), AudioClientError::DeadlinesMissed(3)), "{:?}", err);
+    }
+}
@@ -0,0 +1,201 @@
+//! Loopback capture that moves along with the default playback device, see [`FollowingLoopback`].
+//!
+//! A loopback stream is bound to the endpoint it was activated on, when the user switches outputs it keeps capturing
+//! the old device, which usually plays nothing anymore. Following the default device restarts the stream on every
+//! default change, the callbacks move over to the new stream.
+
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+use log::{debug, warn};
+use thiserror::Error;
+use windows::Win32::Media::Audio::AUDCLNT_E_DEVICE_INVALIDATED;
+
+use crate::audio_client::{AudioClient, AudioClientError};
+use crate::audio_stream::{AudioStream, CapturePacket};
+use crate::com::ComSend;
+use crate::device_query::{DataFlow, DeviceRole};
+use crate::dispatcher::Dispatcher;
+use crate::event_args::DeviceNotificationEventArgs;
+use crate::manager::{Device, DeviceEnumError, DeviceManager};
+use crate::notifications::{NotificationError, Notifications};
+use crate::sample_format::SampleFormat;
+
+type DataFn = Box<dyn FnMut(CapturePacket) + Send>;
+type ErrorFn = Box<dyn FnMut(AudioClientError) + Send>;
+
+#[derive(Error, Debug)]
+pub enum FollowingLoopbackError {
+    #[error("Failed setting up device notifications: {0}")]
+    NotificationError(#[source] NotificationError),
+    #[error("Failed getting the default playback device: {0}")]
+    DeviceError(#[source] DeviceEnumError),
+    #[error("Failed starting capture: {0}")]
+    StreamError(#[source] AudioClientError),
+}
+
+struct Current {
+    client: AudioClient,
+    stream: Option<AudioStream>,
+    device_id: Option<String>,
+    format: Option<SampleFormat>,
+}
+
+struct Shared {
+    current: Mutex<Current>,
+    data_callback: Arc<Mutex<DataFn>>,
+    error_callback: Arc<Mutex<ErrorFn>>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Current> {
+        self.current.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Moves the capture to the device with the given id, `None` stops it until a playback device is back
+    fn default_changed(&self, device_id: Option<String>) {
+        let old = {
+            let mut current = self.lock();
+            if current.device_id == device_id {
+                return;
+            }
+            current.device_id = None;
+            current.format = None;
+            current.stream.take()
+        };
+        // The old stream is joined first and without the lock, the callbacks are never called by two streams
+        drop(old);
+        let Some(device_id) = device_id else {
+            debug!("No default playback device left, waiting for one");
+            return;
+        };
+        let dev = match DeviceManager::get_device(&device_id) {
+            Ok(dev) => dev,
+            Err(err) => {
+                warn!("Failed getting new default playback device {}: {}", device_id, err);
+                return;
+            }
+        };
+        let started = self.start(&mut self.lock(), &dev);
+        // Called after the lock is released, the callback may ask for the device or format
+        if let Err(err) = started {
+            (self.error_callback.lock().unwrap_or_else(|e| e.into_inner()))(err);
+        }
+    }
+
+    fn start(&self, current: &mut Current, dev: &Device) -> Result<(), AudioClientError> {
+        let (data_callback, error_callback) = (self.data_callback.clone(), self.error_callback.clone());
+        let config = current.client.clone().start_recording_loopback_device(
+            Some(dev),
+            move |packet| (data_callback.lock().unwrap_or_else(|e| e.into_inner()))(packet),
+            move |err| {
+                // A removed default device is followed by a default change, which starts the stream again
+                if err.hresult() == Some(AUDCLNT_E_DEVICE_INVALIDATED) {
+                    debug!("Followed loopback device was removed");
+                    return;
+                }
+                (error_callback.lock().unwrap_or_else(|e| e.into_inner()))(err)
+            },
+        )?;
+        current.format = Some(config.format().clone());
+        current.stream = Some(config.start()?);
+        current.device_id = dev.get_id().ok();
+        Ok(())
+    }
+}
+
+/// Loopback capture of whatever device is the default playback device, see
+/// [`AudioClient::start_recording_default_loopback_following`]
+///
+/// The packets are in the mix format of the current device, which can change with the device. Set
+/// [`CaptureOptions::deliver_as`](crate::capture_options::CaptureOptions::deliver_as) on the client to get one format
+/// throughout. Dropping it stops the capture.
+pub struct FollowingLoopback {
+    // Unregistered before the stream is stopped, no switch can start a stream after that
+    _notifications: ComSend<Notifications>,
+    shared: Arc<Shared>,
+}
+
+impl FollowingLoopback {
+    pub(crate) fn start<D, E>(client: AudioClient, data_callback: D, error_callback: E) -> Result<Self, FollowingLoopbackError>
+    where
+        D: FnMut(CapturePacket) + Send + 'static,
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            current: Mutex::new(Current {
+                client,
+                stream: None,
+                device_id: None,
+                format: None,
+            }),
+            data_callback: Arc::new(Mutex::new(Box::new(data_callback))),
+            error_callback: Arc::new(Mutex::new(Box::new(error_callback))),
+        });
+
+        // Restarting a stream activates a device, which can't be done on the COM thread delivering the event
+        let mut notifications =
+            Notifications::sta_compatible(Dispatcher::dedicated_thread()).map_err(FollowingLoopbackError::NotificationError)?;
+        let weak: Weak<Shared> = Arc::downgrade(&shared);
+        notifications
+            .register_device_notification(move |event| {
+                let DeviceNotificationEventArgs::DefaultDeviceChanged(args) = event else {
+                    return;
+                };
+                if args.get_flow() != DataFlow::Render || args.get_role() != DeviceRole::Console {
+                    return;
+                }
+                if let Some(shared) = weak.upgrade() {
+                    shared.default_changed(args.get_default_device().ok());
+                }
+            })
+            .map_err(FollowingLoopbackError::NotificationError)?;
+
+        // Registered first, so a switch right now is either seen here or delivered as an event
+        let dev = DeviceManager::get_default_playback_device().map_err(FollowingLoopbackError::DeviceError)?;
+        {
+            let mut current = shared.lock();
+            if current.stream.is_none() {
+                shared.start(&mut current, &dev).map_err(FollowingLoopbackError::StreamError)?;
+            }
+        }
+        Ok(Self {
+            _notifications: ComSend(notifications),
+            shared,
+        })
+    }
+
+    /// Id of the device captured right now, `None` while there is no playback device
+    pub fn get_device_id(&self) -> Option<String> {
+        self.shared.lock().device_id.clone()
+    }
+
+    /// Format of the packets captured right now
+    pub fn get_format(&self) -> Option<SampleFormat> {
+        self.shared.lock().format.clone()
+    }
+
+    // See drop implementation of the stream for cleanup
+    pub fn stop(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follow_default_device() {
+        let following = AudioClient::new()
+            .start_recording_default_loopback_following(|_| {}, |err| panic!("{}", err))
+            .unwrap();
+        let default_id = DeviceManager::get_default_playback_device().unwrap().get_id().unwrap();
+        assert_eq!(following.get_device_id(), Some(default_id.clone()));
+
+        // Losing the default device stops the capture, a new default starts it again
+        following.shared.default_changed(None);
+        assert_eq!(following.get_device_id(), None);
+        assert_eq!(following.get_format(), None);
+        following.shared.default_changed(Some(default_id.clone()));
+        assert_eq!(following.get_device_id(), Some(default_id));
+        assert!(following.get_format().is_some());
+    }
+}
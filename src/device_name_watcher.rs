@@ -0,0 +1,124 @@
+//! Keeping device names up to date in a UI, see [`DeviceNameWatcher`].
+//!
+//! Renaming an endpoint in the sound settings, or a driver update changing its description, only shows up as a
+//! property change notification with the key that changed. The watcher reacts to the keys the friendly name is made
+//! of, reads the name again and reports the old and the new one.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use log::warn;
+use thiserror::Error;
+use windows::Win32::Devices::Properties::{DEVPKEY_Device_DeviceDesc, DEVPKEY_Device_FriendlyName, DEVPKEY_DeviceInterface_FriendlyName};
+use windows::Win32::Foundation::PROPERTYKEY;
+
+use crate::com::ComSend;
+use crate::device_query::{DataFlow, DeviceQuery};
+use crate::dispatcher::Dispatcher;
+use crate::event_args::DeviceNotificationEventArgs;
+use crate::manager::{AudioError, DeviceManager};
+use crate::notifications::{NotificationError, Notifications};
+
+#[derive(Error, Debug)]
+pub enum DeviceNameWatcherError {
+    #[error("Failed setting up device notifications: {0}")]
+    NotificationError(#[source] NotificationError),
+    #[error("Failed reading the device names: {0}")]
+    DeviceError(#[source] AudioError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceNameEvent {
+    Renamed { device_id: String, old: String, new: String },
+}
+
+/// Reports whenever the friendly name of a device changes
+///
+/// Names of the active devices are read up front. Devices that aren't active yet are learned on their first
+/// notification, so their first rename after that is reported. The names are read and the callback runs on a thread
+/// owned by the watcher, never on the COM thread that raised the notification.
+pub struct DeviceNameWatcher {
+    names: Arc<Mutex<HashMap<String, String>>>,
+    _notifications: ComSend<Notifications>,
+}
+
+impl DeviceNameWatcher {
+    pub fn new<CB>(callback: CB) -> Result<Self, DeviceNameWatcherError>
+    where
+        CB: Fn(DeviceNameEvent) + Send + 'static,
+    {
+        let devices = DeviceManager::find_devices(DeviceQuery::new().flow(DataFlow::All)).map_err(DeviceNameWatcherError::DeviceError)?;
+        let mut names = HashMap::new();
+        for device in devices {
+            let id = device.get_id().map_err(DeviceNameWatcherError::DeviceError)?;
+            names.insert(id, device.get_friendly_name().map_err(DeviceNameWatcherError::DeviceError)?);
+        }
+        let names = Arc::new(Mutex::new(names));
+
+        let mut notifications =
+            Notifications::sta_compatible(Dispatcher::dedicated_thread()).map_err(DeviceNameWatcherError::NotificationError)?;
+        let watched_names = names.clone();
+        notifications
+            .register_device_notification(move |event| {
+                let device_id = match &event {
+                    DeviceNotificationEventArgs::DevicePropertyValueChanged(args) if is_name_key(&args.key) => args.get_device_id(),
+                    DeviceNotificationEventArgs::DeviceAdded(args) => args.get_device_id(),
+                    DeviceNotificationEventArgs::DeviceStateChanged(args) => args.get_device_id(),
+                    _ => return,
+                };
+                let Ok(device_id) = device_id else {
+                    return;
+                };
+                let new = match DeviceManager::get_device(&device_id).and_then(|device| device.get_friendly_name()) {
+                    Ok(name) => name,
+                    Err(err) => {
+                        warn!("Failed reading the name of {}: {}", device_id, err);
+                        return;
+                    }
+                };
+                let old = lock_names(&watched_names).insert(device_id.clone(), new.clone());
+                if let Some(old) = old.filter(|old| *old != new) {
+                    callback(DeviceNameEvent::Renamed { device_id, old, new });
+                }
+            })
+            .map_err(DeviceNameWatcherError::NotificationError)?;
+
+        Ok(Self {
+            names,
+            _notifications: ComSend(notifications),
+        })
+    }
+
+    /// Last known friendly name of the device
+    pub fn get_name(&self, device_id: &str) -> Option<String> {
+        lock_names(&self.names).get(device_id).cloned()
+    }
+}
+
+fn lock_names(names: &Mutex<HashMap<String, String>>) -> MutexGuard<'_, HashMap<String, String>> {
+    names.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Keys the endpoint's friendly name is composed of
+fn is_name_key(key: &PROPERTYKEY) -> bool {
+    [
+        DEVPKEY_Device_FriendlyName,
+        DEVPKEY_Device_DeviceDesc,
+        DEVPKEY_DeviceInterface_FriendlyName,
+    ]
+    .iter()
+    .any(|name_key| name_key.fmtid == key.fmtid && name_key.pid == key.pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn knows_default_device_name() {
+        let device = DeviceManager::get_default_playback_device().unwrap();
+        let watcher = DeviceNameWatcher::new(|_| {}).unwrap();
+        let name = watcher.get_name(&device.get_id().unwrap());
+        assert_eq!(name, Some(device.get_friendly_name().unwrap()));
+    }
+}
@@ -0,0 +1,178 @@
+use windows::Win32::Media::Audio::{
+    DEVICE_STATE, DigitalAudioDisplayDevice, EDataFlow, ERole, EndpointFormFactor, Handset, Headphones, Headset, LineLevel, Microphone,
+    RemoteNetworkDevice, SPDIF, Speakers, UnknownDigitalPassthrough, eAll, eCapture, eCommunications, eConsole, eMultimedia, eRender,
+};
+
+use crate::device_state::{DEVICE_STATE_ACTIVE, DeviceState};
+
+/// Direction of the audio data of an endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DataFlow {
+    Render,
+    Capture,
+    /// Both render and capture endpoints
+    All,
+}
+
+impl From<DataFlow> for EDataFlow {
+    fn from(flow: DataFlow) -> Self {
+        match flow {
+            DataFlow::Render => eRender,
+            DataFlow::Capture => eCapture,
+            DataFlow::All => eAll,
+        }
+    }
+}
+
+impl From<EDataFlow> for DataFlow {
+    #[allow(non_upper_case_globals)]
+    fn from(flow: EDataFlow) -> Self {
+        match flow {
+            eRender => DataFlow::Render,
+            eCapture => DataFlow::Capture,
+            eAll => DataFlow::All,
+            _ => panic!("Invalid data flow"),
+        }
+    }
+}
+
+/// Role the system assigns to a default endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceRole {
+    Console,
+    Multimedia,
+    Communications,
+}
+
+impl From<DeviceRole> for ERole {
+    fn from(role: DeviceRole) -> Self {
+        match role {
+            DeviceRole::Console => eConsole,
+            DeviceRole::Multimedia => eMultimedia,
+            DeviceRole::Communications => eCommunications,
+        }
+    }
+}
+
+impl From<ERole> for DeviceRole {
+    #[allow(non_upper_case_globals)]
+    fn from(role: ERole) -> Self {
+        match role {
+            eConsole => DeviceRole::Console,
+            eMultimedia => DeviceRole::Multimedia,
+            eCommunications => DeviceRole::Communications,
+            _ => panic!("Invalid device role"),
+        }
+    }
+}
+
+/// Physical form factor of an endpoint (`PKEY_AudioEndpoint_FormFactor`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FormFactor {
+    RemoteNetworkDevice,
+    Speakers,
+    LineLevel,
+    Headphones,
+    Microphone,
+    Headset,
+    Handset,
+    UnknownDigitalPassthrough,
+    Spdif,
+    DigitalAudioDisplayDevice,
+    UnknownFormFactor,
+}
+
+impl From<EndpointFormFactor> for FormFactor {
+    #[allow(non_upper_case_globals)]
+    fn from(form_factor: EndpointFormFactor) -> Self {
+        match form_factor {
+            RemoteNetworkDevice => FormFactor::RemoteNetworkDevice,
+            Speakers => FormFactor::Speakers,
+            LineLevel => FormFactor::LineLevel,
+            Headphones => FormFactor::Headphones,
+            Microphone => FormFactor::Microphone,
+            Headset => FormFactor::Headset,
+            Handset => FormFactor::Handset,
+            UnknownDigitalPassthrough => FormFactor::UnknownDigitalPassthrough,
+            SPDIF => FormFactor::Spdif,
+            DigitalAudioDisplayDevice => FormFactor::DigitalAudioDisplayDevice,
+            _ => FormFactor::UnknownFormFactor,
+        }
+    }
+}
+
+/// Filter used by [`DeviceManager::find_devices`](crate::manager::DeviceManager::find_devices)
+///
+/// Every filter that is set has to match. Filters that can be added multiple times (state, form factor, default role)
+/// match if any of the given values match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceQuery {
+    pub(crate) flow: DataFlow,
+    pub(crate) state_mask: Option<DEVICE_STATE>,
+    pub(crate) name_contains: Option<String>,
+    pub(crate) form_factors: Vec<FormFactor>,
+    pub(crate) default_roles: Vec<DeviceRole>,
+}
+
+impl Default for DeviceQuery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceQuery {
+    /// Matches every active render and capture device
+    pub fn new() -> Self {
+        Self {
+            flow: DataFlow::All,
+            state_mask: None,
+            name_contains: None,
+            form_factors: Vec::new(),
+            default_roles: Vec::new(),
+        }
+    }
+
+    pub fn flow(mut self, flow: DataFlow) -> Self {
+        self.flow = flow;
+        self
+    }
+
+    /// Only match devices in the given state. Defaults to [`DeviceState::Active`] if never called.
+    pub fn state(mut self, state: DeviceState) -> Self {
+        let mask = self.state_mask.map(|m| m.0).unwrap_or(0) | DEVICE_STATE::from(state).0;
+        self.state_mask = Some(DEVICE_STATE(mask));
+        self
+    }
+
+    /// Case-insensitive substring match on the friendly name
+    pub fn name_contains(mut self, name: impl Into<String>) -> Self {
+        self.name_contains = Some(name.into());
+        self
+    }
+
+    pub fn form_factor(mut self, form_factor: FormFactor) -> Self {
+        self.form_factors.push(form_factor);
+        self
+    }
+
+    /// Only match devices that are currently the default endpoint for `role`
+    pub fn default_for(mut self, role: DeviceRole) -> Self {
+        self.default_roles.push(role);
+        self
+    }
+
+    pub(crate) fn get_state_mask(&self) -> DEVICE_STATE {
+        self.state_mask.unwrap_or(DEVICE_STATE_ACTIVE)
+    }
+
+    pub(crate) fn matches_name(&self, friendly_name: &str) -> bool {
+        match &self.name_contains {
+            Some(name) => friendly_name.to_lowercase().contains(&name.to_lowercase()),
+            None => true,
+        }
+    }
+
+    pub(crate) fn matches_form_factor(&self, form_factor: FormFactor) -> bool {
+        self.form_factors.is_empty() || self.form_factors.contains(&form_factor)
+    }
+}
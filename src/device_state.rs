@@ -0,0 +1,44 @@
+//! Endpoint device states, shared by device enumeration and device notifications.
+
+use windows::Win32::Media::Audio::DEVICE_STATE;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceState {
+    Active,
+    Disabled,
+    NotPresent,
+    Unplugged,
+    /// Mask: Includes audio endpoint devices in all states active, disabled, not present, and unplugged.
+    All,
+}
+
+impl From<DEVICE_STATE> for DeviceState {
+    fn from(state: DEVICE_STATE) -> Self {
+        match state.0 {
+            1u32 => DeviceState::Active,
+            2u32 => DeviceState::Disabled,
+            4u32 => DeviceState::NotPresent,
+            8u32 => DeviceState::Unplugged,
+            15u32 => DeviceState::All,
+            _ => panic!("Invalid device state"),
+        }
+    }
+}
+
+impl From<DeviceState> for DEVICE_STATE {
+    fn from(state: DeviceState) -> Self {
+        match state {
+            DeviceState::Active => DEVICE_STATE_ACTIVE,
+            DeviceState::Disabled => DEVICE_STATE_DISABLED,
+            DeviceState::NotPresent => DEVICE_STATE_NOTPRESENT,
+            DeviceState::Unplugged => DEVICE_STATE_UNPLUGGED,
+            DeviceState::All => DEVICE_STATE(DEVICE_STATEMASK_ALL),
+        }
+    }
+}
+
+pub const DEVICE_STATEMASK_ALL: u32 = 15u32;
+pub const DEVICE_STATE_ACTIVE: DEVICE_STATE = DEVICE_STATE(1u32);
+pub const DEVICE_STATE_DISABLED: DEVICE_STATE = DEVICE_STATE(2u32);
+pub const DEVICE_STATE_NOTPRESENT: DEVICE_STATE = DEVICE_STATE(4u32);
+pub const DEVICE_STATE_UNPLUGGED: DEVICE_STATE = DEVICE_STATE(8u32);
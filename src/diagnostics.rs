@@ -0,0 +1,351 @@
+//! Opt-in tracking of the COM objects the crate creates, see [`enable`].
+//!
+//! While enabled, every device, session, notification client and stream client records where it was created, and
+//! [`live_objects`] lists the ones that are still alive. Meant for plugin hosts and other long-lived processes, where a
+//! notification client that outlives the unload of the module calls into freed code. Tracking captures a backtrace
+//! per object, so it is off by default and objects created before enabling it aren't tracked.
+//!
+//! [`format_matrix`] is the other half of a support report: which formats every active device accepts.
+
+use std::backtrace::Backtrace;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+use std::time::SystemTime;
+
+use log::warn;
+
+use crate::device_query::DataFlow;
+use crate::manager::{AudioError, DeviceEnumError, DeviceManager, FormatSupport};
+use crate::sample_format::{FormatTag, SampleFormat};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static LIVE_OBJECTS: Mutex<BTreeMap<u64, LiveObject>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectKind {
+    Device,
+    Session,
+    /// Client registered with `IMMDeviceEnumerator::RegisterEndpointNotificationCallback`
+    DeviceNotificationClient,
+    /// Client registered with `IAudioSessionManager2::RegisterSessionNotification`
+    SessionNotificationClient,
+    /// Client registered with `IAudioSessionControl::RegisterAudioSessionNotification`
+    SessionEventClient,
+    /// Client registered with `IAudioEndpointVolume::RegisterControlChangeNotify`
+    EndpointVolumeClient,
+    /// The `IAudioClient` of a capture or playback stream
+    AudioClient,
+}
+
+impl ObjectKind {
+    /// Whether Windows holds a reference to the object and calls into it until it is unregistered
+    pub fn is_registration(&self) -> bool {
+        matches!(
+            self,
+            ObjectKind::DeviceNotificationClient
+                | ObjectKind::SessionNotificationClient
+                | ObjectKind::SessionEventClient
+                | ObjectKind::EndpointVolumeClient
+        )
+    }
+}
+
+/// An object that was created while tracking was enabled and wasn't released yet
+#[derive(Debug, Clone)]
+pub struct LiveObject {
+    kind: ObjectKind,
+    created: SystemTime,
+    thread: ThreadId,
+    backtrace: Arc<Backtrace>,
+}
+
+impl LiveObject {
+    pub fn get_kind(&self) -> ObjectKind {
+        self.kind
+    }
+
+    pub fn get_created(&self) -> SystemTime {
+        self.created
+    }
+
+    /// Thread the object was created on
+    pub fn get_thread(&self) -> ThreadId {
+        self.thread
+    }
+
+    pub fn get_backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+}
+
+/// Tracks objects until the returned guard is dropped, which warns about every object still alive at that point
+///
+/// Keep the guard for the lifetime of the process or module, e.g. in `main` or in the plugin instance, so the leak
+/// report runs at exit or unload.
+pub fn enable() -> DiagnosticsGuard {
+    ENABLED.store(true, Ordering::Relaxed);
+    DiagnosticsGuard(())
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Tracked objects that are still alive, oldest first
+pub fn live_objects() -> Vec<LiveObject> {
+    lock_live_objects().values().cloned().collect()
+}
+
+/// Logs a warning with the creation backtrace of every tracked object that is still alive, and returns them
+pub fn report_leaks() -> Vec<LiveObject> {
+    let live = live_objects();
+    for object in &live {
+        let what = if object.kind.is_registration() { "registration" } else { "object" };
+        warn!(
+            "Leaked {} {:?}, created on {:?} at:\n{}",
+            what, object.kind, object.thread, object.backtrace
+        );
+    }
+    live
+}
+
+/// Stops tracking when dropped and reports the objects still alive, see [`enable`]
+pub struct DiagnosticsGuard(());
+
+impl Drop for DiagnosticsGuard {
+    fn drop(&mut self) {
+        ENABLED.store(false, Ordering::Relaxed);
+        report_leaks();
+    }
+}
+
+fn lock_live_objects() -> MutexGuard<'static, BTreeMap<u64, LiveObject>> {
+    LIVE_OBJECTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Registry entry of one object, held as a field of the tracked object; a no-op if tracking was disabled on creation
+#[derive(Debug)]
+pub(crate) struct Tracked {
+    kind: ObjectKind,
+    id: Option<u64>,
+}
+
+impl Tracked {
+    pub(crate) fn new(kind: ObjectKind) -> Self {
+        if !is_enabled() {
+            return Self { kind, id: None };
+        }
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let object = LiveObject {
+            kind,
+            created: SystemTime::now(),
+            thread: thread::current().id(),
+            backtrace: Arc::new(Backtrace::force_capture()),
+        };
+        lock_live_objects().insert(id, object);
+        Self { kind, id: Some(id) }
+    }
+}
+
+/// A clone holds its own COM reference, so it is tracked as its own object
+impl Clone for Tracked {
+    fn clone(&self) -> Self {
+        Self::new(self.kind)
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            lock_live_objects().remove(&id);
+        }
+    }
+}
+
+/// Formats probed by [`format_matrix`]: 16 and 24 bit PCM and 32 bit float, at the common rates, in mono, stereo and 5.1
+///
+/// Formats a plain `WAVEFORMATEX` can't describe, everything 5.1 or wider than 16 bits, are probed as
+/// `WAVEFORMATEXTENSIBLE` with the default layout for their channel count, the way drivers expect them.
+pub fn standard_formats() -> Vec<SampleFormat> {
+    let sample_types = [
+        (FormatTag::WaveFormatPcm, 16),
+        (FormatTag::WaveFormatPcm, 24),
+        (FormatTag::WaveFormatIeeeFloat, 32),
+    ];
+    let mut formats = Vec::new();
+    for (format_tag, bits_per_sample) in sample_types {
+        for sample_rate in [44100, 48000, 96000, 192000] {
+            for channels in [1, 2, 6] {
+                formats.push(SampleFormat::new(format_tag.clone(), channels, sample_rate, bits_per_sample));
+            }
+        }
+    }
+    formats
+}
+
+/// Support of every active device for a grid of formats, see [`format_matrix`]
+///
+/// `Display` renders it as CSV with one line per device and format, ready to attach to a support ticket.
+#[derive(Debug)]
+pub struct FormatMatrix {
+    formats: Vec<SampleFormat>,
+    devices: Vec<DeviceFormats>,
+}
+
+impl FormatMatrix {
+    /// The probed formats, in the order of [`DeviceFormats::get_support`]
+    pub fn get_formats(&self) -> &[SampleFormat] {
+        &self.formats
+    }
+
+    pub fn get_devices(&self) -> &[DeviceFormats] {
+        &self.devices
+    }
+}
+
+impl Display for FormatMatrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "device_id,device_name,flow,format,support,closest_match")?;
+        for device in &self.devices {
+            for (format, support) in self.formats.iter().zip(&device.support) {
+                let (support, closest) = match support {
+                    Ok(FormatSupport::Supported) => ("supported".to_string(), String::new()),
+                    Ok(FormatSupport::Unsupported) => ("unsupported".to_string(), String::new()),
+                    Ok(FormatSupport::ClosestMatch(closest)) => ("closest".to_string(), closest.to_string()),
+                    Err(err) => (format!("error {:?}", err.hresult()), String::new()),
+                };
+                writeln!(
+                    f,
+                    "{},{},{:?},{},{},{}",
+                    csv_field(&device.id),
+                    csv_field(&device.name),
+                    device.flow,
+                    csv_field(&format.to_string()),
+                    support,
+                    csv_field(&closest)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One row of a [`FormatMatrix`]
+#[derive(Debug)]
+pub struct DeviceFormats {
+    id: String,
+    name: String,
+    flow: DataFlow,
+    mix_format: Option<SampleFormat>,
+    support: Vec<Result<FormatSupport, AudioError>>,
+}
+
+impl DeviceFormats {
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    /// Friendly name, empty if it couldn't be read
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_flow(&self) -> DataFlow {
+        self.flow
+    }
+
+    /// `None` if the device couldn't be activated
+    pub fn get_mix_format(&self) -> Option<&SampleFormat> {
+        self.mix_format.as_ref()
+    }
+
+    /// Support per format of [`FormatMatrix::get_formats`], an error if probing the format failed
+    pub fn get_support(&self) -> &[Result<FormatSupport, AudioError>] {
+        &self.support
+    }
+}
+
+/// Probes every active playback and capture device against [`standard_formats`] in shared mode
+/// Devices that fail to enumerate are left out, see
+/// [`DeviceManager::enumerate_report`](crate::manager::DeviceManager::enumerate_report) for why.
+pub fn format_matrix() -> Result<FormatMatrix, DeviceEnumError> {
+    let formats = standard_formats();
+    let mut devices = Vec::new();
+    for flow in [DataFlow::Render, DataFlow::Capture] {
+        for device in DeviceManager::iter_devices(flow)?.flatten() {
+            let Ok(id) = device.get_id() else {
+                continue;
+            };
+            devices.push(DeviceFormats {
+                id,
+                name: device.get_friendly_name().unwrap_or_default(),
+                flow,
+                mix_format: device.get_mix_format().ok(),
+                support: formats.iter().map(|format| device.format_supported(format)).collect(),
+            });
+        }
+    }
+    Ok(FormatMatrix { formats, devices })
+}
+
+/// Quotes a CSV field if it contains a separator or a quote
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manager::DeviceManager;
+
+    fn own_devices() -> usize {
+        let current = thread::current().id();
+        live_objects()
+            .iter()
+            .filter(|object| object.get_kind() == ObjectKind::Device && object.get_thread() == current)
+            .count()
+    }
+
+    #[test]
+    fn tracks_devices() {
+        let _guard = enable();
+        let device = DeviceManager::get_default_playback_device().unwrap();
+        assert_eq!(own_devices(), 1);
+        let copy = device.clone();
+        assert_eq!(own_devices(), 2);
+        drop((device, copy));
+        assert_eq!(own_devices(), 0);
+    }
+
+    #[test]
+    fn format_matrix_covers_devices() {
+        let matrix = format_matrix().unwrap();
+        let playback_device = DeviceManager::get_default_playback_device().unwrap();
+        let playback_id = playback_device.get_id().unwrap();
+        let playback = matrix.get_devices().iter().find(|device| device.get_id() == playback_id).unwrap();
+        assert_eq!(playback.get_support().len(), standard_formats().len());
+
+        // The mix format is extensible, probing it has to keep the channel mask
+        let mix_format = playback.get_mix_format().unwrap();
+        assert!(matches!(playback_device.format_supported(mix_format), Ok(FormatSupport::Supported)));
+        // Shared mode always offers at least a closest match for common formats
+        assert!(
+            playback
+                .get_support()
+                .iter()
+                .any(|support| matches!(support, Ok(FormatSupport::Supported | FormatSupport::ClosestMatch(_))))
+        );
+
+        let csv = matrix.to_string();
+        assert_eq!(csv.lines().count(), 1 + matrix.get_devices().len() * matrix.get_formats().len());
+        assert_eq!(csv_field("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+}
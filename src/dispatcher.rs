@@ -0,0 +1,106 @@
+//! Controls on which thread notification callbacks run.
+//!
+//! COM invokes notification clients on its own worker threads, blocking inside a callback stalls the audio service
+//! and calling back into WASAPI from it can deadlock. A [`Dispatcher`] moves the user callback off the COM thread.
+
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+/// A callback invocation handed to the dispatcher
+pub type DispatchJob = Box<dyn FnOnce() + Send + 'static>;
+
+type Executor = Arc<dyn Fn(DispatchJob) + Send + Sync + 'static>;
+
+#[derive(Clone, Default)]
+pub struct Dispatcher {
+    executor: Option<Executor>,
+}
+
+impl Dispatcher {
+    /// Run callbacks directly on the COM thread that raised the event
+    pub fn inline() -> Self {
+        Self { executor: None }
+    }
+
+    /// Hand every callback to `executor`, e.g. `Dispatcher::new(move |job| pool.spawn(job))`
+    pub fn new<F>(executor: F) -> Self
+    where
+        F: Fn(DispatchJob) + Send + Sync + 'static,
+    {
+        Self {
+            executor: Some(Arc::new(executor)),
+        }
+    }
+
+    /// Send every callback through a channel, the receiving side is responsible for running the jobs
+    /// Jobs are dropped if the receiver is gone
+    pub fn channel(sender: mpsc::Sender<DispatchJob>) -> Self {
+        Self::new(move |job| {
+            let _ = sender.send(job);
+        })
+    }
+
+    /// Run callbacks in order on a dedicated thread owned by the dispatcher
+    /// The thread exits once every clone of the dispatcher (and every registration using it) is dropped
+    pub fn dedicated_thread() -> Self {
+        let (send, recv) = mpsc::channel::<DispatchJob>();
+        thread::Builder::new()
+            .name("notification dispatch".to_string())
+            .spawn(move || {
+                for job in recv {
+                    job();
+                }
+            })
+            .expect("Failed spawning notification dispatch thread");
+        Self::channel(send)
+    }
+
+    pub fn is_inline(&self) -> bool {
+        self.executor.is_none()
+    }
+
+    pub(crate) fn dispatch(&self, job: impl FnOnce() + Send + 'static) {
+        match &self.executor {
+            Some(executor) => executor(Box::new(job)),
+            None => job(),
+        }
+    }
+
+    /// Wraps a user callback so every invocation goes through the dispatcher
+    /// The callback is behind a mutex since it's neither `Sync` nor `Fn`, so it never runs concurrently with itself
+    pub(crate) fn wrap<A, CB>(&self, callback_fn: CB) -> Box<dyn Fn(A) + Send + 'static>
+    where
+        A: Send + 'static,
+        CB: FnMut(A) + Send + 'static,
+    {
+        let callback_fn = Arc::new(Mutex::new(callback_fn));
+        if self.is_inline() {
+            return Box::new(move |args| (callback_fn.lock().unwrap_or_else(|e| e.into_inner()))(args));
+        }
+        let dispatcher = self.clone();
+        Box::new(move |args| {
+            let callback_fn = callback_fn.clone();
+            dispatcher.dispatch(move || {
+                let mut callback_fn = callback_fn.lock().unwrap_or_else(|e| e.into_inner());
+                (callback_fn)(args)
+            });
+        })
+    }
+
+    /// Same as [`Dispatcher::wrap`] for callbacks that are already `Sync`
+    pub(crate) fn wrap_sync<A, CB>(&self, callback_fn: CB) -> Box<dyn Fn(A) + Send + Sync + 'static>
+    where
+        A: Send + 'static,
+        CB: Fn(A) + Send + Sync + 'static,
+    {
+        if self.is_inline() {
+            return Box::new(callback_fn);
+        }
+        let dispatcher = self.clone();
+        let callback_fn = Arc::new(callback_fn);
+        Box::new(move |args| {
+            let callback_fn = callback_fn.clone();
+            dispatcher.dispatch(move || (callback_fn)(args));
+        })
+    }
+}
@@ -0,0 +1,276 @@
+//! Automatic ducking of other applications, see [`Ducker`].
+//!
+//! A monitor thread checks the trigger every [`DUCK_POLL_INTERVAL`]. While it fires, the sessions of the targeted
+//! applications are faded down with [`Session::ramp_volume`], including sessions that show up while ducking. Once it
+//! stops firing, every ducked session is faded back to the volume it had before. Volume changes the user makes while
+//! ducked are overwritten by the restore. Stopping the [`Ducker`] waits for the fades back, a session whose fade failed
+//! is set to its volume directly.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{debug, warn};
+use thiserror::Error;
+
+use crate::com::com_initialized;
+use crate::manager::{AudioError, AudioSessionState, Session, SessionManager, SessionStateFilter};
+use crate::session_capture::SessionFilter;
+use crate::volume_ramp::{RampOutcome, VolumeRamp};
+
+/// Interval the trigger is checked at
+pub const DUCK_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Error, Debug)]
+pub enum DuckerError {
+    #[error("Failed enumerating sessions: {0}")]
+    SessionEnumError(#[source] AudioError),
+    #[error("Failed starting the ducking thread")]
+    FailedStartingThread,
+}
+
+/// When a [`Ducker`] lowers the other applications
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DuckTrigger {
+    /// Any application records from an input device, e.g. a call or voice chat started
+    CaptureActive,
+    /// A matching application plays audio, its own sessions are never ducked
+    AppPlaying(SessionFilter),
+}
+
+/// Which applications a [`Ducker`] lowers, this process is never ducked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DuckTargets {
+    AllOthers,
+    Only(Vec<SessionFilter>),
+    Except(Vec<SessionFilter>),
+}
+
+impl DuckTargets {
+    fn matches(&self, session: &Session) -> bool {
+        match self {
+            DuckTargets::AllOthers => true,
+            DuckTargets::Only(filters) => filters.iter().any(|filter| filter.matches(session)),
+            DuckTargets::Except(filters) => !filters.iter().any(|filter| filter.matches(session)),
+        }
+    }
+}
+
+/// Lowers the volume of other applications while a [`DuckTrigger`] fires, dropping it restores them
+pub struct Ducker {
+    ducking: Arc<AtomicBool>,
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Ducker {
+    /// Ducks `targets` by `amount` (`0.0` leaves them alone, `1.0` silences them), fading down over `attack` and back
+    /// up over `release`
+    pub fn new(trigger: DuckTrigger, targets: DuckTargets, amount: f32, attack: Duration, release: Duration) -> Result<Self, DuckerError> {
+        let mut state = DuckState {
+            trigger,
+            targets,
+            amount: amount.clamp(0.0, 1.0),
+            attack,
+            release,
+            ducking: Arc::new(AtomicBool::new(false)),
+            originals: HashMap::new(),
+            restoring: HashMap::new(),
+        };
+        // The first check runs right away, so enumeration errors surface here
+        state.update()?;
+
+        let ducking = state.ducking.clone();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("ducker".to_string())
+            .spawn(move || {
+                com_initialized();
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(DUCK_POLL_INTERVAL) {
+                    if let Err(err) = state.update() {
+                        warn!("Failed updating ducking: {}", err);
+                    }
+                }
+                state.restore();
+                state.finish_restore();
+            })
+            .map_err(|_| DuckerError::FailedStartingThread)?;
+        Ok(Self {
+            ducking,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Whether the trigger fired at the last check
+    pub fn is_ducking(&self) -> bool {
+        self.ducking.load(Ordering::Relaxed)
+    }
+
+    // See drop implementation for cleanup
+    pub fn stop(self) {}
+}
+
+impl Drop for Ducker {
+    fn drop(&mut self) {
+        // Closing the channel stops the thread, which restores the ducked sessions before exiting
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct DuckState {
+    trigger: DuckTrigger,
+    targets: DuckTargets,
+    amount: f32,
+    attack: Duration,
+    release: Duration,
+    ducking: Arc<AtomicBool>,
+    /// Volume of every ducked session before it was ducked, by session instance identifier
+    originals: HashMap<String, f32>,
+    /// Latest fade back of every restored session with the volume it fades to, by session instance identifier
+    restoring: HashMap<String, (f32, VolumeRamp)>,
+}
+
+impl DuckState {
+    fn update(&mut self) -> Result<(), DuckerError> {
+        let sessions =
+            SessionManager::get_sessions_filtered(SessionStateFilter::ActiveAndInactive).map_err(DuckerError::SessionEnumError)?;
+        let triggered = self.triggered(&sessions)?;
+        if triggered != self.ducking.swap(triggered, Ordering::Relaxed) {
+            debug!("Ducking {}", if triggered { "started" } else { "stopped" });
+        }
+        if !triggered {
+            self.restore();
+            return Ok(());
+        }
+
+        let own_pid = std::process::id();
+        for session in sessions {
+            let is_trigger = matches!(&self.trigger, DuckTrigger::AppPlaying(filter) if filter.matches(&session));
+            if *session.get_pid() == own_pid
+                || is_trigger
+                || !self.targets.matches(&session)
+                || self.originals.contains_key(session.get_name())
+            {
+                continue;
+            }
+            let duck = session
+                .get_volume()
+                .and_then(|volume| session.ramp_volume(volume * (1.0 - self.amount), self.attack).map(|_| volume));
+            match duck {
+                Ok(volume) => {
+                    self.originals.insert(session.get_name().clone(), volume);
+                }
+                Err(err) => warn!("Failed ducking session {}: {}", session.get_name(), err),
+            }
+        }
+        Ok(())
+    }
+
+    fn triggered(&self, sessions: &[Session]) -> Result<bool, DuckerError> {
+        let is_active = |session: &Session| {
+            session
+                .get_state()
+                .is_ok_and(|state| state == AudioSessionState::AudioSessionStateActive)
+        };
+        Ok(match &self.trigger {
+            DuckTrigger::CaptureActive => SessionManager::get_sessions_deduplicated()
+                .map_err(DuckerError::SessionEnumError)?
+                .iter()
+                .flat_map(|app| app.get_instances())
+                .any(|instance| !instance.get_device().is_playback && is_active(instance.get_session())),
+            DuckTrigger::AppPlaying(filter) => sessions.iter().any(|session| filter.matches(session) && is_active(session)),
+        })
+    }
+
+    /// Fades every ducked session back to its original volume, sessions that went away in the meantime are skipped
+    fn restore(&mut self) {
+        if self.originals.is_empty() {
+            return;
+        }
+        let sessions = match SessionManager::get_sessions_filtered(SessionStateFilter::ActiveAndInactive) {
+            Ok(sessions) => sessions,
+            Err(err) => {
+                warn!("Failed enumerating sessions to restore: {}", err);
+                return;
+            }
+        };
+        self.restoring.retain(|_, (_, ramp)| !ramp.is_finished());
+        for session in sessions {
+            let Some(volume) = self.originals.get(session.get_name()).copied() else {
+                continue;
+            };
+            match session.ramp_volume(volume, self.release) {
+                Ok(ramp) => {
+                    self.restoring.insert(session.get_name().clone(), (volume, ramp));
+                }
+                Err(err) => {
+                    warn!("Failed fading back session {}: {}", session.get_name(), err);
+                    set_volume(&session, volume);
+                }
+            }
+        }
+        self.originals.clear();
+    }
+
+    /// Waits for the fades back, so they aren't cut off when the process exits right after the ducker stopped
+    fn finish_restore(&mut self) {
+        let unfinished: HashMap<String, f32> = self
+            .restoring
+            .drain()
+            .filter(|(_, (_, ramp))| ramp.wait() != RampOutcome::Completed)
+            .map(|(name, (volume, _))| (name, volume))
+            .collect();
+        if unfinished.is_empty() {
+            return;
+        }
+        match SessionManager::get_sessions_filtered(SessionStateFilter::ActiveAndInactive) {
+            Ok(sessions) => {
+                for session in sessions {
+                    if let Some(volume) = unfinished.get(session.get_name()) {
+                        set_volume(&session, *volume);
+                    }
+                }
+            }
+            Err(err) => warn!("Failed enumerating sessions to restore: {}", err),
+        }
+    }
+}
+
+fn set_volume(session: &Session, volume: f32) {
+    if let Err(err) = session.set_volume(volume) {
+        warn!("Failed restoring session {}: {}", session.get_name(), err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_client::AudioClient;
+
+    #[test]
+    fn duck_while_playing() {
+        let (playback, _format) = AudioClient::new().start_playback_device(None, |_| 0, |_| {}).unwrap();
+        let playback = playback.start().unwrap();
+        // Give the session time to become active
+        thread::sleep(Duration::from_millis(100));
+
+        // Only targets this process, which is never ducked, so the other applications on the machine are left alone
+        let ducker = Ducker::new(
+            DuckTrigger::AppPlaying(SessionFilter::Pid(std::process::id())),
+            DuckTargets::Only(vec![SessionFilter::Pid(std::process::id())]),
+            0.5,
+            Duration::from_millis(50),
+            Duration::from_millis(50),
+        )
+        .unwrap();
+        assert!(ducker.is_ducking());
+        drop(playback);
+        ducker.stop();
+    }
+}
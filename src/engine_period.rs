@@ -0,0 +1,108 @@
+//! Shared mode engine periods of `IAudioClient3`, see [`EnginePeriods`].
+//!
+//! Shared mode streams normally run at the default period of the audio engine, 10 ms on most devices, with a buffer of
+//! 20 ms. Drivers that support it let a stream run the engine at a smaller period, down to a few milliseconds, which
+//! lowers the latency of every stream on the device while it runs. See
+//! [`AudioClient::with_low_latency`](crate::audio_client::AudioClient::with_low_latency).
+
+use std::time::Duration;
+
+use windows::Win32::Media::Audio::{IAudioClient, IAudioClient3, WAVEFORMATEX};
+use windows_core::Interface;
+
+/// The engine periods a device supports for one format, in frames of that format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnginePeriods {
+    default: u32,
+    fundamental: u32,
+    min: u32,
+    max: u32,
+    sample_rate: u32,
+}
+
+impl EnginePeriods {
+    /// Queries the periods of `audio_client` for `format`, fails without `IAudioClient3` or if the format isn't the one
+    /// the engine runs at
+    pub(crate) fn query(audio_client: &IAudioClient, format: *const WAVEFORMATEX) -> windows_core::Result<Self> {
+        let audio_client3 = audio_client.cast::<IAudioClient3>()?;
+        let (mut default, mut fundamental, mut min, mut max) = (0, 0, 0, 0);
+        unsafe { audio_client3.GetSharedModeEnginePeriod(format, &mut default, &mut fundamental, &mut min, &mut max) }?;
+        Ok(Self {
+            default,
+            fundamental,
+            min,
+            max,
+            sample_rate: unsafe { (*format).nSamplesPerSec },
+        })
+    }
+
+    /// The period streams run at without asking for another one
+    pub fn get_default(&self) -> u32 {
+        self.default
+    }
+
+    /// Every supported period is a multiple of this one
+    pub fn get_fundamental(&self) -> u32 {
+        self.fundamental
+    }
+
+    pub fn get_min(&self) -> u32 {
+        self.min
+    }
+
+    pub fn get_max(&self) -> u32 {
+        self.max
+    }
+
+    /// Every supported period from the smallest to the largest
+    pub fn get_supported(&self) -> Vec<u32> {
+        if self.fundamental == 0 {
+            return vec![self.default];
+        }
+        (self.min..=self.max).step_by(self.fundamental as usize).collect()
+    }
+
+    /// Duration of a period of `frames` frames
+    pub fn to_duration(&self, frames: u32) -> Duration {
+        if self.sample_rate == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos(frames as u64 * 1_000_000_000 / self.sample_rate as u64)
+    }
+}
+
+/// Initializes `audio_client` as a shared stream at the smallest engine period of `format`
+pub(crate) fn initialize_low_latency(
+    audio_client: &IAudioClient,
+    format: *const WAVEFORMATEX,
+    flags: u32,
+) -> windows_core::Result<EnginePeriods> {
+    let periods = EnginePeriods::query(audio_client, format)?;
+    let audio_client3 = audio_client.cast::<IAudioClient3>()?;
+    unsafe { audio_client3.InitializeSharedAudioStream(flags, periods.min, format, None) }?;
+    Ok(periods)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_client::AudioClient;
+    use crate::manager::DeviceManager;
+
+    #[test]
+    fn low_latency_playback() {
+        let device = DeviceManager::get_default_playback_device().unwrap();
+        let periods = device.get_engine_periods(None).unwrap();
+        assert!(periods.get_min() <= periods.get_default() && periods.get_default() <= periods.get_max());
+        let supported = periods.get_supported();
+        assert_eq!(supported.first(), Some(&periods.get_min()));
+        assert!(supported.contains(&periods.get_default()));
+
+        let (config, _format) = AudioClient::new()
+            .with_low_latency(true)
+            .start_playback_device(None, |_| 0, |_| {})
+            .unwrap();
+        let interval = config.expected_packet_interval();
+        assert!(interval <= periods.to_duration(periods.get_default()) + Duration::from_micros(100));
+    }
+}
@@ -2,10 +2,43 @@ use windows::Win32::{
     Foundation::{self, PROPERTYKEY},
     Media::Audio::{AudioSessionDisconnectReason, AudioSessionState, DEVICE_STATE, EDataFlow, ERole},
 };
-use windows_core::PCWSTR;
+use windows_core::{GUID, HSTRING, PCWSTR};
 
+use crate::device_query::{DataFlow, DeviceRole};
+pub use crate::device_state::{
+    DEVICE_STATE_ACTIVE, DEVICE_STATE_DISABLED, DEVICE_STATE_NOTPRESENT, DEVICE_STATE_UNPLUGGED, DEVICE_STATEMASK_ALL, DeviceState,
+};
+use crate::event_context::EventContext;
 use crate::notifications::NotificationError;
 
+/// Implements `get_event_context` and `is_from` for args with an `eventcontext: Option<GUID>` field
+macro_rules! impl_event_context {
+    ($($args:ty),* $(,)?) => {
+        $(
+            impl $args {
+                /// Context passed to the setter that caused the event, `None` if the change didn't come with one
+                pub fn get_event_context(&self) -> Option<EventContext> {
+                    self.eventcontext.map(EventContext::from)
+                }
+
+                /// Whether the event was caused by a change made with `context`, e.g. to ignore the own changes
+                pub fn is_from(&self, context: &EventContext) -> bool {
+                    self.eventcontext == Some(context.get_guid())
+                }
+            }
+        )*
+    };
+}
+
+impl_event_context!(
+    DisplayNameChangedArgs,
+    SimpleVolumeChangedArgs,
+    ChannelVolumeChangedArgs,
+    GroupingParamChangedArgs,
+    IconPathChangedArgs,
+    EndpointVolumeChangedArgs,
+);
+
 #[derive(Debug)]
 pub enum AudioSessionEventArgs {
     DisplayNameChanged(DisplayNameChangedArgs),
@@ -17,11 +50,31 @@ pub enum AudioSessionEventArgs {
     SessionDisconnected(SessionDisconnectedArgs),
 }
 
+impl AudioSessionEventArgs {
+    /// Whether the event was caused by a change made with `context`, always false for state changes and disconnects
+    pub fn is_from(&self, context: &EventContext) -> bool {
+        match self {
+            AudioSessionEventArgs::DisplayNameChanged(args) => args.is_from(context),
+            AudioSessionEventArgs::IconPathChanged(args) => args.is_from(context),
+            AudioSessionEventArgs::SimpleVolumeChanged(args) => args.is_from(context),
+            AudioSessionEventArgs::ChannelVolumeChanged(args) => args.is_from(context),
+            AudioSessionEventArgs::GroupingParamChanged(args) => args.is_from(context),
+            AudioSessionEventArgs::StateChanged(_) | AudioSessionEventArgs::SessionDisconnected(_) => false,
+        }
+    }
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct DisplayNameChangedArgs {
-    pub(crate) newdisplayname: PCWSTR,
-    pub(crate) eventcontext: *const windows_core::GUID,
+    pub(crate) newdisplayname: HSTRING,
+    pub(crate) eventcontext: Option<GUID>,
+}
+
+impl DisplayNameChangedArgs {
+    pub fn get_display_name(&self) -> Result<String, NotificationError> {
+        String::from_utf16(&self.newdisplayname).map_err(NotificationError::PCWSTRConversionError)
+    }
 }
 
 #[derive(Debug)]
@@ -29,23 +82,40 @@ pub struct DisplayNameChangedArgs {
 pub struct SimpleVolumeChangedArgs {
     pub(crate) newvolume: f32,
     pub(crate) newmute: Foundation::BOOL,
-    pub(crate) eventcontext: *const windows_core::GUID,
+    pub(crate) eventcontext: Option<GUID>,
+}
+
+impl SimpleVolumeChangedArgs {
+    /// Master volume of the session, from `0.0` to `1.0`
+    pub fn get_volume(&self) -> f32 {
+        self.newvolume
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.newmute.as_bool()
+    }
 }
 
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct ChannelVolumeChangedArgs {
     pub(crate) channelcount: u32,
-    pub(crate) newchannelvolumearray: *const f32,
+    pub(crate) newchannelvolumearray: Vec<f32>,
     pub(crate) changedchannel: u32,
-    pub(crate) eventcontext: *const windows_core::GUID,
+    pub(crate) eventcontext: Option<GUID>,
 }
 
 #[derive(Debug)]
-#[allow(dead_code)]
 pub struct GroupingParamChangedArgs {
-    pub(crate) newgroupingparam: *const windows_core::GUID,
-    pub(crate) eventcontext: *const windows_core::GUID,
+    pub(crate) newgroupingparam: Option<GUID>,
+    pub(crate) eventcontext: Option<GUID>,
+}
+
+impl GroupingParamChangedArgs {
+    /// The new grouping param of the session, see [`Session::get_grouping_param`](crate::manager::Session::get_grouping_param)
+    pub fn get_grouping_param(&self) -> Option<GUID> {
+        self.newgroupingparam
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -90,7 +160,7 @@ impl SessionDisconnectedArgs {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SessionDisconnectReason {
     DisconnectReasonDeviceRemoval,
     DisconnectReasonServerShutdown,
@@ -103,16 +173,56 @@ pub enum SessionDisconnectReason {
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct IconPathChangedArgs {
-    pub(crate) newiconpath: PCWSTR,
-    pub(crate) eventcontext: *const windows_core::GUID,
+    pub(crate) newiconpath: HSTRING,
+    pub(crate) eventcontext: Option<GUID>,
 }
 
 impl IconPathChangedArgs {
     pub fn get_icon_path(&self) -> Result<String, NotificationError> {
-        unsafe { self.newiconpath.to_string() }.map_err(NotificationError::PCWSTRConversionError)
+        String::from_utf16(&self.newiconpath).map_err(NotificationError::PCWSTRConversionError)
     }
 }
 
+/// Copies a string only valid for the duration of a COM callback, so the event args can outlive it
+pub(crate) fn copy_pcwstr(str: &PCWSTR) -> HSTRING {
+    if str.is_null() {
+        return HSTRING::new();
+    }
+    unsafe { str.to_hstring() }
+}
+
+/// New volume of an endpoint, see [`Notifications::register_endpoint_volume_notification`]
+///
+/// [`Notifications::register_endpoint_volume_notification`]: crate::notifications::Notifications::register_endpoint_volume_notification
+#[derive(Debug, Clone)]
+pub struct EndpointVolumeChangedArgs {
+    pub(crate) eventcontext: Option<GUID>,
+    pub(crate) muted: bool,
+    pub(crate) master_volume: f32,
+    pub(crate) channel_volumes: Vec<f32>,
+}
+
+impl EndpointVolumeChangedArgs {
+    /// Master volume of the endpoint, from `0.0` to `1.0`
+    pub fn get_master_volume(&self) -> f32 {
+        self.master_volume
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// Volume of every channel of the endpoint, from `0.0` to `1.0`
+    pub fn get_channel_volumes(&self) -> &Vec<f32> {
+        &self.channel_volumes
+    }
+}
+
+/// Copies a GUID pointer only valid for the duration of a COM callback
+pub(crate) fn copy_guid(guid: *const GUID) -> Option<GUID> {
+    unsafe { guid.as_ref() }.copied()
+}
+
 //DeviceEventArgs
 #[derive(Debug)]
 pub enum DeviceNotificationEventArgs {
@@ -125,50 +235,57 @@ pub enum DeviceNotificationEventArgs {
 
 #[derive(Debug)]
 pub struct DefaultDeviceChangedEventArgs {
-    #[allow(dead_code)]
     pub(crate) flow: EDataFlow,
-    #[allow(dead_code)]
     pub(crate) role: ERole,
-    pub(crate) defaultdevice: PCWSTR,
+    pub(crate) defaultdevice: HSTRING,
 }
 
 impl DefaultDeviceChangedEventArgs {
+    pub fn get_flow(&self) -> DataFlow {
+        self.flow.into()
+    }
+
+    pub fn get_role(&self) -> DeviceRole {
+        self.role.into()
+    }
+
+    /// Empty if there is no default device left for this flow and role
     pub fn get_default_device(&self) -> Result<String, NotificationError> {
-        unsafe { self.defaultdevice.to_string() }.map_err(NotificationError::PCWSTRConversionError)
+        String::from_utf16(&self.defaultdevice).map_err(NotificationError::PCWSTRConversionError)
     }
 }
 
 #[derive(Debug)]
 pub struct DeviceAddedEventArgs {
-    pub(crate) pwstrDeviceId: PCWSTR,
+    pub(crate) pwstrDeviceId: HSTRING,
 }
 
 impl DeviceAddedEventArgs {
     pub fn get_device_id(&self) -> Result<String, NotificationError> {
-        unsafe { self.pwstrDeviceId.to_string() }.map_err(NotificationError::PCWSTRConversionError)
+        String::from_utf16(&self.pwstrDeviceId).map_err(NotificationError::PCWSTRConversionError)
     }
 }
 
 #[derive(Debug)]
 pub struct DeviceRemovedEventArgs {
-    pub(crate) pwstrDeviceId: PCWSTR,
+    pub(crate) pwstrDeviceId: HSTRING,
 }
 
 impl DeviceRemovedEventArgs {
     pub fn get_device_id(&self) -> Result<String, NotificationError> {
-        unsafe { self.pwstrDeviceId.to_string() }.map_err(NotificationError::PCWSTRConversionError)
+        String::from_utf16(&self.pwstrDeviceId).map_err(NotificationError::PCWSTRConversionError)
     }
 }
 
 #[derive(Debug)]
 pub struct DeviceStateChangedEventArgs {
-    pub(crate) pwstrDeviceId: PCWSTR,
+    pub(crate) pwstrDeviceId: HSTRING,
     pub(crate) dwNewState: DEVICE_STATE,
 }
 
 impl DeviceStateChangedEventArgs {
     pub fn get_device_id(&self) -> Result<String, NotificationError> {
-        unsafe { self.pwstrDeviceId.to_string() }.map_err(NotificationError::PCWSTRConversionError)
+        String::from_utf16(&self.pwstrDeviceId).map_err(NotificationError::PCWSTRConversionError)
     }
 
     pub fn get_state(&self) -> DeviceState {
@@ -176,48 +293,18 @@ impl DeviceStateChangedEventArgs {
     }
 }
 
-#[derive(Debug)]
-pub enum DeviceState {
-    Active,
-    Disabled,
-    NotPresent,
-    Unplugged,
-    /// Mask: Includes audio endpoint devices in all states active, disabled, not present, and unplugged.
-    All,
-}
-
-impl From<DEVICE_STATE> for DeviceState {
-    fn from(state: DEVICE_STATE) -> Self {
-        match state.0 {
-            1u32 => DeviceState::Active,
-            2u32 => DeviceState::Disabled,
-            4u32 => DeviceState::NotPresent,
-            8u32 => DeviceState::Unplugged,
-            15u32 => DeviceState::All,
-            _ => panic!("Invalid device state"),
-        }
-    }
-}
-
-pub const DEVICE_STATEMASK_ALL: u32 = 15u32;
-pub const DEVICE_STATE_ACTIVE: DEVICE_STATE = DEVICE_STATE(1u32);
-pub const DEVICE_STATE_DISABLED: DEVICE_STATE = DEVICE_STATE(2u32);
-pub const DEVICE_STATE_NOTPRESENT: DEVICE_STATE = DEVICE_STATE(4u32);
-pub const DEVICE_STATE_UNPLUGGED: DEVICE_STATE = DEVICE_STATE(8u32);
-
 #[derive(Debug)]
 pub struct DevicePropertyValueChangedEventArgs {
-    pub(crate) pwstrDeviceId: PCWSTR,
-    #[allow(dead_code)]
+    pub(crate) pwstrDeviceId: HSTRING,
     pub(crate) key: PROPERTYKEY,
 }
 
 impl DevicePropertyValueChangedEventArgs {
     pub fn get_device_id(&self) -> Result<String, NotificationError> {
-        unsafe { self.pwstrDeviceId.to_string() }.map_err(NotificationError::PCWSTRConversionError)
+        String::from_utf16(&self.pwstrDeviceId).map_err(NotificationError::PCWSTRConversionError)
     }
 
-    pub fn get_property_key(&self) {
-        unimplemented!()
+    pub fn get_property_key(&self) -> PROPERTYKEY {
+        self.key
     }
 }
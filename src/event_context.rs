@@ -0,0 +1,60 @@
+//! Event contexts to tell the changes made by this process apart from everyone else's, see [`EventContext`].
+//!
+//! Every session setter hands an event context GUID to the audio engine, which passes it on to the session event
+//! callbacks of every client. Comparing the context of an event against the one used for the change filters out the
+//! events triggered by the change itself.
+
+use std::sync::LazyLock;
+
+use windows_core::GUID;
+
+/// Used when no other context is given, the same for the whole process
+static DEFAULT_CONTEXT: LazyLock<EventContext> = LazyLock::new(|| {
+    // Creating a GUID only fails without a working RPC runtime, the process id still keeps processes apart then
+    EventContext::new().unwrap_or(EventContext(GUID::from_u128(
+        0x5f6e_2a41_9c1d_4b8e_a3c7_0000_0000_0000 | std::process::id() as u128,
+    )))
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventContext(GUID);
+
+impl EventContext {
+    /// A new unique context, e.g. to tell the changes of different parts of an application apart
+    pub fn new() -> windows_core::Result<Self> {
+        GUID::new().map(Self)
+    }
+
+    /// The context the setters of this crate use unless told otherwise
+    pub fn process_default() -> Self {
+        *DEFAULT_CONTEXT
+    }
+
+    pub fn get_guid(&self) -> GUID {
+        self.0
+    }
+
+    /// Pointer for the setters, only valid as long as the borrow
+    pub(crate) fn as_ptr(&self) -> *const GUID {
+        &self.0
+    }
+}
+
+impl From<GUID> for EventContext {
+    fn from(guid: GUID) -> Self {
+        Self(guid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unique_contexts() {
+        let context = EventContext::new().unwrap();
+        assert_ne!(context, EventContext::process_default());
+        assert_eq!(EventContext::process_default(), EventContext::process_default());
+        assert_eq!(EventContext::from(context.get_guid()), context);
+    }
+}
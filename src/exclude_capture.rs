@@ -0,0 +1,337 @@
+//! Capturing everything played except the audio of several processes, see [`ExcludeCapture`].
+//!
+//! A process loopback client excludes exactly one process tree, and the audio of several exclude clients can't be
+//! combined into "everything but all of them". When every excluded process belongs to one tree, e.g. the helper
+//! processes of a conference app, one client excluding the top of that tree is exact. Otherwise the capture falls back
+//! to one include client per other process that has an audio session, mixed on a shared timeline. The fallback misses
+//! processes that start playing after it started, and the system sounds session.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use thiserror::Error;
+use windows::Win32::System::Diagnostics::ToolHelp::{
+    CreateToolhelp32Snapshot, PROCESSENTRY32W, Process32FirstW, Process32NextW, TH32CS_SNAPPROCESS,
+};
+
+use crate::audio_client::{AudioClient, AudioClientError, EventHandleWrapper};
+use crate::audio_stream::AudioStream;
+use crate::convert;
+use crate::manager::{AudioError, SessionManager, SessionStateFilter};
+use crate::process_tracks::{ProcessTracks, RunningProcessTracks};
+use crate::sample_format::SampleFormat;
+
+/// 10 ms at 48 kHz
+const DEFAULT_BLOCK_FRAMES: usize = 480;
+/// Blocks a track may fall behind the newest one before the mix goes on without it
+const MAX_PENDING_BLOCKS: u64 = 10;
+/// Longest parent chain followed, pids are reused so a chain can loop
+const MAX_TREE_DEPTH: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum ExcludeCaptureError {
+    #[error("Failed enumerating processes: {0}")]
+    ProcessEnumError(#[source] windows::core::Error),
+    #[error("Failed enumerating sessions: {0}")]
+    SessionEnumError(#[source] AudioError),
+    #[error("Failed starting capture: {0}")]
+    StreamError(#[source] AudioClientError),
+    /// The fallback mixes samples as floats, which needs a format [`convert`] supports
+    #[error("Format can't be mixed: {0:?}")]
+    UnsupportedFormat(SampleFormat),
+}
+
+/// How an [`ExcludeCapture`] gets the audio
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExcludeStrategy {
+    /// Nothing to exclude, loopback of the default playback device
+    Loopback,
+    /// One process loopback client excluding the tree rooted at the pid, which holds every excluded process
+    ProcessTree(u32),
+    /// One process loopback client per pid, mixed. Clients include the children of their process, so no pid is a
+    /// descendant of another one.
+    Mix(Vec<u32>),
+}
+
+/// Captures every process except the given ones and their children, e.g. everything but the own application
+///
+/// With a single process this is the same as [`CaptureTarget::ProcessTreeExclude`](crate::capture_target::CaptureTarget::ProcessTreeExclude).
+pub struct ExcludeCapture {
+    pids: Vec<u32>,
+    format: SampleFormat,
+    block_frames: usize,
+}
+
+impl ExcludeCapture {
+    pub fn new(pids: impl IntoIterator<Item = u32>) -> Self {
+        Self {
+            pids: pids.into_iter().collect(),
+            format: SampleFormat::default(),
+            block_frames: DEFAULT_BLOCK_FRAMES,
+        }
+    }
+
+    /// Format of the delivered data, 32 bit float stereo at 48 kHz by default
+    pub fn format(mut self, format: SampleFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Length of the mixed blocks if the capture falls back to mixing
+    pub fn block_frames(mut self, block_frames: usize) -> Self {
+        self.block_frames = block_frames.max(1);
+        self
+    }
+
+    /// The strategy [`ExcludeCapture::start`] would use with the processes running right now
+    pub fn get_strategy(&self) -> Result<ExcludeStrategy, ExcludeCaptureError> {
+        let trees = ProcessTrees::snapshot()?;
+        let mut roots: Vec<u32> = self
+            .pids
+            .iter()
+            .copied()
+            .filter(|pid| !trees.ancestors(*pid).any(|ancestor| self.pids.contains(&ancestor)))
+            .collect();
+        roots.sort_unstable();
+        roots.dedup();
+        match roots[..] {
+            [] => return Ok(ExcludeStrategy::Loopback),
+            [root] => return Ok(ExcludeStrategy::ProcessTree(root)),
+            _ => {}
+        }
+
+        let sessions =
+            SessionManager::get_sessions_filtered(SessionStateFilter::ActiveAndInactive).map_err(ExcludeCaptureError::SessionEnumError)?;
+        let mut pids: Vec<u32> = sessions
+            .iter()
+            .filter(|session| !*session.is_system())
+            .map(|session| *session.get_pid())
+            .filter(|pid| !self.pids.contains(pid) && !trees.ancestors(*pid).any(|ancestor| self.pids.contains(&ancestor)))
+            .collect();
+        pids.sort_unstable();
+        pids.dedup();
+        // A child is already in the mix through a parent that has a session
+        let tops = pids
+            .iter()
+            .copied()
+            .filter(|pid| !trees.ancestors(*pid).any(|ancestor| pids.binary_search(&ancestor).is_ok()))
+            .collect();
+        Ok(ExcludeStrategy::Mix(tops))
+    }
+
+    /// Starts capturing, `data_callback` gets interleaved samples in the format of [`ExcludeCapture::format`]
+    pub fn start<D, E>(self, data_callback: D, error_callback: E) -> Result<RunningExcludeCapture, ExcludeCaptureError>
+    where
+        D: FnMut(&[u8]) + Send + 'static,
+        E: Fn(AudioClientError) + Send + Sync + 'static,
+    {
+        let strategy = self.get_strategy()?;
+        let mut data_callback = data_callback;
+        let mut audio_client = AudioClient::new();
+        audio_client
+            .set_format(self.format.clone())
+            .map_err(ExcludeCaptureError::StreamError)?;
+
+        let capture = match &strategy {
+            ExcludeStrategy::Loopback => {
+                let config = audio_client
+                    .start_recording_loopback_device(None, move |packet| data_callback(packet.data()), error_callback)
+                    .map_err(ExcludeCaptureError::StreamError)?;
+                Capture::Stream(config.start().map_err(ExcludeCaptureError::StreamError)?)
+            }
+            ExcludeStrategy::ProcessTree(root) => {
+                let config = audio_client
+                    .start_recording_process_excluding(*root, move |packet| data_callback(packet.data()), error_callback)
+                    .map_err(ExcludeCaptureError::StreamError)?;
+                Capture::Stream(config.start().map_err(ExcludeCaptureError::StreamError)?)
+            }
+            ExcludeStrategy::Mix(pids) => {
+                if !convert::is_convertible(&self.format) {
+                    return Err(ExcludeCaptureError::UnsupportedFormat(self.format));
+                }
+                // The callback runs on the thread of whichever track completes a block, the lock hands it over
+                let mixer = Mutex::new((BlockMixer::new(self.format.clone(), pids.len()), data_callback));
+                let tracks = ProcessTracks::new(pids.iter().copied(), self.format.clone())
+                    .block_frames(self.block_frames)
+                    .start(
+                        move |block| {
+                            let (mixer, data_callback) = &mut *mixer.lock().unwrap_or_else(|e| e.into_inner());
+                            mixer.push(block.get_index(), block.data(), data_callback);
+                        },
+                        move |_, err| error_callback(err),
+                    )
+                    .map_err(ExcludeCaptureError::StreamError)?;
+                Capture::Tracks(tracks)
+            }
+        };
+        Ok(RunningExcludeCapture {
+            strategy,
+            format: self.format,
+            capture,
+        })
+    }
+}
+
+enum Capture {
+    Stream(AudioStream),
+    Tracks(RunningProcessTracks),
+}
+
+/// Capture started by [`ExcludeCapture::start`], dropping it stops every stream
+pub struct RunningExcludeCapture {
+    strategy: ExcludeStrategy,
+    format: SampleFormat,
+    capture: Capture,
+}
+
+impl RunningExcludeCapture {
+    pub fn get_strategy(&self) -> &ExcludeStrategy {
+        &self.strategy
+    }
+
+    pub fn get_format(&self) -> &SampleFormat {
+        &self.format
+    }
+
+    /// The stream of a [`ExcludeStrategy::Loopback`] or [`ExcludeStrategy::ProcessTree`] capture
+    pub fn audio_stream(&self) -> Option<&AudioStream> {
+        match &self.capture {
+            Capture::Stream(stream) => Some(stream),
+            Capture::Tracks(_) => None,
+        }
+    }
+
+    /// The streams of a [`ExcludeStrategy::Mix`] capture
+    pub fn tracks(&self) -> Option<&RunningProcessTracks> {
+        match &self.capture {
+            Capture::Stream(_) => None,
+            Capture::Tracks(tracks) => Some(tracks),
+        }
+    }
+
+    // See drop implementation of the streams for cleanup
+    pub fn stop(self) {}
+}
+
+/// Parent of every running process, taken at one point in time
+struct ProcessTrees {
+    parents: HashMap<u32, u32>,
+}
+
+impl ProcessTrees {
+    fn snapshot() -> Result<Self, ExcludeCaptureError> {
+        let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) }.map_err(ExcludeCaptureError::ProcessEnumError)?;
+        let snapshot = EventHandleWrapper(snapshot);
+        let mut entry = PROCESSENTRY32W {
+            dwSize: size_of::<PROCESSENTRY32W>() as u32,
+            ..Default::default()
+        };
+        let mut parents = HashMap::new();
+        let mut next = unsafe { Process32FirstW(snapshot.0, &mut entry) };
+        while next.is_ok() {
+            parents.insert(entry.th32ProcessID, entry.th32ParentProcessID);
+            next = unsafe { Process32NextW(snapshot.0, &mut entry) };
+        }
+        Ok(Self { parents })
+    }
+
+    /// Parent, grandparent and so on of `pid`, up to the first process that exited
+    fn ancestors(&self, pid: u32) -> impl Iterator<Item = u32> + '_ {
+        std::iter::successors(self.parents.get(&pid).copied(), |parent| self.parents.get(parent).copied())
+            .take_while(|parent| *parent != 0)
+            .take(MAX_TREE_DEPTH)
+    }
+}
+
+/// Sums the blocks with the same index of every track, see [`crate::process_tracks`]
+pub(crate) struct BlockMixer {
+    format: SampleFormat,
+    tracks: usize,
+    /// Sum of the tracks delivered so far and their count, per block index
+    pending: BTreeMap<u64, (Vec<f32>, usize)>,
+    next: u64,
+    newest: u64,
+    samples: Vec<f32>,
+    out: Vec<u8>,
+}
+
+impl BlockMixer {
+    pub(crate) fn new(format: SampleFormat, tracks: usize) -> Self {
+        Self {
+            format,
+            tracks: tracks.max(1),
+            pending: BTreeMap::new(),
+            next: 0,
+            newest: 0,
+            samples: Vec::new(),
+            out: Vec::new(),
+        }
+    }
+
+    /// Adds a block of one track, `emit` gets every mixed block in order once all tracks delivered it, or once a track
+    /// fell too far behind. Blocks arriving after their mix was emitted are dropped.
+    pub(crate) fn push(&mut self, index: u64, data: &[u8], mut emit: impl FnMut(&[u8])) {
+        if index < self.next {
+            return;
+        }
+        self.samples.clear();
+        convert::bytes_to_f32(&self.format, data, &mut self.samples);
+        let (mix, count) = self.pending.entry(index).or_insert_with(|| (vec![0.0; self.samples.len()], 0));
+        for (mixed, sample) in mix.iter_mut().zip(&self.samples) {
+            *mixed += sample;
+        }
+        *count += 1;
+        self.newest = self.newest.max(index);
+
+        while let Some(entry) = self.pending.first_entry() {
+            let complete = entry.get().1 >= self.tracks;
+            if !complete && self.newest - entry.key() < MAX_PENDING_BLOCKS {
+                break;
+            }
+            let (index, (mix, _)) = entry.remove_entry();
+            self.out.clear();
+            convert::f32_to_bytes(&self.format, &mix, &mut self.out);
+            emit(&self.out);
+            self.next = index + 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(samples: &[f32]) -> Vec<u8> {
+        samples.iter().flat_map(|sample| sample.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn mix_blocks() {
+        let format = SampleFormat::default();
+        let mut mixer = BlockMixer::new(format.clone(), 2);
+        let mut mixed = Vec::new();
+        let mut push = |mixer: &mut BlockMixer, index: u64, samples: &[f32]| {
+            mixer.push(index, &block(samples), |data| {
+                let mut out = Vec::new();
+                convert::bytes_to_f32(&format, data, &mut out);
+                mixed.push(out);
+            })
+        };
+
+        push(&mut mixer, 0, &[0.25, 0.5]);
+        push(&mut mixer, 1, &[0.25, 0.25]);
+        push(&mut mixer, 0, &[0.25, -0.5]);
+        // The second track stops delivering, block 1 goes out once it is too far behind
+        for index in 2..=MAX_PENDING_BLOCKS + 1 {
+            push(&mut mixer, index, &[0.0, 0.0]);
+        }
+        push(&mut mixer, 1, &[1.0, 1.0]);
+        assert_eq!(mixed[..2], [vec![0.5, 0.0], vec![0.25, 0.25]]);
+        assert_eq!(mixed.len(), 2);
+
+        // A single excluded process is always its own tree
+        let strategy = ExcludeCapture::new([std::process::id()]).get_strategy().unwrap();
+        assert_eq!(strategy, ExcludeStrategy::ProcessTree(std::process::id()));
+        assert_eq!(ExcludeCapture::new([]).get_strategy().unwrap(), ExcludeStrategy::Loopback);
+    }
+}
@@ -0,0 +1,227 @@
+//! Bounded log of the glitches a stream ran into, see [`GlitchRecorder`].
+//!
+//! Pops reported by end users are usually gone by the time anyone looks. With a recorder attached the stream keeps the
+//! last glitches with their timestamps, to be read through
+//! [`AudioStream::glitch_report`](crate::audio_stream::AudioStream::glitch_report) after the fact.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use windows::Win32::Media::Audio::{
+    AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY, AUDCLNT_BUFFERFLAGS_SILENT, AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR,
+};
+
+use crate::stream_instant::StreamInstant;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GlitchKind {
+    /// `AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY`, the audio engine lost data before the packet
+    Discontinuity,
+    /// `AUDCLNT_BUFFERFLAGS_SILENT`, only recorded for the first packet of a silent stretch
+    Silent,
+    /// `AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR`, the timestamp of the packet is unreliable
+    TimestampError,
+    /// The device position of a packet doesn't continue where the previous packet ended
+    PositionJump { expected: u64, actual: u64 },
+    /// The callback took longer than the audio it handled lasts
+    CallbackOverrun { elapsed: Duration, budget: Duration },
+    /// The playback callback wrote fewer frames than requested, the rest of the period was zeroed
+    PartialFill { written: u32, requested: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Glitch {
+    kind: GlitchKind,
+    timestamp: StreamInstant,
+}
+
+impl Glitch {
+    pub fn get_kind(&self) -> &GlitchKind {
+        &self.kind
+    }
+
+    /// Performance counter time of the packet, see [`StreamInstant::now`]
+    pub fn get_timestamp(&self) -> &StreamInstant {
+        &self.timestamp
+    }
+}
+
+/// Attaches a glitch log to a stream, see [`AudioStreamConfig::with_glitch_recorder`](crate::audio_stream::AudioStreamConfig::with_glitch_recorder)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GlitchRecorder {
+    capacity: usize,
+}
+
+impl Default for GlitchRecorder {
+    fn default() -> Self {
+        Self { capacity: 1024 }
+    }
+}
+
+impl GlitchRecorder {
+    /// Keeps the last `capacity` glitches, older ones are dropped
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity: capacity.max(1) }
+    }
+
+    pub fn get_capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GlitchReport {
+    glitches: Vec<Glitch>,
+    dropped: u64,
+}
+
+impl GlitchReport {
+    /// The recorded glitches, oldest first
+    pub fn get_glitches(&self) -> &[Glitch] {
+        &self.glitches
+    }
+
+    /// Glitches that didn't fit into the log anymore
+    pub fn get_dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// Shared between the stream thread recording into it and the stream handle reading it
+pub(crate) struct GlitchLog {
+    capacity: usize,
+    state: Mutex<GlitchLogState>,
+}
+
+#[derive(Default)]
+struct GlitchLogState {
+    glitches: VecDeque<Glitch>,
+    dropped: u64,
+}
+
+impl GlitchLog {
+    pub(crate) fn new(recorder: GlitchRecorder) -> Self {
+        Self {
+            capacity: recorder.capacity,
+            state: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn record(&self, kind: GlitchKind, timestamp: StreamInstant) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.glitches.len() == self.capacity {
+            state.glitches.pop_front();
+            state.dropped += 1;
+        }
+        state.glitches.push_back(Glitch { kind, timestamp });
+    }
+
+    pub(crate) fn report(&self) -> GlitchReport {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        GlitchReport {
+            glitches: state.glitches.iter().cloned().collect(),
+            dropped: state.dropped,
+        }
+    }
+}
+
+/// Turns the buffer flags, device positions and callback durations of one stream into glitches
+pub(crate) struct GlitchTracker {
+    log: Arc<GlitchLog>,
+    sample_rate: u32,
+    next_position: Option<u64>,
+    silent: bool,
+}
+
+impl GlitchTracker {
+    pub(crate) fn new(log: Arc<GlitchLog>, sample_rate: u32) -> Self {
+        Self {
+            log,
+            sample_rate,
+            next_position: None,
+            silent: false,
+        }
+    }
+
+    /// Checks the flags and device position `GetBuffer` returned for a captured packet of `frames` frames
+    pub(crate) fn packet(&mut self, flags: u32, position: u64, frames: u32, timestamp: StreamInstant) {
+        if flags & AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY.0 as u32 != 0 {
+            self.log.record(GlitchKind::Discontinuity, timestamp);
+        }
+        let silent = flags & AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 != 0;
+        if silent && !self.silent {
+            self.log.record(GlitchKind::Silent, timestamp);
+        }
+        self.silent = silent;
+        if flags & AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR.0 as u32 != 0 {
+            self.log.record(GlitchKind::TimestampError, timestamp);
+        }
+        if let Some(expected) = self.next_position
+            && expected != position
+        {
+            self.log.record(
+                GlitchKind::PositionJump {
+                    expected,
+                    actual: position,
+                },
+                timestamp,
+            );
+        }
+        self.next_position = Some(position + frames as u64);
+    }
+
+    /// Forgets the device position after the stream moved to another client, whose position starts over
+    pub(crate) fn restart(&mut self) {
+        self.next_position = None;
+        self.silent = false;
+    }
+
+    /// Checks how long the callback took for `frames` frames
+    pub(crate) fn callback(&mut self, elapsed: Duration, frames: u32, timestamp: StreamInstant) {
+        let budget = Duration::from_nanos(frames as u64 * 1_000_000_000 / self.sample_rate.max(1) as u64);
+        if elapsed > budget {
+            self.log.record(GlitchKind::CallbackOverrun { elapsed, budget }, timestamp);
+        }
+    }
+
+    /// Records a playback period the callback only filled `written` of `requested` frames of
+    pub(crate) fn partial_fill(&mut self, written: u32, requested: u32, timestamp: StreamInstant) {
+        self.log.record(GlitchKind::PartialFill { written, requested }, timestamp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_glitches() {
+        let log = Arc::new(GlitchLog::new(GlitchRecorder::new(3)));
+        let mut tracker = GlitchTracker::new(log.clone(), 1000);
+        let at = StreamInstant::from_nanos(0);
+
+        tracker.packet(0, 0, 10, at);
+        tracker.packet(AUDCLNT_BUFFERFLAGS_SILENT.0 as u32, 10, 10, at);
+        // Only the start of a silent stretch is recorded
+        tracker.packet(AUDCLNT_BUFFERFLAGS_SILENT.0 as u32, 20, 10, at);
+        tracker.packet(AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY.0 as u32, 50, 10, at);
+        tracker.callback(Duration::from_millis(5), 10, at);
+        tracker.callback(Duration::from_millis(20), 10, at);
+
+        let report = log.report();
+        let kinds: Vec<_> = report.get_glitches().iter().map(|glitch| glitch.get_kind().clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                GlitchKind::Discontinuity,
+                GlitchKind::PositionJump { expected: 30, actual: 50 },
+                GlitchKind::CallbackOverrun {
+                    elapsed: Duration::from_millis(20),
+                    budget: Duration::from_millis(10)
+                },
+            ]
+        );
+        assert_eq!(report.get_dropped(), 1);
+    }
+}
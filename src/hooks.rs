@@ -0,0 +1,197 @@
+//! Observing every stream and notification of the crate from one place, see [`Hooks`].
+//!
+//! Hooks are meant for metrics exporters and tracing: they see stream starts and stops, the frames of every packet,
+//! stream errors and incoming notifications without the data callbacks having to report anything. Streams use the
+//! hooks of their [`AudioClient`](crate::audio_client::AudioClient), falling back to the global ones installed with
+//! [`set_global_hooks`]. Notifications always report to the global hooks.
+//!
+//! `on_packet` runs on the stream thread for every packet, implementations should only bump counters there.
+
+use std::sync::{Arc, RwLock};
+
+use crate::audio_client::AudioClientError;
+use crate::audio_stream::{CapturePacket, RenderRequest, StreamId};
+use crate::sample_format::SampleFormat;
+
+static GLOBAL_HOOKS: RwLock<Option<Arc<dyn Hooks>>> = RwLock::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StreamDirection {
+    Capture,
+    Playback,
+}
+
+/// Which notification arrived, see [`Hooks::on_notification`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NotificationKind {
+    Device,
+    SessionCreated,
+    SessionEvent,
+    EndpointVolume,
+}
+
+/// A stream as reported to [`Hooks::on_stream_start`] and [`Hooks::on_stream_stop`]
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    pub(crate) id: StreamId,
+    pub(crate) direction: StreamDirection,
+    pub(crate) format: SampleFormat,
+    pub(crate) label: Option<String>,
+}
+
+impl StreamInfo {
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    pub fn direction(&self) -> StreamDirection {
+        self.direction
+    }
+
+    /// Format the data callback sees
+    pub fn format(&self) -> &SampleFormat {
+        &self.format
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+}
+
+/// Lifecycle observer for streams and notifications, every method defaults to doing nothing
+pub trait Hooks: Send + Sync + 'static {
+    /// The client of the stream was started
+    fn on_stream_start(&self, _stream: &StreamInfo) {}
+
+    /// The stream was stopped, or its runner dropped while running
+    fn on_stream_stop(&self, _stream: &StreamInfo) {}
+
+    /// A packet was captured, or a playback period filled with `frames` frames
+    fn on_packet(&self, _stream: StreamId, _frames: u32) {}
+
+    /// The stream's error callback is about to be called with `err`
+    fn on_error(&self, _stream: StreamId, _err: &AudioClientError) {}
+
+    /// A notification arrived, before it is handed to the dispatcher
+    fn on_notification(&self, _kind: NotificationKind) {}
+}
+
+/// Installs hooks for every stream of clients without their own hooks and for every notification
+///
+/// Streams pick their hooks up when they are created, streams that already exist keep the previous ones.
+pub fn set_global_hooks(hooks: impl Hooks) {
+    *GLOBAL_HOOKS.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(hooks));
+}
+
+pub fn clear_global_hooks() {
+    *GLOBAL_HOOKS.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+pub(crate) fn global_hooks() -> Option<Arc<dyn Hooks>> {
+    GLOBAL_HOOKS.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Reports a notification to the global hooks
+#[cfg(feature = "notifications")]
+pub(crate) fn notification_arrived(kind: NotificationKind) {
+    if let Some(hooks) = global_hooks() {
+        hooks.on_notification(kind);
+    }
+}
+
+pub(crate) fn observe_capture<D>(
+    hooks: Option<Arc<dyn Hooks>>,
+    stream: StreamId,
+    block_align: usize,
+    mut data_callback: D,
+) -> impl FnMut(CapturePacket) + Send + 'static
+where
+    D: FnMut(CapturePacket) + Send + 'static,
+{
+    move |packet| {
+        if let Some(hooks) = &hooks {
+            hooks.on_packet(stream, (packet.data().len() / block_align.max(1)) as u32);
+        }
+        data_callback(packet)
+    }
+}
+
+pub(crate) fn observe_playback<D>(
+    hooks: Option<Arc<dyn Hooks>>,
+    stream: StreamId,
+    mut data_callback: D,
+) -> impl FnMut(RenderRequest) -> u32 + Send + 'static
+where
+    D: FnMut(RenderRequest) -> u32 + Send + 'static,
+{
+    move |request| {
+        let frames = data_callback(request);
+        if let Some(hooks) = &hooks {
+            hooks.on_packet(stream, frames);
+        }
+        frames
+    }
+}
+
+pub(crate) fn observe_errors<E>(
+    hooks: Option<Arc<dyn Hooks>>,
+    stream: StreamId,
+    mut error_callback: E,
+) -> impl FnMut(AudioClientError) + Send + 'static
+where
+    E: FnMut(AudioClientError) + Send + 'static,
+{
+    move |err| {
+        if let Some(hooks) = &hooks {
+            hooks.on_error(stream, &err);
+        }
+        error_callback(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_client::AudioClient;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[derive(Default)]
+    struct Counters {
+        started: Mutex<Vec<StreamId>>,
+        stopped: Mutex<Vec<StreamId>>,
+        frames: AtomicU64,
+    }
+
+    struct CountingHooks(Arc<Counters>);
+
+    impl Hooks for CountingHooks {
+        fn on_stream_start(&self, stream: &StreamInfo) {
+            self.0.started.lock().unwrap().push(stream.id());
+        }
+
+        fn on_stream_stop(&self, stream: &StreamInfo) {
+            self.0.stopped.lock().unwrap().push(stream.id());
+        }
+
+        fn on_packet(&self, _stream: StreamId, frames: u32) {
+            self.0.frames.fetch_add(frames as u64, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn client_hooks_see_stream() {
+        let counters = Arc::new(Counters::default());
+        let mut client = AudioClient::new();
+        client.set_hooks(CountingHooks(counters.clone()));
+        let (config, _format) = client.start_playback_device(None, |request| request.frames(), |_| {}).unwrap();
+        let id = config.id();
+        let stream = config.start().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        drop(stream);
+
+        assert_eq!(*counters.started.lock().unwrap(), vec![id]);
+        assert_eq!(*counters.stopped.lock().unwrap(), vec![id]);
+        assert!(counters.frames.load(Ordering::Relaxed) > 0);
+    }
+}
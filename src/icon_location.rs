@@ -0,0 +1,95 @@
+//! Parsed session icon paths, see [`IconLocation`].
+//!
+//! Applications set icon paths in the form `ExtractIcon` and the volume mixer understand, e.g.
+//! `@%SystemRoot%\System32\SndVolSSO.dll,-101`: an optional `@`, environment variables, and a resource suffix that is
+//! an icon index when positive and a negated resource id when negative.
+
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IconLocation {
+    path: PathBuf,
+    resource_index: Option<i32>,
+}
+
+impl IconLocation {
+    /// Parses a raw icon path, `None` if it is empty
+    pub fn parse(raw: &str) -> Option<Self> {
+        let raw = raw.trim();
+        let raw = raw.strip_prefix('@').unwrap_or(raw);
+        let (path, resource_index) = match raw.rsplit_once(',') {
+            Some((path, index)) => match index.trim().parse::<i32>() {
+                Ok(index) => (path, Some(index)),
+                Err(_) => (raw, None),
+            },
+            None => (raw, None),
+        };
+        let path = path.trim().trim_matches('"');
+        if path.is_empty() {
+            return None;
+        }
+        Some(Self {
+            path: PathBuf::from(expand_environment(path)),
+            resource_index,
+        })
+    }
+
+    /// Path of the icon, executable or library, with the environment variables expanded
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Icon index in the file if positive, the negated resource id if negative, `None` for `.ico` files
+    pub fn get_resource_index(&self) -> Option<i32> {
+        self.resource_index
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.is_file()
+    }
+}
+
+/// Replaces `%NAME%` with the value of the variable, like `ExpandEnvironmentStrings` unknown variables are kept
+fn expand_environment(path: &str) -> String {
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(start) = rest.find('%') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('%') else {
+            rest = &rest[start..];
+            break;
+        };
+        match std::env::var(&after[..end]) {
+            Ok(value) if end > 0 => expanded.push_str(&value),
+            _ => expanded.push_str(&rest[start..start + end + 2]),
+        }
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_icon_paths() {
+        let windir = std::env::var("SystemRoot").unwrap();
+        let icon = IconLocation::parse(r"@%SystemRoot%\System32\SndVolSSO.dll,-101").unwrap();
+        assert_eq!(icon.get_path(), Path::new(&format!(r"{}\System32\SndVolSSO.dll", windir)));
+        assert_eq!(icon.get_resource_index(), Some(-101));
+        assert!(icon.exists());
+
+        let icon = IconLocation::parse(r#""C:\App, Inc\app.ico""#).unwrap();
+        assert_eq!(icon.get_path(), Path::new(r"C:\App, Inc\app.ico"));
+        assert_eq!(icon.get_resource_index(), None);
+
+        assert_eq!(
+            IconLocation::parse(r"%NO_SUCH_VARIABLE%\a.exe,0").unwrap().get_path(),
+            Path::new(r"%NO_SUCH_VARIABLE%\a.exe")
+        );
+        assert_eq!(IconLocation::parse("  "), None);
+    }
+}
@@ -0,0 +1,172 @@
+//! Level meters computed on the capture thread, see [`CaptureOptions::monitor`].
+//!
+//! Every packet is metered before it reaches the data callback, the meter callback only gets peak and RMS per channel
+//! once per interval. A UI showing meters then doesn't have to receive or copy the audio itself. The interval counts
+//! captured frames, not wall clock time: while a loopback capture gets no packets, nothing is reported either.
+//!
+//! [`CaptureOptions::monitor`]: crate::capture_options::CaptureOptions::monitor
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::audio_stream::CapturePacket;
+use crate::convert::{bytes_to_f32, is_convertible};
+use crate::sample_format::SampleFormat;
+use crate::stream_instant::StreamInstant;
+
+pub(crate) type LevelsFn = Arc<dyn Fn(Levels) + Send + Sync + 'static>;
+
+/// Levels of one interval, linear from `0.0` to `1.0` (full scale) per channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Levels {
+    peaks: Vec<f32>,
+    rms: Vec<f32>,
+    frames: u64,
+    timestamp: StreamInstant,
+}
+
+impl Levels {
+    /// Largest absolute sample per channel
+    pub fn get_peaks(&self) -> &[f32] {
+        &self.peaks
+    }
+
+    pub fn get_rms(&self) -> &[f32] {
+        &self.rms
+    }
+
+    /// Loudest channel peak in dBFS
+    pub fn get_peak_db(&self) -> f32 {
+        linear_to_db(self.peaks.iter().copied().fold(0.0, f32::max))
+    }
+
+    /// Loudest channel RMS in dBFS
+    pub fn get_rms_db(&self) -> f32 {
+        linear_to_db(self.rms.iter().copied().fold(0.0, f32::max))
+    }
+
+    /// Frames the levels were measured over
+    pub fn get_frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Timestamp of the last packet of the interval
+    pub fn timestamp(&self) -> &StreamInstant {
+        &self.timestamp
+    }
+}
+
+fn linear_to_db(linear: f32) -> f32 {
+    20.0 * linear.max(f32::MIN_POSITIVE).log10()
+}
+
+/// Interval and callback set with [`CaptureOptions::monitor`](crate::capture_options::CaptureOptions::monitor)
+#[derive(Clone)]
+pub(crate) struct LevelMonitor {
+    pub(crate) interval: Duration,
+    pub(crate) callback: LevelsFn,
+}
+
+/// Accumulates the levels of the packets of one interval
+struct LevelMeter {
+    format: SampleFormat,
+    interval_frames: u64,
+    peaks: Vec<f32>,
+    squares: Vec<f64>,
+    frames: u64,
+    samples: Vec<f32>,
+}
+
+impl LevelMeter {
+    fn new(format: SampleFormat, interval: Duration) -> Self {
+        let channels = format.get_channel().max(1) as usize;
+        let interval_frames = (interval.as_secs_f64() * format.get_n_samples_per_sec() as f64).round() as u64;
+        Self {
+            format,
+            interval_frames: interval_frames.max(1),
+            peaks: vec![0.0; channels],
+            squares: vec![0.0; channels],
+            frames: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Adds a packet, returns the levels once the interval is complete
+    fn add(&mut self, data: &[u8], timestamp: StreamInstant) -> Option<Levels> {
+        self.samples.clear();
+        bytes_to_f32(&self.format, data, &mut self.samples);
+        let channels = self.peaks.len();
+        for frame in self.samples.chunks_exact(channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                self.peaks[channel] = self.peaks[channel].max(sample.abs());
+                self.squares[channel] += (*sample as f64) * (*sample as f64);
+            }
+        }
+        self.frames += (self.samples.len() / channels) as u64;
+        if self.frames < self.interval_frames {
+            return None;
+        }
+        let frames = self.frames;
+        let levels = Levels {
+            peaks: self.peaks.clone(),
+            rms: self.squares.iter().map(|squares| (squares / frames as f64).sqrt() as f32).collect(),
+            frames,
+            timestamp,
+        };
+        self.peaks.fill(0.0);
+        self.squares.fill(0.0);
+        self.frames = 0;
+        Some(levels)
+    }
+}
+
+/// Meters the packets handed to `data_callback`, which are in `format`. Formats that can't be decoded aren't metered.
+pub(crate) fn observe_levels<D>(
+    monitor: Option<LevelMonitor>,
+    format: &SampleFormat,
+    mut data_callback: D,
+) -> impl FnMut(CapturePacket) + Send + 'static
+where
+    D: FnMut(CapturePacket) + Send + 'static,
+{
+    let mut meter = monitor
+        .filter(|_| is_convertible(format))
+        .map(|monitor| (LevelMeter::new(format.clone(), monitor.interval), monitor.callback));
+    move |packet| {
+        if let Some((meter, callback)) = &mut meter
+            && let Some(levels) = meter.add(packet.data(), *packet.timestamp())
+        {
+            callback(levels);
+        }
+        data_callback(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample_format::FormatTag;
+
+    #[test]
+    fn meter_intervals() {
+        let format = SampleFormat::new(FormatTag::WaveFormatIeeeFloat, 2, 1000, 32);
+        let mut meter = LevelMeter::new(format, Duration::from_millis(4));
+        let packet = |left: f32, right: f32, frames: usize| -> Vec<u8> {
+            (0..frames)
+                .flat_map(|_| [left.to_le_bytes(), right.to_le_bytes()].concat())
+                .collect()
+        };
+
+        assert!(meter.add(&packet(0.5, -0.25, 2), StreamInstant::new(0, 0)).is_none());
+        let levels = meter.add(&packet(-1.0, 0.0, 2), StreamInstant::new(1, 0)).unwrap();
+        assert_eq!(levels.get_frames(), 4);
+        assert_eq!(levels.get_peaks(), [1.0, 0.25]);
+        assert!((levels.get_rms()[0] - (2.5f32 / 4.0).sqrt()).abs() < 1e-6);
+        assert_eq!(levels.get_peak_db(), 0.0);
+        assert_eq!(*levels.timestamp(), StreamInstant::new(1, 0));
+
+        // The next interval starts from silence again
+        let levels = meter.add(&packet(0.0, 0.0, 4), StreamInstant::new(2, 0)).unwrap();
+        assert_eq!(levels.get_peaks(), [0.0, 0.0]);
+    }
+}
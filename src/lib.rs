@@ -1,12 +1,94 @@
 #![allow(non_snake_case)]
 
 pub mod activation_params;
+pub mod activation_retry;
+#[cfg(feature = "notifications")]
+pub mod activity_feed;
+pub mod agc;
+#[cfg(feature = "notifications")]
+pub mod app_group;
+#[cfg(feature = "async")]
+pub mod async_stream;
 pub mod audio_client;
 pub mod audio_stream;
+mod block_processor;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod capture_options;
+pub mod capture_reader;
+pub mod capture_registry;
+pub mod capture_target;
+pub mod clock;
+#[cfg(feature = "notifications")]
+pub mod coalesce;
 pub mod com;
+pub mod companion_session;
+pub mod convert;
+pub mod deadline;
+#[cfg(feature = "notifications")]
+pub mod default_loopback;
+#[cfg(feature = "notifications")]
+pub mod device_name_watcher;
+pub mod device_query;
+pub mod device_state;
+pub mod diagnostics;
+#[cfg(feature = "notifications")]
+pub mod dispatcher;
+#[cfg(feature = "notifications")]
+pub mod ducker;
+pub mod engine_period;
+#[cfg(feature = "notifications")]
 pub mod event_args;
+pub mod event_context;
+pub mod exclude_capture;
+pub mod glitch_recorder;
+pub mod hooks;
+pub mod icon_location;
+pub mod level_monitor;
+pub mod listen;
 pub mod manager;
+#[cfg(feature = "notifications")]
+pub mod mixer;
+#[cfg(feature = "notifications")]
 pub mod notifications;
+pub mod packet_interval;
+pub mod path_resolver;
+pub mod playback_options;
+pub mod preflight;
+pub mod process_tracks;
+pub mod property_store;
+pub mod recorder;
+#[cfg(feature = "resampler")]
+pub mod resampler;
+#[cfg(feature = "notifications")]
+pub mod role_watcher;
+pub mod router;
+#[cfg(feature = "notifications")]
+pub mod runtime;
 pub mod sample_format;
+#[cfg(feature = "notifications")]
+pub mod session_capture;
+mod session_manager_cache;
+pub mod session_name;
+#[cfg(feature = "notifications")]
 pub mod session_notification;
+#[cfg(feature = "notifications")]
+mod session_tracker;
+#[cfg(feature = "notifications")]
+mod session_watch;
+pub mod shm_ring;
+pub mod sinks;
+pub mod sound;
+pub mod speaker_layout;
+pub mod stable_key;
+pub mod stream_category;
+pub mod stream_group;
 pub mod stream_instant;
+pub mod stream_recovery;
+pub mod stream_summary;
+pub mod volume_ramp;
+pub mod wav;
+#[cfg(feature = "notifications")]
+pub mod weak_handle;
+#[cfg(feature = "winrt-events")]
+mod winrt_events;
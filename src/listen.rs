@@ -0,0 +1,277 @@
+//! Monitoring an input device on an output device, like "Listen to this device" in the Windows sound settings,
+//! see [`Listen`].
+//!
+//! A capture stream on the input pushes into a queue that a playback stream on the output plays from. The playback
+//! stream uses the capture format and lets the audio engine convert it for the output. The two devices run on their
+//! own clocks, so the queue is kept near the target latency: once it holds more than twice the target, the oldest
+//! audio is dropped, and when it runs dry the output plays silence until new audio arrives.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::audio_client::{AudioClient, AudioClientError};
+use crate::manager::Device;
+use crate::sample_format::SampleFormat;
+use crate::stream_group::{RunningStreamGroup, StreamGroup};
+
+const DEFAULT_TARGET_LATENCY: Duration = Duration::from_millis(20);
+
+pub struct Listen {
+    input: Option<Device>,
+    output: Option<Device>,
+    target_latency: Duration,
+}
+
+impl Default for Listen {
+    fn default() -> Self {
+        Self {
+            input: None,
+            output: None,
+            target_latency: DEFAULT_TARGET_LATENCY,
+        }
+    }
+}
+
+impl Listen {
+    /// Listens to the default input device on the default playback device
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn input(mut self, input: &Device) -> Self {
+        self.input = Some(input.clone());
+        self
+    }
+
+    pub fn output(mut self, output: &Device) -> Self {
+        self.output = Some(output.clone());
+        self
+    }
+
+    /// Audio kept queued between the two devices, on top of the device buffers. Lower values drop out more often when
+    /// the capture thread is late.
+    pub fn target_latency(mut self, target_latency: Duration) -> Self {
+        self.target_latency = target_latency;
+        self
+    }
+
+    /// Starts the capture and the playback stream together, errors of both streams go to `error_callback`
+    pub fn start<E>(self, error_callback: E) -> Result<RunningListen, AudioClientError>
+    where
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        let error_callback = Arc::new(Mutex::new(error_callback));
+        let queue = Arc::new(ListenQueue::default());
+
+        let (capture_queue, capture_error_callback) = (queue.clone(), error_callback.clone());
+        let capture = AudioClient::new().start_recording_device(
+            self.input.as_ref(),
+            move |packet| capture_queue.push(packet.data()),
+            move |err| (capture_error_callback.lock().unwrap_or_else(|e| e.into_inner()))(err),
+        )?;
+        let format = capture.format().clone();
+        queue.configure(&format, self.target_latency);
+
+        let render_queue = queue.clone();
+        let playback = AudioClient::new().start_playback_device_with_format(
+            self.output.as_ref(),
+            &format,
+            move |mut request| match render_queue.pop(request.buffer()) {
+                true => request.frames(),
+                false => 0,
+            },
+            move |err| (error_callback.lock().unwrap_or_else(|e| e.into_inner()))(err),
+        )?;
+
+        let streams = StreamGroup::new()
+            .with(capture.with_label("listen"))
+            .with(playback.with_label("listen"))
+            .start()?;
+        Ok(RunningListen {
+            _streams: streams,
+            queue,
+            format,
+        })
+    }
+}
+
+/// A running [`Listen`], dropping it stops both streams
+pub struct RunningListen {
+    _streams: RunningStreamGroup,
+    queue: Arc<ListenQueue>,
+    format: SampleFormat,
+}
+
+impl RunningListen {
+    /// Format of the captured audio, the output converts from it
+    pub fn get_format(&self) -> &SampleFormat {
+        &self.format
+    }
+
+    pub fn get_metrics(&self) -> ListenMetrics {
+        self.queue.metrics()
+    }
+
+    // See drop implementation of the stream group for cleanup
+    pub fn stop(self) {}
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ListenMetrics {
+    latency: Duration,
+    drift_ppm: f64,
+    underruns: u64,
+    dropped_frames: u64,
+}
+
+impl ListenMetrics {
+    /// Audio currently queued between the devices, the device buffers come on top
+    pub fn get_latency(&self) -> Duration {
+        self.latency
+    }
+
+    /// How much faster the input clock runs than the output clock, in parts per million
+    pub fn get_drift_ppm(&self) -> f64 {
+        self.drift_ppm
+    }
+
+    /// Render buffers that couldn't be filled completely
+    pub fn get_underruns(&self) -> u64 {
+        self.underruns
+    }
+
+    /// Captured frames dropped to keep the latency bounded
+    pub fn get_dropped_frames(&self) -> u64 {
+        self.dropped_frames
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ListenQueue {
+    state: Mutex<QueueState>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    data: VecDeque<u8>,
+    block_align: usize,
+    sample_rate: u32,
+    target_frames: usize,
+    captured: FrameCounter,
+    rendered: FrameCounter,
+    underruns: u64,
+    dropped_frames: u64,
+}
+
+/// Frames passed since the first one, to compare the rates of the two devices
+#[derive(Default)]
+pub(crate) struct FrameCounter {
+    since: Option<Instant>,
+    pub(crate) frames: u64,
+}
+
+impl FrameCounter {
+    pub(crate) fn add(&mut self, frames: usize) {
+        self.since.get_or_insert_with(Instant::now);
+        self.frames += frames as u64;
+    }
+
+    pub(crate) fn rate(&self) -> Option<f64> {
+        let elapsed = self.since?.elapsed().as_secs_f64();
+        (elapsed > 0.0 && self.frames > 0).then(|| self.frames as f64 / elapsed)
+    }
+}
+
+impl ListenQueue {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn configure(&self, format: &SampleFormat, target_latency: Duration) {
+        let mut state = self.lock();
+        state.block_align = format.block_align() as usize;
+        state.sample_rate = format.get_n_samples_per_sec();
+        state.target_frames = (target_latency.as_nanos() * state.sample_rate as u128 / 1_000_000_000) as usize;
+    }
+
+    pub(crate) fn push(&self, data: &[u8]) {
+        let mut state = self.lock();
+        if state.block_align == 0 {
+            return;
+        }
+        state.data.extend(data);
+        let frames = data.len() / state.block_align;
+        state.captured.add(frames);
+
+        let (queued, target) = (state.data.len() / state.block_align, state.target_frames.max(1));
+        if queued > target * 2 {
+            let drop_frames = queued - target;
+            let block_align = state.block_align;
+            state.data.drain(..drop_frames * block_align);
+            state.dropped_frames += drop_frames as u64;
+        }
+    }
+
+    /// Fills `buffer` from the queue, the rest stays silent. Returns false while nothing was captured yet.
+    pub(crate) fn pop(&self, buffer: &mut [u8]) -> bool {
+        let mut state = self.lock();
+        if state.captured.frames == 0 || state.block_align == 0 {
+            return false;
+        }
+        let len = state.data.len().min(buffer.len());
+        for (dst, src) in buffer.iter_mut().zip(state.data.drain(..len)) {
+            *dst = src;
+        }
+        buffer[len..].fill(0);
+        if len < buffer.len() {
+            state.underruns += 1;
+        }
+        let frames = buffer.len() / state.block_align;
+        state.rendered.add(frames);
+        true
+    }
+
+    pub(crate) fn metrics(&self) -> ListenMetrics {
+        let state = self.lock();
+        let queued_frames = state.data.len().checked_div(state.block_align).unwrap_or(0);
+        let drift_ppm = match (state.captured.rate(), state.rendered.rate()) {
+            (Some(captured), Some(rendered)) => (captured / rendered - 1.0) * 1_000_000.0,
+            _ => 0.0,
+        };
+        ListenMetrics {
+            latency: Duration::from_nanos(queued_frames as u64 * 1_000_000_000 / state.sample_rate.max(1) as u64),
+            drift_ppm,
+            underruns: state.underruns,
+            dropped_frames: state.dropped_frames,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample_format::FormatTag;
+
+    #[test]
+    fn bounded_queue() {
+        // One frame per millisecond, one byte per frame
+        let queue = ListenQueue::default();
+        queue.configure(&SampleFormat::new(FormatTag::WaveFormatPcm, 1, 1000, 8), Duration::from_millis(4));
+
+        let mut buffer = [9u8; 3];
+        // Nothing captured yet, the output stays silent
+        assert!(!queue.pop(&mut buffer));
+
+        queue.push(&[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        // More than twice the target was queued, the oldest frames are dropped down to the target
+        assert_eq!(queue.metrics().get_dropped_frames(), 5);
+        assert_eq!(queue.metrics().get_latency(), Duration::from_millis(4));
+
+        assert!(queue.pop(&mut buffer));
+        assert_eq!(buffer, [6, 7, 8]);
+        assert!(queue.pop(&mut buffer));
+        assert_eq!(buffer, [9, 0, 0]);
+        assert_eq!(queue.metrics().get_underruns(), 1);
+    }
+}
@@ -1,25 +1,46 @@
-use std::{ffi::OsString, ops::Deref, os::windows::ffi::OsStrExt, string::FromUtf16Error};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{cell::OnceCell, collections::HashMap, ops::Deref, string::FromUtf16Error};
+
+use log::debug;
 
 use thiserror::Error;
 use windows::Win32::{
-    Devices::Properties,
-    Foundation::{self, GetLastError, S_FALSE, S_OK},
+    Foundation::{APPMODEL_ERROR_NO_PACKAGE, ERROR_INSUFFICIENT_BUFFER, S_FALSE, S_OK},
     Media::Audio::{
         AUDCLNT_E_UNSUPPORTED_FORMAT, AUDCLNT_SHAREMODE_SHARED, AudioSessionStateActive, AudioSessionStateExpired,
-        AudioSessionStateInactive, DEVICE_STATE_ACTIVE, EDataFlow, IAudioSessionControl, IAudioSessionControl2, IAudioSessionEnumerator,
-        IAudioSessionManager2, IMMDevice, IMMDeviceCollection, IMMDeviceEnumerator, MMDeviceEnumerator, WAVEFORMATEX, eCapture, eConsole,
-        eRender,
+        AudioSessionStateInactive, DEVICE_STATE, DEVICE_STATE_ACTIVE, EDataFlow, IAudioSessionControl, IAudioSessionControl2,
+        IAudioSessionEnumerator, IAudioSessionManager2, IMMDevice, IMMDeviceCollection, IMMDeviceEnumerator, IMMEndpoint,
+        ISimpleAudioVolume, MMDeviceEnumerator, PKEY_AudioEndpoint_FormFactor, WAVEFORMATEX, eCapture, eCommunications, eConsole, eRender,
     },
-    Storage::FileSystem::QueryDosDeviceW,
+    Storage::Packaging::Appx::GetPackageFamilyName,
     System::{
-        Com::{self, CLSCTX_ALL, CoCreateInstance, STGM_READ},
-        Variant::VT_LPWSTR,
+        Com::{self, CLSCTX_ALL, CoCreateInstance},
+        Threading::{OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION},
     },
 };
-use windows_core::{Interface, PCWSTR, PWSTR};
-
-use crate::audio_client::PWSTRWrapper;
-use crate::{com::com_initialized, event_args::DeviceState, sample_format::SampleFormat};
+use windows_core::{GUID, HSTRING, Interface, PWSTR};
+
+use crate::audio_client::{EventHandleWrapper, PWSTRWrapper, io_error_kind};
+use crate::com::map_parallel;
+use crate::device_query::{DataFlow, DeviceQuery, DeviceRole, FormFactor};
+use crate::diagnostics::{ObjectKind, Tracked};
+use crate::engine_period::EnginePeriods;
+use crate::event_context::EventContext;
+use crate::icon_location::IconLocation;
+use crate::path_resolver::PathResolver;
+use crate::property_store::DevicePropertyStore;
+use crate::session_manager_cache::SessionManagerCache;
+use crate::session_name::{self, SessionNameInput, SessionNameParser};
+use crate::stable_key::{DeviceKey, KeyMatch, SessionKey};
+use crate::volume_ramp::{self, VolumeRamp};
+use crate::{
+    com::com_initialized,
+    device_state::DeviceState,
+    sample_format::{SampleFormat, WaveFormat},
+};
 
 #[derive(Error, Debug)]
 pub enum AudioError {
@@ -45,6 +66,10 @@ pub enum AudioError {
     GetStateError(windows::core::Error),
     #[error("Failed getting icon path: {0}")]
     IconPathError(windows::core::Error),
+    #[error("Failed accessing grouping param: {0}")]
+    GroupingParamError(windows::core::Error),
+    #[error("Failed accessing session volume: {0}")]
+    VolumeError(windows::core::Error),
     #[error("Failed parsing raw utf16 string: {0}")]
     RawStringParseError(FromUtf16Error),
     #[error("Session not found")]
@@ -53,10 +78,14 @@ pub enum AudioError {
     SessionNotFound,
     #[error("Failed reading from property store: {0}")]
     PropertyStoreError(windows::core::Error),
+    #[error("Failed writing to property store: {0}")]
+    PropertyWriteError(windows::core::Error),
     #[error("Read invalid prop variant")]
     InvalidPropVariant,
     #[error("Failed getting mix format: {0}")]
     FailedGettingMixFormat(windows::core::Error),
+    #[error("Failed getting engine periods: {0}")]
+    FailedGettingEnginePeriods(windows::core::Error),
     #[error("Failed reading closest format match")]
     FailedReadingClosestFormatMatch,
     #[error("Failed getting volume path name: {0}")]
@@ -67,6 +96,61 @@ pub enum AudioError {
     FailedGettingDosPath(u32),
     #[error("Failed getting nt path: {0}")]
     FailedGettingNtPath(u32),
+    #[error("Failed opening process: {0}")]
+    FailedOpeningProcess(windows::core::Error),
+    #[error("Failed getting package family name: {0}")]
+    FailedGettingPackageFamilyName(u32),
+    #[error("Unexpected session identifier: {0}")]
+    InvalidSessionIdentifier(String),
+    #[error("Failed passing session to another thread: {0}")]
+    AgileReferenceError(windows::core::Error),
+    #[error("Failed creating thread")]
+    FailedToCreateThread,
+}
+
+impl AudioError {
+    pub fn windows_error(&self) -> Option<&windows::core::Error> {
+        match self {
+            AudioError::DeviceEnumError(err) => err.windows_error(),
+            AudioError::DeviceError(err)
+            | AudioError::DeviceActivationError(err)
+            | AudioError::SessionEnumeratorError(err)
+            | AudioError::SessionCountError(err)
+            | AudioError::SessionError(err)
+            | AudioError::SessionCastError(err)
+            | AudioError::ProcessIdError(err)
+            | AudioError::DisplayNameError(err)
+            | AudioError::GetStateError(err)
+            | AudioError::IconPathError(err)
+            | AudioError::GroupingParamError(err)
+            | AudioError::VolumeError(err)
+            | AudioError::GetSessionError(err)
+            | AudioError::PropertyStoreError(err)
+            | AudioError::PropertyWriteError(err)
+            | AudioError::FailedGettingMixFormat(err)
+            | AudioError::FailedGettingEnginePeriods(err)
+            | AudioError::FailedGettingVolumePathName(err)
+            | AudioError::FailedOpeningProcess(err)
+            | AudioError::AgileReferenceError(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    pub fn hresult(&self) -> Option<windows::core::HRESULT> {
+        self.windows_error().map(|err| err.code())
+    }
+}
+
+impl From<AudioError> for io::Error {
+    fn from(err: AudioError) -> Self {
+        let kind = match &err {
+            AudioError::SessionNotFound => io::ErrorKind::NotFound,
+            AudioError::InvalidPath | AudioError::InvalidSessionIdentifier(_) => io::ErrorKind::InvalidInput,
+            AudioError::RawStringParseError(_) | AudioError::InvalidPropVariant => io::ErrorKind::InvalidData,
+            _ => io_error_kind(err.hresult()),
+        };
+        io::Error::new(kind, err)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -77,6 +161,8 @@ pub struct Session {
     is_system: bool,
     session: IAudioSessionControl2,
     session1: IAudioSessionControl,
+    device: Device,
+    _tracked: Tracked,
 }
 
 impl PartialEq for Session {
@@ -85,6 +171,39 @@ impl PartialEq for Session {
     }
 }
 
+/// A [`Session`] that can be handed to another thread, which resolves it into a session of its own apartment
+#[cfg(feature = "notifications")]
+#[derive(Debug, Clone)]
+pub(crate) struct AgileSession {
+    name: String,
+    session: windows_core::AgileReference<IAudioSessionControl2>,
+    device: Device,
+}
+
+#[cfg(feature = "notifications")]
+impl AgileSession {
+    pub(crate) fn new(session: &Session) -> Result<Self, AudioError> {
+        Ok(Self {
+            name: session.name.clone(),
+            session: windows_core::AgileReference::new(&session.session).map_err(AudioError::AgileReferenceError)?,
+            device: session.device.clone(),
+        })
+    }
+
+    pub(crate) fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    pub(crate) fn get_device(&self) -> &Device {
+        &self.device
+    }
+
+    pub(crate) fn resolve(&self) -> Result<Session, AudioError> {
+        let session = self.session.resolve().map_err(AudioError::AgileReferenceError)?;
+        Session::from_session(session, self.device.clone())
+    }
+}
+
 impl Session {
     pub fn get_name(&self) -> &String {
         &self.name
@@ -106,14 +225,27 @@ impl Session {
         &self.session
     }
 
-    pub(crate) fn from_session(session: IAudioSessionControl2) -> Result<Self, AudioError> {
+    /// The endpoint the session was enumerated from, i.e. the output the application plays on
+    pub fn get_device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn get_device_id(&self) -> Result<String, AudioError> {
+        self.device.get_id()
+    }
+
+    pub(crate) fn from_session(session: IAudioSessionControl2, device: Device) -> Result<Self, AudioError> {
         let pid = unsafe { session.GetProcessId() }.map_err(AudioError::ProcessIdError)?;
         let name_pwstr = unsafe { session.GetSessionInstanceIdentifier().map_err(AudioError::DisplayNameError)? };
         let name_pwstr = PWSTRWrapper(name_pwstr);
         let name = unsafe { name_pwstr.0.to_string() }.map_err(AudioError::RawStringParseError)?;
-        let process_name = Self::parse_process_name(&name);
         let is_system = unsafe { session.IsSystemSoundsSession() };
         let session1 = session.cast::<IAudioSessionControl>().map_err(AudioError::SessionCastError)?;
+        let process_name = session_name::parse_name(&SessionNameInput {
+            identifier: &name,
+            pid,
+            control: &session1,
+        });
         Ok(Self {
             name,
             process_name,
@@ -121,15 +253,11 @@ impl Session {
             is_system: is_system == S_OK,
             session,
             session1,
+            device,
+            _tracked: Tracked::new(ObjectKind::Session),
         })
     }
 
-    /// Try to parse process name from the session identifier
-    /// This is not a good idea, since the session identifier is not guaranteed to be in the same format
-    fn parse_process_name(name_string: &String) -> Option<String> {
-        Some(name_string.split_once('|')?.1.split_once('%')?.0.into())
-    }
-
     pub fn get_display_name(&self) -> Result<String, AudioError> {
         let display_name = unsafe { self.session1.GetDisplayName() }.map_err(AudioError::DisplayNameError)?;
         let display_name = PWSTRWrapper(display_name);
@@ -141,11 +269,259 @@ impl Session {
         Ok(state.into())
     }
 
+    /// DOS path of the session's executable, e.g. `C:\Program Files\App\app.exe`
+    pub fn get_process_path(&self) -> Result<String, AudioError> {
+        get_dos_path(self.process_name.as_ref().ok_or(AudioError::InvalidPath)?)
+    }
+
+    pub fn get_muted(&self) -> Result<bool, AudioError> {
+        let volume = self.session.cast::<ISimpleAudioVolume>().map_err(AudioError::VolumeError)?;
+        Ok(unsafe { volume.GetMute() }.map_err(AudioError::VolumeError)?.as_bool())
+    }
+
+    /// Mutes the session under [`EventContext::process_default`]
+    pub fn set_muted(&self, muted: bool) -> Result<(), AudioError> {
+        self.set_muted_with_context(muted, &EventContext::process_default())
+    }
+
+    /// Mutes the session, the resulting volume event carries `context`
+    pub fn set_muted_with_context(&self, muted: bool, context: &EventContext) -> Result<(), AudioError> {
+        let volume = self.session.cast::<ISimpleAudioVolume>().map_err(AudioError::VolumeError)?;
+        unsafe { volume.SetMute(muted, context.as_ptr()) }.map_err(AudioError::VolumeError)
+    }
+
+    /// Session volume from `0.0` to `1.0`
+    pub fn get_volume(&self) -> Result<f32, AudioError> {
+        let volume = self.session.cast::<ISimpleAudioVolume>().map_err(AudioError::VolumeError)?;
+        unsafe { volume.GetMasterVolume() }.map_err(AudioError::VolumeError)
+    }
+
+    /// Sets the session volume from `0.0` to `1.0` under [`EventContext::process_default`]
+    pub fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+        self.set_volume_with_context(volume, &EventContext::process_default())
+    }
+
+    /// Sets the session volume from `0.0` to `1.0`, the resulting volume event carries `context`
+    pub fn set_volume_with_context(&self, volume: f32, context: &EventContext) -> Result<(), AudioError> {
+        let simple_volume = self.session.cast::<ISimpleAudioVolume>().map_err(AudioError::VolumeError)?;
+        unsafe { simple_volume.SetMasterVolume(volume, context.as_ptr()) }.map_err(AudioError::VolumeError)
+    }
+
+    /// Fades the session volume from where it is to `target` over `duration`, under [`EventContext::process_default`]
+    ///
+    /// The fade runs in the background, the returned handle cancels it or waits for it to finish. The volume is read
+    /// and set on the ramp thread, failing there finishes the ramp with [`RampOutcome::Failed`](crate::volume_ramp::RampOutcome::Failed).
+    pub fn ramp_volume(&self, target: f32, duration: Duration) -> Result<VolumeRamp, AudioError> {
+        volume_ramp::start(
+            self.name.clone(),
+            self.session.clone(),
+            target,
+            duration,
+            EventContext::process_default(),
+        )
+    }
+
+    /// Sets the name shown in the volume mixer under [`EventContext::process_default`]
+    pub fn set_display_name(&self, display_name: &str) -> Result<(), AudioError> {
+        self.set_display_name_with_context(display_name, &EventContext::process_default())
+    }
+
+    /// Sets the name shown in the volume mixer, the resulting display name event carries `context`
+    pub fn set_display_name_with_context(&self, display_name: &str, context: &EventContext) -> Result<(), AudioError> {
+        let display_name = HSTRING::from(display_name);
+        unsafe { self.session1.SetDisplayName(&display_name, context.as_ptr()) }.map_err(AudioError::DisplayNameError)
+    }
+
+    /// Identity of the session that stays the same across restarts of the application, for persisting per app settings
+    pub fn get_stable_key(&self) -> Result<SessionKey, AudioError> {
+        let identifier = unsafe { self.session.GetSessionIdentifier() }.map_err(AudioError::DisplayNameError)?;
+        let identifier = PWSTRWrapper(identifier);
+        let identifier = unsafe { identifier.0.to_string() }.map_err(AudioError::RawStringParseError)?;
+        SessionKey::from_identifier(&identifier, |nt_path| get_dos_path(nt_path).unwrap_or_else(|_| nt_path.to_string()))
+            .ok_or(AudioError::InvalidSessionIdentifier(identifier))
+    }
+
     pub fn get_icon_path(&self) -> Result<String, AudioError> {
         let icon_path = unsafe { self.session1.GetIconPath() }.map_err(AudioError::IconPathError)?;
         let icon_path = PWSTRWrapper(icon_path);
         Ok(unsafe { icon_path.0.to_string() }.unwrap())
     }
+
+    /// Sessions with the same grouping param are shown as one entry in the volume mixer, see
+    /// [`AppGroups`](crate::app_group::AppGroups). Zero if the application didn't set one.
+    pub fn get_grouping_param(&self) -> Result<GUID, AudioError> {
+        unsafe { self.session1.GetGroupingParam() }.map_err(AudioError::GroupingParamError)
+    }
+
+    /// Moves the session into the group of `grouping_param` under [`EventContext::process_default`]
+    pub fn set_grouping_param(&self, grouping_param: &GUID) -> Result<(), AudioError> {
+        self.set_grouping_param_with_context(grouping_param, &EventContext::process_default())
+    }
+
+    /// Moves the session into the group of `grouping_param`, the resulting grouping event carries `context`
+    pub fn set_grouping_param_with_context(&self, grouping_param: &GUID, context: &EventContext) -> Result<(), AudioError> {
+        unsafe { self.session1.SetGroupingParam(grouping_param, context.as_ptr()) }.map_err(AudioError::GroupingParamError)
+    }
+
+    /// [`Session::get_icon_path`] parsed and with the environment variables expanded, `None` if the session has no icon
+    pub fn get_icon_location(&self) -> Result<Option<IconLocation>, AudioError> {
+        Ok(IconLocation::parse(&self.get_icon_path()?))
+    }
+}
+
+/// One session of an application on a specific device
+#[derive(Debug, Clone)]
+pub struct SessionInstance {
+    device: Device,
+    session: Session,
+}
+
+impl SessionInstance {
+    pub fn get_device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn get_session(&self) -> &Session {
+        &self.session
+    }
+}
+
+/// All sessions of a single process across every endpoint, this is what mixer UIs display as one entry
+#[derive(Debug, Clone)]
+pub struct AppSession {
+    pid: u32,
+    process_name: Option<String>,
+    instances: Vec<SessionInstance>,
+}
+
+impl AppSession {
+    pub fn get_pid(&self) -> &u32 {
+        &self.pid
+    }
+
+    pub fn get_process_name(&self) -> &Option<String> {
+        &self.process_name
+    }
+
+    pub fn get_instances(&self) -> &Vec<SessionInstance> {
+        &self.instances
+    }
+}
+
+/// A device together with what a device picker shows about it, see [`DeviceManager::get_devices_annotated`]
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    device: Device,
+    id: String,
+    friendly_name: String,
+    default_render: bool,
+    default_capture: bool,
+    default_communications: bool,
+}
+
+impl DeviceInfo {
+    pub fn get_device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn get_id(&self) -> &String {
+        &self.id
+    }
+
+    pub fn get_friendly_name(&self) -> &String {
+        &self.friendly_name
+    }
+
+    pub fn is_playback(&self) -> bool {
+        self.device.is_playback
+    }
+
+    /// Default render device for the console role
+    pub fn is_default_render(&self) -> bool {
+        self.default_render
+    }
+
+    /// Default capture device for the console role
+    pub fn is_default_capture(&self) -> bool {
+        self.default_capture
+    }
+
+    /// Default device for the communications role of its data flow
+    pub fn is_default_communications(&self) -> bool {
+        self.default_communications
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.default_render || self.default_capture || self.default_communications
+    }
+}
+
+/// Result of [`DeviceManager::enumerate_report`]: the devices that could be read and the ones that could not
+#[derive(Debug)]
+pub struct EnumerationReport {
+    devices: Vec<Device>,
+    failures: Vec<EnumerationFailure>,
+}
+
+impl EnumerationReport {
+    pub fn get_devices(&self) -> &Vec<Device> {
+        &self.devices
+    }
+
+    pub fn get_failures(&self) -> &Vec<EnumerationFailure> {
+        &self.failures
+    }
+
+    /// Whether every endpoint could be read
+    pub fn is_complete(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    pub fn into_devices(self) -> Vec<Device> {
+        self.devices
+    }
+}
+
+/// Step at which reading an endpoint failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EnumerationStage {
+    /// Getting the endpoint out of the device collection
+    Item,
+    Id,
+    FriendlyName,
+}
+
+/// An endpoint that [`DeviceManager::enumerate_report`] could not read
+#[derive(Debug)]
+pub struct EnumerationFailure {
+    flow: DataFlow,
+    index: u32,
+    stage: EnumerationStage,
+    error: AudioError,
+}
+
+impl EnumerationFailure {
+    pub fn get_flow(&self) -> DataFlow {
+        self.flow
+    }
+
+    /// Index of the endpoint in the collection of active endpoints of its data flow
+    pub fn get_index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn get_stage(&self) -> EnumerationStage {
+        self.stage
+    }
+
+    pub fn get_error(&self) -> &AudioError {
+        &self.error
+    }
+
+    /// The HRESULT the driver or the audio service returned, if the failure came from a COM call
+    pub fn hresult(&self) -> Option<windows::core::HRESULT> {
+        self.error.hresult()
+    }
 }
 
 struct WaveFormatExPtr(*mut WAVEFORMATEX);
@@ -177,6 +553,7 @@ pub enum FormatSupport {
 pub struct Device {
     pub(crate) inner: IMMDevice,
     pub(crate) is_playback: bool,
+    _tracked: Tracked,
 }
 
 unsafe impl Send for Device {}
@@ -194,8 +571,38 @@ impl Device {
     }
 
     pub fn get_friendly_name(&self) -> Result<String, AudioError> {
-        let prop_key: *const Foundation::PROPERTYKEY = &Properties::DEVPKEY_Device_FriendlyName as *const _ as *const _;
-        self.read_string_property(prop_key)
+        self.property_store()?.get_friendly_name()
+    }
+
+    pub fn get_form_factor(&self) -> Result<FormFactor, AudioError> {
+        self.property_store()?.get_form_factor()
+    }
+
+    /// Id of the physical device the endpoint belongs to (`DEVPKEY_Device_ContainerId`)
+    pub fn get_container_id(&self) -> Result<GUID, AudioError> {
+        self.property_store()?.get_container_id()
+    }
+
+    /// Properties of the endpoint beyond the typed getters above, e.g. its jack or description
+    pub fn property_store(&self) -> Result<DevicePropertyStore, AudioError> {
+        DevicePropertyStore::open(&self.inner, false)
+    }
+
+    /// Property store opened for writing, which fails with [`AudioError::PropertyWriteError`] for processes that
+    /// aren't elevated
+    pub fn writable_property_store(&self) -> Result<DevicePropertyStore, AudioError> {
+        DevicePropertyStore::open(&self.inner, true)
+    }
+
+    /// Identity of the endpoint that doesn't depend on the display language, for persisting device choices
+    pub fn get_stable_key(&self) -> Result<DeviceKey, AudioError> {
+        Ok(DeviceKey {
+            flow: if self.is_playback { DataFlow::Render } else { DataFlow::Capture },
+            // Virtual endpoints don't always have a container
+            container_id: self.get_container_id().ok(),
+            form_factor: self.property_store()?.get_u32(&PKEY_AudioEndpoint_FormFactor)?,
+            endpoint_id: self.get_id()?,
+        })
     }
 
     pub fn get_mix_format(&self) -> Result<SampleFormat, AudioError> {
@@ -217,11 +624,11 @@ impl Device {
         let audio_client = unsafe { self.inner.Activate::<windows::Win32::Media::Audio::IAudioClient>(CLSCTX_ALL, None) }
             .map_err(AudioError::DeviceActivationError)?;
         let mut closest_match_ptr: *mut WAVEFORMATEX = std::ptr::null_mut();
-        let wave_format: WAVEFORMATEX = format.clone().into();
+        let wave_format = WaveFormat::from(format.clone());
         let hr = unsafe {
             audio_client.IsFormatSupported(
                 AUDCLNT_SHAREMODE_SHARED,
-                &wave_format,
+                wave_format.as_ptr(),
                 Some(&mut closest_match_ptr as *mut *mut WAVEFORMATEX),
             )
         };
@@ -242,21 +649,55 @@ impl Device {
         }
     }
 
-    pub(crate) fn from(dev: IMMDevice, is_playback: bool) -> Self {
-        Self { inner: dev, is_playback }
+    /// Engine periods the device supports for `format`, or for its mix format if `None`. Only formats the engine runs
+    /// at have periods other than the default, and devices without `IAudioClient3` support fail.
+    pub fn get_engine_periods(&self, format: Option<&SampleFormat>) -> Result<EnginePeriods, AudioError> {
+        com_initialized();
+        let audio_client = unsafe { self.inner.Activate::<windows::Win32::Media::Audio::IAudioClient>(CLSCTX_ALL, None) }
+            .map_err(AudioError::DeviceActivationError)?;
+        match format {
+            Some(format) => {
+                let wave_format = WaveFormat::from(format.clone());
+                EnginePeriods::query(&audio_client, wave_format.as_ptr())
+            }
+            // Passed as the client returned it, a round trip through SampleFormat could lose part of it
+            None => {
+                let mix_format = unsafe { audio_client.GetMixFormat() }
+                    .map(WaveFormatExPtr)
+                    .map_err(AudioError::FailedGettingMixFormat)?;
+                EnginePeriods::query(&audio_client, mix_format.0 as *const WAVEFORMATEX)
+            }
+        }
+        .map_err(AudioError::FailedGettingEnginePeriods)
     }
 
-    fn read_string_property(&self, prop_key: *const Foundation::PROPERTYKEY) -> Result<String, AudioError> {
-        let store = unsafe { self.inner.OpenPropertyStore(STGM_READ) }.map_err(AudioError::PropertyStoreError)?;
-        let propvar = unsafe { store.GetValue(prop_key).map_err(AudioError::PropertyStoreError)? };
-        let propvar = unsafe { &propvar.Anonymous.Anonymous };
-        if propvar.vt != VT_LPWSTR {
-            return Err(AudioError::InvalidPropVariant);
+    /// Default roles the device currently serves, an endpoint only ever serves the flow it belongs to
+    pub fn get_current_roles(&self) -> Result<Vec<(DataFlow, DeviceRole)>, AudioError> {
+        com_initialized();
+        let flow = if self.is_playback { DataFlow::Render } else { DataFlow::Capture };
+        let id = self.get_id()?;
+        let enumerator = device_enumerator().map_err(AudioError::DeviceEnumError)?;
+        let mut roles = Vec::new();
+        for role in [DeviceRole::Console, DeviceRole::Multimedia, DeviceRole::Communications] {
+            let Ok(dev) = (unsafe { enumerator.GetDefaultAudioEndpoint(flow.into(), role.into()) }) else {
+                continue;
+            };
+            if Device::from(dev, self.is_playback)
+                .get_id()
+                .is_ok_and(|default_id| default_id == id)
+            {
+                roles.push((flow, role));
+            }
         }
+        Ok(roles)
+    }
 
-        let ptr = unsafe { *(&propvar.Anonymous as *const _ as *const *mut u16) };
-        let str = PWSTR::from_raw(ptr);
-        Ok(unsafe { str.to_string() }.map_err(AudioError::RawStringParseError)?)
+    pub(crate) fn from(dev: IMMDevice, is_playback: bool) -> Self {
+        Self {
+            inner: dev,
+            is_playback,
+            _tracked: Tracked::new(ObjectKind::Device),
+        }
     }
 }
 
@@ -271,6 +712,72 @@ impl PartialEq for Device {
 
 pub struct SessionManager {}
 
+/// Maximum number of threads [`SessionManager::get_sessions`] queries devices on
+pub const SESSION_QUERY_THREADS: usize = 4;
+
+thread_local!(static DEVICE_ENUMERATOR: OnceCell<IMMDeviceEnumerator> = const { OnceCell::new() });
+
+/// The device enumerator of the calling thread, created on first use
+pub(crate) fn device_enumerator() -> Result<IMMDeviceEnumerator, DeviceEnumError> {
+    DEVICE_ENUMERATOR.with(|enumerator| {
+        if let Some(enumerator) = enumerator.get() {
+            return Ok(enumerator.clone());
+        }
+        let created: IMMDeviceEnumerator =
+            unsafe { CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL) }.map_err(DeviceEnumError::InstanceCreation)?;
+        Ok(enumerator.get_or_init(|| created).clone())
+    })
+}
+
+/// Timings of a [`SessionManager::get_sessions_with_report`] call
+#[derive(Debug, Clone, Default)]
+pub struct SessionQueryReport {
+    pub total: Duration,
+    pub threads: usize,
+    pub devices: Vec<DeviceSessionTiming>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceSessionTiming {
+    pub device_id: String,
+    /// True if the session manager came from the cache instead of being activated
+    pub cached_manager: bool,
+    pub activation: Duration,
+    pub enumeration: Duration,
+    pub sessions: usize,
+}
+
+/// Returns the session manager of the device, activating it only if it isn't cached yet
+fn session_manager(device: &IMMDevice, device_id: &str) -> Result<(IAudioSessionManager2, bool), AudioError> {
+    SessionManagerCache::get(device, device_id).map_err(AudioError::DeviceActivationError)
+}
+
+fn query_device_sessions(device: &IMMDevice) -> Result<(Vec<IAudioSessionControl2>, DeviceSessionTiming), AudioError> {
+    let device_id = Device::from(device.clone(), true).get_id()?;
+    let start = Instant::now();
+    let (mgr, cached_manager) = session_manager(device, &device_id)?;
+    let activation = start.elapsed();
+    let sessions = match AudioSessions::from_manager(&mgr) {
+        Ok(sessions) => sessions,
+        // The cached manager may belong to a device that went away and came back, retry with a fresh one
+        Err(_) if cached_manager => {
+            SessionManagerCache::invalidate(&device_id);
+            let (mgr, _) = session_manager(device, &device_id)?;
+            AudioSessions::from_manager(&mgr)?
+        }
+        Err(err) => return Err(err),
+    };
+    let sessions: Vec<_> = sessions.collect();
+    let timing = DeviceSessionTiming {
+        device_id,
+        cached_manager,
+        activation,
+        enumeration: start.elapsed() - activation,
+        sessions: sessions.len(),
+    };
+    Ok((sessions, timing))
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AudioSessionState {
     AudioSessionStateInactive,
@@ -278,6 +785,33 @@ pub enum AudioSessionState {
     AudioSessionStateExpired,
 }
 
+/// Which sessions [`SessionManager::get_sessions_filtered`] returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SessionStateFilter {
+    /// Sessions that are currently playing
+    ActiveOnly,
+    /// Everything except expired sessions, i.e. what the Windows volume mixer shows
+    ActiveAndInactive,
+    /// Every session, without querying the state
+    #[default]
+    All,
+}
+
+impl SessionStateFilter {
+    fn matches(&self, session: &IAudioSessionControl2) -> bool {
+        if *self == SessionStateFilter::All {
+            return true;
+        }
+        // Sessions whose state can't be read are treated as expired
+        let state = unsafe { session.GetState() }.unwrap_or(AudioSessionStateExpired);
+        match self {
+            SessionStateFilter::ActiveOnly => state == AudioSessionStateActive,
+            SessionStateFilter::ActiveAndInactive => state != AudioSessionStateExpired,
+            SessionStateFilter::All => true,
+        }
+    }
+}
+
 impl From<windows::Win32::Media::Audio::AudioSessionState> for AudioSessionState {
     #[allow(non_upper_case_globals)]
     fn from(state: windows::Win32::Media::Audio::AudioSessionState) -> Self {
@@ -293,20 +827,132 @@ impl From<windows::Win32::Media::Audio::AudioSessionState> for AudioSessionState
 impl SessionManager {
     /// Queries all active audio sessions
     pub fn get_sessions() -> Result<Vec<Session>, AudioError> {
+        Self::get_sessions_filtered(SessionStateFilter::All)
+    }
+
+    /// Queries the audio sessions whose state passes `filter`, e.g. to hide long expired entries in a mixer
+    pub fn get_sessions_filtered(filter: SessionStateFilter) -> Result<Vec<Session>, AudioError> {
+        Self::get_sessions_with_report_filtered(filter).map(|(sessions, _)| sessions)
+    }
+
+    /// Sessions of a single playback or capture device that pass `filter`, system sessions left out like in
+    /// [`SessionManager::get_sessions_filtered`]
+    #[cfg(feature = "notifications")]
+    pub(crate) fn get_device_sessions(device: &Device, filter: SessionStateFilter) -> Result<Vec<Session>, AudioError> {
         com_initialized();
-        let dev_collection = Devices::new(eRender).map_err(AudioError::DeviceEnumError)?;
+        let (sessions, _) = query_device_sessions(&device.inner)?;
+        sessions
+            .into_iter()
+            .filter(|session| filter.matches(session))
+            .map(|session| Session::from_session(session, device.clone()))
+            .filter(|session| !matches!(session, Ok(session) if *session.is_system()))
+            .collect()
+    }
+
+    /// Same as [`SessionManager::get_sessions`], also reporting how long each device took
+    ///
+    /// Devices are queried in parallel on up to [`SESSION_QUERY_THREADS`] threads, and the session manager of every
+    /// device is cached, so refreshing a mixer only pays for the activation once per device.
+    pub fn get_sessions_with_report() -> Result<(Vec<Session>, SessionQueryReport), AudioError> {
+        Self::get_sessions_with_report_filtered(SessionStateFilter::All)
+    }
+
+    /// Same as [`SessionManager::get_sessions_filtered`], also reporting how long each device took
+    pub fn get_sessions_with_report_filtered(filter: SessionStateFilter) -> Result<(Vec<Session>, SessionQueryReport), AudioError> {
+        com_initialized();
+        let start = Instant::now();
+        let devices: Vec<IMMDevice> = Devices::new(eRender).map_err(AudioError::DeviceEnumError)?.collect();
+        let threads = devices.len().clamp(1, SESSION_QUERY_THREADS);
+
+        let results = map_parallel(devices, threads, |dev| {
+            let (sessions, timing) = query_device_sessions(dev)?;
+            let sessions = sessions
+                .into_iter()
+                .filter(|session| filter.matches(session))
+                .map(|session| Session::from_session(session, Device::from(dev.clone(), true)))
+                .filter(|session| !matches!(session, Ok(session) if *session.is_system()))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok((sessions, timing))
+        });
 
         let mut processes = Vec::new();
-        for dev in dev_collection {
-            let sessions = AudioSessions::new(dev)?;
-            for session in sessions {
-                let s = Session::from_session(session)?;
-                if !s.is_system() {
-                    processes.push(s);
+        let mut report = SessionQueryReport {
+            threads,
+            ..Default::default()
+        };
+        for result in results {
+            let (sessions, timing) = result?;
+            processes.extend(sessions);
+            report.devices.push(timing);
+        }
+        report.total = start.elapsed();
+        debug!(
+            "Queried {} sessions on {} devices in {:?} using {} threads",
+            processes.len(),
+            report.devices.len(),
+            report.total,
+            threads
+        );
+        Ok((processes, report))
+    }
+
+    /// Finds the process name of every session created from now on with `parser`, see [`crate::session_name`]
+    /// Sessions that were already created keep their name
+    pub fn set_name_parser(parser: impl SessionNameParser) {
+        session_name::set_name_parser(Some(Arc::new(parser)));
+    }
+
+    /// Goes back to the default [`InstanceIdentifierParser`](crate::session_name::InstanceIdentifierParser)
+    pub fn reset_name_parser() {
+        session_name::set_name_parser(None);
+    }
+
+    /// Lazily goes through the sessions of every active render device, like [`SessionManager::get_sessions`] without the
+    /// system sounds session. Devices are queried one after the other once the iterator gets to them, instead of in
+    /// parallel up front.
+    pub fn iter_sessions() -> Result<SessionIter, AudioError> {
+        Ok(SessionIter {
+            devices: DeviceManager::iter_devices(DataFlow::Render).map_err(AudioError::DeviceEnumError)?,
+            current: None,
+        })
+    }
+
+    /// Drops the cached session managers, e.g. after devices were removed
+    pub fn clear_cache() {
+        SessionManagerCache::clear();
+    }
+
+    /// Queries all active audio sessions on render and capture devices, merging the sessions of the same process into one entry
+    pub fn get_sessions_deduplicated() -> Result<Vec<AppSession>, AudioError> {
+        com_initialized();
+        let mut apps: Vec<AppSession> = Vec::new();
+        let mut app_index: HashMap<u32, usize> = HashMap::new();
+        for (flow, is_playback) in [(eRender, true), (eCapture, false)] {
+            let dev_collection = Devices::new(flow).map_err(AudioError::DeviceEnumError)?;
+            for dev in dev_collection {
+                let device = Device::from(dev, is_playback);
+                let sessions = AudioSessions::new(device.inner.clone())?;
+                for session in sessions {
+                    let session = Session::from_session(session, device.clone())?;
+                    if *session.is_system() {
+                        continue;
+                    }
+                    let index = *app_index.entry(session.pid).or_insert_with(|| {
+                        apps.push(AppSession {
+                            pid: session.pid,
+                            process_name: session.process_name.clone(),
+                            instances: Vec::new(),
+                        });
+                        apps.len() - 1
+                    });
+                    apps[index].instances.push(SessionInstance {
+                        device: device.clone(),
+                        session,
+                    });
                 }
             }
         }
-        Ok(processes)
+        Ok(apps)
     }
 
     pub fn session_from_id(searched_id: &str) -> Result<Session, AudioError> {
@@ -315,7 +961,7 @@ impl SessionManager {
         // It's still plenty fast, so it's not a big deal (on the order of tenths of microseconds)
         for dev in dev_collection {
             let dev: Device = Device::from(dev, true);
-            let sessions = AudioSessions::new(dev.inner)?;
+            let sessions = AudioSessions::new(dev.inner.clone())?;
             for session in sessions {
                 let id = unsafe {
                     session
@@ -325,7 +971,7 @@ impl SessionManager {
                         .map_err(AudioError::RawStringParseError)?
                 };
                 if id == searched_id {
-                    return Ok(Session::from_session(session)?);
+                    return Ok(Session::from_session(session, dev)?);
                 }
             }
         }
@@ -333,65 +979,40 @@ impl SessionManager {
     }
 }
 
-const MAX_PATH_LEN: usize = 1024;
-/// Gets the NT path (\\Device\\HarddiskVolumeX\\...)
+/// Gets the NT path (\\Device\\HarddiskVolumeX\\...), see [`PathResolver::to_nt_path`]
 pub fn get_nt_path(path: &str) -> Result<String, AudioError> {
-    let (drive_letter, path) = path.split_once(":\\").ok_or(AudioError::InvalidPath)?;
-    let drive_letter = format!("{}:\0", drive_letter);
-    let drive_letter_u16 = drive_letter.encode_utf16().collect::<Vec<u16>>();
-    let drive_letter_wc = PCWSTR::from_raw(drive_letter_u16.as_ptr());
-    let mut target_path_u16 = vec![0u16; MAX_PATH_LEN];
-    let res = unsafe { QueryDosDeviceW(drive_letter_wc, Some(&mut target_path_u16)) };
-    if res == 0 {
-        let err = unsafe { GetLastError() };
-        return Err(AudioError::FailedGettingNtPath(err.0));
-    }
-    let target_path_u16 = target_path_u16.into_iter().take_while(|&c| c != 0).collect::<Vec<u16>>(); // Take the first null terminated string
-    let target_path = String::from_utf16(&target_path_u16).map_err(AudioError::RawStringParseError)?;
-    Ok(format!("{}\\{}", target_path, path))
-}
-
-/// Convert the NT path to dos path by mapping drive letters
+    let path = PathResolver::shared().to_nt_path(Path::new(path))?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// Convert the NT path to dos path by mapping drive letters, see [`PathResolver::to_dos_path`]
 /// e.g. \\Device\\HarddiskVolume3\\... -> D:\...
 pub fn get_dos_path(path: &str) -> Result<String, AudioError> {
-    if !path.starts_with("\\Device\\") {
-        return Err(AudioError::InvalidPath);
-    }
-
-    let parts: Vec<&str> = path.split('\\').collect();
-    if parts.len() >= 3 {
-        let device_name = parts[2]; // e.g., "HarddiskVolume3"
-
-        for drive_letter in b'A'..=b'Z' {
-            let dos_device = format!("{}:", drive_letter as char);
-            let mut buffer = [0u16; 512];
-
-            let wide_dos_device: Vec<u16> = OsString::from(dos_device)
-                .as_os_str()
-                .encode_wide()
-                .chain(std::iter::once(0))
-                .collect();
-
-            let length = unsafe { QueryDosDeviceW(windows_core::PCWSTR(wide_dos_device.as_ptr()), Some(&mut buffer)) };
-
-            if length > 0 {
-                let device_path = String::from_utf16(&buffer[..length as usize - 1]).map_err(AudioError::RawStringParseError)?;
+    let path = PathResolver::shared().to_dos_path(path)?;
+    Ok(path.to_string_lossy().into_owned())
+}
 
-                // Check if this device contains our target device name
-                if device_path.contains(device_name) {
-                    // Reconstruct the DOS path
-                    if parts.len() > 2 {
-                        let remaining_path: Vec<&str> = parts[3..].to_vec();
-                        return Ok(format!("{}:\\{}", drive_letter as char, remaining_path.join("\\")));
-                    } else {
-                        return Ok(format!("{}:\\", drive_letter as char));
-                    }
-                }
-            }
-        }
-        return Err(AudioError::FailedGettingDosPath(2)); // ERROR_FILE_NOT_FOUND
+/// Package family name of a packaged (UWP or MSIX) process, e.g. `Microsoft.ZuneMusic_8wekyb3d8bbwe`
+/// Returns `None` for processes that don't belong to a package
+pub fn get_package_family_name(pid: u32) -> Result<Option<String>, AudioError> {
+    let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid) }.map_err(AudioError::FailedOpeningProcess)?;
+    let process = EventHandleWrapper(process);
+    let mut len = 0u32;
+    let res = unsafe { GetPackageFamilyName(process.0, &mut len, None) };
+    if res == APPMODEL_ERROR_NO_PACKAGE {
+        return Ok(None);
+    }
+    if res != ERROR_INSUFFICIENT_BUFFER {
+        return Err(AudioError::FailedGettingPackageFamilyName(res.0));
+    }
+    let mut name = vec![0u16; len as usize];
+    let res = unsafe { GetPackageFamilyName(process.0, &mut len, Some(PWSTR(name.as_mut_ptr()))) };
+    if res.is_err() {
+        return Err(AudioError::FailedGettingPackageFamilyName(res.0));
     }
-    return Err(AudioError::InvalidPath);
+    // The length includes the null terminator
+    name.truncate(len.saturating_sub(1) as usize);
+    String::from_utf16(&name).map(Some).map_err(AudioError::RawStringParseError)
 }
 
 #[derive(Error, Debug, Clone)]
@@ -406,25 +1027,48 @@ pub enum DeviceEnumError {
     DefaultDeviceError(windows::core::Error),
 }
 
+impl DeviceEnumError {
+    pub fn windows_error(&self) -> Option<&windows::core::Error> {
+        match self {
+            DeviceEnumError::InstanceCreation(err)
+            | DeviceEnumError::EndpointEnumeration(err)
+            | DeviceEnumError::DeviceCountError(err)
+            | DeviceEnumError::DefaultDeviceError(err) => Some(err),
+        }
+    }
+
+    pub fn hresult(&self) -> Option<windows::core::HRESULT> {
+        self.windows_error().map(|err| err.code())
+    }
+}
+
 pub struct DeviceManager {}
 
 impl DeviceManager {
     pub fn get_default_playback_device() -> Result<Device, DeviceEnumError> {
         com_initialized();
-        let enumerator: IMMDeviceEnumerator =
-            unsafe { CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL) }.map_err(DeviceEnumError::InstanceCreation)?;
+        let enumerator = device_enumerator()?;
         let dev = unsafe { enumerator.GetDefaultAudioEndpoint(eRender, eConsole) }.map_err(DeviceEnumError::DefaultDeviceError)?;
         Ok(Device::from(dev, true))
     }
 
     pub fn get_default_input_device() -> Result<Device, DeviceEnumError> {
         com_initialized();
-        let enumerator: IMMDeviceEnumerator =
-            unsafe { CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL) }.map_err(DeviceEnumError::InstanceCreation)?;
+        let enumerator = device_enumerator()?;
         let dev = unsafe { enumerator.GetDefaultAudioEndpoint(eCapture, eConsole) }.map_err(DeviceEnumError::DefaultDeviceError)?;
         Ok(Device::from(dev, false))
     }
 
+    /// Default device of the communications role, the one VoIP applications should use
+    /// `flow` has to be [`DataFlow::Render`] or [`DataFlow::Capture`]
+    pub fn get_default_communications_device(flow: DataFlow) -> Result<Device, DeviceEnumError> {
+        com_initialized();
+        let enumerator = device_enumerator()?;
+        let dev =
+            unsafe { enumerator.GetDefaultAudioEndpoint(flow.into(), eCommunications) }.map_err(DeviceEnumError::DefaultDeviceError)?;
+        Ok(Device::from(dev, flow == DataFlow::Render))
+    }
+
     pub fn get_playback_devices() -> Result<Vec<Device>, DeviceEnumError> {
         com_initialized();
         let dev_collection = Devices::new(eRender)?;
@@ -436,6 +1080,178 @@ impl DeviceManager {
         let dev_collection = Devices::new(eCapture)?;
         Ok(dev_collection.map(|d| Device::from(d, false)).collect())
     }
+
+    /// The endpoint with the given id, in any state
+    pub fn get_device(id: &str) -> Result<Device, AudioError> {
+        com_initialized();
+        let enumerator = device_enumerator().map_err(AudioError::DeviceEnumError)?;
+        let dev = unsafe { enumerator.GetDevice(&HSTRING::from(id)) }.map_err(AudioError::DeviceError)?;
+        let is_playback = is_render_endpoint(&dev)?;
+        Ok(Device::from(dev, is_playback))
+    }
+
+    /// Lazily goes through the active devices of `flow`, a [`Device`] is only created when the iterator gets to it
+    /// e.g. to stop at the first device with a given name
+    pub fn iter_devices(flow: DataFlow) -> Result<DeviceIter, DeviceEnumError> {
+        com_initialized();
+        Ok(DeviceIter {
+            devices: Devices::new(flow.into())?,
+            flow,
+        })
+    }
+
+    /// Finds all devices matching the given query
+    /// e.g. `DeviceQuery::new().flow(DataFlow::Capture).form_factor(FormFactor::Microphone).name_contains("usb")`
+    pub fn find_devices(query: DeviceQuery) -> Result<Vec<Device>, AudioError> {
+        com_initialized();
+        let default_ids = if query.default_roles.is_empty() {
+            Vec::new()
+        } else {
+            Self::get_default_device_ids(query.flow, &query.default_roles).map_err(AudioError::DeviceEnumError)?
+        };
+        let dev_collection = Devices::with_state(query.flow.into(), query.get_state_mask()).map_err(AudioError::DeviceEnumError)?;
+
+        let mut devices = Vec::new();
+        for dev in dev_collection {
+            let is_playback = match query.flow {
+                DataFlow::Render => true,
+                DataFlow::Capture => false,
+                DataFlow::All => is_render_endpoint(&dev)?,
+            };
+            let dev = Device::from(dev, is_playback);
+
+            if !query.default_roles.is_empty() && !default_ids.contains(&dev.get_id()?) {
+                continue;
+            }
+            // An endpoint whose name can't be read, e.g. an unplugged one, doesn't match a name filter
+            if query.name_contains.is_some() && !dev.get_friendly_name().is_ok_and(|name| query.matches_name(&name)) {
+                continue;
+            }
+            if !query.form_factors.is_empty() {
+                let form_factor = match dev.get_form_factor() {
+                    Ok(form_factor) => form_factor,
+                    // Virtual endpoints may not set the key, they aren't of any form factor asked for
+                    Err(AudioError::InvalidPropVariant) => continue,
+                    Err(err) => return Err(err),
+                };
+                if !query.matches_form_factor(form_factor) {
+                    continue;
+                }
+            }
+            devices.push(dev);
+        }
+        Ok(devices)
+    }
+
+    /// Finds the active endpoint a stored key refers to: the endpoint with the same id, or else the only endpoint that
+    /// [`KeyMatch::Likely`] matches. Returns `None` if there is no such endpoint or several likely ones.
+    pub fn find_by_key(key: &DeviceKey) -> Result<Option<Device>, AudioError> {
+        let devices = Self::find_devices(DeviceQuery::new().flow(key.get_flow()))?;
+        let mut likely = Vec::new();
+        for device in devices {
+            // A device whose properties can't be read, e.g. one being removed right now, isn't the one asked for
+            let Ok(device_key) = device.get_stable_key() else {
+                continue;
+            };
+            match key.matches(&device_key) {
+                KeyMatch::Exact => return Ok(Some(device)),
+                KeyMatch::Likely => likely.push(device),
+                KeyMatch::NoMatch => {}
+            }
+        }
+        Ok(if likely.len() == 1 { likely.pop() } else { None })
+    }
+
+    /// All active render and capture devices, each annotated with whether it is a default endpoint
+    /// The defaults are queried once per call instead of once per device, so device pickers can mark "(default)" cheaply
+    pub fn get_devices_annotated() -> Result<Vec<DeviceInfo>, AudioError> {
+        com_initialized();
+        let enumerator = device_enumerator().map_err(AudioError::DeviceEnumError)?;
+        let default_id = |flow: EDataFlow, role: DeviceRole| {
+            let dev = unsafe { enumerator.GetDefaultAudioEndpoint(flow, role.into()) }.ok()?;
+            Device::from(dev, flow == eRender).get_id().ok()
+        };
+        let default_render = default_id(eRender, DeviceRole::Console);
+        let default_capture = default_id(eCapture, DeviceRole::Console);
+        let default_communications = [
+            default_id(eRender, DeviceRole::Communications),
+            default_id(eCapture, DeviceRole::Communications),
+        ];
+
+        let mut devices = Vec::new();
+        for (flow, is_playback) in [(eRender, true), (eCapture, false)] {
+            for dev in Devices::new(flow).map_err(AudioError::DeviceEnumError)? {
+                let device = Device::from(dev, is_playback);
+                let id = device.get_id()?;
+                devices.push(DeviceInfo {
+                    friendly_name: device.get_friendly_name()?,
+                    default_render: default_render.as_ref() == Some(&id),
+                    default_capture: default_capture.as_ref() == Some(&id),
+                    default_communications: default_communications.iter().any(|default| default.as_ref() == Some(&id)),
+                    id,
+                    device,
+                });
+            }
+        }
+        Ok(devices)
+    }
+
+    /// All active render and capture devices, like [`Self::get_playback_devices`] and [`Self::get_capture_devices`], but
+    /// an endpoint that can't be read (e.g. of a misbehaving virtual driver) is listed as a failure instead of failing the
+    /// whole enumeration. Only failing to get the device collections is an error.
+    /// An endpoint counts as readable if its id and friendly name can be read.
+    pub fn enumerate_report() -> Result<EnumerationReport, DeviceEnumError> {
+        com_initialized();
+        let mut report = EnumerationReport {
+            devices: Vec::new(),
+            failures: Vec::new(),
+        };
+        for (flow, is_playback) in [(DataFlow::Render, true), (DataFlow::Capture, false)] {
+            let dev_collection = Devices::new(flow.into())?;
+            for index in 0..dev_collection.dev_count {
+                let failure = |stage, error| EnumerationFailure { flow, index, stage, error };
+                let dev = match dev_collection.item(index) {
+                    Ok(dev) => Device::from(dev, is_playback),
+                    Err(err) => {
+                        report.failures.push(failure(EnumerationStage::Item, AudioError::DeviceError(err)));
+                        continue;
+                    }
+                };
+                if let Err(err) = dev.get_id() {
+                    report.failures.push(failure(EnumerationStage::Id, err));
+                } else if let Err(err) = dev.get_friendly_name() {
+                    report.failures.push(failure(EnumerationStage::FriendlyName, err));
+                } else {
+                    report.devices.push(dev);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Ids of the default devices for the given roles
+    /// Roles without a default device (e.g. no capture device plugged in) are skipped
+    fn get_default_device_ids(flow: DataFlow, roles: &[DeviceRole]) -> Result<Vec<String>, DeviceEnumError> {
+        let enumerator = device_enumerator()?;
+        let flows = match flow {
+            DataFlow::Render => vec![eRender],
+            DataFlow::Capture => vec![eCapture],
+            DataFlow::All => vec![eRender, eCapture],
+        };
+
+        let mut ids = Vec::new();
+        for flow in flows {
+            for role in roles {
+                let Ok(dev) = (unsafe { enumerator.GetDefaultAudioEndpoint(flow, (*role).into()) }) else {
+                    continue;
+                };
+                if let Ok(id) = Device::from(dev, flow == eRender).get_id() {
+                    ids.push(id);
+                }
+            }
+        }
+        Ok(ids)
+    }
 }
 
 // Once again, taken from CPAL, thank you!
@@ -447,10 +1263,13 @@ pub(crate) struct Devices {
 
 impl Devices {
     pub(crate) fn new(dataflow: EDataFlow) -> Result<Self, DeviceEnumError> {
-        let enumerator: IMMDeviceEnumerator =
-            unsafe { CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL) }.map_err(DeviceEnumError::InstanceCreation)?;
+        Self::with_state(dataflow, DEVICE_STATE_ACTIVE)
+    }
+
+    pub(crate) fn with_state(dataflow: EDataFlow, state_mask: DEVICE_STATE) -> Result<Self, DeviceEnumError> {
+        let enumerator = device_enumerator()?;
         let dev_collection =
-            unsafe { enumerator.EnumAudioEndpoints(dataflow, DEVICE_STATE_ACTIVE) }.map_err(DeviceEnumError::EndpointEnumeration)?;
+            unsafe { enumerator.EnumAudioEndpoints(dataflow, state_mask) }.map_err(DeviceEnumError::EndpointEnumeration)?;
         let dev_count = unsafe { dev_collection.GetCount() }.map_err(DeviceEnumError::DeviceCountError)?;
         Ok(Self {
             dev_collection,
@@ -458,6 +1277,10 @@ impl Devices {
             next_index: 0,
         })
     }
+
+    fn item(&self, index: u32) -> windows::core::Result<IMMDevice> {
+        unsafe { self.dev_collection.Item(index) }
+    }
 }
 
 impl Iterator for Devices {
@@ -465,7 +1288,7 @@ impl Iterator for Devices {
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.next_index < self.dev_count {
-            let dev = unsafe { self.dev_collection.Item(self.next_index) }.expect("Failed iterating device");
+            let dev = self.item(self.next_index).expect("Failed iterating device");
             self.next_index += 1;
             Some(dev)
         } else {
@@ -479,6 +1302,74 @@ impl Iterator for Devices {
     }
 }
 
+/// Whether `dev` is a render endpoint
+fn is_render_endpoint(dev: &IMMDevice) -> Result<bool, AudioError> {
+    let endpoint = dev.cast::<IMMEndpoint>().map_err(AudioError::DeviceError)?;
+    Ok(unsafe { endpoint.GetDataFlow() }.map_err(AudioError::DeviceError)? == eRender)
+}
+
+/// Active devices created one at a time, see [`DeviceManager::iter_devices`]
+pub struct DeviceIter {
+    devices: Devices,
+    flow: DataFlow,
+}
+
+impl Iterator for DeviceIter {
+    type Item = Result<Device, AudioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.devices.next_index;
+        if index >= self.devices.dev_count {
+            return None;
+        }
+        self.devices.next_index += 1;
+        let dev = match self.devices.item(index) {
+            Ok(dev) => dev,
+            Err(err) => return Some(Err(AudioError::DeviceError(err))),
+        };
+        let is_playback = match self.flow {
+            DataFlow::Render => Ok(true),
+            DataFlow::Capture => Ok(false),
+            DataFlow::All => is_render_endpoint(&dev),
+        };
+        Some(is_playback.map(|is_playback| Device::from(dev, is_playback)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.devices.size_hint()
+    }
+}
+
+/// Sessions created one at a time, see [`SessionManager::iter_sessions`]
+pub struct SessionIter {
+    devices: DeviceIter,
+    current: Option<(Device, AudioSessions)>,
+}
+
+impl Iterator for SessionIter {
+    type Item = Result<Session, AudioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((device, sessions)) = &mut self.current {
+                match sessions.next().map(|session| Session::from_session(session, device.clone())) {
+                    Some(Ok(session)) if *session.is_system() => continue,
+                    Some(session) => return Some(session),
+                    None => self.current = None,
+                }
+            }
+            let device = match self.devices.next()? {
+                Ok(device) => device,
+                Err(err) => return Some(Err(err)),
+            };
+            match AudioSessions::new(device.inner.clone()) {
+                Ok(sessions) => self.current = Some((device, sessions)),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
 pub(crate) struct AudioSessions {
     session_enum: IAudioSessionEnumerator,
     session_count: i32,
@@ -487,7 +1378,12 @@ pub(crate) struct AudioSessions {
 
 impl AudioSessions {
     pub fn new(device: IMMDevice) -> Result<Self, AudioError> {
-        let mgr = unsafe { device.Activate::<IAudioSessionManager2>(CLSCTX_ALL, None) }.map_err(AudioError::DeviceActivationError)?;
+        let device_id = Device::from(device.clone(), true).get_id()?;
+        let (mgr, _) = session_manager(&device, &device_id)?;
+        Self::from_manager(&mgr)
+    }
+
+    fn from_manager(mgr: &IAudioSessionManager2) -> Result<Self, AudioError> {
         let session_enum = unsafe { mgr.GetSessionEnumerator() }.map_err(AudioError::SessionEnumeratorError)?;
         let session_count = unsafe { session_enum.GetCount() }.map_err(AudioError::SessionCountError)?;
         Ok(Self {
@@ -530,6 +1426,124 @@ mod tests {
         assert!(SessionManager::get_sessions().is_ok());
     }
 
+    #[test]
+    fn test_iterators() {
+        let playback_ids: Vec<String> = DeviceManager::get_playback_devices()
+            .unwrap()
+            .iter()
+            .map(|dev| dev.get_id().unwrap())
+            .collect();
+        let iterated_ids: Vec<String> = DeviceManager::iter_devices(DataFlow::Render)
+            .unwrap()
+            .map(|dev| dev.unwrap().get_id().unwrap())
+            .collect();
+        assert_eq!(iterated_ids, playback_ids);
+        assert!(DeviceManager::iter_devices(DataFlow::All).unwrap().all(|dev| dev.is_ok()));
+
+        let mut session_names: Vec<String> = SessionManager::get_sessions()
+            .unwrap()
+            .iter()
+            .map(|session| session.get_name().clone())
+            .collect();
+        let mut iterated_names: Vec<String> = SessionManager::iter_sessions()
+            .unwrap()
+            .map(|session| session.unwrap().get_name().clone())
+            .collect();
+        session_names.sort();
+        iterated_names.sort();
+        assert_eq!(iterated_names, session_names);
+    }
+
+    #[test]
+    fn test_enumerate_report() {
+        let report = DeviceManager::enumerate_report().unwrap();
+        let read = report.get_devices().len() + report.get_failures().len();
+        let listed = DeviceManager::get_playback_devices().unwrap().len() + DeviceManager::get_capture_devices().unwrap().len();
+        assert_eq!(read, listed);
+        for failure in report.get_failures() {
+            assert!(failure.get_flow() != DataFlow::All);
+        }
+    }
+
+    #[test]
+    fn test_sessions_filtered() {
+        let active = SessionManager::get_sessions_filtered(SessionStateFilter::ActiveOnly).unwrap();
+        assert!(
+            active
+                .iter()
+                .all(|s| s.get_state().unwrap() == AudioSessionState::AudioSessionStateActive)
+        );
+        let listed = SessionManager::get_sessions_filtered(SessionStateFilter::ActiveAndInactive).unwrap();
+        assert!(
+            listed
+                .iter()
+                .all(|s| s.get_state().unwrap() != AudioSessionState::AudioSessionStateExpired)
+        );
+        assert!(active.len() <= listed.len());
+    }
+
+    #[test]
+    fn test_session_device() {
+        let playback_ids: Vec<String> = DeviceManager::get_playback_devices()
+            .unwrap()
+            .iter()
+            .map(|dev| dev.get_id().unwrap())
+            .collect();
+        for session in SessionManager::get_sessions().unwrap() {
+            assert!(session.get_device().is_playback);
+            assert!(playback_ids.contains(&session.get_device_id().unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_package_family_name() {
+        // Test binaries aren't packaged
+        assert_eq!(get_package_family_name(std::process::id()).unwrap(), None);
+        assert!(get_package_family_name(u32::MAX).is_err());
+    }
+
+    #[test]
+    fn test_ramp_volume() {
+        let (playback, _format) = crate::audio_client::AudioClient::new()
+            .start_playback_device(None, |_| 0, |_| {})
+            .unwrap();
+        let _playback = playback.start().unwrap();
+        let session = SessionManager::get_sessions()
+            .unwrap()
+            .into_iter()
+            .find(|session| *session.get_pid() == std::process::id())
+            .unwrap();
+
+        let volume = session.get_volume().unwrap();
+        let ramp = session.ramp_volume(volume / 2.0, Duration::from_millis(50)).unwrap();
+        assert_eq!(ramp.wait(), crate::volume_ramp::RampOutcome::Completed);
+        assert!((session.get_volume().unwrap() - volume / 2.0).abs() < 0.01);
+
+        let ramp = session.ramp_volume(volume, Duration::from_secs(10)).unwrap();
+        ramp.cancel();
+        assert_eq!(ramp.wait(), crate::volume_ramp::RampOutcome::Cancelled);
+        session.set_volume(volume).unwrap();
+    }
+
+    #[test]
+    fn test_sessions_report() {
+        let (sessions, report) = SessionManager::get_sessions_with_report().unwrap();
+        assert!(report.devices.iter().map(|dev| dev.sessions).sum::<usize>() >= sessions.len());
+        // The second query reuses the session managers of the first one
+        let (_, report) = SessionManager::get_sessions_with_report().unwrap();
+        assert!(report.devices.iter().all(|dev| dev.cached_manager));
+    }
+
+    #[test]
+    fn test_sessions_deduplicated() {
+        let apps = SessionManager::get_sessions_deduplicated().unwrap();
+        let mut pids: Vec<u32> = apps.iter().map(|app| *app.get_pid()).collect();
+        pids.sort();
+        pids.dedup();
+        assert_eq!(pids.len(), apps.len());
+        assert!(apps.iter().all(|app| !app.get_instances().is_empty()));
+    }
+
     #[test]
     fn test_default_format() {
         let devs = DeviceManager::get_capture_devices().unwrap();
@@ -538,6 +1552,20 @@ mod tests {
         dev.format_supported(&format.into()).unwrap();
     }
 
+    #[test]
+    fn test_current_roles() {
+        let roles = DeviceManager::get_default_playback_device().unwrap().get_current_roles().unwrap();
+        assert!(roles.contains(&(DataFlow::Render, DeviceRole::Console)));
+        assert!(roles.iter().all(|(flow, _)| *flow == DataFlow::Render));
+    }
+
+    #[test]
+    fn test_find_by_key() {
+        let device = DeviceManager::get_default_playback_device().unwrap();
+        let key: DeviceKey = device.get_stable_key().unwrap().to_string().parse().unwrap();
+        assert_eq!(DeviceManager::find_by_key(&key).unwrap(), Some(device));
+    }
+
     #[test]
     fn test_device() {
         let devs = DeviceManager::get_capture_devices().unwrap();
@@ -545,4 +1573,26 @@ mod tests {
         assert!(dev.get_id().is_ok());
         assert!(dev.get_friendly_name().is_ok());
     }
+
+    #[test]
+    fn test_devices_annotated() {
+        let devices = DeviceManager::get_devices_annotated().unwrap();
+        let default_dev = DeviceManager::get_default_playback_device().unwrap();
+        let default_id = default_dev.get_id().unwrap();
+        let annotated = devices.iter().find(|dev| *dev.get_id() == default_id).unwrap();
+        assert!(annotated.is_default_render() && annotated.is_playback());
+        assert_eq!(devices.iter().filter(|dev| dev.is_default_render()).count(), 1);
+        assert!(devices.iter().filter(|dev| dev.is_default_capture()).all(|dev| !dev.is_playback()));
+    }
+
+    #[test]
+    fn test_find_devices() {
+        let capture_devs = DeviceManager::get_capture_devices().unwrap();
+        let found = DeviceManager::find_devices(DeviceQuery::new().flow(DataFlow::Capture)).unwrap();
+        assert_eq!(capture_devs, found);
+
+        let default_dev = DeviceManager::get_default_playback_device().unwrap();
+        let found = DeviceManager::find_devices(DeviceQuery::new().flow(DataFlow::Render).default_for(DeviceRole::Console)).unwrap();
+        assert_eq!(vec![default_dev], found);
+    }
 }
@@ -0,0 +1,332 @@
+//! Per application mute rules, see [`Mixer`], and group operations over sessions, see [`SessionMixer`].
+//!
+//! A rule mutes every session of an executable, the ones that exist when the rule is set and the ones the application
+//! creates later. The rules only live as long as the mixer, store them through [`Mixer::on_rules_changed`] and restore
+//! them with [`Mixer::with_muted_apps`] to make them permanent.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use log::warn;
+use thiserror::Error;
+
+use crate::com::ComSend;
+use crate::dispatcher::Dispatcher;
+use crate::manager::{AudioError, DeviceManager, Session, SessionManager, SessionStateFilter};
+use crate::notifications::{NotificationError, Notifications};
+use crate::session_notification::SessionCreated;
+
+#[derive(Error, Debug)]
+pub enum MixerError {
+    #[error("Failed setting up session notifications: {0}")]
+    NotificationError(#[source] NotificationError),
+    #[error("Failed enumerating sessions: {0}")]
+    SessionEnumError(#[source] AudioError),
+    #[error("Failed muting session: {0}")]
+    FailedSettingMute(#[source] AudioError),
+    #[error("Failed reading session volume: {0}")]
+    FailedGettingVolume(#[source] AudioError),
+    #[error("Failed setting session volume: {0}")]
+    FailedSettingVolume(#[source] AudioError),
+}
+
+type RulesChangedFn = Box<dyn Fn(&[String]) + Send + Sync + 'static>;
+
+pub struct Mixer {
+    shared: Arc<Shared>,
+    _notifications: ComSend<Notifications>,
+}
+
+#[derive(Default)]
+struct Shared {
+    muted_apps: Mutex<Vec<String>>,
+    on_rules_changed: Mutex<Option<RulesChangedFn>>,
+}
+
+impl Mixer {
+    pub fn new() -> Result<Self, MixerError> {
+        Self::with_muted_apps(Vec::<String>::new())
+    }
+
+    /// Restores previously stored rules, the sessions of these applications are muted right away
+    pub fn with_muted_apps<I, S>(muted_apps: I) -> Result<Self, MixerError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let shared = Arc::new(Shared {
+            muted_apps: Mutex::new(muted_apps.into_iter().map(Into::into).collect()),
+            ..Default::default()
+        });
+        let mut notifications = Notifications::sta_compatible(Dispatcher::inline()).map_err(MixerError::NotificationError)?;
+        let devices =
+            DeviceManager::get_playback_devices().map_err(|err| MixerError::SessionEnumError(AudioError::DeviceEnumError(err)))?;
+        for dev in devices {
+            let weak = Arc::downgrade(&shared);
+            notifications
+                .register_session_notification(dev, move |created: SessionCreated| {
+                    if let Some(shared) = weak.upgrade() {
+                        match created.get_session() {
+                            Ok(session) => shared.session_created(&session),
+                            Err(err) => warn!("Failed resolving new session {}: {}", created.get_name(), err),
+                        }
+                    }
+                })
+                .map_err(MixerError::NotificationError)?;
+        }
+
+        for session in Self::sessions()? {
+            shared.session_created(&session);
+        }
+        Ok(Self {
+            shared,
+            _notifications: ComSend(notifications),
+        })
+    }
+
+    /// Mutes or unmutes every session of the executable at `process_path` and remembers the rule for sessions created
+    /// later, returns the number of sessions that were changed
+    ///
+    /// `process_path` is compared case insensitively against the DOS path (`C:\...`) and the NT path
+    /// (`\Device\HarddiskVolumeX\...`) of the sessions. Unmuting removes the rule.
+    pub fn set_app_muted(&self, process_path: &str, muted: bool) -> Result<usize, MixerError> {
+        let changed = {
+            let mut muted_apps = self.shared.lock_muted_apps();
+            let index = muted_apps.iter().position(|app| app.eq_ignore_ascii_case(process_path));
+            match (index, muted) {
+                (None, true) => muted_apps.push(process_path.to_string()),
+                (Some(index), false) => {
+                    muted_apps.remove(index);
+                }
+                _ => {}
+            }
+            (index.is_some() != muted).then(|| muted_apps.clone())
+        };
+        if let Some(muted_apps) = changed
+            && let Some(callback) = &*self.shared.on_rules_changed.lock().unwrap_or_else(|e| e.into_inner())
+        {
+            callback(&muted_apps);
+        }
+
+        let mut count = 0;
+        for session in Self::sessions()?.iter().filter(|session| session_matches(session, process_path)) {
+            session.set_muted(muted).map_err(MixerError::FailedSettingMute)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Executables with a mute rule, as passed to [`Mixer::set_app_muted`]
+    pub fn get_muted_apps(&self) -> Vec<String> {
+        self.shared.lock_muted_apps().clone()
+    }
+
+    /// Called with every rule after a rule was added or removed, the hook to persist the rules
+    pub fn on_rules_changed<F>(&self, callback: F)
+    where
+        F: Fn(&[String]) + Send + Sync + 'static,
+    {
+        *self.shared.on_rules_changed.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(callback));
+    }
+
+    fn sessions() -> Result<Vec<Session>, MixerError> {
+        SessionManager::get_sessions_filtered(SessionStateFilter::ActiveAndInactive).map_err(MixerError::SessionEnumError)
+    }
+}
+
+/// Mute state and volume of sessions, taken by [`SessionMixer::snapshot`] and put back by [`SessionMixer::restore`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MixerSnapshot {
+    states: Vec<SessionVolumeState>,
+}
+
+impl MixerSnapshot {
+    pub fn get_states(&self) -> &[SessionVolumeState] {
+        &self.states
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionVolumeState {
+    name: String,
+    muted: bool,
+    volume: f32,
+}
+
+impl SessionVolumeState {
+    /// Instance identifier of the session, see [`Session::get_name`]
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    pub fn get_volume(&self) -> f32 {
+        self.volume
+    }
+}
+
+/// Mutes and unmutes a fixed set of sessions together, e.g. everything but a game while it runs
+///
+/// Every group operation returns a snapshot of the state before it, [`SessionMixer::restore`] undoes the operation.
+/// Unlike [`Mixer`] nothing is applied to sessions created later, add them with [`SessionMixer::add_session`].
+#[derive(Debug, Clone, Default)]
+pub struct SessionMixer {
+    sessions: Vec<Session>,
+}
+
+impl SessionMixer {
+    pub fn new(sessions: Vec<Session>) -> Self {
+        Self { sessions }
+    }
+
+    /// Every session on every playback device, except the system sounds session
+    pub fn from_sessions() -> Result<Self, MixerError> {
+        let sessions = Mixer::sessions()?.into_iter().filter(|session| !*session.is_system()).collect();
+        Ok(Self::new(sessions))
+    }
+
+    /// Adds a session unless it is part of the mixer already
+    pub fn add_session(&mut self, session: Session) {
+        if !self.sessions.contains(&session) {
+            self.sessions.push(session);
+        }
+    }
+
+    pub fn remove_session(&mut self, session: &Session) {
+        self.sessions.retain(|s| s != session);
+    }
+
+    pub fn get_sessions(&self) -> &[Session] {
+        &self.sessions
+    }
+
+    pub fn snapshot(&self) -> Result<MixerSnapshot, MixerError> {
+        let states = self
+            .sessions
+            .iter()
+            .map(|session| {
+                Ok(SessionVolumeState {
+                    name: session.get_name().clone(),
+                    muted: session.get_muted().map_err(MixerError::FailedGettingVolume)?,
+                    volume: session.get_volume().map_err(MixerError::FailedGettingVolume)?,
+                })
+            })
+            .collect::<Result<_, MixerError>>()?;
+        Ok(MixerSnapshot { states })
+    }
+
+    /// Mutes every session except those of the process `pid`, which are unmuted. Nothing changes if one of them fails.
+    pub fn mute_all_except(&self, pid: u32) -> Result<MixerSnapshot, MixerError> {
+        self.mute_where(|session| *session.get_pid() != pid)
+    }
+
+    /// Mutes every session except `session`, which is unmuted. Nothing changes if one of them fails.
+    pub fn solo(&self, session: &Session) -> Result<MixerSnapshot, MixerError> {
+        self.mute_where(|s| s != session)
+    }
+
+    /// Puts back the mute state and volume of the sessions in `snapshot` that are still part of the mixer, returns the
+    /// number of sessions restored
+    pub fn restore(&self, snapshot: &MixerSnapshot) -> Result<usize, MixerError> {
+        let mut count = 0;
+        for state in &snapshot.states {
+            let Some(session) = self.sessions.iter().find(|session| *session.get_name() == state.name) else {
+                continue;
+            };
+            session.set_volume(state.volume).map_err(MixerError::FailedSettingVolume)?;
+            session.set_muted(state.muted).map_err(MixerError::FailedSettingMute)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Mutes the sessions `muted` returns `true` for and unmutes the others, nothing is changed if that fails for one
+    fn mute_where(&self, muted: impl Fn(&Session) -> bool) -> Result<MixerSnapshot, MixerError> {
+        let snapshot = self.snapshot()?;
+        for (index, session) in self.sessions.iter().enumerate() {
+            if let Err(err) = session.set_muted(muted(session)) {
+                // All or nothing, the sessions changed so far get their mute state back
+                for (session, state) in self.sessions.iter().zip(&snapshot.states).take(index) {
+                    if let Err(err) = session.set_muted(state.muted) {
+                        warn!("Failed restoring mute state of session {}: {}", session.get_name(), err);
+                    }
+                }
+                return Err(MixerError::FailedSettingMute(err));
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+impl Shared {
+    fn lock_muted_apps(&self) -> MutexGuard<'_, Vec<String>> {
+        self.muted_apps.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn session_created(&self, session: &Session) {
+        let muted = self.lock_muted_apps().iter().any(|app| session_matches(session, app));
+        if muted && let Err(err) = session.set_muted(true) {
+            warn!("Failed muting session {}: {}", session.get_name(), err);
+        }
+    }
+}
+
+fn session_matches(session: &Session, process_path: &str) -> bool {
+    if *session.is_system() {
+        return false;
+    }
+    session
+        .get_process_name()
+        .as_ref()
+        .is_some_and(|nt_path| nt_path.eq_ignore_ascii_case(process_path))
+        || session.get_process_path().is_ok_and(|path| path.eq_ignore_ascii_case(process_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_client::AudioClient;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn mute_own_process() {
+        // Playing audio makes sure this process has a session
+        let (playback, _format) = AudioClient::new().start_playback_device(None, |_| 0, |_| {}).unwrap();
+        let _playback = playback.start().unwrap();
+        let exe = std::env::current_exe().unwrap().to_string_lossy().into_owned();
+
+        let mixer = Mixer::new().unwrap();
+        let (rules_send, rules_recv) = channel();
+        let rules_send = Mutex::new(rules_send);
+        mixer.on_rules_changed(move |rules| rules_send.lock().unwrap().send(rules.to_vec()).unwrap());
+
+        assert!(mixer.set_app_muted(&exe, true).unwrap() > 0);
+        assert_eq!(rules_recv.try_recv().unwrap(), vec![exe.clone()]);
+        mixer.set_app_muted(&exe, false).unwrap();
+        assert!(mixer.get_muted_apps().is_empty());
+    }
+
+    #[test]
+    fn session_mixer() {
+        let (playback, _format) = AudioClient::new().start_playback_device(None, |_| 0, |_| {}).unwrap();
+        let _playback = playback.start().unwrap();
+        // Only this process' sessions, to not touch the rest of the machine
+        let own = Mixer::sessions()
+            .unwrap()
+            .into_iter()
+            .filter(|session| *session.get_pid() == std::process::id())
+            .collect::<Vec<_>>();
+        let mixer = SessionMixer::new(own);
+        let own = mixer.get_sessions()[0].clone();
+
+        let before = mixer.mute_all_except(0).unwrap();
+        assert!(own.get_muted().unwrap());
+        mixer.solo(&own).unwrap();
+        assert!(!own.get_muted().unwrap());
+
+        assert_eq!(mixer.restore(&before).unwrap(), mixer.get_sessions().len());
+        assert_eq!(mixer.snapshot().unwrap(), before);
+    }
+}
@@ -1,5 +1,9 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc::{self};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, string::FromUtf16Error};
 
 use log::trace;
@@ -8,21 +12,33 @@ use windows::Win32::Media::Audio::IAudioSessionControl2;
 use windows::Win32::{
     Foundation::{self, PROPERTYKEY},
     Media::Audio::{
-        DEVICE_STATE, EDataFlow, ERole, IAudioSessionEvents, IAudioSessionEvents_Impl, IMMDeviceEnumerator, IMMNotificationClient,
-        IMMNotificationClient_Impl, MMDeviceEnumerator,
+        AUDIO_VOLUME_NOTIFICATION_DATA, AudioSessionDisconnectReason, AudioSessionState, DEVICE_STATE, EDataFlow, ERole,
+        Endpoints::{IAudioEndpointVolume, IAudioEndpointVolumeCallback, IAudioEndpointVolumeCallback_Impl},
+        IAudioSessionEvents, IAudioSessionEvents_Impl, IMMDeviceEnumerator, IMMNotificationClient, IMMNotificationClient_Impl,
+        MMDeviceEnumerator,
     },
     System::Com::{CLSCTX_ALL, CoCreateInstance},
 };
-use windows_core::{PCWSTR, implement};
+use windows_core::{GUID, HSTRING, PCWSTR, implement};
 
-use crate::com::com_initialized;
+use crate::audio_client::PWSTRWrapper;
+use crate::coalesce::{Coalescing, coalesced};
+use crate::com::{ComSend, MtaWorker, com_initialized};
+use crate::diagnostics::{ObjectKind, Tracked};
+use crate::dispatcher::Dispatcher;
 use crate::event_args::{
     AudioSessionEventArgs, ChannelVolumeChangedArgs, DefaultDeviceChangedEventArgs, DeviceAddedEventArgs, DeviceNotificationEventArgs,
     DevicePropertyValueChangedEventArgs, DeviceRemovedEventArgs, DeviceStateChangedEventArgs, DisplayNameChangedArgs,
-    GroupingParamChangedArgs, IconPathChangedArgs, SessionDisconnectedArgs, SimpleVolumeChangedArgs, StateChangedArgs,
+    EndpointVolumeChangedArgs, GroupingParamChangedArgs, IconPathChangedArgs, SessionDisconnectedArgs, SimpleVolumeChangedArgs,
+    StateChangedArgs, copy_guid, copy_pcwstr,
 };
-use crate::manager::{AudioError, Device, Session};
-use crate::session_notification::{SessionCreated, SessionNotificationCommand, SessionNotificationMessage, session_notification_thread};
+use crate::hooks::{NotificationKind, notification_arrived};
+use crate::manager::{AudioError, Device, DeviceManager, Session, SessionManager, SessionStateFilter};
+use crate::session_notification::{
+    CommandSender, MessageReceiver, SessionCreated, SessionNotificationCommand, SessionNotificationMessage, session_notification_thread,
+};
+#[cfg(feature = "winrt-events")]
+use crate::winrt_events::WinRtRegistration;
 
 #[derive(Error, Debug)]
 pub enum NotificationError {
@@ -42,6 +58,8 @@ pub enum NotificationError {
     FailedSettingUpNotification(windows::core::Error),
     #[error("Failed enumerating devices: {0}")]
     FailedEnumeratingDevices(AudioError),
+    #[error("Failed enumerating sessions: {0}")]
+    FailedEnumeratingSessions(AudioError),
     #[error("Failed activating session manager: {0}")]
     FailedActivatingSessionManager(windows::core::Error),
     #[error("Failed getting device id: {0}")]
@@ -52,42 +70,181 @@ pub enum NotificationError {
     FailedRegisteringSessionNotification,
     #[error("Failed unregistering notification")]
     FailedUnregisteringSessionNotification,
+    #[error("Failed activating endpoint volume: {0}")]
+    EndpointVolumeActivationError(windows::core::Error),
     #[error("Notification thread not running, can't unregister notification")]
     SessionNotificationThreadNotRunning,
+    #[error("Session notification thread exited, recreate the notifications")]
+    NotificationThreadDead,
+    #[error("Session notification thread didn't answer within {0:?}")]
+    NotificationThreadTimeout(Duration),
 }
 
+/// How long a registration waits for the session notification thread by default, see
+/// [`Notifications::set_command_timeout`]
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Notifications {
+    dispatcher: Dispatcher,
+    mta_worker: Option<MtaWorker>,
+    command_timeout: Duration,
     _device_notification_client: Option<(IMMDeviceEnumerator, IMMNotificationClient)>,
+    #[cfg(feature = "winrt-events")]
+    _winrt_registration: Option<WinRtRegistration>,
     _session_event_client: HashMap<String, (IAudioSessionControl2, IAudioSessionEvents)>,
-    _session_notification: Option<(
-        mpsc::Sender<SessionNotificationCommand>,
-        mpsc::Receiver<SessionNotificationMessage>,
-        JoinHandle<()>,
-    )>,
+    _endpoint_volume_client: HashMap<String, (IAudioEndpointVolume, IAudioEndpointVolumeCallback)>,
+    _session_notification: Option<(CommandSender, MessageReceiver, JoinHandle<()>)>,
+    /// Sequence number of the last command sent to the session notification thread, its reply carries the same one
+    session_command_seq: AtomicU64,
+}
+
+/// Instance handed out by [`Notifications::global`], registrations made through it live until the process exits
+static GLOBAL_NOTIFICATIONS: Mutex<Option<ComSend<Notifications>>> = Mutex::new(None);
+
+/// Locked access to the process wide [`Notifications`], see [`Notifications::global`]
+pub struct GlobalNotifications(MutexGuard<'static, Option<ComSend<Notifications>>>);
+
+impl Deref for GlobalNotifications {
+    type Target = Notifications;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+            .as_ref()
+            .map(|n| &n.0)
+            .expect("global notifications are set before the guard is handed out")
+    }
+}
+
+impl DerefMut for GlobalNotifications {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0
+            .as_mut()
+            .map(|n| &mut n.0)
+            .expect("global notifications are set before the guard is handed out")
+    }
 }
 
 impl Notifications {
+    /// A process wide instance for tray utilities and services that never tear their notifications down and don't
+    /// want to pass ownership around. Created in STA compatible mode on first use and never dropped.
+    ///
+    /// The returned guard holds a lock, don't keep it around and don't call this from inside a callback.
+    pub fn global() -> Result<GlobalNotifications, NotificationError> {
+        let mut global = GLOBAL_NOTIFICATIONS.lock().unwrap_or_else(|e| e.into_inner());
+        if global.is_none() {
+            *global = Some(ComSend(Self::sta_compatible(Dispatcher::inline())?));
+        }
+        Ok(GlobalNotifications(global))
+    }
+
+    /// Keeps every registration of this instance alive for the rest of the process without holding on to it
+    pub fn detach(self) {
+        std::mem::forget(self);
+    }
+
+    /// Starts a [`NotificationsBuilder`] to set up several registrations in one expression
+    pub fn builder() -> NotificationsBuilder {
+        NotificationsBuilder::default()
+    }
+
     pub fn new() -> Self {
+        Self::with_dispatcher(Dispatcher::inline())
+    }
+
+    /// Every callback registered through this instance will be run through `dispatcher` instead of on the COM thread
+    pub fn with_dispatcher(dispatcher: Dispatcher) -> Self {
         Self {
+            dispatcher,
+            mta_worker: None,
+            command_timeout: DEFAULT_COMMAND_TIMEOUT,
             _device_notification_client: None,
+            #[cfg(feature = "winrt-events")]
+            _winrt_registration: None,
             _session_event_client: HashMap::new(),
+            _endpoint_volume_client: HashMap::new(),
             _session_notification: None,
+            session_command_seq: AtomicU64::new(0),
         }
     }
+
+    /// Notifications for hosts that own an STA thread (GUI apps, plugins inside a DAW) and don't pump messages for the crate.
+    ///
+    /// All registrations are done from a crate owned MTA worker, so the calling thread's apartment doesn't matter.
+    /// Callbacks still arrive on COM threads; to get them back onto the host thread, pass a [`Dispatcher::channel`]
+    /// and drain the receiver from the host's message loop.
+    pub fn sta_compatible(dispatcher: Dispatcher) -> Result<Self, NotificationError> {
+        let mta_worker = MtaWorker::spawn().map_err(|_| NotificationError::FailedStartingNotificationThread)?;
+        let mut notifications = Self::with_dispatcher(dispatcher);
+        notifications.mta_worker = Some(mta_worker);
+        Ok(notifications)
+    }
+
+    /// Calls `callback_fn` for every event of `session`, one event at a time so it can keep state across events
     pub fn register_session_event<CB>(&mut self, session: &Session, callback_fn: CB) -> Result<(), NotificationError>
     where
-        CB: Fn(AudioSessionEventArgs) + Send + 'static,
+        CB: FnMut(AudioSessionEventArgs) + Send + 'static,
+    {
+        let callback_fn = observed(NotificationKind::SessionEvent, self.dispatcher.wrap(callback_fn));
+        self.register_session_events(session, move |name| ISessionEventClient::new(name, callback_fn).into())
+    }
+
+    /// Same as [`Notifications::register_session_event`], with bursts of events thinned out by `coalescing`, e.g. to
+    /// only get the volume a slider settled on. State changes and disconnects are never dropped.
+    pub fn register_session_event_coalesced<CB>(
+        &mut self,
+        session: &Session,
+        coalescing: Coalescing,
+        callback_fn: CB,
+    ) -> Result<(), NotificationError>
+    where
+        CB: FnMut(AudioSessionEventArgs) + Send + 'static,
+    {
+        let callback_fn = observed(
+            NotificationKind::SessionEvent,
+            coalesced(coalescing, self.dispatcher.wrap(callback_fn)),
+        );
+        self.register_session_events(session, move |name| ISessionEventClient::new(name, callback_fn).into())
+    }
+
+    /// Hands every `IAudioSessionEvents` call of `session` to `handler` with all of its parameters, copied so they
+    /// outlive the COM call. For when [`AudioSessionEventArgs`] hides something that's needed.
+    ///
+    /// Shares the registration with [`Notifications::register_session_event`], a session can only have one of them
+    /// and both are removed with [`Notifications::unregister_session_event`].
+    pub fn register_session_event_raw<H>(&mut self, session: &Session, mut handler: H) -> Result<(), NotificationError>
+    where
+        H: RawSessionEventHandler,
+    {
+        let callback_fn = observed(
+            NotificationKind::SessionEvent,
+            self.dispatcher.wrap(move |event: RawSessionEvent| event.dispatch(&mut handler)),
+        );
+        self.register_session_events(session, move |_| {
+            IRawSessionEventClient {
+                callback_fn,
+                _tracked: Tracked::new(ObjectKind::SessionEventClient),
+            }
+            .into()
+        })
+    }
+
+    fn register_session_events<F>(&mut self, session: &Session, create_client: F) -> Result<(), NotificationError>
+    where
+        F: FnOnce(String) -> IAudioSessionEvents + Send + 'static,
     {
         if self._session_event_client.contains_key(session.get_name()) {
             return Err(NotificationError::NotificationAlreadyRegistered);
         }
-        com_initialized();
-        let session_notification_client = ISessionEventClient::new(session.get_name().clone(), callback_fn);
-        let session_notification_client = session_notification_client.into();
+        let name = session.get_name().clone();
+        let session_control = ComSend(session.get_session().clone());
+        let ComSend(session_notification_client) = self.run_in_apartment(move || {
+            let session_notification_client = create_client(name);
 
-        // Set up the notification
-        unsafe { session.get_session().RegisterAudioSessionNotification(&session_notification_client) }
-            .map_err(NotificationError::FailedSettingUpNotification)?;
+            // Set up the notification
+            unsafe { session_control.get().RegisterAudioSessionNotification(&session_notification_client) }
+                .map_err(NotificationError::FailedSettingUpNotification)?;
+            Ok::<_, NotificationError>(ComSend(session_notification_client))
+        })?;
 
         self._session_event_client.insert(
             session.get_name().clone(),
@@ -98,42 +255,124 @@ impl Notifications {
     }
 
     pub fn unregister_session_event(&mut self, name: &str) -> Result<(), NotificationError> {
-        if let Some((sc, nc)) = self._session_event_client.remove(name) {
-            unsafe { sc.UnregisterAudioSessionNotification(&nc) }.map_err(NotificationError::NotificationUnregisterError)?;
+        if let Some(registration) = self._session_event_client.remove(name) {
+            let registration = ComSend(registration);
+            self.run_in_apartment(move || {
+                let (sc, nc) = registration.get();
+                unsafe { sc.UnregisterAudioSessionNotification(nc) }
+            })
+            .map_err(NotificationError::NotificationUnregisterError)?;
         }
         trace!("Session event unregistered: {}", name);
         Ok(())
     }
 
+    /// Calls `callback_fn` whenever the master volume, the mute state or a channel volume of `device` changes, by
+    /// anyone including the volume mixer and the hardware volume keys
+    pub fn register_endpoint_volume_notification<CB>(&mut self, device: &Device, callback_fn: CB) -> Result<(), NotificationError>
+    where
+        CB: Fn(EndpointVolumeChangedArgs) + Send + 'static,
+    {
+        let callback_fn = observed(NotificationKind::EndpointVolume, self.dispatcher.wrap(callback_fn));
+        self.register_endpoint_volume(device, callback_fn)
+    }
+
+    /// Same as [`Notifications::register_endpoint_volume_notification`], with bursts of changes thinned out by
+    /// `coalescing`
+    pub fn register_endpoint_volume_notification_coalesced<CB>(
+        &mut self,
+        device: &Device,
+        coalescing: Coalescing,
+        callback_fn: CB,
+    ) -> Result<(), NotificationError>
+    where
+        CB: Fn(EndpointVolumeChangedArgs) + Send + 'static,
+    {
+        let callback_fn = observed(
+            NotificationKind::EndpointVolume,
+            coalesced(coalescing, self.dispatcher.wrap(callback_fn)),
+        );
+        self.register_endpoint_volume(device, callback_fn)
+    }
+
+    fn register_endpoint_volume(
+        &mut self,
+        device: &Device,
+        callback_fn: Box<dyn Fn(EndpointVolumeChangedArgs) + Send + 'static>,
+    ) -> Result<(), NotificationError> {
+        let device_id = endpoint_id(device)?;
+        if self._endpoint_volume_client.contains_key(&device_id) {
+            return Err(NotificationError::NotificationAlreadyRegistered);
+        }
+        let device = ComSend(device.inner.clone());
+        let ComSend(registration) = self.run_in_apartment(move || {
+            let endpoint_volume = unsafe { device.get().Activate::<IAudioEndpointVolume>(CLSCTX_ALL, None) }
+                .map_err(NotificationError::EndpointVolumeActivationError)?;
+            let volume_client: IAudioEndpointVolumeCallback = IEndpointVolumeClient {
+                callback_fn,
+                _tracked: Tracked::new(ObjectKind::EndpointVolumeClient),
+            }
+            .into();
+            unsafe { endpoint_volume.RegisterControlChangeNotify(&volume_client) }.map_err(NotificationError::NotificationRegisterError)?;
+            Ok::<_, NotificationError>(ComSend((endpoint_volume, volume_client)))
+        })?;
+        trace!("Endpoint volume notification registered: {}", device_id);
+        self._endpoint_volume_client.insert(device_id, registration);
+        Ok(())
+    }
+
+    pub fn unregister_endpoint_volume_notification(&mut self, device: &Device) -> Result<(), NotificationError> {
+        let device_id = endpoint_id(device)?;
+        if let Some(registration) = self._endpoint_volume_client.remove(&device_id) {
+            let registration = ComSend(registration);
+            self.run_in_apartment(move || {
+                let (endpoint_volume, volume_client) = registration.get();
+                unsafe { endpoint_volume.UnregisterControlChangeNotify(volume_client) }
+            })
+            .map_err(NotificationError::NotificationUnregisterError)?;
+            trace!("Endpoint volume notification unregistered: {}", device_id);
+        }
+        Ok(())
+    }
+
     pub fn register_session_notification(
         &mut self,
         dev: Device,
         callback_fn: impl Fn(SessionCreated) + Send + 'static + Clone + Sync,
     ) -> Result<(), NotificationError> {
-        self.notification_thread_running()
-            .map_err(|_| NotificationError::FailedStartingNotificationThread)?;
-        let (send, recv, _) = self._session_notification.as_ref().unwrap();
-        send.send(SessionNotificationCommand::RegisterNotification(Box::new(callback_fn), dev))
-            .unwrap();
-        match recv.recv() {
-            Ok(SessionNotificationMessage::NotificationRegistered) => Ok(()),
+        self.notification_thread_running()?;
+        let callback_fn = observed_sync(NotificationKind::SessionCreated, self.dispatcher.wrap_sync(callback_fn));
+        let result = self.session_notification_command(SessionNotificationCommand::RegisterNotification(callback_fn, dev.clone()));
+        if let Err(NotificationError::NotificationThreadTimeout(_)) = result {
+            // The thread runs commands in order, a registration that still completes is undone right after it
+            let _ = self.send_session_notification_command(SessionNotificationCommand::UnregisterNotification(dev));
+        }
+        match result? {
+            SessionNotificationMessage::NotificationRegistered => Ok(()),
             _ => Err(NotificationError::FailedRegisteringSessionNotification),
         }
     }
 
     pub fn unregister_session_notification(&mut self, dev: Device) -> Result<(), NotificationError> {
-        match &self._session_notification {
-            Some((send, recv, _)) => {
-                send.send(SessionNotificationCommand::UnregisterNotification(dev)).unwrap();
-                match recv.recv() {
-                    Ok(SessionNotificationMessage::NotificationUnregistered) => Ok(()),
-                    _ => Err(NotificationError::FailedUnregisteringSessionNotification),
-                }
-            }
-            None => Err(NotificationError::SessionNotificationThreadNotRunning),
+        if self._session_notification.is_none() {
+            return Err(NotificationError::SessionNotificationThreadNotRunning);
+        }
+        match self.session_notification_command(SessionNotificationCommand::UnregisterNotification(dev))? {
+            SessionNotificationMessage::NotificationUnregistered => Ok(()),
+            _ => Err(NotificationError::FailedUnregisteringSessionNotification),
         }
     }
 
+    /// How long registering or unregistering a session notification waits for the session notification thread before
+    /// failing with [`NotificationError::NotificationThreadTimeout`], defaults to [`DEFAULT_COMMAND_TIMEOUT`]
+    pub fn set_command_timeout(&mut self, timeout: Duration) {
+        self.command_timeout = timeout;
+    }
+
+    pub fn get_command_timeout(&self) -> Duration {
+        self.command_timeout
+    }
+
     pub fn register_device_notification<CB>(&mut self, callback_fn: CB) -> Result<(), NotificationError>
     where
         CB: Fn(DeviceNotificationEventArgs) + Send + 'static,
@@ -141,25 +380,118 @@ impl Notifications {
         if self._device_notification_client.is_some() {
             return Err(NotificationError::NotificationAlreadyRegistered);
         }
-        com_initialized();
-        let device_enumerator: IMMDeviceEnumerator =
-            unsafe { CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL) }.map_err(NotificationError::InstanceCreationError)?;
-        let nclient: IMMNotificationClient = IDeviceNotificationClient::new(callback_fn).into();
+        let callback_fn = observed(NotificationKind::Device, self.dispatcher.wrap(callback_fn));
+        let ComSend(registration) = self.run_in_apartment(move || {
+            let device_enumerator: IMMDeviceEnumerator =
+                unsafe { CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL) }.map_err(NotificationError::InstanceCreationError)?;
+            let nclient: IMMNotificationClient = IDeviceNotificationClient::new(callback_fn).into();
 
-        unsafe { device_enumerator.RegisterEndpointNotificationCallback(&nclient) }
-            .map_err(NotificationError::NotificationRegisterError)?;
-        self._device_notification_client = Some((device_enumerator, nclient));
+            unsafe { device_enumerator.RegisterEndpointNotificationCallback(&nclient) }
+                .map_err(NotificationError::NotificationRegisterError)?;
+            Ok::<_, NotificationError>(ComSend((device_enumerator, nclient)))
+        })?;
+        self._device_notification_client = Some(registration);
         Ok(())
     }
 
+    /// Hands the device notifications to the methods of `handler`, for applications that keep their device state in a
+    /// type of their own rather than in a closure.
+    ///
+    /// Shares the registration with [`Notifications::register_device_notification`], only one of them can be active
+    /// and both are removed with [`Notifications::unregister_device_notification`].
+    pub fn register_device_notification_handler<H>(&mut self, handler: H) -> Result<(), NotificationError>
+    where
+        H: DeviceNotificationHandler,
+    {
+        let handler = Mutex::new(handler);
+        self.register_device_notification(move |event| {
+            let mut handler = handler.lock().unwrap_or_else(|e| e.into_inner());
+            dispatch_device_notification(&mut *handler, event)
+        })
+    }
+
     pub fn unregister_device_notification(&mut self) -> Result<(), NotificationError> {
-        if let Some((enumerator, nclient)) = self._device_notification_client.take() {
-            unsafe { enumerator.UnregisterEndpointNotificationCallback(&nclient) }
-                .map_err(NotificationError::NotificationUnregisterError)?;
+        if let Some(registration) = self._device_notification_client.take() {
+            let registration = ComSend(registration);
+            self.run_in_apartment(move || {
+                let (enumerator, nclient) = registration.get();
+                unsafe { enumerator.UnregisterEndpointNotificationCallback(nclient) }
+            })
+            .map_err(NotificationError::NotificationUnregisterError)?;
+        }
+        Ok(())
+    }
+
+    /// Forwards default device changes raised by `Windows.Media.Devices.MediaDevice` as
+    /// [`DeviceNotificationEventArgs::DefaultDeviceChanged`], with the same MMDevice ids as the regular device notifications.
+    /// Both registrations can share a callback; a change that shows up in both APIs is reported twice.
+    #[cfg(feature = "winrt-events")]
+    pub fn register_winrt_device_notification<CB>(&mut self, callback_fn: CB) -> Result<(), NotificationError>
+    where
+        CB: Fn(DeviceNotificationEventArgs) + Send + Sync + 'static,
+    {
+        if self._winrt_registration.is_some() {
+            return Err(NotificationError::NotificationAlreadyRegistered);
         }
+        let callback_fn = observed_sync(NotificationKind::Device, self.dispatcher.wrap_sync(callback_fn)).into();
+        let registration = self.run_in_apartment(move || WinRtRegistration::register(callback_fn))?;
+        self._winrt_registration = Some(registration);
         Ok(())
     }
 
+    #[cfg(feature = "winrt-events")]
+    pub fn unregister_winrt_device_notification(&mut self) -> Result<(), NotificationError> {
+        match self._winrt_registration.take() {
+            Some(registration) => self.run_in_apartment(move || registration.unregister()),
+            None => Ok(()),
+        }
+    }
+
+    /// Tears the registrations down in a fixed order: session events, endpoint volume notifications, device
+    /// notifications, then the session notification thread and the MTA worker. Unlike dropping, which panics when
+    /// unregistering fails, every step runs and the first failure is returned.
+    pub fn shutdown(mut self) -> Result<(), NotificationError> {
+        let mut result = Ok(());
+        let names: Vec<String> = self._session_event_client.keys().cloned().collect();
+        for name in names {
+            result = result.and(self.unregister_session_event(&name));
+        }
+
+        let registrations = ComSend(self._endpoint_volume_client.drain().map(|(_, r)| r).collect::<Vec<_>>());
+        let unregistered = self.run_in_apartment(move || {
+            registrations
+                .get()
+                .iter()
+                .map(|(endpoint_volume, volume_client)| unsafe { endpoint_volume.UnregisterControlChangeNotify(volume_client) })
+                .fold(Ok(()), Result::and)
+        });
+        result = result.and(unregistered.map_err(NotificationError::NotificationUnregisterError));
+
+        result = result.and(self.unregister_device_notification());
+        #[cfg(feature = "winrt-events")]
+        {
+            result = result.and(self.unregister_winrt_device_notification());
+        }
+        // Dropping stops the threads, there is nothing left that could fail
+        drop(self);
+        result
+    }
+
+    /// Runs the COM calls on the MTA worker in STA compatible mode, otherwise on the calling thread
+    fn run_in_apartment<R, F>(&self, job: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce() -> R + Send + 'static,
+    {
+        match &self.mta_worker {
+            Some(worker) => worker.run(job),
+            None => {
+                com_initialized();
+                job()
+            }
+        }
+    }
+
     fn notification_thread_running(&mut self) -> Result<(), NotificationError> {
         if self._session_notification.is_some() {
             return Ok(());
@@ -169,48 +501,248 @@ impl Notifications {
         let (comm_send, comm_recv) = std::sync::mpsc::channel();
 
         let t = thread::spawn(move || session_notification_thread(response_send, comm_recv));
-        match response_recv.recv() {
-            Ok(SessionNotificationMessage::Ready) => {}
+        match response_recv.recv_timeout(self.command_timeout) {
+            Ok((_, SessionNotificationMessage::Ready)) => {}
             _ => return Err(NotificationError::FailedStartingNotificationThread),
         }
         self._session_notification = Some((comm_send, response_recv, t));
         Ok(())
     }
+
+    /// Sends `command` to the session notification thread without waiting for the answer, returns its sequence number
+    fn send_session_notification_command(&self, command: SessionNotificationCommand) -> Result<u64, NotificationError> {
+        let Some((send, _, thread)) = &self._session_notification else {
+            return Err(NotificationError::SessionNotificationThreadNotRunning);
+        };
+        if thread.is_finished() {
+            return Err(NotificationError::NotificationThreadDead);
+        }
+        let seq = self.session_command_seq.fetch_add(1, Ordering::Relaxed) + 1;
+        send.send((seq, command)).map_err(|_| NotificationError::NotificationThreadDead)?;
+        Ok(seq)
+    }
+
+    /// Sends `command` to the session notification thread and waits for its answer, at most for the command timeout
+    fn session_notification_command(&self, command: SessionNotificationCommand) -> Result<SessionNotificationMessage, NotificationError> {
+        let seq = self.send_session_notification_command(command)?;
+        let Some((_, recv, thread)) = &self._session_notification else {
+            return Err(NotificationError::SessionNotificationThreadNotRunning);
+        };
+        let deadline = Instant::now() + self.command_timeout;
+        loop {
+            match recv.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok((reply_seq, message)) if reply_seq == seq => return Ok(message),
+                // Late answer to a command that timed out earlier
+                Ok((reply_seq, _)) => trace!("Discarding answer to session notification command {}", reply_seq),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Err(NotificationError::NotificationThreadDead),
+                Err(mpsc::RecvTimeoutError::Timeout) if thread.is_finished() => return Err(NotificationError::NotificationThreadDead),
+                Err(mpsc::RecvTimeoutError::Timeout) => return Err(NotificationError::NotificationThreadTimeout(self.command_timeout)),
+            }
+        }
+    }
 }
 
 impl Drop for Notifications {
     fn drop(&mut self) {
-        if let Some((enumerator, nclient)) = self._device_notification_client.take() {
-            unsafe {
-                enumerator
-                    .UnregisterEndpointNotificationCallback(&nclient)
-                    .expect("Failed unregistering notification client");
-            };
+        if let Some(registration) = self._device_notification_client.take() {
+            let registration = ComSend(registration);
+            self.run_in_apartment(move || {
+                let (enumerator, nclient) = registration.get();
+                unsafe { enumerator.UnregisterEndpointNotificationCallback(nclient) }
+            })
+            .expect("Failed unregistering notification client");
             trace!("Device notification unregistered");
         }
 
-        for (_, (sc, nc)) in self._session_event_client.drain() {
-            unsafe {
-                sc.UnregisterAudioSessionNotification(&nc)
-                    .expect("Failed unregistering session notification client");
-            };
-            trace!("Session event unregistered");
+        #[cfg(feature = "winrt-events")]
+        if let Some(registration) = self._winrt_registration.take() {
+            self.run_in_apartment(move || drop(registration));
+            trace!("WinRT device notification unregistered");
         }
 
+        let registrations = ComSend(self._session_event_client.drain().map(|(_, r)| r).collect::<Vec<_>>());
+        self.run_in_apartment(move || {
+            for (sc, nc) in registrations.get() {
+                unsafe {
+                    sc.UnregisterAudioSessionNotification(nc)
+                        .expect("Failed unregistering session notification client");
+                };
+                trace!("Session event unregistered");
+            }
+        });
+
+        let registrations = ComSend(self._endpoint_volume_client.drain().map(|(_, r)| r).collect::<Vec<_>>());
+        self.run_in_apartment(move || {
+            for (endpoint_volume, volume_client) in registrations.get() {
+                unsafe {
+                    endpoint_volume
+                        .UnregisterControlChangeNotify(volume_client)
+                        .expect("Failed unregistering endpoint volume client");
+                };
+                trace!("Endpoint volume notification unregistered");
+            }
+        });
+
         if let Some((send, _recv, t)) = self._session_notification.take() {
-            send.send(SessionNotificationCommand::Stop).unwrap();
-            t.join().unwrap();
+            // A thread that already exited has nothing left to unregister
+            let _ = send.send((u64::MAX, SessionNotificationCommand::Stop));
+            let _ = t.join();
             trace!("Session notification thread stopped");
         }
     }
 }
 
+/// Id of `device`, the key of its endpoint volume registration
+fn endpoint_id(device: &Device) -> Result<String, NotificationError> {
+    let id = PWSTRWrapper(unsafe { device.inner.GetId() }.map_err(NotificationError::FailedGettingDeviceId)?);
+    unsafe { id.0.to_string() }.map_err(NotificationError::PCWSTRConversionError)
+}
+
+/// Reports every call to the global [`Hooks`](crate::hooks::Hooks) on the COM thread, before the dispatcher
+fn observed<A: 'static>(kind: NotificationKind, callback_fn: Box<dyn Fn(A) + Send + 'static>) -> Box<dyn Fn(A) + Send + 'static> {
+    Box::new(move |args| {
+        notification_arrived(kind);
+        callback_fn(args)
+    })
+}
+
+/// Same as [`observed`] for `Sync` callbacks
+fn observed_sync<A: 'static>(
+    kind: NotificationKind,
+    callback_fn: Box<dyn Fn(A) + Send + Sync + 'static>,
+) -> Box<dyn Fn(A) + Send + Sync + 'static> {
+    Box::new(move |args| {
+        notification_arrived(kind);
+        callback_fn(args)
+    })
+}
+
+type SharedCallback<A> = Arc<dyn Fn(A) + Send + Sync + 'static>;
+
+/// Registers device notifications, new session notifications for every render device and session events for every
+/// current session when building, see [`Notifications::builder`]
+#[derive(Default)]
+pub struct NotificationsBuilder {
+    dispatcher: Option<Dispatcher>,
+    sta_compatible: bool,
+    command_timeout: Option<Duration>,
+    on_device: Option<Box<dyn Fn(DeviceNotificationEventArgs) + Send + 'static>>,
+    on_new_session: Option<SharedCallback<SessionCreated>>,
+    on_session_event: Option<SharedCallback<AudioSessionEventArgs>>,
+}
+
+impl NotificationsBuilder {
+    /// See [`Notifications::with_dispatcher`]
+    pub fn dispatcher(mut self, dispatcher: Dispatcher) -> Self {
+        self.dispatcher = Some(dispatcher);
+        self
+    }
+
+    /// See [`Notifications::sta_compatible`]
+    pub fn sta_compatible(mut self) -> Self {
+        self.sta_compatible = true;
+        self
+    }
+
+    /// See [`Notifications::set_command_timeout`]
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = Some(timeout);
+        self
+    }
+
+    pub fn on_device(mut self, callback_fn: impl Fn(DeviceNotificationEventArgs) + Send + 'static) -> Self {
+        self.on_device = Some(Box::new(callback_fn));
+        self
+    }
+
+    /// Called for sessions created on any of the playback devices present when building
+    pub fn on_new_session(mut self, callback_fn: impl Fn(SessionCreated) + Send + Sync + 'static) -> Self {
+        self.on_new_session = Some(Arc::new(callback_fn));
+        self
+    }
+
+    /// Called for events of every active or inactive session present when building
+    /// The events don't tell which session they belong to, register per session for that
+    pub fn on_session_event(mut self, callback_fn: impl Fn(AudioSessionEventArgs) + Send + Sync + 'static) -> Self {
+        self.on_session_event = Some(Arc::new(callback_fn));
+        self
+    }
+
+    pub fn build(self) -> Result<Notifications, NotificationError> {
+        let dispatcher = self.dispatcher.unwrap_or_else(Dispatcher::inline);
+        let mut notifications = match self.sta_compatible {
+            true => Notifications::sta_compatible(dispatcher)?,
+            false => Notifications::with_dispatcher(dispatcher),
+        };
+        if let Some(timeout) = self.command_timeout {
+            notifications.set_command_timeout(timeout);
+        }
+
+        if let Some(callback_fn) = self.on_device {
+            notifications.register_device_notification(callback_fn)?;
+        }
+
+        if let Some(callback_fn) = self.on_new_session {
+            com_initialized();
+            let devices = DeviceManager::get_playback_devices()
+                .map_err(|err| NotificationError::FailedEnumeratingDevices(AudioError::DeviceEnumError(err)))?;
+            for dev in devices {
+                let callback_fn = callback_fn.clone();
+                notifications.register_session_notification(dev, move |session| callback_fn(session))?;
+            }
+        }
+
+        if let Some(callback_fn) = self.on_session_event {
+            com_initialized();
+            let sessions = SessionManager::get_sessions_filtered(SessionStateFilter::ActiveAndInactive)
+                .map_err(NotificationError::FailedEnumeratingSessions)?;
+            for session in sessions {
+                let callback_fn = callback_fn.clone();
+                match notifications.register_session_event(&session, move |event| callback_fn(event)) {
+                    // Sessions are registered by name, the first session of a name gets the events
+                    Ok(()) | Err(NotificationError::NotificationAlreadyRegistered) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(notifications)
+    }
+}
+
+/// Receives the device notifications, see [`Notifications::register_device_notification_handler`]
+///
+/// Every method does nothing by default. They are called on the thread of the dispatcher the notifications were created
+/// with, one at a time.
+#[allow(unused_variables)]
+pub trait DeviceNotificationHandler: Send + 'static {
+    fn on_default_changed(&mut self, args: DefaultDeviceChangedEventArgs) {}
+
+    fn on_added(&mut self, args: DeviceAddedEventArgs) {}
+
+    fn on_removed(&mut self, args: DeviceRemovedEventArgs) {}
+
+    fn on_state_changed(&mut self, args: DeviceStateChangedEventArgs) {}
+
+    fn on_property_value_changed(&mut self, args: DevicePropertyValueChangedEventArgs) {}
+}
+
+fn dispatch_device_notification(handler: &mut impl DeviceNotificationHandler, event: DeviceNotificationEventArgs) {
+    match event {
+        DeviceNotificationEventArgs::DefaultDeviceChanged(args) => handler.on_default_changed(args),
+        DeviceNotificationEventArgs::DeviceAdded(args) => handler.on_added(args),
+        DeviceNotificationEventArgs::DeviceRemoved(args) => handler.on_removed(args),
+        DeviceNotificationEventArgs::DeviceStateChanged(args) => handler.on_state_changed(args),
+        DeviceNotificationEventArgs::DevicePropertyValueChanged(args) => handler.on_property_value_changed(args),
+    }
+}
+
 #[implement(IMMNotificationClient)]
 struct IDeviceNotificationClient<CB>
 where
     CB: Fn(DeviceNotificationEventArgs) + Send + 'static,
 {
     callback_fn: CB,
+    _tracked: Tracked,
 }
 
 impl<CB> IDeviceNotificationClient<CB>
@@ -218,7 +750,10 @@ where
     CB: Fn(DeviceNotificationEventArgs) + Send + 'static,
 {
     pub fn new(callback_fn: CB) -> Self {
-        Self { callback_fn }
+        Self {
+            callback_fn,
+            _tracked: Tracked::new(ObjectKind::DeviceNotificationClient),
+        }
     }
 }
 
@@ -230,28 +765,28 @@ where
         (self.callback_fn)(DeviceNotificationEventArgs::DefaultDeviceChanged(DefaultDeviceChangedEventArgs {
             flow,
             role,
-            defaultdevice: pwstrDefaultDevice.clone(),
+            defaultdevice: copy_pcwstr(pwstrDefaultDevice),
         }));
         Ok(())
     }
 
     fn OnDeviceAdded(&self, pwstrDeviceId: &PCWSTR) -> windows::core::Result<()> {
         (self.callback_fn)(DeviceNotificationEventArgs::DeviceAdded(DeviceAddedEventArgs {
-            pwstrDeviceId: pwstrDeviceId.clone(),
+            pwstrDeviceId: copy_pcwstr(pwstrDeviceId),
         }));
         Ok(())
     }
 
     fn OnDeviceRemoved(&self, pwstrDeviceId: &PCWSTR) -> windows::core::Result<()> {
         (self.callback_fn)(DeviceNotificationEventArgs::DeviceRemoved(DeviceRemovedEventArgs {
-            pwstrDeviceId: pwstrDeviceId.clone(),
+            pwstrDeviceId: copy_pcwstr(pwstrDeviceId),
         }));
         Ok(())
     }
 
     fn OnDeviceStateChanged(&self, pwstrDeviceId: &PCWSTR, dwNewState: DEVICE_STATE) -> windows::core::Result<()> {
         (self.callback_fn)(DeviceNotificationEventArgs::DeviceStateChanged(DeviceStateChangedEventArgs {
-            pwstrDeviceId: pwstrDeviceId.clone(),
+            pwstrDeviceId: copy_pcwstr(pwstrDeviceId),
             dwNewState,
         }));
         Ok(())
@@ -260,7 +795,7 @@ where
     fn OnPropertyValueChanged(&self, pwstrDeviceId: &PCWSTR, key: &PROPERTYKEY) -> windows::core::Result<()> {
         (self.callback_fn)(DeviceNotificationEventArgs::DevicePropertyValueChanged(
             DevicePropertyValueChangedEventArgs {
-                pwstrDeviceId: pwstrDeviceId.clone(),
+                pwstrDeviceId: copy_pcwstr(pwstrDeviceId),
                 key: key.clone(),
             },
         ));
@@ -268,6 +803,39 @@ where
     }
 }
 
+#[implement(IAudioEndpointVolumeCallback)]
+struct IEndpointVolumeClient {
+    callback_fn: Box<dyn Fn(EndpointVolumeChangedArgs) + Send + 'static>,
+    _tracked: Tracked,
+}
+
+impl IAudioEndpointVolumeCallback_Impl for IEndpointVolumeClient_Impl {
+    fn OnNotify(&self, pnotify: *mut AUDIO_VOLUME_NOTIFICATION_DATA) -> windows_core::Result<()> {
+        let Some(data) = (unsafe { pnotify.as_ref() }) else {
+            return Ok(());
+        };
+        // The channel volumes continue past the one element array of the struct
+        let channel_volumes = unsafe { std::slice::from_raw_parts(data.afChannelVolumes.as_ptr(), data.nChannels as usize) }.to_vec();
+        (self.callback_fn)(EndpointVolumeChangedArgs {
+            // A zero GUID is what a change without a context reports
+            eventcontext: (data.guidEventContext != GUID::zeroed()).then_some(data.guidEventContext),
+            muted: data.bMuted.as_bool(),
+            master_volume: data.fMasterVolume,
+            channel_volumes,
+        });
+        Ok(())
+    }
+}
+
+/// Session event client for a registration outside of [`Notifications`], e.g. of a stream watching its own session
+pub(crate) fn session_event_client<CB>(session_id: String, callback_fn: CB) -> IAudioSessionEvents
+where
+    CB: Fn(AudioSessionEventArgs) + Send + 'static,
+{
+    let callback_fn = observed(NotificationKind::SessionEvent, Box::new(callback_fn));
+    ISessionEventClient::new(session_id, callback_fn).into()
+}
+
 #[implement(IAudioSessionEvents)]
 struct ISessionEventClient<CB>
 where
@@ -275,6 +843,7 @@ where
 {
     _session_id: String,
     _callback_fn: CB,
+    _tracked: Tracked,
 }
 
 impl<CB> ISessionEventClient<CB>
@@ -285,6 +854,7 @@ where
         Self {
             _session_id: session_id,
             _callback_fn: callback_fn,
+            _tracked: Tracked::new(ObjectKind::SessionEventClient),
         }
     }
 }
@@ -299,16 +869,16 @@ where
         eventcontext: *const windows_core::GUID,
     ) -> windows_core::Result<()> {
         (self._callback_fn)(AudioSessionEventArgs::DisplayNameChanged(DisplayNameChangedArgs {
-            newdisplayname: newdisplayname.clone(),
-            eventcontext,
+            newdisplayname: copy_pcwstr(newdisplayname),
+            eventcontext: copy_guid(eventcontext),
         }));
         Ok(())
     }
 
     fn OnIconPathChanged(&self, newiconpath: &windows_core::PCWSTR, eventcontext: *const windows_core::GUID) -> windows_core::Result<()> {
         (self._callback_fn)(AudioSessionEventArgs::IconPathChanged(IconPathChangedArgs {
-            newiconpath: newiconpath.clone(),
-            eventcontext,
+            newiconpath: copy_pcwstr(newiconpath),
+            eventcontext: copy_guid(eventcontext),
         }));
         Ok(())
     }
@@ -322,7 +892,7 @@ where
         (self._callback_fn)(AudioSessionEventArgs::SimpleVolumeChanged(SimpleVolumeChangedArgs {
             newvolume,
             newmute,
-            eventcontext,
+            eventcontext: copy_guid(eventcontext),
         }));
         Ok(())
     }
@@ -334,11 +904,16 @@ where
         changedchannel: u32,
         eventcontext: *const windows_core::GUID,
     ) -> windows_core::Result<()> {
+        let newchannelvolumearray = if newchannelvolumearray.is_null() {
+            Vec::new()
+        } else {
+            unsafe { std::slice::from_raw_parts(newchannelvolumearray, channelcount as usize) }.to_vec()
+        };
         (self._callback_fn)(AudioSessionEventArgs::ChannelVolumeChanged(ChannelVolumeChangedArgs {
             channelcount,
             newchannelvolumearray,
             changedchannel,
-            eventcontext,
+            eventcontext: copy_guid(eventcontext),
         }));
         Ok(())
     }
@@ -349,8 +924,8 @@ where
         eventcontext: *const windows_core::GUID,
     ) -> windows_core::Result<()> {
         (self._callback_fn)(AudioSessionEventArgs::GroupingParamChanged(GroupingParamChangedArgs {
-            newgroupingparam,
-            eventcontext,
+            newgroupingparam: copy_guid(newgroupingparam),
+            eventcontext: copy_guid(eventcontext),
         }));
         Ok(())
     }
@@ -370,3 +945,363 @@ where
         Ok(())
     }
 }
+
+/// Receives the unprocessed session events, see [`Notifications::register_session_event_raw`]
+///
+/// Every method does nothing by default. The parameters are those of the `IAudioSessionEvents` method of the same name,
+/// pointers are replaced by owned copies and null pointers by `None` or an empty value.
+#[allow(unused_variables)]
+pub trait RawSessionEventHandler: Send + 'static {
+    fn on_display_name_changed(&mut self, new_display_name: HSTRING, event_context: Option<GUID>) {}
+
+    fn on_icon_path_changed(&mut self, new_icon_path: HSTRING, event_context: Option<GUID>) {}
+
+    fn on_simple_volume_changed(&mut self, new_volume: f32, new_mute: Foundation::BOOL, event_context: Option<GUID>) {}
+
+    /// `new_channel_volumes` holds `channel_count` volumes, `changed_channel` is `u32::MAX` if more than one changed
+    fn on_channel_volume_changed(
+        &mut self,
+        channel_count: u32,
+        new_channel_volumes: Vec<f32>,
+        changed_channel: u32,
+        event_context: Option<GUID>,
+    ) {
+    }
+
+    fn on_grouping_param_changed(&mut self, new_grouping_param: Option<GUID>, event_context: Option<GUID>) {}
+
+    fn on_state_changed(&mut self, new_state: AudioSessionState) {}
+
+    fn on_session_disconnected(&mut self, disconnect_reason: AudioSessionDisconnectReason) {}
+}
+
+/// Copied parameters of one `IAudioSessionEvents` call, carried through the dispatcher to the raw handler
+enum RawSessionEvent {
+    DisplayNameChanged(HSTRING, Option<GUID>),
+    IconPathChanged(HSTRING, Option<GUID>),
+    SimpleVolumeChanged(f32, Foundation::BOOL, Option<GUID>),
+    ChannelVolumeChanged(u32, Vec<f32>, u32, Option<GUID>),
+    GroupingParamChanged(Option<GUID>, Option<GUID>),
+    StateChanged(AudioSessionState),
+    SessionDisconnected(AudioSessionDisconnectReason),
+}
+
+impl RawSessionEvent {
+    fn dispatch(self, handler: &mut impl RawSessionEventHandler) {
+        match self {
+            RawSessionEvent::DisplayNameChanged(name, context) => handler.on_display_name_changed(name, context),
+            RawSessionEvent::IconPathChanged(path, context) => handler.on_icon_path_changed(path, context),
+            RawSessionEvent::SimpleVolumeChanged(volume, mute, context) => handler.on_simple_volume_changed(volume, mute, context),
+            RawSessionEvent::ChannelVolumeChanged(count, volumes, changed, context) => {
+                handler.on_channel_volume_changed(count, volumes, changed, context)
+            }
+            RawSessionEvent::GroupingParamChanged(param, context) => handler.on_grouping_param_changed(param, context),
+            RawSessionEvent::StateChanged(state) => handler.on_state_changed(state),
+            RawSessionEvent::SessionDisconnected(reason) => handler.on_session_disconnected(reason),
+        }
+    }
+}
+
+#[implement(IAudioSessionEvents)]
+struct IRawSessionEventClient {
+    callback_fn: Box<dyn Fn(RawSessionEvent) + Send + 'static>,
+    _tracked: Tracked,
+}
+
+impl IAudioSessionEvents_Impl for IRawSessionEventClient_Impl {
+    fn OnDisplayNameChanged(&self, newdisplayname: &PCWSTR, eventcontext: *const GUID) -> windows_core::Result<()> {
+        (self.callback_fn)(RawSessionEvent::DisplayNameChanged(
+            copy_pcwstr(newdisplayname),
+            copy_guid(eventcontext),
+        ));
+        Ok(())
+    }
+
+    fn OnIconPathChanged(&self, newiconpath: &PCWSTR, eventcontext: *const GUID) -> windows_core::Result<()> {
+        (self.callback_fn)(RawSessionEvent::IconPathChanged(copy_pcwstr(newiconpath), copy_guid(eventcontext)));
+        Ok(())
+    }
+
+    fn OnSimpleVolumeChanged(&self, newvolume: f32, newmute: Foundation::BOOL, eventcontext: *const GUID) -> windows_core::Result<()> {
+        (self.callback_fn)(RawSessionEvent::SimpleVolumeChanged(newvolume, newmute, copy_guid(eventcontext)));
+        Ok(())
+    }
+
+    fn OnChannelVolumeChanged(
+        &self,
+        channelcount: u32,
+        newchannelvolumearray: *const f32,
+        changedchannel: u32,
+        eventcontext: *const GUID,
+    ) -> windows_core::Result<()> {
+        let volumes = if newchannelvolumearray.is_null() {
+            Vec::new()
+        } else {
+            unsafe { std::slice::from_raw_parts(newchannelvolumearray, channelcount as usize) }.to_vec()
+        };
+        (self.callback_fn)(RawSessionEvent::ChannelVolumeChanged(
+            channelcount,
+            volumes,
+            changedchannel,
+            copy_guid(eventcontext),
+        ));
+        Ok(())
+    }
+
+    fn OnGroupingParamChanged(&self, newgroupingparam: *const GUID, eventcontext: *const GUID) -> windows_core::Result<()> {
+        (self.callback_fn)(RawSessionEvent::GroupingParamChanged(
+            copy_guid(newgroupingparam),
+            copy_guid(eventcontext),
+        ));
+        Ok(())
+    }
+
+    fn OnStateChanged(&self, newstate: AudioSessionState) -> windows_core::Result<()> {
+        (self.callback_fn)(RawSessionEvent::StateChanged(newstate));
+        Ok(())
+    }
+
+    fn OnSessionDisconnected(&self, disconnectreason: AudioSessionDisconnectReason) -> windows_core::Result<()> {
+        (self.callback_fn)(RawSessionEvent::SessionDisconnected(disconnectreason));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::com::is_sta_thread;
+    use crate::manager::SessionManager;
+    use windows::Win32::Media::Audio::DEVICE_STATE_ACTIVE;
+
+    #[test]
+    fn sta_host_device_notification() {
+        com_initialized();
+        assert!(is_sta_thread());
+
+        let mut notifications = Notifications::sta_compatible(Dispatcher::inline()).unwrap();
+        assert!(!notifications.run_in_apartment(is_sta_thread));
+        notifications.register_device_notification(|_| {}).unwrap();
+        notifications.unregister_device_notification().unwrap();
+    }
+
+    #[test]
+    fn device_notification_handler() {
+        #[derive(Default)]
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        impl DeviceNotificationHandler for Recorder {
+            fn on_added(&mut self, args: DeviceAddedEventArgs) {
+                self.0.lock().unwrap().push(args.get_device_id().unwrap());
+            }
+
+            fn on_property_value_changed(&mut self, args: DevicePropertyValueChangedEventArgs) {
+                assert_eq!(args.get_property_key(), PROPERTYKEY::default());
+                self.0.lock().unwrap().push(args.get_device_id().unwrap());
+            }
+        }
+
+        com_initialized();
+        let recorder = Recorder::default();
+        let seen = recorder.0.clone();
+        let mut notifications = Notifications::with_dispatcher(Dispatcher::inline());
+        notifications.register_device_notification_handler(recorder).unwrap();
+        assert!(matches!(
+            notifications.register_device_notification(|_| {}),
+            Err(NotificationError::NotificationAlreadyRegistered)
+        ));
+        notifications.unregister_device_notification().unwrap();
+
+        // Calls through the COM interface end up in the methods, the ones not implemented are ignored
+        let handler = Mutex::new(Recorder(seen.clone()));
+        let client: IMMNotificationClient =
+            IDeviceNotificationClient::new(move |event| dispatch_device_notification(&mut *handler.lock().unwrap(), event)).into();
+        let id = HSTRING::from("{0.0.0.00000000}.{test}");
+        unsafe {
+            client.OnDeviceAdded(&id).unwrap();
+            client.OnDeviceStateChanged(&id, DEVICE_STATE_ACTIVE).unwrap();
+            client.OnPropertyValueChanged(&id, PROPERTYKEY::default()).unwrap();
+        }
+        assert_eq!(*seen.lock().unwrap(), vec![id.to_string(); 2]);
+    }
+
+    #[test]
+    fn session_notification_commands_answer() {
+        com_initialized();
+        let mut notifications = Notifications::builder().command_timeout(Duration::from_secs(1)).build().unwrap();
+        assert_eq!(notifications.get_command_timeout(), Duration::from_secs(1));
+        assert!(matches!(
+            notifications.unregister_session_notification(DeviceManager::get_default_playback_device().unwrap()),
+            Err(NotificationError::SessionNotificationThreadNotRunning)
+        ));
+
+        let device = DeviceManager::get_default_playback_device().unwrap();
+        notifications.register_session_notification(device.clone(), |_| {}).unwrap();
+        notifications.unregister_session_notification(device.clone()).unwrap();
+        // Unknown devices get an answer too instead of leaving the caller waiting
+        notifications.unregister_session_notification(device).unwrap();
+    }
+
+    #[cfg(feature = "winrt-events")]
+    #[test]
+    fn winrt_device_notification() {
+        let mut notifications = Notifications::new();
+        notifications.register_winrt_device_notification(|_| {}).unwrap();
+        assert!(matches!(
+            notifications.register_winrt_device_notification(|_| {}),
+            Err(NotificationError::NotificationAlreadyRegistered)
+        ));
+        notifications.unregister_winrt_device_notification().unwrap();
+    }
+
+    #[test]
+    fn endpoint_volume_notification() {
+        com_initialized();
+        let device = DeviceManager::get_default_playback_device().unwrap();
+        let endpoint_volume = unsafe { device.inner.Activate::<IAudioEndpointVolume>(CLSCTX_ALL, None) }.unwrap();
+        let muted = unsafe { endpoint_volume.GetMute() }.unwrap().as_bool();
+
+        let mut notifications = Notifications::new();
+        let (send, recv) = mpsc::channel();
+        notifications
+            .register_endpoint_volume_notification(&device, move |args| {
+                let _ = send.send((args.is_muted(), args.get_channel_volumes().len()));
+            })
+            .unwrap();
+        assert!(matches!(
+            notifications.register_endpoint_volume_notification(&device, |_| {}),
+            Err(NotificationError::NotificationAlreadyRegistered)
+        ));
+        unsafe { endpoint_volume.SetMute(!muted, std::ptr::null()) }.unwrap();
+        let (new_muted, channels) = recv.recv_timeout(Duration::from_secs(1)).unwrap();
+        unsafe { endpoint_volume.SetMute(muted, std::ptr::null()) }.unwrap();
+        assert_eq!(new_muted, !muted);
+        assert!(channels > 0);
+        notifications.unregister_endpoint_volume_notification(&device).unwrap();
+    }
+
+    #[test]
+    fn global_notifications() {
+        Notifications::global().unwrap().register_device_notification(|_| {}).unwrap();
+        assert!(matches!(
+            Notifications::global().unwrap().register_device_notification(|_| {}),
+            Err(NotificationError::NotificationAlreadyRegistered)
+        ));
+    }
+
+    #[test]
+    fn builder() {
+        let notifications = Notifications::builder()
+            .on_device(|_| {})
+            .on_new_session(|_| {})
+            .on_session_event(|_| {})
+            .build()
+            .unwrap();
+        assert!(notifications._device_notification_client.is_some());
+        assert!(notifications._session_notification.is_some());
+    }
+
+    #[test]
+    fn sta_host_session_event() {
+        com_initialized();
+        let mut notifications = Notifications::sta_compatible(Dispatcher::dedicated_thread()).unwrap();
+        for session in SessionManager::get_sessions().unwrap() {
+            notifications.register_session_event(&session, |_| {}).unwrap();
+            notifications.unregister_session_event(session.get_name()).unwrap();
+        }
+    }
+
+    #[test]
+    fn stateful_session_event() {
+        let (playback, _format) = crate::audio_client::AudioClient::new()
+            .start_playback_device(None, |_| 0, |_| {})
+            .unwrap();
+        let _playback = playback.start().unwrap();
+        let session = SessionManager::get_sessions()
+            .unwrap()
+            .into_iter()
+            .find(|session| *session.get_pid() == std::process::id())
+            .unwrap();
+
+        let mut notifications = Notifications::sta_compatible(Dispatcher::inline()).unwrap();
+        let (send, recv) = mpsc::channel();
+        let mut volume_events = 0;
+        notifications
+            .register_session_event(&session, move |event| {
+                if let AudioSessionEventArgs::SimpleVolumeChanged(_) = event {
+                    volume_events += 1;
+                    let _ = send.send(volume_events);
+                }
+            })
+            .unwrap();
+        session.set_muted(true).unwrap();
+        session.set_muted(false).unwrap();
+        let timeout = std::time::Duration::from_secs(1);
+        assert_eq!(recv.recv_timeout(timeout), Ok(1));
+        assert_eq!(recv.recv_timeout(timeout), Ok(2));
+    }
+
+    #[test]
+    fn coalesced_session_event() {
+        let (playback, _format) = crate::audio_client::AudioClient::new()
+            .start_playback_device(None, |_| 0, |_| {})
+            .unwrap();
+        let _playback = playback.start().unwrap();
+        let session = SessionManager::get_sessions()
+            .unwrap()
+            .into_iter()
+            .find(|session| *session.get_pid() == std::process::id())
+            .unwrap();
+
+        let mut notifications = Notifications::new();
+        let (send, recv) = mpsc::channel();
+        notifications
+            .register_session_event_coalesced(&session, Coalescing::Debounce(Duration::from_millis(100)), move |event| {
+                if let AudioSessionEventArgs::SimpleVolumeChanged(args) = event {
+                    let _ = send.send(args.get_volume());
+                }
+            })
+            .unwrap();
+        let volume = session.get_volume().unwrap();
+        for step in 1..=10 {
+            session.set_volume(step as f32 / 10.0).unwrap();
+        }
+        assert_eq!(recv.recv_timeout(Duration::from_secs(1)), Ok(1.0));
+        assert!(recv.recv_timeout(Duration::from_millis(300)).is_err());
+        session.set_volume(volume).unwrap();
+    }
+
+    #[test]
+    fn raw_session_event() {
+        struct VolumeHandler(mpsc::Sender<(f32, bool)>);
+
+        impl RawSessionEventHandler for VolumeHandler {
+            fn on_simple_volume_changed(&mut self, new_volume: f32, new_mute: Foundation::BOOL, _: Option<GUID>) {
+                let _ = self.0.send((new_volume, new_mute.as_bool()));
+            }
+        }
+
+        let (playback, _format) = crate::audio_client::AudioClient::new()
+            .start_playback_device(None, |_| 0, |_| {})
+            .unwrap();
+        let _playback = playback.start().unwrap();
+        let session = SessionManager::get_sessions()
+            .unwrap()
+            .into_iter()
+            .find(|session| *session.get_pid() == std::process::id())
+            .unwrap();
+
+        let mut notifications = Notifications::sta_compatible(Dispatcher::inline()).unwrap();
+        let (send, recv) = mpsc::channel();
+        notifications.register_session_event_raw(&session, VolumeHandler(send)).unwrap();
+        assert!(matches!(
+            notifications.register_session_event(&session, |_| {}),
+            Err(NotificationError::NotificationAlreadyRegistered)
+        ));
+        session.set_muted(true).unwrap();
+        let (_, muted) = recv.recv_timeout(std::time::Duration::from_secs(1)).unwrap();
+        assert!(muted);
+        session.set_muted(false).unwrap();
+        notifications.unregister_session_event(session.get_name()).unwrap();
+    }
+}
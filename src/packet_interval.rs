@@ -0,0 +1,191 @@
+//! The cadence of buffer events, see [`AudioStreamConfig::expected_packet_interval`].
+//!
+//! A shared mode stream gets a buffer event once per engine period, which is what sizes the packets. Some drivers
+//! signal in bursts or at a multiple of the period they report, the packets then come less often and larger than
+//! expected, and buffers sized downstream from the period run dry. Watching the intervals shows such devices.
+//!
+//! [`AudioStreamConfig::expected_packet_interval`]: crate::audio_stream::AudioStreamConfig::expected_packet_interval
+
+use std::time::{Duration, Instant};
+
+use log::warn;
+use windows::Win32::{
+    Media::Audio::{IAudioClient, IAudioClient3},
+    System::Com::CoTaskMemFree,
+};
+use windows_core::Interface;
+
+use crate::audio_stream::CurrentStream;
+use crate::sample_format::SampleFormat;
+
+/// The default period of shared mode streams on every device seen so far
+const FALLBACK_PERIOD: Duration = Duration::from_millis(10);
+
+type DeviationFn = Box<dyn FnMut(IntervalDeviation) + Send>;
+
+/// Interval between buffer events of a stream set up on `audio_client`
+///
+/// The engine period the stream runs at, or the default period of the device without `IAudioClient3`, limited to the
+/// buffer duration. Process loopback clients report neither and fall back to 10 ms.
+pub(crate) fn expected_interval(audio_client: &IAudioClient, format: &SampleFormat) -> Duration {
+    let rate = format.get_n_samples_per_sec() as u64;
+    let frames_to_duration = |frames: u32| Duration::from_nanos(frames as u64 * 1_000_000_000 / rate);
+
+    let engine_period = audio_client.cast::<IAudioClient3>().ok().and_then(|client| {
+        let (mut format, mut frames) = (std::ptr::null_mut(), 0);
+        unsafe { client.GetCurrentSharedModeEnginePeriod(&mut format, &mut frames) }.ok()?;
+        // Only the frames are needed, the format is allocated by the call
+        unsafe { CoTaskMemFree(Some(format as *const _)) };
+        (rate > 0 && frames > 0).then(|| frames_to_duration(frames))
+    });
+    let period = engine_period.unwrap_or_else(|| {
+        let mut default_period = 0i64;
+        match unsafe { audio_client.GetDevicePeriod(Some(&mut default_period), None) } {
+            Ok(()) if default_period > 0 => Duration::from_nanos(default_period as u64 * 100),
+            _ => FALLBACK_PERIOD,
+        }
+    });
+    match unsafe { audio_client.GetBufferSize() } {
+        Ok(frames) if rate > 0 && frames > 0 => period.min(frames_to_duration(frames)),
+        _ => period,
+    }
+}
+
+/// Observed buffer events that came at another interval than expected, see
+/// [`AudioStreamConfig::with_interval_monitor`](crate::audio_stream::AudioStreamConfig::with_interval_monitor)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IntervalDeviation {
+    expected: Duration,
+    mean: Duration,
+    max: Duration,
+    events: u32,
+}
+
+impl IntervalDeviation {
+    /// See [`AudioStreamConfig::expected_packet_interval`](crate::audio_stream::AudioStreamConfig::expected_packet_interval)
+    pub fn get_expected(&self) -> Duration {
+        self.expected
+    }
+
+    /// Mean interval over the measured events
+    pub fn get_mean(&self) -> Duration {
+        self.mean
+    }
+
+    /// Longest single interval over the measured events
+    pub fn get_max(&self) -> Duration {
+        self.max
+    }
+
+    /// Number of intervals measured
+    pub fn get_events(&self) -> u32 {
+        self.events
+    }
+}
+
+/// Measures the intervals between buffer events in windows of `window` events
+pub(crate) struct IntervalMonitor {
+    expected: Duration,
+    tolerance: f64,
+    window: u32,
+    last: Option<Instant>,
+    sum: Duration,
+    max: Duration,
+    events: u32,
+    callback: DeviationFn,
+}
+
+impl IntervalMonitor {
+    pub(crate) fn new(expected: Duration, tolerance: f64, window: u32, callback: DeviationFn) -> Self {
+        Self {
+            expected,
+            tolerance,
+            window: window.max(1),
+            last: None,
+            sum: Duration::ZERO,
+            max: Duration::ZERO,
+            events: 0,
+            callback,
+        }
+    }
+
+    /// Starts over, e.g. after the stream moved to another client
+    pub(crate) fn reset(&mut self) {
+        self.last = None;
+        self.sum = Duration::ZERO;
+        self.max = Duration::ZERO;
+        self.events = 0;
+    }
+
+    /// Called for every buffer event, reports the window it completes if its mean is off by more than the tolerance
+    pub(crate) fn buffer_event(&mut self, now: Instant) {
+        let Some(last) = self.last.replace(now) else {
+            return;
+        };
+        let interval = now.saturating_duration_since(last);
+        self.sum += interval;
+        self.max = self.max.max(interval);
+        self.events += 1;
+        if self.events < self.window {
+            return;
+        }
+        let mean = self.sum / self.events;
+        let deviation = (mean.as_secs_f64() - self.expected.as_secs_f64()).abs();
+        if deviation > self.expected.as_secs_f64() * self.tolerance {
+            warn!(
+                "Buffer events of stream {} came every {:?} on average instead of every {:?}, up to {:?}",
+                CurrentStream, mean, self.expected, self.max
+            );
+            (self.callback)(IntervalDeviation {
+                expected: self.expected,
+                mean,
+                max: self.max,
+                events: self.events,
+            });
+        }
+        self.sum = Duration::ZERO;
+        self.max = Duration::ZERO;
+        self.events = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::audio_client::AudioClient;
+
+    #[test]
+    fn report_deviating_windows() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let sink = reported.clone();
+        let expected = Duration::from_millis(10);
+        let mut monitor = IntervalMonitor::new(expected, 0.5, 4, Box::new(move |dev| sink.lock().unwrap().push(dev)));
+
+        let start = Instant::now();
+        // A window at the expected interval, then one where the driver signals every 30 ms
+        for i in 0..=4 {
+            monitor.buffer_event(start + expected * i);
+        }
+        for i in 1..=4 {
+            monitor.buffer_event(start + expected * 4 + Duration::from_millis(30) * i);
+        }
+        let reported = reported.lock().unwrap().clone();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].get_mean(), Duration::from_millis(30));
+        assert_eq!(reported[0].get_events(), 4);
+
+        // The first event after a reset only sets the start
+        monitor.reset();
+        monitor.buffer_event(start + Duration::from_secs(10));
+        assert_eq!(monitor.events, 0);
+    }
+
+    #[test]
+    fn expected_interval_of_stream() {
+        let config = AudioClient::new().start_recording_loopback_device(None, |_| {}, |_| {}).unwrap();
+        let interval = config.expected_packet_interval();
+        assert!(interval > Duration::ZERO && interval <= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,202 @@
+//! Converting between NT paths and DOS paths, see [`PathResolver`].
+//!
+//! Session identifiers name executables by their NT path, e.g. `\Device\HarddiskVolume3\Windows\explorer.exe`. The
+//! resolver queries the target of every drive letter once and maps paths through that table. Network paths are
+//! handled through the multiple UNC provider: `\Device\Mup\server\share\...` becomes `\\server\share\...`, or the
+//! letter of a network drive mapped to that share.
+
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, RwLock, RwLockReadGuard};
+
+use log::trace;
+use windows::Win32::Storage::FileSystem::{GetLogicalDrives, QueryDosDeviceW};
+use windows_core::HSTRING;
+
+use crate::manager::AudioError;
+
+const MUP_PREFIX: &str = r"\Device\Mup";
+/// Buffer for the target of a drive letter, `QueryDosDeviceW` fails with `ERROR_INSUFFICIENT_BUFFER` beyond it
+const MAX_TARGET_LEN: usize = 1024;
+const ERROR_FILE_NOT_FOUND: u32 = 2;
+
+static SHARED: LazyLock<PathResolver> = LazyLock::new(PathResolver::new);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DriveMapping {
+    letter: char,
+    /// Target as reported by `QueryDosDeviceW`, e.g. `\Device\HarddiskVolume3`
+    target: String,
+    /// Target NT paths of files on the drive start with, the UNC provider path for network drives
+    prefix: String,
+}
+
+impl DriveMapping {
+    fn new(letter: char, target: String) -> Self {
+        Self {
+            letter,
+            prefix: network_prefix(&target).unwrap_or_else(|| target.clone()),
+            target,
+        }
+    }
+}
+
+/// Cached drive letter table for NT and DOS path conversion
+///
+/// Lookups of an unknown volume refresh the table once, so drives mounted after it was built are found without
+/// calling [`PathResolver::refresh`]. Removed or remapped drives are only noticed on a refresh.
+pub struct PathResolver {
+    drives: RwLock<Vec<DriveMapping>>,
+}
+
+impl Default for PathResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PathResolver {
+    pub fn new() -> Self {
+        Self {
+            drives: RwLock::new(query_drives()),
+        }
+    }
+
+    /// Process wide resolver, used by [`get_dos_path`](crate::manager::get_dos_path) and
+    /// [`get_nt_path`](crate::manager::get_nt_path)
+    pub fn shared() -> &'static PathResolver {
+        &SHARED
+    }
+
+    /// Queries the drive letters again, e.g. after a drive was removed or a network drive remapped
+    pub fn refresh(&self) {
+        let drives = query_drives();
+        trace!("Drive letters refreshed: {:?}", drives);
+        *self.drives.write().unwrap_or_else(|e| e.into_inner()) = drives;
+    }
+
+    fn drives(&self) -> RwLockReadGuard<'_, Vec<DriveMapping>> {
+        self.drives.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// `\Device\HarddiskVolume3\Windows` becomes `C:\Windows`, `\Device\Mup\server\share` becomes `\\server\share`
+    /// unless a drive letter is mapped to the share
+    pub fn to_dos_path(&self, nt_path: &str) -> Result<PathBuf, AudioError> {
+        if !nt_path.starts_with(r"\Device\") {
+            return Err(AudioError::InvalidPath);
+        }
+        if let Some(path) = dos_path(&self.drives(), nt_path) {
+            return Ok(path);
+        }
+        if let Some(unc) = strip_prefix_ignore_case(nt_path, MUP_PREFIX) {
+            return Ok(PathBuf::from(format!(r"\{}", unc)));
+        }
+        self.refresh();
+        dos_path(&self.drives(), nt_path).ok_or(AudioError::FailedGettingDosPath(ERROR_FILE_NOT_FOUND))
+    }
+
+    /// `C:\Windows` becomes `\Device\HarddiskVolume3\Windows`, `\\server\share` becomes `\Device\Mup\server\share`
+    pub fn to_nt_path(&self, dos_path: &Path) -> Result<PathBuf, AudioError> {
+        let dos_path = dos_path.to_str().ok_or(AudioError::InvalidPath)?;
+        if let Some(unc) = dos_path.strip_prefix(r"\\") {
+            return Ok(PathBuf::from(format!(r"{}\{}", MUP_PREFIX, unc)));
+        }
+        let (letter, rest) = split_drive(dos_path).ok_or(AudioError::InvalidPath)?;
+        if let Some(path) = nt_path(&self.drives(), letter, rest) {
+            return Ok(path);
+        }
+        self.refresh();
+        nt_path(&self.drives(), letter, rest).ok_or(AudioError::FailedGettingNtPath(ERROR_FILE_NOT_FOUND))
+    }
+}
+
+fn query_drives() -> Vec<DriveMapping> {
+    let letters = unsafe { GetLogicalDrives() };
+    (0..26)
+        .filter(|bit| letters & (1 << bit) != 0)
+        .filter_map(|bit| {
+            let letter = (b'A' + bit as u8) as char;
+            let mut buffer = vec![0u16; MAX_TARGET_LEN];
+            let len = unsafe { QueryDosDeviceW(&HSTRING::from(format!("{}:", letter)), Some(&mut buffer)) } as usize;
+            // The result is a list of null terminated strings, the first one is the current target
+            let target = buffer[..len].split(|&c| c == 0).next()?;
+            let target = String::from_utf16(target).ok().filter(|target| !target.is_empty())?;
+            Some(DriveMapping::new(letter, target))
+        })
+        .collect()
+}
+
+/// Network redirector targets like `\Device\LanmanRedirector\;Z:0000000000012345\server\share` as the path of the share
+/// under the UNC provider, `\Device\Mup\server\share`
+fn network_prefix(target: &str) -> Option<String> {
+    let (_, share) = target.split_once(r"\;")?;
+    let (_, share) = share.split_once('\\')?;
+    Some(format!(r"{}\{}", MUP_PREFIX, share))
+}
+
+fn strip_prefix_ignore_case<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = path.get(prefix.len()..)?;
+    let matches = path[..prefix.len()].eq_ignore_ascii_case(prefix) && (rest.is_empty() || rest.starts_with('\\'));
+    matches.then_some(rest)
+}
+
+fn split_drive(path: &str) -> Option<(char, &str)> {
+    let mut chars = path.chars();
+    let letter = chars.next().filter(char::is_ascii_alphabetic)?;
+    let rest = chars.as_str().strip_prefix(':')?;
+    Some((letter.to_ascii_uppercase(), rest))
+}
+
+/// The longest prefix wins, so a share mapped to a drive is preferred over a parent share
+fn dos_path(drives: &[DriveMapping], nt_path: &str) -> Option<PathBuf> {
+    drives
+        .iter()
+        .filter_map(|drive| Some((drive, strip_prefix_ignore_case(nt_path, &drive.prefix)?)))
+        .max_by_key(|(drive, _)| drive.prefix.len())
+        .map(|(drive, rest)| match rest {
+            "" => PathBuf::from(format!(r"{}:\", drive.letter)),
+            rest => PathBuf::from(format!("{}:{}", drive.letter, rest)),
+        })
+}
+
+fn nt_path(drives: &[DriveMapping], letter: char, rest: &str) -> Option<PathBuf> {
+    let drive = drives.iter().find(|drive| drive.letter == letter)?;
+    let rest = rest.strip_prefix('\\').unwrap_or(rest);
+    Some(PathBuf::from(format!(r"{}\{}", drive.target, rest)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_paths() {
+        let drives = vec![
+            DriveMapping::new('C', r"\Device\HarddiskVolume1".to_string()),
+            DriveMapping::new('D', r"\Device\HarddiskVolume10".to_string()),
+            DriveMapping::new('Z', r"\Device\LanmanRedirector\;Z:0000000000012345\server\share".to_string()),
+        ];
+        assert_eq!(
+            dos_path(&drives, r"\Device\HarddiskVolume10\a.exe"),
+            Some(PathBuf::from(r"D:\a.exe"))
+        );
+        assert_eq!(dos_path(&drives, r"\device\harddiskvolume1"), Some(PathBuf::from(r"C:\")));
+        assert_eq!(
+            dos_path(&drives, r"\Device\Mup\server\share\a.exe"),
+            Some(PathBuf::from(r"Z:\a.exe"))
+        );
+        assert_eq!(dos_path(&drives, r"\Device\HarddiskVolume2\a.exe"), None);
+        assert_eq!(
+            nt_path(&drives, 'C', r"\Windows"),
+            Some(PathBuf::from(r"\Device\HarddiskVolume1\Windows"))
+        );
+
+        let resolver = PathResolver::new();
+        assert_eq!(
+            resolver.to_dos_path(r"\Device\Mup\other\share\a.exe").unwrap(),
+            PathBuf::from(r"\\other\share\a.exe")
+        );
+        let windows = PathBuf::from(std::env::var("SystemRoot").unwrap());
+        let nt_windows = resolver.to_nt_path(&windows).unwrap();
+        assert_eq!(resolver.to_dos_path(nt_windows.to_str().unwrap()).unwrap(), windows);
+    }
+}
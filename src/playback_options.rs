@@ -0,0 +1,81 @@
+//! Options applied to playback streams started by an [`AudioClient`](crate::audio_client::AudioClient).
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::manager::Device;
+
+/// A playback stream moved to another device because its device was removed or invalidated
+#[derive(Debug, Clone)]
+pub struct OutputSwitched {
+    from: Option<Device>,
+    to: Device,
+}
+
+impl OutputSwitched {
+    pub(crate) fn new(from: Option<Device>, to: Device) -> Self {
+        Self { from, to }
+    }
+
+    /// The lost device, `None` if the stream was started on the default device
+    pub fn get_from(&self) -> Option<&Device> {
+        self.from.as_ref()
+    }
+
+    pub fn get_to(&self) -> &Device {
+        &self.to
+    }
+}
+
+pub(crate) type OutputSwitchedFn = Arc<dyn Fn(OutputSwitched) + Send + Sync + 'static>;
+
+#[derive(Clone, Default)]
+pub struct PlaybackOptions {
+    failover: Vec<Device>,
+    on_output_switched: Option<OutputSwitchedFn>,
+}
+
+impl fmt::Debug for PlaybackOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PlaybackOptions")
+            .field("failover", &self.failover)
+            .field("on_output_switched", &self.on_output_switched.is_some())
+            .finish()
+    }
+}
+
+impl PlaybackOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Devices to continue playback on when the stream's device is removed or invalidated
+    ///
+    /// The stream moves to the first active device of the list, or to the default playback device if none of them
+    /// can be opened. The callback keeps getting the same format, the audio engine converts it for the new device.
+    /// [`AudioStream::service`](crate::audio_stream::AudioStream::service) and
+    /// [`AudioStream::set_sample_rate`](crate::audio_stream::AudioStream::set_sample_rate) keep referring to the first device.
+    pub fn failover(mut self, devices: &[Device]) -> Self {
+        self.failover = devices.to_vec();
+        self
+    }
+
+    pub fn get_failover(&self) -> &[Device] {
+        &self.failover
+    }
+
+    /// Called on the stream thread every time the stream moved to another device
+    pub fn on_output_switched<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(OutputSwitched) + Send + Sync + 'static,
+    {
+        self.on_output_switched = Some(Arc::new(callback));
+        self
+    }
+
+    pub(crate) fn output_switched(&self, switched: OutputSwitched) {
+        if let Some(callback) = &self.on_output_switched {
+            callback(switched);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! Result of [`AudioClient::preflight`](crate::audio_client::AudioClient::preflight).
+
+use windows::core::HRESULT;
+
+use crate::device_state::DeviceState;
+use crate::manager::FormatSupport;
+
+/// Reason a capture of the target would fail
+#[derive(Debug, Clone, PartialEq)]
+pub enum PreflightIssue {
+    /// There is no default device for the target's direction
+    NoDefaultDevice,
+    DeviceNotActive(DeviceState),
+    /// Capture targets need an input device, loopback targets a playback device
+    WrongDeviceDirection,
+    /// Denied by the privacy settings (microphone access)
+    AccessDenied,
+    FormatUnsupported,
+    ProcessNotFound,
+    /// Process loopback requires Windows 10 build 20348 or newer
+    ProcessLoopbackUnavailable(HRESULT),
+    /// Activating or initializing the audio client failed for another reason
+    ClientInitializationFailed(HRESULT),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Preflight {
+    /// State of the resolved device, `None` for process targets
+    pub device_state: Option<DeviceState>,
+    /// Support of the client's format, `None` if no format was set and the mix format is used
+    pub format_support: Option<FormatSupport>,
+    pub issues: Vec<PreflightIssue>,
+}
+
+impl Preflight {
+    /// True if starting a capture of the target is expected to succeed
+    pub fn can_capture(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
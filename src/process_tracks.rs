@@ -0,0 +1,260 @@
+//! One capture stream per process on a shared timeline, see [`ProcessTracks`].
+//!
+//! Every track is cut into blocks of the same length. Block `n` of every track covers the same span of time, counted
+//! from a performance counter zero taken right before the streams start, so writing out the blocks of each track gives
+//! files that line up sample accurately in a DAW. Gaps in a track are filled with silence.
+
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use crate::audio_client::{AudioClient, AudioClientError};
+use crate::sample_format::SampleFormat;
+use crate::stream_group::{RunningStreamGroup, StreamGroup};
+use crate::stream_instant::StreamInstant;
+
+/// 10 ms at 48 kHz
+const DEFAULT_BLOCK_FRAMES: usize = 480;
+
+/// A block of one track, the block with the same index in every other track covers the same span of time
+pub struct TrackBlock<'a> {
+    track: usize,
+    pid: u32,
+    index: u64,
+    timestamp: StreamInstant,
+    data: &'a [u8],
+}
+
+impl<'a> TrackBlock<'a> {
+    /// Position of the process in the list given to [`ProcessTracks::new`]
+    pub fn get_track(&self) -> usize {
+        self.track
+    }
+
+    pub fn get_pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn get_index(&self) -> u64 {
+        self.index
+    }
+
+    /// Start of the block, the shared zero plus `index` block lengths
+    pub fn get_timestamp(&self) -> &StreamInstant {
+        &self.timestamp
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+pub struct ProcessTracks {
+    pids: Vec<u32>,
+    format: SampleFormat,
+    block_frames: usize,
+}
+
+impl ProcessTracks {
+    /// One track per process in `pids`, all captured in `format`
+    pub fn new(pids: impl IntoIterator<Item = u32>, format: SampleFormat) -> Self {
+        Self {
+            pids: pids.into_iter().collect(),
+            format,
+            block_frames: DEFAULT_BLOCK_FRAMES,
+        }
+    }
+
+    /// Length of the blocks handed to the callback, the same for every track
+    pub fn block_frames(mut self, block_frames: usize) -> Self {
+        self.block_frames = block_frames.max(1);
+        self
+    }
+
+    /// Starts every track together, errors of a track's stream go to `error_callback` with the track index
+    pub fn start<D, E>(self, data_callback: D, error_callback: E) -> Result<RunningProcessTracks, AudioClientError>
+    where
+        D: Fn(TrackBlock) + Send + Sync + 'static,
+        E: Fn(usize, AudioClientError) + Send + Sync + 'static,
+    {
+        let data_callback = Arc::new(data_callback);
+        let error_callback = Arc::new(error_callback);
+        // Set right before the group starts, the streams can't deliver packets before that
+        let zero: Arc<OnceLock<StreamInstant>> = Arc::default();
+
+        let mut group = StreamGroup::new();
+        for (track, pid) in self.pids.into_iter().enumerate() {
+            let mut audio_client = AudioClient::new();
+            audio_client.set_format(self.format.clone())?;
+            let mut aligner = TrackAligner::new(&self.format, self.block_frames);
+            let (data_callback, error_callback, zero) = (data_callback.clone(), error_callback.clone(), zero.clone());
+            let config = audio_client
+                .start_recording_process(
+                    pid,
+                    move |packet| {
+                        let zero = *zero.get().expect("zero is set before the streams start");
+                        let offset = packet.timestamp().as_nanos() - zero.as_nanos();
+                        aligner.push(packet.data(), offset, |index, duration, data| {
+                            data_callback(TrackBlock {
+                                track,
+                                pid,
+                                index,
+                                timestamp: zero.add(duration).unwrap_or(zero),
+                                data,
+                            })
+                        });
+                    },
+                    move |err| error_callback(track, err),
+                )?
+                .with_label(format!("track {}", track));
+            group.add(config);
+        }
+
+        let zero = *zero.get_or_init(StreamInstant::now);
+        Ok(RunningProcessTracks {
+            group: group.start()?,
+            zero,
+        })
+    }
+}
+
+/// Tracks started by [`ProcessTracks::start`], dropping it stops all of them
+pub struct RunningProcessTracks {
+    group: RunningStreamGroup,
+    zero: StreamInstant,
+}
+
+impl RunningProcessTracks {
+    /// The time block 0 of every track starts at
+    pub fn get_zero(&self) -> &StreamInstant {
+        &self.zero
+    }
+
+    pub fn len(&self) -> usize {
+        self.group.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.group.is_empty()
+    }
+
+    // See drop implementation of the group for cleanup
+    pub fn stop(self) {}
+}
+
+/// Places the packets of one track on the shared timeline and cuts them into blocks
+pub(crate) struct TrackAligner {
+    block_align: usize,
+    sample_rate: u32,
+    block_frames: usize,
+    /// Frame on the timeline the next written frame lands on, `None` before the first packet
+    next_frame: Option<u64>,
+    block: Vec<u8>,
+    block_index: u64,
+    silence: Vec<u8>,
+}
+
+impl TrackAligner {
+    pub(crate) fn new(format: &SampleFormat, block_frames: usize) -> Self {
+        Self {
+            block_align: format.block_align() as usize,
+            sample_rate: format.get_n_samples_per_sec(),
+            block_frames,
+            next_frame: None,
+            block: Vec::new(),
+            block_index: 0,
+            silence: Vec::new(),
+        }
+    }
+
+    /// `offset_nanos` is the time of the first frame of `data` relative to the shared zero
+    /// `emit` gets the index, the start relative to the zero and the data of every completed block
+    pub(crate) fn push(&mut self, data: &[u8], offset_nanos: i128, mut emit: impl FnMut(u64, Duration, &[u8])) {
+        if self.block_align == 0 {
+            return;
+        }
+        let mut data = data;
+        let mut frame_at = offset_nanos * self.sample_rate as i128 / 1_000_000_000;
+        // Frames from before the zero aren't part of the timeline
+        if frame_at < 0 {
+            let skip = (-frame_at).min((data.len() / self.block_align) as i128);
+            data = &data[skip as usize * self.block_align..];
+            frame_at += skip;
+        }
+        let frame_at = frame_at.max(0) as u64;
+
+        // The first packet is placed exactly, later ones only when the gap is larger than the timestamp jitter
+        let tolerance = if self.next_frame.is_none() {
+            0
+        } else {
+            self.block_frames as u64 / 2
+        };
+        let expected = *self.next_frame.get_or_insert(0);
+        if frame_at > expected + tolerance {
+            self.write_silence(frame_at - expected, &mut emit);
+        }
+        self.write(data, &mut emit);
+    }
+
+    fn write_silence(&mut self, frames: u64, emit: &mut impl FnMut(u64, Duration, &[u8])) {
+        let mut silence = std::mem::take(&mut self.silence);
+        silence.resize(self.block_frames * self.block_align, 0);
+        let mut remaining = frames as usize;
+        while remaining > 0 {
+            let len = remaining.min(self.block_frames);
+            self.write(&silence[..len * self.block_align], emit);
+            remaining -= len;
+        }
+        self.silence = silence;
+    }
+
+    fn write(&mut self, mut data: &[u8], emit: &mut impl FnMut(u64, Duration, &[u8])) {
+        let block_len = self.block_frames * self.block_align;
+        while !data.is_empty() {
+            let len = (block_len - self.block.len()).min(data.len());
+            self.block.extend_from_slice(&data[..len]);
+            data = &data[len..];
+            self.next_frame = self.next_frame.map(|frame| frame + (len / self.block_align) as u64);
+            if self.block.len() == block_len {
+                let start_frame = self.block_index * self.block_frames as u64;
+                let start = Duration::from_nanos(start_frame * 1_000_000_000 / self.sample_rate.max(1) as u64);
+                emit(self.block_index, start, &self.block);
+                self.block.clear();
+                self.block_index += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample_format::FormatTag;
+
+    #[test]
+    fn align_blocks() {
+        // One frame per millisecond, one byte per frame
+        let format = SampleFormat::new(FormatTag::WaveFormatPcm, 1, 1000, 8);
+        let mut aligner = TrackAligner::new(&format, 4);
+        let mut blocks = Vec::new();
+        let mut push = |aligner: &mut TrackAligner, data: &[u8], offset_ms: i128| {
+            aligner.push(data, offset_ms * 1_000_000, |index, start, data| {
+                blocks.push((index, start.as_millis(), data.to_vec()))
+            })
+        };
+
+        push(&mut aligner, &[1, 2, 3], 2);
+        push(&mut aligner, &[4, 5, 6, 7], 5);
+        // Packet after a gap lands on its own position again
+        push(&mut aligner, &[8, 9], 20);
+        assert_eq!(
+            blocks,
+            vec![
+                (0, 0, vec![0, 0, 1, 2]),
+                (1, 4, vec![3, 4, 5, 6]),
+                (2, 8, vec![7, 0, 0, 0]),
+                (3, 12, vec![0, 0, 0, 0]),
+                (4, 16, vec![0, 0, 0, 0]),
+            ]
+        );
+    }
+}
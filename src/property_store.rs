@@ -0,0 +1,249 @@
+//! Reading and writing endpoint properties, see [`DevicePropertyStore`].
+//!
+//! Most endpoint properties can only be written by an elevated process, and drivers or the audio service may overwrite
+//! them again, e.g. when the device is reinstalled. The description is the exception that matters in practice: it is
+//! the name shown in the sound settings, and renaming an endpoint is a plain write to it.
+
+use std::mem::ManuallyDrop;
+
+use windows::Win32::{
+    Devices::Properties,
+    Foundation::{DEVPROPKEY, E_OUTOFMEMORY, PROPERTYKEY, VARIANT_BOOL},
+    Media::Audio::{EndpointFormFactor, IMMDevice, PKEY_AudioEndpoint_FormFactor, PKEY_AudioEndpoint_JackSubType},
+    System::{
+        Com::{
+            CoTaskMemAlloc, STGM, STGM_READ, STGM_READWRITE,
+            StructuredStorage::{PROPVARIANT, PROPVARIANT_0, PROPVARIANT_0_0, PROPVARIANT_0_0_0, PropVariantClear},
+        },
+        Variant::{VARENUM, VT_BOOL, VT_CLSID, VT_EMPTY, VT_LPWSTR, VT_UI4},
+    },
+    UI::Shell::PropertiesSystem::IPropertyStore,
+};
+use windows_core::{GUID, PWSTR};
+
+use crate::device_query::FormFactor;
+use crate::manager::AudioError;
+
+/// A property value, reduced to the variant types endpoint properties use
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyValue {
+    /// The property isn't set
+    Empty,
+    String(String),
+    U32(u32),
+    Guid(GUID),
+    Bool(bool),
+    /// A variant type without a conversion, the raw `VARENUM` value
+    Unsupported(u16),
+}
+
+/// Owns a variant read from or written to the store, and frees its payload
+struct OwnedPropVariant(PROPVARIANT);
+
+impl OwnedPropVariant {
+    fn vt(&self) -> VARENUM {
+        unsafe { self.0.Anonymous.Anonymous.vt }
+    }
+
+    fn data(&self) -> &PROPVARIANT_0_0_0 {
+        unsafe { &self.0.Anonymous.Anonymous.Anonymous }
+    }
+
+    fn new(vt: VARENUM, data: PROPVARIANT_0_0_0) -> Self {
+        Self(PROPVARIANT {
+            Anonymous: PROPVARIANT_0 {
+                Anonymous: ManuallyDrop::new(PROPVARIANT_0_0 {
+                    vt,
+                    wReserved1: 0,
+                    wReserved2: 0,
+                    wReserved3: 0,
+                    Anonymous: data,
+                }),
+            },
+        })
+    }
+
+    /// Copies `value` into task memory, which [`PropVariantClear`] frees again
+    fn string(value: &str) -> Result<Self, AudioError> {
+        let wide: Vec<u16> = value.encode_utf16().chain(std::iter::once(0)).collect();
+        let ptr = unsafe { CoTaskMemAlloc(wide.len() * size_of::<u16>()) } as *mut u16;
+        if ptr.is_null() {
+            return Err(AudioError::PropertyWriteError(E_OUTOFMEMORY.into()));
+        }
+        unsafe { std::ptr::copy_nonoverlapping(wide.as_ptr(), ptr, wide.len()) };
+        Ok(Self::new(VT_LPWSTR, PROPVARIANT_0_0_0 { pwszVal: PWSTR(ptr) }))
+    }
+
+    fn to_value(&self) -> Result<PropertyValue, AudioError> {
+        let data = self.data();
+        Ok(match self.vt() {
+            VT_EMPTY => PropertyValue::Empty,
+            VT_LPWSTR => PropertyValue::String(unsafe { data.pwszVal.to_string() }.map_err(AudioError::RawStringParseError)?),
+            VT_UI4 => PropertyValue::U32(unsafe { data.ulVal }),
+            VT_CLSID => PropertyValue::Guid(unsafe { data.puuid.as_ref() }.copied().ok_or(AudioError::InvalidPropVariant)?),
+            VT_BOOL => PropertyValue::Bool(unsafe { data.boolVal }.as_bool()),
+            vt => PropertyValue::Unsupported(vt.0),
+        })
+    }
+}
+
+impl Drop for OwnedPropVariant {
+    fn drop(&mut self) {
+        let _ = unsafe { PropVariantClear(&mut self.0) };
+    }
+}
+
+/// `DEVPKEY_*` keys have the layout of a `PROPERTYKEY`, the property store accepts both
+pub(crate) fn dev_key(key: &DEVPROPKEY) -> &PROPERTYKEY {
+    unsafe { &*(key as *const DEVPROPKEY as *const PROPERTYKEY) }
+}
+
+/// Property store of an endpoint, see [`Device::property_store`](crate::manager::Device::property_store)
+///
+/// Keys are the `PKEY_*` constants of the `windows` crate, `DEVPKEY_*` ones go through their typed getter or a cast.
+pub struct DevicePropertyStore {
+    store: IPropertyStore,
+}
+
+unsafe impl Send for DevicePropertyStore {}
+
+impl DevicePropertyStore {
+    pub(crate) fn open(dev: &IMMDevice, writable: bool) -> Result<Self, AudioError> {
+        let (mode, map_err): (STGM, fn(windows::core::Error) -> AudioError) = if writable {
+            (STGM_READWRITE, AudioError::PropertyWriteError)
+        } else {
+            (STGM_READ, AudioError::PropertyStoreError)
+        };
+        let store = unsafe { dev.OpenPropertyStore(mode) }.map_err(map_err)?;
+        Ok(Self { store })
+    }
+
+    fn read(&self, key: &PROPERTYKEY) -> Result<OwnedPropVariant, AudioError> {
+        let propvar = unsafe { self.store.GetValue(key) }.map_err(AudioError::PropertyStoreError)?;
+        Ok(OwnedPropVariant(propvar))
+    }
+
+    pub fn get_value(&self, key: &PROPERTYKEY) -> Result<PropertyValue, AudioError> {
+        self.read(key)?.to_value()
+    }
+
+    /// Keys set on the endpoint, including ones without a `PKEY_*` constant
+    pub fn get_keys(&self) -> Result<Vec<PROPERTYKEY>, AudioError> {
+        let count = unsafe { self.store.GetCount() }.map_err(AudioError::PropertyStoreError)?;
+        (0..count)
+            .map(|i| {
+                let mut key = PROPERTYKEY::default();
+                unsafe { self.store.GetAt(i, &mut key) }.map_err(AudioError::PropertyStoreError)?;
+                Ok(key)
+            })
+            .collect()
+    }
+
+    pub fn get_string(&self, key: &PROPERTYKEY) -> Result<String, AudioError> {
+        let propvar = self.read(key)?;
+        if propvar.vt() != VT_LPWSTR {
+            return Err(AudioError::InvalidPropVariant);
+        }
+        unsafe { propvar.data().pwszVal.to_string() }.map_err(AudioError::RawStringParseError)
+    }
+
+    pub fn get_u32(&self, key: &PROPERTYKEY) -> Result<u32, AudioError> {
+        let propvar = self.read(key)?;
+        if propvar.vt() != VT_UI4 {
+            return Err(AudioError::InvalidPropVariant);
+        }
+        Ok(unsafe { propvar.data().ulVal })
+    }
+
+    pub fn get_guid(&self, key: &PROPERTYKEY) -> Result<GUID, AudioError> {
+        let propvar = self.read(key)?;
+        if propvar.vt() != VT_CLSID {
+            return Err(AudioError::InvalidPropVariant);
+        }
+        unsafe { propvar.data().puuid.as_ref() }
+            .copied()
+            .ok_or(AudioError::InvalidPropVariant)
+    }
+
+    /// Description and adapter combined, e.g. "Speakers (Realtek(R) Audio)"
+    pub fn get_friendly_name(&self) -> Result<String, AudioError> {
+        self.get_string(dev_key(&Properties::DEVPKEY_Device_FriendlyName))
+    }
+
+    /// Name of the endpoint itself, e.g. "Speakers", the part users rename
+    pub fn get_device_description(&self) -> Result<String, AudioError> {
+        self.get_string(dev_key(&Properties::DEVPKEY_Device_DeviceDesc))
+    }
+
+    /// Name of the adapter the endpoint belongs to, e.g. "Realtek(R) Audio"
+    pub fn get_interface_friendly_name(&self) -> Result<String, AudioError> {
+        self.get_string(dev_key(&Properties::DEVPKEY_DeviceInterface_FriendlyName))
+    }
+
+    pub fn get_container_id(&self) -> Result<GUID, AudioError> {
+        self.get_guid(dev_key(&Properties::DEVPKEY_Device_ContainerId))
+    }
+
+    pub fn get_form_factor(&self) -> Result<FormFactor, AudioError> {
+        let form_factor = self.get_u32(&PKEY_AudioEndpoint_FormFactor)?;
+        Ok(EndpointFormFactor(form_factor as i32).into())
+    }
+
+    /// Kind of jack the endpoint is plugged into, one of the `KSNODETYPE_*` guids, not set for most virtual endpoints
+    pub fn get_jack_subtype(&self) -> Result<GUID, AudioError> {
+        self.get_guid(&PKEY_AudioEndpoint_JackSubType)
+    }
+
+    /// Writes a string property, visible to other processes after [`DevicePropertyStore::commit`]
+    pub fn set_string(&self, key: &PROPERTYKEY, value: &str) -> Result<(), AudioError> {
+        self.write(key, OwnedPropVariant::string(value)?)
+    }
+
+    pub fn set_u32(&self, key: &PROPERTYKEY, value: u32) -> Result<(), AudioError> {
+        self.write(key, OwnedPropVariant::new(VT_UI4, PROPVARIANT_0_0_0 { ulVal: value }))
+    }
+
+    pub fn set_bool(&self, key: &PROPERTYKEY, value: bool) -> Result<(), AudioError> {
+        let value = VARIANT_BOOL::from(value);
+        self.write(key, OwnedPropVariant::new(VT_BOOL, PROPVARIANT_0_0_0 { boolVal: value }))
+    }
+
+    /// Renames the endpoint, the friendly name follows as "{description} ({adapter})"
+    pub fn set_device_description(&self, description: &str) -> Result<(), AudioError> {
+        self.set_string(dev_key(&Properties::DEVPKEY_Device_DeviceDesc), description)
+    }
+
+    fn write(&self, key: &PROPERTYKEY, propvar: OwnedPropVariant) -> Result<(), AudioError> {
+        unsafe { self.store.SetValue(key, &propvar.0) }.map_err(AudioError::PropertyWriteError)
+    }
+
+    /// Saves the values set so far
+    pub fn commit(&self) -> Result<(), AudioError> {
+        unsafe { self.store.Commit() }.map_err(AudioError::PropertyWriteError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manager::DeviceManager;
+
+    #[test]
+    fn read_properties() {
+        let dev = DeviceManager::get_default_playback_device().unwrap();
+        let store = dev.property_store().unwrap();
+        assert_eq!(store.get_friendly_name().unwrap(), dev.get_friendly_name().unwrap());
+        assert_eq!(store.get_form_factor().unwrap(), dev.get_form_factor().unwrap());
+
+        let description = store.get_device_description().unwrap();
+        assert!(dev.get_friendly_name().unwrap().starts_with(&description));
+        let keys = store.get_keys().unwrap();
+        assert!(keys.contains(&PKEY_AudioEndpoint_FormFactor));
+        assert!(matches!(
+            store.get_value(&PKEY_AudioEndpoint_FormFactor).unwrap(),
+            PropertyValue::U32(_)
+        ));
+        // Reading doesn't need write access, writing through a read-only store fails
+        assert!(store.set_device_description(&description).is_err());
+    }
+}
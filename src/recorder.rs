@@ -0,0 +1,242 @@
+//! Recording a device or process straight into a WAV file, see [`WavRecorder`].
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::audio_client::{AudioClient, AudioClientError};
+use crate::capture_target::CaptureTarget;
+use crate::manager::Device;
+use crate::sample_format::SampleFormat;
+use crate::wav::WavWriter;
+
+/// How often a recording checks its [`StopToken`] while no packets arrive, e.g. loopback of a silent device
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// Time given to WASAPI to hand over the packets still buffered when the recording stops
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Error, Debug)]
+pub enum RecorderError {
+    #[error("Failed writing wav file: {0}")]
+    FailedWritingFile(#[source] io::Error),
+    /// The stream failed to start or failed while recording, the file holds what was recorded until then
+    #[error("Capture stream failed: {0}")]
+    StreamError(#[source] AudioClientError),
+}
+
+/// Stops a recording from another thread
+#[derive(Debug, Clone, Default)]
+pub struct StopToken {
+    stopped: Arc<AtomicBool>,
+}
+
+impl StopToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+}
+
+/// When a recording ends, `Duration` and [`StopToken`] convert into it
+#[derive(Debug, Clone)]
+pub enum RecordUntil {
+    /// Record for a fixed time, counted from the start of the stream
+    Elapsed(Duration),
+    /// Record until the token is stopped
+    Stopped(StopToken),
+}
+
+impl From<Duration> for RecordUntil {
+    fn from(duration: Duration) -> Self {
+        RecordUntil::Elapsed(duration)
+    }
+}
+
+impl From<StopToken> for RecordUntil {
+    fn from(token: StopToken) -> Self {
+        RecordUntil::Stopped(token)
+    }
+}
+
+/// What ended up in the file of a finished recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct WavRecording {
+    format: SampleFormat,
+    frames: u64,
+}
+
+impl WavRecording {
+    pub fn format(&self) -> &SampleFormat {
+        &self.format
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.frames as f64 / self.format.get_n_samples_per_sec().max(1) as f64)
+    }
+}
+
+/// Captures into a WAV file, e.g. `WavRecorder::new().record_device(None, "mic.wav", Duration::from_secs(5))`
+///
+/// The recording blocks the calling thread, which also does the file writes so the capture callback never waits on
+/// the disk. The file is written in the format delivered by the stream, set it on the client passed to
+/// [`WavRecorder::with_client`] to record e.g. 16 bit PCM instead of the float mix format.
+pub struct WavRecorder {
+    client: AudioClient,
+}
+
+impl Default for WavRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WavRecorder {
+    pub fn new() -> Self {
+        Self::with_client(AudioClient::new())
+    }
+
+    /// Records with the format and options of `client`
+    pub fn with_client(client: AudioClient) -> Self {
+        Self { client }
+    }
+
+    /// Records an input device, or everything played on a playback device, `None` for the default input device
+    pub fn record_device(
+        self,
+        dev: Option<&Device>,
+        path: impl AsRef<Path>,
+        until: impl Into<RecordUntil>,
+    ) -> Result<WavRecording, RecorderError> {
+        let target = match dev {
+            Some(dev) if dev.is_playback => CaptureTarget::LoopbackDevice(dev.clone()),
+            Some(dev) => CaptureTarget::InputDevice(dev.clone()),
+            None => CaptureTarget::DefaultInput,
+        };
+        self.record(&target, path, until)
+    }
+
+    /// Records `target`, e.g. a single application with [`CaptureTarget::Process`]
+    ///
+    /// The file is finished when a stream or write error ends the recording early, the error is returned after that.
+    pub fn record(
+        self,
+        target: &CaptureTarget,
+        path: impl AsRef<Path>,
+        until: impl Into<RecordUntil>,
+    ) -> Result<WavRecording, RecorderError> {
+        let until = until.into();
+        let (packet_send, packet_recv) = mpsc::channel();
+        let error_send = packet_send.clone();
+        let config = self
+            .client
+            .capture(
+                target,
+                move |packet| {
+                    let _ = packet_send.send(Ok(packet.data().to_vec()));
+                },
+                move |err| {
+                    let _ = error_send.send(Err(err));
+                },
+            )
+            .map_err(RecorderError::StreamError)?;
+        let format = config.format().clone();
+        let file = File::create(path).map_err(RecorderError::FailedWritingFile)?;
+        let mut writer = WavWriter::new(BufWriter::new(file), format.clone()).map_err(RecorderError::FailedWritingFile)?;
+
+        let stream = config.start().map_err(RecorderError::StreamError)?;
+        let deadline = match &until {
+            RecordUntil::Elapsed(duration) => Some(Instant::now() + *duration),
+            RecordUntil::Stopped(_) => None,
+        };
+        let mut failure = None;
+        loop {
+            let timeout = match (&until, deadline) {
+                (RecordUntil::Stopped(token), _) if token.is_stopped() => break,
+                (_, Some(deadline)) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) if !remaining.is_zero() => remaining,
+                    _ => break,
+                },
+                _ => STOP_POLL_INTERVAL,
+            };
+            match packet_recv.recv_timeout(timeout.min(STOP_POLL_INTERVAL)) {
+                Ok(Ok(data)) => {
+                    if let Err(err) = writer.write(&data) {
+                        failure = Some(RecorderError::FailedWritingFile(err));
+                        break;
+                    }
+                }
+                Ok(Err(err)) => {
+                    failure = Some(RecorderError::StreamError(err));
+                    break;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        // The callbacks are dropped with the stream thread, after that the channel only holds the drained packets
+        stream.stop_and_drain(DRAIN_TIMEOUT);
+        if failure.is_none()
+            && let Err(err) = packet_recv
+                .try_iter()
+                .filter_map(Result::ok)
+                .try_for_each(|data| writer.write(&data))
+        {
+            failure = Some(RecorderError::FailedWritingFile(err));
+        }
+        let frames = writer.data_len() / format.block_align().max(1) as u64;
+        // The sizes are filled in after a failed write too, so the file holds everything that made it to disk
+        let finished = writer.finish();
+        match (failure, finished) {
+            (Some(err), _) => Err(err),
+            (None, Err(err)) => Err(RecorderError::FailedWritingFile(err)),
+            (None, Ok(_)) => Ok(WavRecording { format, frames }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::wav::parse_wav;
+
+    #[test]
+    fn record_device() {
+        let path = std::env::temp_dir().join(format!("wav_recorder_{}.wav", std::process::id()));
+        let recording = WavRecorder::new().record_device(None, &path, Duration::from_millis(300)).unwrap();
+        assert!(recording.duration() >= Duration::from_millis(200));
+
+        let wav = std::fs::read(&path).unwrap();
+        let (format, data) = parse_wav(&wav).unwrap();
+        assert_eq!(&format, recording.format());
+        assert_eq!(data.len() as u64, recording.frames() * format.block_align() as u64);
+
+        let token = StopToken::new();
+        let stopper = token.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            stopper.stop();
+        });
+        WavRecorder::new().record_device(None, &path, token).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+//! Streaming sample rate conversion for interleaved `f32` audio.
+//!
+//! Uses linear interpolation, which is cheap enough to run inside the capture callback and good enough for speech and
+//! monitoring. The resampler keeps the last frame of every chunk, so packets of any size can be fed in one after the other.
+
+pub struct Resampler {
+    channels: usize,
+    from_rate: u32,
+    to_rate: u32,
+    /// Input frames advanced per output frame
+    step: f64,
+    /// Position of the next output frame, frame 0 being the last frame of the previous chunk
+    pos: f64,
+    last: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(channels: u16, from_rate: u32, to_rate: u32) -> Self {
+        Self {
+            channels: channels as usize,
+            from_rate,
+            to_rate,
+            step: from_rate as f64 / to_rate as f64,
+            // Frame 0 is silence before the first chunk, start right on the first real frame
+            pos: 1.0,
+            last: vec![0.0; channels as usize],
+        }
+    }
+
+    pub fn from_rate(&self) -> u32 {
+        self.from_rate
+    }
+
+    pub fn to_rate(&self) -> u32 {
+        self.to_rate
+    }
+
+    /// Resamples a chunk of interleaved frames, appending the result to `out`
+    pub fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        let channels = self.channels;
+        if channels == 0 {
+            return;
+        }
+        let frames = input.len() / channels;
+        if frames == 0 {
+            return;
+        }
+        let frame = |index: usize, channel: usize| {
+            if index == 0 {
+                self.last[channel]
+            } else {
+                input[(index - 1) * channels + channel]
+            }
+        };
+
+        out.reserve(((frames as f64 / self.step) as usize + 1) * channels);
+        while (self.pos as usize) < frames {
+            let index = self.pos as usize;
+            let frac = (self.pos - index as f64) as f32;
+            for channel in 0..channels {
+                let a = frame(index, channel);
+                let b = frame(index + 1, channel);
+                out.push(a + (b - a) * frac);
+            }
+            self.pos += self.step;
+        }
+        self.pos -= frames as f64;
+        self.last.copy_from_slice(&input[(frames - 1) * channels..frames * channels]);
+    }
+
+    /// Forgets the buffered frame, e.g. after a discontinuity in the input
+    pub fn reset(&mut self) {
+        self.pos = 1.0;
+        self.last.fill(0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resample_rates() {
+        let mut resampler = Resampler::new(2, 48000, 44100);
+        let input = vec![0.5; 480 * 2];
+        let mut out = Vec::new();
+        for _ in 0..100 {
+            resampler.process(&input, &mut out);
+        }
+        // One second of audio
+        assert!((out.len() / 2).abs_diff(44100) <= 1, "{}", out.len() / 2);
+        assert!(out[2..].iter().all(|s| (s - 0.5).abs() < 0.0001));
+
+        let mut resampler = Resampler::new(1, 8000, 16000);
+        let mut out = Vec::new();
+        resampler.process(&[0.0, 1.0], &mut out);
+        // The last frame is held back until the next chunk arrives
+        assert_eq!(out, [0.0, 0.5]);
+        resampler.process(&[1.0], &mut out);
+        assert_eq!(out, [0.0, 0.5, 1.0, 1.0]);
+    }
+}
@@ -0,0 +1,98 @@
+//! Watching the default roles of one device, see [`RoleWatcher`].
+
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+
+use crate::com::ComSend;
+use crate::device_query::{DataFlow, DeviceRole};
+use crate::dispatcher::Dispatcher;
+use crate::event_args::DeviceNotificationEventArgs;
+use crate::manager::{AudioError, Device};
+use crate::notifications::{NotificationError, Notifications};
+
+#[derive(Error, Debug)]
+pub enum RoleWatcherError {
+    #[error("Failed setting up device notifications: {0}")]
+    NotificationError(#[source] NotificationError),
+    #[error("Failed reading the device roles: {0}")]
+    DeviceError(#[source] AudioError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoleChange {
+    /// The device became the default endpoint for the flow and role
+    Gained(DataFlow, DeviceRole),
+    /// Another device, or none, became the default endpoint for the flow and role
+    Lost(DataFlow, DeviceRole),
+}
+
+/// Reports whenever a device gains or loses a default role, e.g. for routing dashboards
+pub struct RoleWatcher {
+    roles: Arc<Mutex<Vec<(DataFlow, DeviceRole)>>>,
+    _notifications: ComSend<Notifications>,
+}
+
+impl RoleWatcher {
+    /// Starts from the roles `device` serves right now, `callback` only gets the changes
+    pub fn new<CB>(device: &Device, callback: CB) -> Result<Self, RoleWatcherError>
+    where
+        CB: Fn(RoleChange) + Send + 'static,
+    {
+        let device_id = device.get_id().map_err(RoleWatcherError::DeviceError)?;
+        let roles = Arc::new(Mutex::new(device.get_current_roles().map_err(RoleWatcherError::DeviceError)?));
+
+        let mut notifications = Notifications::sta_compatible(Dispatcher::inline()).map_err(RoleWatcherError::NotificationError)?;
+        let watched_roles = roles.clone();
+        notifications
+            .register_device_notification(move |event| {
+                let DeviceNotificationEventArgs::DefaultDeviceChanged(args) = event else {
+                    return;
+                };
+                let role = (args.get_flow(), args.get_role());
+                let is_default = args.get_default_device().is_ok_and(|default_id| default_id == device_id);
+                let change = {
+                    let mut roles = watched_roles.lock().unwrap_or_else(|e| e.into_inner());
+                    let had_role = roles.contains(&role);
+                    match (had_role, is_default) {
+                        (false, true) => {
+                            roles.push(role);
+                            Some(RoleChange::Gained(role.0, role.1))
+                        }
+                        (true, false) => {
+                            roles.retain(|r| *r != role);
+                            Some(RoleChange::Lost(role.0, role.1))
+                        }
+                        _ => None,
+                    }
+                };
+                if let Some(change) = change {
+                    callback(change);
+                }
+            })
+            .map_err(RoleWatcherError::NotificationError)?;
+
+        Ok(Self {
+            roles,
+            _notifications: ComSend(notifications),
+        })
+    }
+
+    /// Roles the device serves, as of the last default device change
+    pub fn get_roles(&self) -> Vec<(DataFlow, DeviceRole)> {
+        self.roles.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manager::DeviceManager;
+
+    #[test]
+    fn watch_default_device() {
+        let device = DeviceManager::get_default_playback_device().unwrap();
+        let watcher = RoleWatcher::new(&device, |_| {}).unwrap();
+        assert!(watcher.get_roles().contains(&(DataFlow::Render, DeviceRole::Console)));
+    }
+}
@@ -0,0 +1,336 @@
+//! Routing any capture source to a playback device, like a virtual audio cable, see [`Router`].
+//!
+//! Works like [`Listen`](crate::listen::Listen), but the source can be a loopback or process capture too, and the
+//! queue between the streams adapts to the timing of the two devices instead of being cut back to a fixed size:
+//! - Drift between the two clocks is corrected one frame per period, by skipping a frame when more than the target is
+//!   queued and repeating one when less is, which is inaudible at the rates clocks drift at.
+//! - Every underrun raises the target by a quarter, up to the maximum latency. After ten seconds without one, it is
+//!   lowered by an eighth again, down to the configured target.
+//!
+//! The playback stream uses the capture format and lets the audio engine convert it to the output's mix format.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::audio_client::{AudioClient, AudioClientError};
+use crate::audio_stream::{AudioStreamConfig, CapturePacket};
+use crate::capture_target::CaptureTarget;
+use crate::listen::FrameCounter;
+use crate::manager::Device;
+use crate::sample_format::SampleFormat;
+use crate::stream_group::{RunningStreamGroup, StreamGroup};
+
+const DEFAULT_TARGET_LATENCY: Duration = Duration::from_millis(30);
+const DEFAULT_MAX_LATENCY: Duration = Duration::from_millis(200);
+/// Time without underruns after which the target is lowered again
+const SHRINK_AFTER: Duration = Duration::from_secs(10);
+
+pub struct Router {
+    source: CaptureTarget,
+    output: Device,
+    target_latency: Duration,
+    max_latency: Duration,
+}
+
+impl Router {
+    /// Routes `source` to the playback device `output`
+    pub fn route(source: CaptureTarget, output: &Device) -> Self {
+        Self {
+            source,
+            output: output.clone(),
+            target_latency: DEFAULT_TARGET_LATENCY,
+            max_latency: DEFAULT_MAX_LATENCY,
+        }
+    }
+
+    /// Audio kept queued between the two streams at the start, and the lowest the target adapts down to
+    pub fn target_latency(mut self, target_latency: Duration) -> Self {
+        self.target_latency = target_latency;
+        self
+    }
+
+    /// Highest the target adapts up to after underruns, queued audio beyond four times the target is dropped
+    pub fn max_latency(mut self, max_latency: Duration) -> Self {
+        self.max_latency = max_latency;
+        self
+    }
+
+    /// Starts the capture and the playback stream together, errors of both streams go to `error_callback`
+    pub fn start<E>(self, error_callback: E) -> Result<RunningRoute, AudioClientError>
+    where
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        let error_callback = Arc::new(Mutex::new(error_callback));
+        let buffer = Arc::new(JitterBuffer::default());
+
+        let capture = self.start_capture(buffer.clone(), error_callback.clone())?;
+        let format = capture.format().clone();
+        buffer.configure(&format, self.target_latency, self.max_latency);
+
+        let render_buffer = buffer.clone();
+        let playback = AudioClient::new().start_playback_device_with_format(
+            Some(&self.output),
+            &format,
+            move |mut request| match render_buffer.pop(request.buffer()) {
+                true => request.frames(),
+                false => 0,
+            },
+            move |err| (error_callback.lock().unwrap_or_else(|e| e.into_inner()))(err),
+        )?;
+
+        let streams = StreamGroup::new()
+            .with(capture.with_label("route"))
+            .with(playback.with_label("route"))
+            .start()?;
+        Ok(RunningRoute {
+            _streams: streams,
+            buffer,
+            format,
+        })
+    }
+
+    fn start_capture<E>(&self, buffer: Arc<JitterBuffer>, error_callback: Arc<Mutex<E>>) -> Result<AudioStreamConfig, AudioClientError>
+    where
+        E: FnMut(AudioClientError) + Send + 'static,
+    {
+        let data_callback = move |packet: CapturePacket| buffer.push(packet.data());
+        let error_callback = move |err| (error_callback.lock().unwrap_or_else(|e| e.into_inner()))(err);
+        AudioClient::new().capture(&self.source, data_callback, error_callback)
+    }
+}
+
+/// A running [`Router`], dropping it stops both streams
+pub struct RunningRoute {
+    _streams: RunningStreamGroup,
+    buffer: Arc<JitterBuffer>,
+    format: SampleFormat,
+}
+
+impl RunningRoute {
+    /// Format of the captured audio, the output converts from it
+    pub fn get_format(&self) -> &SampleFormat {
+        &self.format
+    }
+
+    pub fn get_metrics(&self) -> RouteMetrics {
+        self.buffer.metrics()
+    }
+
+    // See drop implementation of the stream group for cleanup
+    pub fn stop(self) {}
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RouteMetrics {
+    latency: Duration,
+    target_latency: Duration,
+    drift_ppm: f64,
+    underruns: u64,
+    dropped_frames: u64,
+    inserted_frames: u64,
+}
+
+impl RouteMetrics {
+    /// Audio currently queued between the streams, the device buffers come on top
+    pub fn get_latency(&self) -> Duration {
+        self.latency
+    }
+
+    /// Latency the buffer currently steers towards
+    pub fn get_target_latency(&self) -> Duration {
+        self.target_latency
+    }
+
+    /// How much faster the capture clock runs than the output clock, in parts per million
+    pub fn get_drift_ppm(&self) -> f64 {
+        self.drift_ppm
+    }
+
+    /// Render buffers that couldn't be filled completely
+    pub fn get_underruns(&self) -> u64 {
+        self.underruns
+    }
+
+    /// Captured frames skipped to correct drift or bound the latency
+    pub fn get_dropped_frames(&self) -> u64 {
+        self.dropped_frames
+    }
+
+    /// Frames repeated to correct drift
+    pub fn get_inserted_frames(&self) -> u64 {
+        self.inserted_frames
+    }
+}
+
+#[derive(Default)]
+struct JitterBuffer {
+    state: Mutex<BufferState>,
+}
+
+#[derive(Default)]
+struct BufferState {
+    data: VecDeque<u8>,
+    block_align: usize,
+    sample_rate: u32,
+    target_frames: usize,
+    min_target_frames: usize,
+    max_target_frames: usize,
+    /// Frames rendered since the last underrun or target change
+    stable_frames: usize,
+    captured: FrameCounter,
+    rendered: FrameCounter,
+    underruns: u64,
+    dropped_frames: u64,
+    inserted_frames: u64,
+}
+
+impl BufferState {
+    fn queued_frames(&self) -> usize {
+        self.data.len().checked_div(self.block_align).unwrap_or(0)
+    }
+
+    fn duration(&self, frames: usize) -> Duration {
+        Duration::from_nanos(frames as u64 * 1_000_000_000 / self.sample_rate.max(1) as u64)
+    }
+}
+
+impl JitterBuffer {
+    fn lock(&self) -> MutexGuard<'_, BufferState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn configure(&self, format: &SampleFormat, target_latency: Duration, max_latency: Duration) {
+        let mut state = self.lock();
+        state.block_align = format.block_align() as usize;
+        state.sample_rate = format.get_n_samples_per_sec();
+        let sample_rate = state.sample_rate as u128;
+        let frames = |latency: Duration| ((latency.as_nanos() * sample_rate / 1_000_000_000) as usize).max(1);
+        state.min_target_frames = frames(target_latency);
+        state.max_target_frames = frames(max_latency).max(state.min_target_frames);
+        state.target_frames = state.min_target_frames;
+    }
+
+    fn push(&self, data: &[u8]) {
+        let mut state = self.lock();
+        if state.block_align == 0 {
+            return;
+        }
+        state.data.extend(data);
+        let frames = data.len() / state.block_align;
+        state.captured.add(frames);
+
+        let (queued, target) = (state.queued_frames(), state.target_frames);
+        if queued > target * 4 {
+            let drop_frames = queued - target;
+            let block_align = state.block_align;
+            state.data.drain(..drop_frames * block_align);
+            state.dropped_frames += drop_frames as u64;
+        }
+    }
+
+    /// Fills `buffer` from the queue, correcting drift by one frame. Returns false while nothing was captured yet.
+    fn pop(&self, buffer: &mut [u8]) -> bool {
+        let mut state = self.lock();
+        let block_align = state.block_align;
+        if state.captured.frames == 0 || block_align == 0 {
+            return false;
+        }
+        let needed = buffer.len() / block_align;
+        let queued = state.queued_frames();
+        let tolerance = (state.target_frames / 4).max(1);
+
+        if queued < needed {
+            let len = queued * block_align;
+            for (dst, src) in buffer.iter_mut().zip(state.data.drain(..len)) {
+                *dst = src;
+            }
+            buffer[len..].fill(0);
+            state.underruns += 1;
+            state.target_frames = (state.target_frames + state.target_frames / 4 + 1).min(state.max_target_frames);
+            state.stable_frames = 0;
+        } else if queued - needed > state.target_frames + tolerance {
+            // Capture runs ahead, skip the oldest frame
+            state.data.drain(..block_align);
+            state.dropped_frames += 1;
+            for (dst, src) in buffer.iter_mut().zip(state.data.drain(..needed * block_align)) {
+                *dst = src;
+            }
+        } else if queued - needed + tolerance < state.target_frames && needed > 1 {
+            // Output runs ahead, play the first frame twice
+            let (first, rest) = buffer.split_at_mut(block_align);
+            for (dst, src) in rest.iter_mut().zip(state.data.drain(..(needed - 1) * block_align)) {
+                *dst = src;
+            }
+            first.copy_from_slice(&rest[..block_align]);
+            state.inserted_frames += 1;
+        } else {
+            for (dst, src) in buffer.iter_mut().zip(state.data.drain(..needed * block_align)) {
+                *dst = src;
+            }
+        }
+
+        state.rendered.add(needed);
+        state.stable_frames += needed;
+        if state.stable_frames > state.sample_rate as usize * SHRINK_AFTER.as_secs() as usize {
+            state.target_frames = (state.target_frames - state.target_frames / 8).max(state.min_target_frames);
+            state.stable_frames = 0;
+        }
+        true
+    }
+
+    fn metrics(&self) -> RouteMetrics {
+        let state = self.lock();
+        let drift_ppm = match (state.captured.rate(), state.rendered.rate()) {
+            (Some(captured), Some(rendered)) => (captured / rendered - 1.0) * 1_000_000.0,
+            _ => 0.0,
+        };
+        RouteMetrics {
+            latency: state.duration(state.queued_frames()),
+            target_latency: state.duration(state.target_frames),
+            drift_ppm,
+            underruns: state.underruns,
+            dropped_frames: state.dropped_frames,
+            inserted_frames: state.inserted_frames,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample_format::FormatTag;
+
+    #[test]
+    fn adaptive_buffer() {
+        // One frame per millisecond, one byte per frame
+        let buffer = JitterBuffer::default();
+        buffer.configure(
+            &SampleFormat::new(FormatTag::WaveFormatPcm, 1, 1000, 8),
+            Duration::from_millis(4),
+            Duration::from_millis(8),
+        );
+
+        let mut out = [9u8; 2];
+        assert!(!buffer.pop(&mut out));
+
+        // One frame would be left after the pop, short of the target by more than the tolerance: the first frame repeats
+        buffer.push(&[1, 2, 3]);
+        assert!(buffer.pop(&mut out));
+        assert_eq!(out, [1, 1]);
+        assert_eq!(buffer.metrics().get_inserted_frames(), 1);
+
+        // Seven frames would be left after the pop, over the target: the oldest frame is skipped
+        buffer.push(&[4, 5, 6, 7, 8, 9, 10]);
+        assert!(buffer.pop(&mut out));
+        assert_eq!(out, [3, 4]);
+        assert_eq!(buffer.metrics().get_dropped_frames(), 1);
+
+        // Running dry raises the target by a quarter
+        let mut out = [0u8; 8];
+        assert!(buffer.pop(&mut out));
+        assert_eq!(out, [5, 6, 7, 8, 9, 10, 0, 0]);
+        assert_eq!(buffer.metrics().get_underruns(), 1);
+        assert_eq!(buffer.metrics().get_target_latency(), Duration::from_millis(6));
+    }
+}
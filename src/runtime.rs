@@ -0,0 +1,126 @@
+//! One owner for the crate objects of an application, torn down in a safe order, see [`Runtime`].
+//!
+//! Dropping streams, watchers and notification registrations in whatever order the fields of an application struct
+//! happen to be declared in leads to callbacks firing into half destroyed state, and to unregistration failures
+//! panicking in `Drop` while the process exits. A runtime owns them instead and always tears them down the same way.
+
+use log::warn;
+
+use crate::audio_stream::{AudioStream, StreamId};
+use crate::notifications::{NotificationError, Notifications};
+use crate::session_manager_cache::SessionManagerCache;
+
+/// Owns streams, watchers and a [`Notifications`] instance, see [`Runtime::shutdown`] for the teardown order
+#[derive(Default)]
+pub struct Runtime {
+    streams: Vec<AudioStream>,
+    watchers: Vec<Box<dyn Send>>,
+    notifications: Option<Notifications>,
+}
+
+impl Runtime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses `notifications` instead of creating a default instance on first use, e.g. one built with a dispatcher
+    pub fn with_notifications(notifications: Notifications) -> Self {
+        Self {
+            streams: Vec::new(),
+            watchers: Vec::new(),
+            notifications: Some(notifications),
+        }
+    }
+
+    /// The notifications of the runtime, created with [`Notifications::new`] on first use
+    pub fn notifications(&mut self) -> &mut Notifications {
+        self.notifications.get_or_insert_with(Notifications::new)
+    }
+
+    /// Takes ownership of a running stream, it is stopped on shutdown at the latest
+    pub fn add_stream(&mut self, stream: AudioStream) -> StreamId {
+        let id = stream.id();
+        self.streams.push(stream);
+        id
+    }
+
+    pub fn get_stream(&self, id: StreamId) -> Option<&AudioStream> {
+        self.streams.iter().find(|stream| stream.id() == id)
+    }
+
+    /// Hands a stream back, e.g. to stop it before the runtime shuts down
+    pub fn remove_stream(&mut self, id: StreamId) -> Option<AudioStream> {
+        let index = self.streams.iter().position(|stream| stream.id() == id)?;
+        Some(self.streams.remove(index))
+    }
+
+    /// Takes ownership of an object that holds registrations of its own, e.g. a
+    /// [`RoleWatcher`](crate::role_watcher::RoleWatcher) or a [`Ducker`](crate::ducker::Ducker)
+    pub fn add_watcher(&mut self, watcher: impl Send + 'static) {
+        self.watchers.push(Box::new(watcher));
+    }
+
+    /// Tears everything down: the streams, newest first, so no callback runs into the objects below; the watchers,
+    /// newest first; the notifications through [`Notifications::shutdown`]; and finally the cached session managers.
+    /// Every step runs even if an earlier one failed, the first notification failure is returned.
+    ///
+    /// Dropping the runtime does the same and logs the failure instead.
+    pub fn shutdown(mut self) -> Result<(), NotificationError> {
+        self.teardown()
+    }
+
+    fn teardown(&mut self) -> Result<(), NotificationError> {
+        while let Some(stream) = self.streams.pop() {
+            drop(stream);
+        }
+        while let Some(watcher) = self.watchers.pop() {
+            drop(watcher);
+        }
+        let result = match self.notifications.take() {
+            Some(notifications) => notifications.shutdown(),
+            None => Ok(()),
+        };
+        // Releases the COM references the cache holds, other users activate the managers again when needed
+        SessionManagerCache::clear();
+        result
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        if let Err(err) = self.teardown() {
+            warn!("Failed shutting down notifications: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::audio_client::AudioClient;
+
+    struct Watcher(&'static str, Arc<Mutex<Vec<&'static str>>>);
+
+    impl Drop for Watcher {
+        fn drop(&mut self) {
+            self.1.lock().unwrap().push(self.0);
+        }
+    }
+
+    #[test]
+    fn shutdown_order() {
+        let mut runtime = Runtime::new();
+        runtime.notifications().register_device_notification(|_| {}).unwrap();
+        let (config, _format) = AudioClient::new().start_playback_device(None, |_| 0, |_| {}).unwrap();
+        let id = runtime.add_stream(config.start().unwrap());
+        assert!(runtime.get_stream(id).is_some());
+
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        runtime.add_watcher(Watcher("first", dropped.clone()));
+        runtime.add_watcher(Watcher("second", dropped.clone()));
+        runtime.shutdown().unwrap();
+        assert_eq!(*dropped.lock().unwrap(), ["second", "first"]);
+    }
+}
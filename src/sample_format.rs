@@ -2,30 +2,57 @@ use core::fmt;
 use std::fmt::Display;
 
 use windows::Win32::Media::{
-    Audio::{WAVEFORMATEX, WAVEFORMATEXTENSIBLE, WAVE_FORMAT_PCM},
+    Audio::{WAVE_FORMAT_PCM, WAVEFORMATEX, WAVEFORMATEXTENSIBLE},
     KernelStreaming::{KSDATAFORMAT_SUBTYPE_PCM, WAVE_FORMAT_EXTENSIBLE},
     Multimedia::{KSDATAFORMAT_SUBTYPE_IEEE_FLOAT, WAVE_FORMAT_IEEE_FLOAT},
 };
 use windows_core::GUID;
 
-#[derive(Debug, Clone, PartialEq)]
+use crate::convert::is_convertible;
+use crate::speaker_layout::SpeakerLayout;
+
+#[derive(Clone)]
 pub struct SampleFormat {
     format_tag: FormatTag,
     channels: u16,
     sample_rate: u32,
     bits_per_sample: u16,
+    /// `dwChannelMask` of extensible formats, plain formats use the default layout for their channel count
+    channel_mask: Option<u32>,
 }
 
+// A missing channel mask and the default mask for the channel count describe the same format
+impl PartialEq for SampleFormat {
+    fn eq(&self, other: &Self) -> bool {
+        self.format_tag == other.format_tag
+            && self.channels == other.channels
+            && self.sample_rate == other.sample_rate
+            && self.bits_per_sample == other.bits_per_sample
+            && self.get_speaker_layout() == other.get_speaker_layout()
+    }
+}
+
+/// E.g. `f32 / 48000 Hz / 2 ch / 32-bit`
 impl Display for SampleFormat {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{{ {:?} {}bit, {}HZ {}ch  }}",
-            self.format_tag, self.bits_per_sample, self.sample_rate, self.channels
+            "{} / {} Hz / {} ch / {}-bit",
+            self.sample_type(),
+            self.sample_rate,
+            self.channels,
+            self.bits_per_sample
         )
     }
 }
 
+/// The [`Display`] output followed by the speaker layout
+impl fmt::Debug for SampleFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SampleFormat({}, {:?})", self, self.get_speaker_layout())
+    }
+}
+
 impl SampleFormat {
     pub fn new(format_tag: FormatTag, channel: u16, n_samples_per_sec: u32, w_bits_per_sample: u16) -> Self {
         Self {
@@ -33,6 +60,27 @@ impl SampleFormat {
             channels: channel,
             sample_rate: n_samples_per_sec,
             bits_per_sample: w_bits_per_sample,
+            channel_mask: None,
+        }
+    }
+
+    /// Same format at another sample rate
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Assigns the channels to speakers, the layout should have as many speakers as the format has channels
+    pub fn with_speaker_layout(mut self, layout: SpeakerLayout) -> Self {
+        self.channel_mask = Some(layout.get_mask());
+        self
+    }
+
+    /// Which interleaved channel is which speaker
+    pub fn get_speaker_layout(&self) -> SpeakerLayout {
+        match self.channel_mask {
+            Some(mask) => SpeakerLayout::from_mask(mask),
+            None => SpeakerLayout::default_for_channels(self.channels),
         }
     }
 
@@ -60,12 +108,41 @@ impl SampleFormat {
         self.sample_rate * self.block_align() as u32
     }
 
+    /// Whether a plain `WAVEFORMATEX` can't describe the format: it has a speaker layout, more than two channels or
+    /// samples wider than 16 bits
+    pub(crate) fn needs_extensible(&self) -> bool {
+        self.channel_mask.is_some() || self.channels > 2 || self.bits_per_sample > 16
+    }
+
+    /// Whether the crate can deliver packets of this format as `other`: the formats are equal, or both are
+    /// [convertible](crate::convert::is_convertible) and run at the same rate. Different rates only need the
+    /// `resampler` feature.
+    pub fn is_compatible_with(&self, other: &SampleFormat) -> bool {
+        if self == other {
+            return true;
+        }
+        let same_rate = self.sample_rate == other.sample_rate || cfg!(feature = "resampler");
+        same_rate && is_convertible(self) && is_convertible(other)
+    }
+
+    /// Rust name of the sample type, e.g. `i16` or `f32`
+    fn sample_type(&self) -> String {
+        match self.format_tag {
+            FormatTag::WaveFormatPcm if self.bits_per_sample == 8 => "u8".to_string(),
+            FormatTag::WaveFormatPcm => format!("i{}", self.bits_per_sample),
+            FormatTag::WaveFormatIeeeFloat => format!("f{}", self.bits_per_sample),
+            FormatTag::WaveFormatExtensible => "extensible".to_string(),
+            FormatTag::Unsupported => "unsupported".to_string(),
+        }
+    }
+
     pub const fn default() -> Self {
         Self {
             format_tag: FormatTag::WaveFormatIeeeFloat,
             channels: 2,
             sample_rate: 48000,
             bits_per_sample: 32,
+            channel_mask: None,
         }
     }
 
@@ -75,6 +152,7 @@ impl SampleFormat {
             (a.data1, a.data2, a.data3, a.data4) == (b.data1, b.data2, b.data3, b.data4)
         }
         let format_tag: FormatTag = unsafe { *wave_format_ex }.wFormatTag.into();
+        let mut channel_mask = None;
         let format_tag = match format_tag {
             FormatTag::WaveFormatExtensible => {
                 if unsafe { *wave_format_ex }.cbSize < (size_of::<WAVEFORMATEXTENSIBLE>() - size_of::<WAVEFORMATEX>()) as u16 {
@@ -82,6 +160,7 @@ impl SampleFormat {
                 }
                 let wave_format_extensible_ptr = wave_format_ex as *const WAVEFORMATEXTENSIBLE;
                 let subformat = unsafe { *wave_format_extensible_ptr }.SubFormat;
+                channel_mask = Some(unsafe { *wave_format_extensible_ptr }.dwChannelMask);
                 if cmp_guid(&subformat, &KSDATAFORMAT_SUBTYPE_PCM) {
                     FormatTag::WaveFormatPcm
                 } else if cmp_guid(&subformat, &KSDATAFORMAT_SUBTYPE_IEEE_FLOAT) {
@@ -98,10 +177,45 @@ impl SampleFormat {
             channels: wave_format_ex.nChannels,
             sample_rate: wave_format_ex.nSamplesPerSec,
             bits_per_sample: wave_format_ex.wBitsPerSample,
+            channel_mask,
         }
     }
 }
 
+/// Spells out every field of a raw wave format, including the extensible part, for logging formats the crate can't
+/// represent as a [`SampleFormat`]
+///
+/// # Safety
+/// `wave_format_ex` has to be null or point to a valid `WAVEFORMATEX`, followed by `cbSize` bytes of extra data.
+pub unsafe fn describe_wave_format_ex(wave_format_ex: *const WAVEFORMATEX) -> String {
+    if wave_format_ex.is_null() {
+        return "null".to_string();
+    }
+    let format = unsafe { wave_format_ex.read_unaligned() };
+    let mut description = format!(
+        "tag 0x{:04X}, {} ch, {} Hz, {}-bit, block align {}, {} bytes/s, cbSize {}",
+        { format.wFormatTag },
+        { format.nChannels },
+        { format.nSamplesPerSec },
+        { format.wBitsPerSample },
+        { format.nBlockAlign },
+        { format.nAvgBytesPerSec },
+        { format.cbSize }
+    );
+    let extensible_size = (size_of::<WAVEFORMATEXTENSIBLE>() - size_of::<WAVEFORMATEX>()) as u16;
+    if { format.wFormatTag } as u32 == WAVE_FORMAT_EXTENSIBLE && { format.cbSize } >= extensible_size {
+        let extensible = unsafe { (wave_format_ex as *const WAVEFORMATEXTENSIBLE).read_unaligned() };
+        let (sub_format, channel_mask, valid_bits) = (extensible.SubFormat, extensible.dwChannelMask, unsafe {
+            extensible.Samples.wValidBitsPerSample
+        });
+        description += &format!(
+            ", sub format {:?}, channel mask 0x{:X}, {} valid bits",
+            sub_format, channel_mask, valid_bits
+        );
+    }
+    description
+}
+
 impl From<SampleFormat> for WAVEFORMATEX {
     fn from(sample_format: SampleFormat) -> Self {
         let sample_size_bytes = sample_format.bits_per_sample / 8;
@@ -116,6 +230,39 @@ impl From<SampleFormat> for WAVEFORMATEX {
     }
 }
 
+/// Owned raw format for `Initialize` and `IsFormatSupported`, a `WAVEFORMATEXTENSIBLE` carrying the channel mask when
+/// the format [needs one](SampleFormat::needs_extensible), a plain `WAVEFORMATEX` otherwise
+pub(crate) struct WaveFormat(WAVEFORMATEXTENSIBLE);
+
+impl WaveFormat {
+    /// Valid as long as `self` is, `cbSize` tells the reader whether the extensible part follows
+    pub(crate) fn as_ptr(&self) -> *const WAVEFORMATEX {
+        &self.0 as *const WAVEFORMATEXTENSIBLE as *const WAVEFORMATEX
+    }
+}
+
+impl From<SampleFormat> for WaveFormat {
+    fn from(sample_format: SampleFormat) -> Self {
+        let sub_format = match sample_format.format_tag {
+            FormatTag::WaveFormatPcm => Some(KSDATAFORMAT_SUBTYPE_PCM),
+            FormatTag::WaveFormatIeeeFloat => Some(KSDATAFORMAT_SUBTYPE_IEEE_FLOAT),
+            FormatTag::WaveFormatExtensible | FormatTag::Unsupported => None,
+        };
+        let mut wave_format = WAVEFORMATEXTENSIBLE {
+            Format: sample_format.clone().into(),
+            ..Default::default()
+        };
+        if let Some(sub_format) = sub_format.filter(|_| sample_format.needs_extensible()) {
+            wave_format.Format.wFormatTag = WAVE_FORMAT_EXTENSIBLE as u16;
+            wave_format.Format.cbSize = (size_of::<WAVEFORMATEXTENSIBLE>() - size_of::<WAVEFORMATEX>()) as u16;
+            wave_format.Samples.wValidBitsPerSample = sample_format.bits_per_sample;
+            wave_format.dwChannelMask = sample_format.get_speaker_layout().get_mask();
+            wave_format.SubFormat = sub_format;
+        }
+        Self(wave_format)
+    }
+}
+
 impl Default for SampleFormat {
     fn default() -> Self {
         Self::default()
@@ -151,3 +298,47 @@ impl From<u16> for FormatTag {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describe_formats() {
+        let float = SampleFormat::default();
+        assert_eq!(float.to_string(), "f32 / 48000 Hz / 2 ch / 32-bit");
+        assert_eq!(format!("{:?}", float), "SampleFormat(f32 / 48000 Hz / 2 ch / 32-bit, Stereo)");
+        let pcm = SampleFormat::new(FormatTag::WaveFormatPcm, 1, 48000, 16);
+        assert_eq!(pcm.to_string(), "i16 / 48000 Hz / 1 ch / 16-bit");
+
+        assert!(float.is_compatible_with(&pcm));
+        let other_rate = SampleFormat::new(FormatTag::WaveFormatPcm, 1, 44100, 16);
+        assert_eq!(float.is_compatible_with(&other_rate), cfg!(feature = "resampler"));
+        assert!(!float.is_compatible_with(&SampleFormat::new(FormatTag::Unsupported, 2, 48000, 32)));
+
+        let raw: WAVEFORMATEX = pcm.into();
+        let description = unsafe { describe_wave_format_ex(&raw) };
+        assert_eq!(
+            description,
+            "tag 0x0001, 1 ch, 48000 Hz, 16-bit, block align 2, 96000 bytes/s, cbSize 0"
+        );
+        assert_eq!(unsafe { describe_wave_format_ex(std::ptr::null()) }, "null");
+    }
+
+    #[test]
+    fn extensible_wave_format() {
+        let stereo = SampleFormat::new(FormatTag::WaveFormatPcm, 2, 48000, 16);
+        let plain = WaveFormat::from(stereo.clone());
+        assert_eq!({ unsafe { *plain.as_ptr() }.wFormatTag }, WAVE_FORMAT_PCM as u16);
+        assert_eq!({ unsafe { *plain.as_ptr() }.cbSize }, 0);
+
+        let quad = SampleFormat::new(FormatTag::WaveFormatPcm, 4, 48000, 16);
+        let surround = stereo.with_speaker_layout(SpeakerLayout::from_mask(0x3F));
+        for format in [SampleFormat::default(), quad, surround] {
+            let raw = WaveFormat::from(format.clone());
+            assert_eq!({ unsafe { *raw.as_ptr() }.wFormatTag }, WAVE_FORMAT_EXTENSIBLE as u16);
+            assert_eq!({ raw.0.dwChannelMask }, format.get_speaker_layout().get_mask());
+            assert_eq!(SampleFormat::from_wave_format_ex(raw.as_ptr()), format);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Automatic per process capture driven by audio sessions, see [`SessionCaptureManager`].
+//!
+//! Every process that has an audio session matching the filter gets its own process loopback stream. Streams are
+//! started for sessions that already exist and for sessions created later on any playback device, and stopped once
+//! every session of the process expired or disconnected.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+use thiserror::Error;
+
+use crate::audio_client::{AudioClient, AudioClientError};
+use crate::audio_stream::{AudioStream, CapturePacket};
+use crate::event_args::{AudioSessionEventArgs, SessionState};
+use crate::manager::{AudioError, Session, get_package_family_name};
+use crate::notifications::NotificationError;
+use crate::sample_format::SampleFormat;
+use crate::session_tracker::{SessionHandler, SessionTracker};
+
+#[derive(Error, Debug)]
+pub enum SessionCaptureError {
+    #[error("Failed setting up session notifications: {0}")]
+    NotificationError(#[source] NotificationError),
+    #[error("Failed enumerating sessions: {0}")]
+    SessionEnumError(#[source] AudioError),
+}
+
+/// Selects the processes a [`SessionCaptureManager`] captures
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionFilter {
+    Pid(u32),
+    /// Executable file name such as `app.exe`, compared case insensitively with the last component of the path
+    /// [`Session::get_process_name`] reports
+    ProcessName(String),
+    /// Every process of a UWP or MSIX package, e.g. `Microsoft.ZuneMusic_8wekyb3d8bbwe`, compared case insensitively
+    PackageFamilyName(String),
+}
+
+impl SessionFilter {
+    pub fn matches(&self, session: &Session) -> bool {
+        if *session.is_system() {
+            return false;
+        }
+        match self {
+            SessionFilter::Pid(pid) => session.get_pid() == pid,
+            SessionFilter::ProcessName(name) => session
+                .get_process_name()
+                .as_deref()
+                .and_then(|path| path.rsplit(['\\', '/']).next())
+                .is_some_and(|file_name| file_name.eq_ignore_ascii_case(name)),
+            SessionFilter::PackageFamilyName(package) => get_package_family_name(*session.get_pid())
+                .ok()
+                .flatten()
+                .is_some_and(|family_name| family_name.eq_ignore_ascii_case(package)),
+        }
+    }
+}
+
+/// A packet captured from one of the processes matched by a [`SessionCaptureManager`]
+pub struct SessionPacket<'a> {
+    pid: u32,
+    process_name: Option<&'a str>,
+    packet: CapturePacket<'a>,
+}
+
+impl<'a> SessionPacket<'a> {
+    pub fn get_pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn get_process_name(&self) -> Option<&'a str> {
+        self.process_name
+    }
+
+    pub fn packet(&self) -> &CapturePacket<'a> {
+        &self.packet
+    }
+}
+
+type DataCallback = Arc<dyn Fn(SessionPacket) + Send + Sync + 'static>;
+type ErrorCallback = Arc<dyn Fn(u32, AudioClientError) + Send + Sync + 'static>;
+
+/// Starts and stops process loopback capture for every process whose sessions match a [`SessionFilter`], delivering
+/// all packets through one callback tagged with the process id
+pub struct SessionCaptureManager {
+    tracker: SessionTracker<Captures>,
+}
+
+struct Captures {
+    filter: SessionFilter,
+    format: SampleFormat,
+    data_callback: DataCallback,
+    error_callback: ErrorCallback,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    captures: HashMap<u32, ProcessCapture>,
+    stopped: bool,
+}
+
+struct ProcessCapture {
+    /// `None` while the stream is being started
+    _stream: Option<AudioStream>,
+    sessions: HashSet<String>,
+}
+
+impl SessionCaptureManager {
+    /// Captures every matching process in `format`, errors of a single process's stream go to `error_callback`
+    pub fn start<D, E>(
+        filter: SessionFilter,
+        format: SampleFormat,
+        data_callback: D,
+        error_callback: E,
+    ) -> Result<Self, SessionCaptureError>
+    where
+        D: Fn(SessionPacket) + Send + Sync + 'static,
+        E: Fn(u32, AudioClientError) + Send + Sync + 'static,
+    {
+        let captures = Captures {
+            filter,
+            format,
+            data_callback: Arc::new(data_callback),
+            error_callback: Arc::new(error_callback),
+            state: Mutex::new(State::default()),
+        };
+        let tracker = SessionTracker::start(captures).map_err(|err| match err {
+            NotificationError::FailedEnumeratingSessions(err) => SessionCaptureError::SessionEnumError(err),
+            err => SessionCaptureError::NotificationError(err),
+        })?;
+        Ok(Self { tracker })
+    }
+
+    /// Processes that are currently captured
+    pub fn get_captured_pids(&self) -> Vec<u32> {
+        self.tracker.handler().lock_state().captures.keys().copied().collect()
+    }
+
+    // See drop implementation for cleanup
+    pub fn stop(self) {}
+}
+
+impl Drop for SessionCaptureManager {
+    fn drop(&mut self) {
+        self.tracker.stop();
+        // Streams are stopped without holding the lock, a capture that is being started sees the flag
+        let captures = {
+            let mut state = self.tracker.handler().lock_state();
+            state.stopped = true;
+            std::mem::take(&mut state.captures)
+        };
+        drop(captures);
+    }
+}
+
+impl SessionHandler for Captures {
+    fn session_added(&self, session: &Session, _created: bool) -> bool {
+        // Process loopback captures what a process plays, its sessions on capture devices don't matter
+        if !session.get_device().is_playback || !self.filter.matches(session) {
+            return false;
+        }
+        self.session_started(session);
+        true
+    }
+
+    fn session_event(&self, name: &str, event: AudioSessionEventArgs) {
+        let ended = match event {
+            AudioSessionEventArgs::StateChanged(args) => matches!(args.get_state(), SessionState::AudioSessionStateExpired),
+            AudioSessionEventArgs::SessionDisconnected(_) => true,
+            _ => false,
+        };
+        if ended {
+            self.session_ended(name);
+        }
+    }
+}
+
+impl Captures {
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn session_started(&self, session: &Session) {
+        let pid = *session.get_pid();
+        let mut state = self.lock_state();
+        if state.stopped {
+            return;
+        }
+        if let Some(capture) = state.captures.get_mut(&pid) {
+            capture.sessions.insert(session.get_name().clone());
+            return;
+        }
+        // Reserved before the lock is released, sessions of the process created meanwhile join this capture
+        let sessions = HashSet::from([session.get_name().clone()]);
+        state.captures.insert(pid, ProcessCapture { _stream: None, sessions });
+        drop(state);
+
+        // Activation blocks and starting spawns the stream thread, neither happens under the lock
+        let started = self.start_capture(pid, session.get_process_name().clone());
+        let mut state = self.lock_state();
+        let stream = match started {
+            Ok(stream) => stream,
+            Err(err) => {
+                state.captures.remove(&pid);
+                drop(state);
+                (self.error_callback)(pid, err);
+                return;
+            }
+        };
+        let stopped = state.stopped;
+        match state.captures.get_mut(&pid).filter(|_| !stopped) {
+            Some(capture) => {
+                capture._stream = Some(stream);
+                debug!("Started capturing process {}", pid);
+            }
+            // Stopped, or every session of the process ended while the stream started
+            None => {
+                drop(state);
+                drop(stream);
+            }
+        }
+    }
+
+    fn session_ended(&self, name: &str) {
+        let capture = {
+            let mut state = self.lock_state();
+            let Some((&pid, capture)) = state.captures.iter_mut().find(|(_, capture)| capture.sessions.contains(name)) else {
+                return;
+            };
+            capture.sessions.remove(name);
+            if !capture.sessions.is_empty() {
+                return;
+            }
+            state.captures.remove(&pid).map(|capture| (pid, capture))
+        };
+        // Joining the stream thread happens outside of the lock
+        if let Some((pid, capture)) = capture {
+            drop(capture);
+            debug!("Stopped capturing process {}", pid);
+        }
+    }
+
+    fn start_capture(&self, pid: u32, process_name: Option<String>) -> Result<AudioStream, AudioClientError> {
+        let mut audio_client = AudioClient::new();
+        audio_client.set_format(self.format.clone())?;
+        let data_callback = self.data_callback.clone();
+        let error_callback = self.error_callback.clone();
+        audio_client
+            .start_recording_process(
+                pid,
+                move |packet| {
+                    data_callback(SessionPacket {
+                        pid,
+                        process_name: process_name.as_deref(),
+                        packet,
+                    })
+                },
+                move |err| error_callback(pid, err),
+            )?
+            .with_label(format!("pid {}", pid))
+            .start()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manager::SessionManager;
+    use std::process;
+
+    #[test]
+    fn capture_own_process() {
+        let format = SampleFormat::default();
+        let manager = SessionCaptureManager::start(SessionFilter::Pid(process::id()), format, |_| {}, |_, _| {}).unwrap();
+
+        // Playing audio creates a session for this process if there is none yet, which starts the capture
+        let (playback, _format) = AudioClient::new().start_playback_device(None, |_| 0, |_| {}).unwrap();
+        let _playback = playback.start().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        assert_eq!(manager.get_captured_pids(), vec![process::id()]);
+
+        let session = SessionManager::get_sessions()
+            .unwrap()
+            .into_iter()
+            .find(|session| *session.get_pid() == process::id())
+            .unwrap();
+        let exe = std::env::current_exe().unwrap();
+        let file_name = exe.file_name().unwrap().to_str().unwrap().to_uppercase();
+        assert!(SessionFilter::ProcessName(file_name).matches(&session));
+    }
+}
@@ -0,0 +1,148 @@
+//! Process wide cache of the `IAudioSessionManager2` of every device, see [`SessionManagerCache`].
+//!
+//! Activating a session manager is a cross-process call into the audio service, and session enumeration, session
+//! lookups and the session notification thread all need the manager of the same devices over and over. The managers
+//! are free-threaded, so one instance per device is shared by every thread. The cache registers its own device
+//! notification client with the first manager it keeps, entries are dropped when it sees the device being removed or
+//! leaving the active state, and callers retry with a fresh manager when a cached one fails.
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex, MutexGuard};
+
+use log::{trace, warn};
+use windows::Win32::Foundation::PROPERTYKEY;
+use windows::Win32::Media::Audio::{
+    DEVICE_STATE, DEVICE_STATE_ACTIVE, EDataFlow, ERole, IAudioSessionManager2, IMMDevice, IMMDeviceEnumerator, IMMNotificationClient,
+    IMMNotificationClient_Impl,
+};
+use windows::Win32::System::Com::CLSCTX_ALL;
+use windows_core::{PCWSTR, implement};
+
+use crate::com::ComSend;
+use crate::manager::device_enumerator;
+
+static SESSION_MANAGERS: LazyLock<Mutex<HashMap<String, ComSend<IAudioSessionManager2>>>> = LazyLock::new(Default::default);
+
+/// Registration of the [`Invalidator`], `None` until the first manager is cached. Kept for the rest of the process.
+static INVALIDATOR: Mutex<Option<ComSend<(IMMDeviceEnumerator, IMMNotificationClient)>>> = Mutex::new(None);
+
+pub(crate) struct SessionManagerCache;
+
+impl SessionManagerCache {
+    fn lock() -> MutexGuard<'static, HashMap<String, ComSend<IAudioSessionManager2>>> {
+        SESSION_MANAGERS.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The session manager of `device`, activating it only if it isn't cached yet. The flag is true for a cached one.
+    pub(crate) fn get(device: &IMMDevice, device_id: &str) -> windows::core::Result<(IAudioSessionManager2, bool)> {
+        if let Some(mgr) = Self::lock().get(device_id) {
+            return Ok((mgr.get().clone(), true));
+        }
+        let mgr = unsafe { device.Activate::<IAudioSessionManager2>(CLSCTX_ALL, None) }?;
+        // Without the notification a manager of a removed device would stay, it isn't cached then
+        if Self::watch_devices() {
+            Self::lock().insert(device_id.to_string(), ComSend(mgr.clone()));
+        }
+        Ok((mgr, false))
+    }
+
+    /// Registers the [`Invalidator`] unless it is already, false if that fails
+    fn watch_devices() -> bool {
+        let mut invalidator = INVALIDATOR.lock().unwrap_or_else(|e| e.into_inner());
+        if invalidator.is_some() {
+            return true;
+        }
+        let registered = device_enumerator().map_err(|err| err.to_string()).and_then(|enumerator| {
+            let client: IMMNotificationClient = Invalidator.into();
+            unsafe { enumerator.RegisterEndpointNotificationCallback(&client) }.map_err(|err| err.to_string())?;
+            Ok((enumerator, client))
+        });
+        match registered {
+            Ok(registration) => {
+                *invalidator = Some(ComSend(registration));
+                true
+            }
+            Err(err) => {
+                warn!("Failed watching devices for the session manager cache: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Drops the manager of a device that went away, the next [`SessionManagerCache::get`] activates a new one
+    pub(crate) fn invalidate(device_id: &str) {
+        if Self::lock().remove(device_id).is_some() {
+            trace!("Session manager of {} invalidated", device_id);
+        }
+    }
+
+    pub(crate) fn clear() {
+        Self::lock().clear();
+    }
+}
+
+/// Drops the managers of devices that are removed or leave the active state
+#[implement(IMMNotificationClient)]
+struct Invalidator;
+
+impl IMMNotificationClient_Impl for Invalidator_Impl {
+    fn OnDefaultDeviceChanged(&self, _flow: EDataFlow, _role: ERole, _default_device: &PCWSTR) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnDeviceAdded(&self, _device_id: &PCWSTR) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnDeviceRemoved(&self, device_id: &PCWSTR) -> windows::core::Result<()> {
+        if let Ok(device_id) = unsafe { device_id.to_string() } {
+            SessionManagerCache::invalidate(&device_id);
+        }
+        Ok(())
+    }
+
+    fn OnDeviceStateChanged(&self, device_id: &PCWSTR, new_state: DEVICE_STATE) -> windows::core::Result<()> {
+        if new_state != DEVICE_STATE_ACTIVE
+            && let Ok(device_id) = unsafe { device_id.to_string() }
+        {
+            SessionManagerCache::invalidate(&device_id);
+        }
+        Ok(())
+    }
+
+    fn OnPropertyValueChanged(&self, _device_id: &PCWSTR, _key: &PROPERTYKEY) -> windows::core::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::com::com_initialized;
+    use crate::manager::DeviceManager;
+    use windows::Win32::Media::Audio::DEVICE_STATE_DISABLED;
+
+    #[test]
+    fn reuses_until_invalidated() {
+        com_initialized();
+        let device = DeviceManager::get_default_playback_device().unwrap();
+        let device_id = device.get_id().unwrap();
+        SessionManagerCache::invalidate(&device_id);
+
+        let (first, cached) = SessionManagerCache::get(&device.inner, &device_id).unwrap();
+        assert!(!cached);
+        let (second, cached) = SessionManagerCache::get(&device.inner, &device_id).unwrap();
+        assert!(cached);
+        assert_eq!(first, second);
+
+        SessionManagerCache::invalidate(&device_id);
+        assert!(!SessionManagerCache::get(&device.inner, &device_id).unwrap().1);
+
+        // What the registered client does when the device is disabled
+        assert!(INVALIDATOR.lock().unwrap().is_some());
+        let client: IMMNotificationClient = Invalidator.into();
+        let id = windows_core::HSTRING::from(device_id.as_str());
+        unsafe { client.OnDeviceStateChanged(&id, DEVICE_STATE_DISABLED) }.unwrap();
+        assert!(!SessionManagerCache::get(&device.inner, &device_id).unwrap().1);
+    }
+}
@@ -0,0 +1,169 @@
+//! How [`Session::get_process_name`](crate::manager::Session::get_process_name) is found, see [`SessionNameParser`].
+//!
+//! The default, [`InstanceIdentifierParser`], reads the image path out of the session instance identifier and needs no
+//! extra calls, but relies on an undocumented layout. [`ProcessImageParser`] asks the process itself and
+//! [`DisplayNameParser`] falls back to the name the application gave its session. [`ParserChain`] tries several in
+//! order, and [`SessionManager::set_name_parser`](crate::manager::SessionManager::set_name_parser) picks the one used for
+//! every session created afterwards.
+
+use std::sync::{Arc, RwLock};
+
+use windows::Win32::{
+    Media::Audio::IAudioSessionControl,
+    System::Threading::{OpenProcess, PROCESS_NAME_NATIVE, PROCESS_QUERY_LIMITED_INFORMATION, QueryFullProcessImageNameW},
+};
+use windows_core::PWSTR;
+
+use crate::audio_client::{EventHandleWrapper, PWSTRWrapper};
+
+static NAME_PARSER: RwLock<Option<Arc<dyn SessionNameParser>>> = RwLock::new(None);
+
+/// Finds the process name of a session when it is enumerated or created
+///
+/// The name should be the NT path of the process image (`\Device\HarddiskVolume3\...\app.exe`), that is what
+/// [`Session::get_process_path`](crate::manager::Session::get_process_path) converts. Parsers that can only come up
+/// with something else, like [`DisplayNameParser`], make `get_process_path` fail for their sessions.
+pub trait SessionNameParser: Send + Sync + 'static {
+    fn parse(&self, session: &SessionNameInput) -> Option<String>;
+}
+
+/// What a [`SessionNameParser`] gets to work with
+pub struct SessionNameInput<'a> {
+    pub(crate) identifier: &'a str,
+    pub(crate) pid: u32,
+    pub(crate) control: &'a IAudioSessionControl,
+}
+
+impl SessionNameInput<'_> {
+    /// The session instance identifier, see [`Session::get_name`](crate::manager::Session::get_name)
+    pub fn get_identifier(&self) -> &str {
+        self.identifier
+    }
+
+    pub fn get_pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Display name the application set on its session, `None` if it didn't set one
+    /// Costs a call into the audio service
+    pub fn get_display_name(&self) -> Option<String> {
+        let display_name = PWSTRWrapper(unsafe { self.control.GetDisplayName() }.ok()?);
+        unsafe { display_name.0.to_string() }.ok().filter(|name| !name.is_empty())
+    }
+}
+
+/// Reads the image path out of the `...|<image path>%b{...}` layout of the instance identifier, without extra calls
+/// This is the default, it yields `None` for identifiers that don't follow the layout
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InstanceIdentifierParser;
+
+impl SessionNameParser for InstanceIdentifierParser {
+    fn parse(&self, session: &SessionNameInput) -> Option<String> {
+        Some(session.identifier.split_once('|')?.1.split_once('%')?.0.into())
+    }
+}
+
+/// Queries the image path of the session's process, costs opening the process
+/// Yields `None` for the system sounds session and for processes that can't be opened
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessImageParser;
+
+impl SessionNameParser for ProcessImageParser {
+    fn parse(&self, session: &SessionNameInput) -> Option<String> {
+        if session.pid == 0 {
+            return None;
+        }
+        let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, session.pid) }.ok()?;
+        let process = EventHandleWrapper(process);
+        let mut path = vec![0u16; 1024];
+        let mut len = path.len() as u32;
+        unsafe { QueryFullProcessImageNameW(process.0, PROCESS_NAME_NATIVE, PWSTR(path.as_mut_ptr()), &mut len) }.ok()?;
+        String::from_utf16(&path[..len as usize]).ok()
+    }
+}
+
+/// Uses the display name of the session, see [`SessionNameInput::get_display_name`]
+/// Meant as the last parser of a [`ParserChain`], the name is not a path
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DisplayNameParser;
+
+impl SessionNameParser for DisplayNameParser {
+    fn parse(&self, session: &SessionNameInput) -> Option<String> {
+        session.get_display_name()
+    }
+}
+
+/// Tries parsers in order and takes the first name found
+/// e.g. `ParserChain::new().then(InstanceIdentifierParser).then(ProcessImageParser).then(DisplayNameParser)`
+#[derive(Default)]
+pub struct ParserChain {
+    parsers: Vec<Box<dyn SessionNameParser>>,
+}
+
+impl ParserChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn then(mut self, parser: impl SessionNameParser) -> Self {
+        self.parsers.push(Box::new(parser));
+        self
+    }
+}
+
+impl SessionNameParser for ParserChain {
+    fn parse(&self, session: &SessionNameInput) -> Option<String> {
+        self.parsers.iter().find_map(|parser| parser.parse(session))
+    }
+}
+
+pub(crate) fn set_name_parser(parser: Option<Arc<dyn SessionNameParser>>) {
+    *NAME_PARSER.write().unwrap_or_else(|e| e.into_inner()) = parser;
+}
+
+/// Runs the configured parser, [`InstanceIdentifierParser`] if none was set
+pub(crate) fn parse_name(session: &SessionNameInput) -> Option<String> {
+    match NAME_PARSER.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(parser) => parser.parse(session),
+        None => InstanceIdentifierParser.parse(session),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use windows_core::Interface;
+
+    use super::*;
+    use crate::manager::SessionManager;
+
+    #[test]
+    fn parsers_agree() {
+        let (playback, _format) = crate::audio_client::AudioClient::new()
+            .start_playback_device(None, |_| 0, |_| {})
+            .unwrap();
+        let _playback = playback.start().unwrap();
+        let session = SessionManager::get_sessions()
+            .unwrap()
+            .into_iter()
+            .find(|session| *session.get_pid() == std::process::id())
+            .unwrap();
+
+        let control = session.get_session().cast::<IAudioSessionControl>().unwrap();
+        let input = SessionNameInput {
+            identifier: session.get_name(),
+            pid: *session.get_pid(),
+            control: &control,
+        };
+        let from_identifier = InstanceIdentifierParser.parse(&input).unwrap();
+        let from_process = ProcessImageParser.parse(&input).unwrap();
+        assert!(from_identifier.eq_ignore_ascii_case(&from_process));
+
+        let unparsable = SessionNameInput {
+            identifier: "no layout",
+            ..input
+        };
+        assert_eq!(InstanceIdentifierParser.parse(&unparsable), None);
+        let chain = ParserChain::new().then(InstanceIdentifierParser).then(ProcessImageParser);
+        assert_eq!(chain.parse(&unparsable), Some(from_process));
+    }
+}
@@ -1,17 +1,19 @@
 use std::{collections::HashMap, sync::mpsc};
 
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use windows::Win32::{
     Media::Audio::{
         IAudioSessionControl, IAudioSessionControl2, IAudioSessionManager2, IAudioSessionNotification, IAudioSessionNotification_Impl,
     },
-    System::Com::{CLSCTX_ALL, COINIT_MULTITHREADED, CoInitializeEx},
+    System::Com::{COINIT_MULTITHREADED, CoInitializeEx},
 };
 use windows_core::{Interface, implement};
 
 use crate::{
-    manager::{Device, Session},
+    diagnostics::{ObjectKind, Tracked},
+    manager::{AgileSession, AudioError, Device, Session},
     notifications::NotificationError,
+    session_manager_cache::SessionManagerCache,
 };
 
 pub(crate) enum SessionNotificationMessage {
@@ -31,26 +33,38 @@ pub(super) enum SessionNotificationCommand {
     Stop,
 }
 
+/// Commands to the session notification thread, tagged with a sequence number
+pub(crate) type CommandSender = mpsc::Sender<(u64, SessionNotificationCommand)>;
+/// Answers of the session notification thread, tagged with the sequence number of the command they answer
+pub(crate) type MessageReceiver = mpsc::Receiver<(u64, SessionNotificationMessage)>;
+
 type NotificationsMap = HashMap<String, (IAudioSessionManager2, IAudioSessionNotification)>;
 
+/// Runs the commands sent to it in order, every answer carries the sequence number of the command it answers. The
+/// ready message has sequence number 0.
 pub(crate) fn session_notification_thread(
-    send: mpsc::Sender<SessionNotificationMessage>,
-    recv: mpsc::Receiver<SessionNotificationCommand>,
+    send: mpsc::Sender<(u64, SessionNotificationMessage)>,
+    recv: mpsc::Receiver<(u64, SessionNotificationCommand)>,
 ) {
     unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }.unwrap();
     let mut notifications: NotificationsMap = HashMap::new();
-    send.send(SessionNotificationMessage::Ready).expect("Failed sending ready message");
+    send.send((0, SessionNotificationMessage::Ready))
+        .expect("Failed sending ready message");
     loop {
-        match thread_inner(&send, &recv, &mut notifications) {
-            Ok(LoopResult::Continue) => {}
+        let Ok((seq, command)) = recv.recv() else {
+            panic!("Notification thread crashed, command sender dropped");
+        };
+        match thread_inner(command, &mut notifications) {
+            Ok(LoopResult::Continue(message)) => {
+                // The caller may have given up on the answer already
+                let _ = send.send((seq, message));
+            }
             Ok(LoopResult::Stop) => {
-                send.send(SessionNotificationMessage::Stopped)
-                    .expect("Failed sending stopped message");
+                let _ = send.send((seq, SessionNotificationMessage::Stopped));
                 break;
             }
             Err(err) => {
-                send.send(SessionNotificationMessage::Error(err))
-                    .expect("Failed sending error message");
+                let _ = send.send((seq, SessionNotificationMessage::Error(err)));
                 break;
             }
         }
@@ -58,23 +72,26 @@ pub(crate) fn session_notification_thread(
 }
 
 enum LoopResult {
-    Continue,
+    /// Answer to the command
+    Continue(SessionNotificationMessage),
     Stop,
 }
 
-fn thread_inner(
-    send: &mpsc::Sender<SessionNotificationMessage>,
-    recv: &mpsc::Receiver<SessionNotificationCommand>,
-    notifications: &mut NotificationsMap,
-) -> Result<LoopResult, NotificationError> {
-    match recv.recv() {
-        Ok(SessionNotificationCommand::RegisterNotification(cb, dev)) => {
-            let session_notification_client = IAudioSessionNotificationClient::new(cb);
+fn thread_inner(command: SessionNotificationCommand, notifications: &mut NotificationsMap) -> Result<LoopResult, NotificationError> {
+    match command {
+        SessionNotificationCommand::RegisterNotification(cb, dev) => {
+            let session_notification_client = IAudioSessionNotificationClient::new(cb, dev.clone());
             let session_notification_client: IAudioSessionNotification = session_notification_client.into();
             let dev = dev.inner;
+            let dev_id = unsafe {
+                dev.GetId()
+                    .map_err(NotificationError::FailedGettingDeviceId)?
+                    .to_string()
+                    .map_err(NotificationError::PCWSTRConversionError)?
+            };
 
-            let session_manager = unsafe { dev.Activate::<IAudioSessionManager2>(CLSCTX_ALL, None) }
-                .map_err(NotificationError::FailedActivatingSessionManager)?;
+            let (session_manager, _) =
+                SessionManagerCache::get(&dev, &dev_id).map_err(NotificationError::FailedActivatingSessionManager)?;
             let session_enumerator = unsafe {
                 session_manager
                     .GetSessionEnumerator()
@@ -82,12 +99,6 @@ fn thread_inner(
             };
             unsafe { session_manager.RegisterSessionNotification(&session_notification_client) }
                 .map_err(NotificationError::FailedSettingUpNotification)?;
-            let dev_id = unsafe {
-                dev.GetId()
-                    .map_err(NotificationError::FailedGettingDeviceId)?
-                    .to_string()
-                    .map_err(NotificationError::PCWSTRConversionError)?
-            };
             notifications.insert(dev_id, (session_manager, session_notification_client));
             // Have to call GetCount() to start th enotifications (MS documentation)
             unsafe {
@@ -97,10 +108,9 @@ fn thread_inner(
             }
 
             trace!("Notification registered, notifications: {}", notifications.len());
-            send.send(SessionNotificationMessage::NotificationRegistered)
-                .expect("Failed sending notification registered message");
+            Ok(LoopResult::Continue(SessionNotificationMessage::NotificationRegistered))
         }
-        Ok(SessionNotificationCommand::UnregisterNotification(dev)) => {
+        SessionNotificationCommand::UnregisterNotification(dev) => {
             let dev = dev.inner;
             let dev_id = unsafe {
                 dev.GetId()
@@ -112,53 +122,72 @@ fn thread_inner(
                 unsafe { session_manager.UnregisterSessionNotification(&notification_client) }
                     .map_err(|_| NotificationError::FailedUnregisteringSessionNotification)?;
                 // TODO: Don't throw away inner error
-                send.send(SessionNotificationMessage::NotificationUnregistered)
-                    .expect("Failed sending notification unregistered message");
             }
             trace!("Notification unregistered, notifications: {}", notifications.len());
+            // Answered for unknown devices too, the caller waits for it
+            Ok(LoopResult::Continue(SessionNotificationMessage::NotificationUnregistered))
         }
-        Ok(SessionNotificationCommand::Stop) => {
+        SessionNotificationCommand::Stop => {
             // Unregister all notifications
             for (id, (session_manager, notification_client)) in notifications.drain() {
                 unsafe { session_manager.UnregisterSessionNotification(&notification_client) }
                     .map_err(|_| NotificationError::FailedUnregisteringSessionNotification)?;
                 debug!("Notification {} unregistered", id);
             }
-            return Ok(LoopResult::Stop);
-        }
-        Err(err) => {
-            panic!("Notification thread crashed, receiver error: {:?}", err);
+            Ok(LoopResult::Stop)
         }
     }
-    Ok(LoopResult::Continue)
 }
 
 #[derive(Debug)]
-pub struct SessionCreated(String);
+pub struct SessionCreated(AgileSession);
 
 impl SessionCreated {
     pub fn get_name(&self) -> &String {
-        &self.0
+        self.0.get_name()
+    }
+
+    /// The new session for the calling thread, e.g. to register session events for it or to read its process id
+    pub fn get_session(&self) -> Result<Session, AudioError> {
+        self.0.resolve()
+    }
+
+    /// The device the session was created on
+    pub fn get_device(&self) -> &Device {
+        self.0.get_device()
     }
 }
 
 #[implement(IAudioSessionNotification)]
 struct IAudioSessionNotificationClient {
     callback_fn: SessionNotificationCallback,
+    device: Device,
+    _tracked: Tracked,
 }
 
 impl IAudioSessionNotificationClient {
-    pub fn new(callback_fn: SessionNotificationCallback) -> Self {
-        Self { callback_fn }
+    pub fn new(callback_fn: SessionNotificationCallback, device: Device) -> Self {
+        Self {
+            callback_fn,
+            device,
+            _tracked: Tracked::new(ObjectKind::SessionNotificationClient),
+        }
     }
 }
 
 impl IAudioSessionNotification_Impl for IAudioSessionNotificationClient_Impl {
     fn OnSessionCreated(&self, newsession: windows_core::Ref<'_, IAudioSessionControl>) -> windows_core::Result<()> {
         let s = newsession.clone().expect("Failed cloning session");
-        let new_session =
-            Session::from_session(s.cast::<IAudioSessionControl2>().expect("Failed casting session")).expect("Failed creating session");
-        (self.callback_fn)(SessionCreated(new_session.get_name().clone()));
+        let new_session = Session::from_session(
+            s.cast::<IAudioSessionControl2>().expect("Failed casting session"),
+            self.device.clone(),
+        )
+        .expect("Failed creating session");
+        // Callbacks may run on another thread, which can't use the session control of this one
+        match AgileSession::new(&new_session) {
+            Ok(session) => (self.callback_fn)(SessionCreated(session)),
+            Err(err) => warn!("Failed passing on new session {}: {}", new_session.get_name(), err),
+        }
         Ok(())
     }
 }
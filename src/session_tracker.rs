@@ -0,0 +1,287 @@
+//! Following the sessions of every device, the part [`ActivityFeed`](crate::activity_feed::ActivityFeed),
+//! [`AppGroups`](crate::app_group::AppGroups), [`SessionCaptureManager`](crate::session_capture::SessionCaptureManager)
+//! and the [weak handles](crate::weak_handle) share, see [`SessionTracker`].
+//!
+//! Every callback runs on one dispatcher thread owned by the tracker, in the order the notifications arrived. The
+//! sessions that already exist are offered on that thread too, so no event of a session reaches the handler before
+//! the session itself.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, mpsc};
+
+use log::warn;
+
+use crate::com::ComSend;
+use crate::device_query::{DataFlow, DeviceQuery};
+use crate::device_state::DeviceState;
+use crate::dispatcher::Dispatcher;
+use crate::event_args::{AudioSessionEventArgs, DeviceNotificationEventArgs, SessionState};
+use crate::manager::{Device, DeviceManager, Session, SessionManager, SessionStateFilter};
+use crate::notifications::{NotificationError, Notifications};
+use crate::session_notification::SessionCreated;
+
+/// What a [`SessionTracker`] reports to its owner, every method runs on the tracker's dispatcher thread
+pub(crate) trait SessionHandler: Send + Sync + 'static {
+    /// A session showed up, its events are watched if this returns `true`. `created` is `false` for the sessions that
+    /// existed when the tracker started.
+    fn session_added(&self, session: &Session, created: bool) -> bool;
+
+    /// An event of a watched session, by session name. Sessions that expired or were disconnected aren't watched
+    /// anymore after their event.
+    fn session_event(&self, name: &str, event: AudioSessionEventArgs);
+
+    /// Every device notification, after the tracker started or stopped watching the device
+    fn device_event(&self, _event: DeviceNotificationEventArgs) {}
+}
+
+/// Watches the sessions of every active playback and capture device, including devices that become active later,
+/// until it is dropped
+pub(crate) struct SessionTracker<H: SessionHandler> {
+    shared: Arc<Shared<H>>,
+}
+
+struct Shared<H> {
+    handler: H,
+    dispatcher: Dispatcher,
+    /// `None` once the tracker stopped
+    notifications: Mutex<Option<ComSend<Notifications>>>,
+    watched: Mutex<Watched>,
+}
+
+#[derive(Default)]
+struct Watched {
+    /// Devices with a session notification, by id
+    devices: HashMap<String, Device>,
+    /// Sessions with registered events by name, with the id of their device
+    sessions: HashMap<String, String>,
+}
+
+impl<H: SessionHandler> SessionTracker<H> {
+    /// Starts watching and offers every existing session to `handler` before returning
+    pub(crate) fn start(handler: H) -> Result<Self, NotificationError> {
+        let dispatcher = Dispatcher::dedicated_thread();
+        let notifications = Notifications::sta_compatible(dispatcher.clone())?;
+        let shared = Arc::new(Shared {
+            handler,
+            dispatcher,
+            notifications: Mutex::new(Some(ComSend(notifications))),
+            watched: Mutex::new(Watched::default()),
+        });
+
+        // Registered first, devices that become active from here on are added by the notification
+        let weak = Arc::downgrade(&shared);
+        shared
+            .with_notifications(|notifications| {
+                notifications.register_device_notification(move |event| {
+                    if let Some(shared) = weak.upgrade() {
+                        shared.device_event(event);
+                    }
+                })
+            })
+            .unwrap_or(Ok(()))?;
+        let devices =
+            DeviceManager::find_devices(DeviceQuery::new().flow(DataFlow::All)).map_err(NotificationError::FailedEnumeratingDevices)?;
+        for device in devices {
+            shared.watch_device(device)?;
+        }
+
+        let (send, recv) = mpsc::channel();
+        let existing = shared.clone();
+        shared.dispatcher.dispatch(move || {
+            let _ = send.send(existing.offer_existing());
+        });
+        recv.recv().unwrap_or(Ok(()))?;
+        Ok(Self { shared })
+    }
+
+    pub(crate) fn handler(&self) -> &H {
+        &self.shared.handler
+    }
+
+    /// Watches the events of `session` without asking the handler, sessions that are watched already are left alone
+    pub(crate) fn watch(&self, session: &Session) -> Result<(), NotificationError> {
+        if self.shared.reserve(session) {
+            self.shared.register_session_events(session)?;
+        }
+        Ok(())
+    }
+
+    /// Unregisters everything, callbacks that already run finish on the dispatcher thread
+    pub(crate) fn stop(&self) {
+        // Taken out first, dropping the registrations waits for the notification threads
+        let notifications = self.shared.lock_notifications().take();
+        drop(notifications);
+    }
+}
+
+impl<H: SessionHandler> Drop for SessionTracker<H> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl<H: SessionHandler> Shared<H> {
+    fn lock_notifications(&self) -> MutexGuard<'_, Option<ComSend<Notifications>>> {
+        self.notifications.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_watched(&self) -> MutexGuard<'_, Watched> {
+        self.watched.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Runs `job` on the notifications, `None` once the tracker stopped. The watched state isn't locked meanwhile,
+    /// registering waits for the notification threads.
+    fn with_notifications<R>(&self, job: impl FnOnce(&mut Notifications) -> R) -> Option<R> {
+        self.lock_notifications().as_mut().map(|notifications| job(&mut notifications.0))
+    }
+
+    fn offer_existing(self: &Arc<Self>) -> Result<(), NotificationError> {
+        let devices: Vec<Device> = self.lock_watched().devices.values().cloned().collect();
+        for device in devices {
+            let sessions = SessionManager::get_device_sessions(&device, SessionStateFilter::ActiveAndInactive)
+                .map_err(NotificationError::FailedEnumeratingSessions)?;
+            for session in sessions {
+                self.session_added(&session, false);
+            }
+        }
+        Ok(())
+    }
+
+    fn watch_device(self: &Arc<Self>, device: Device) -> Result<(), NotificationError> {
+        let id = device.get_id().map_err(NotificationError::FailedEnumeratingDevices)?;
+        if self.lock_watched().devices.insert(id.clone(), device.clone()).is_some() {
+            return Ok(());
+        }
+        let weak = Arc::downgrade(self);
+        let registered = self.with_notifications(|notifications| {
+            notifications.register_session_notification(device, move |created: SessionCreated| {
+                if let Some(shared) = weak.upgrade() {
+                    match created.get_session() {
+                        Ok(session) => shared.session_added(&session, true),
+                        Err(err) => warn!("Failed resolving new session {}: {}", created.get_name(), err),
+                    }
+                }
+            })
+        });
+        if let Some(Err(err)) = registered {
+            self.lock_watched().devices.remove(&id);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn unwatch_device(&self, id: &str) {
+        let (device, sessions) = {
+            let mut watched = self.lock_watched();
+            let Some(device) = watched.devices.remove(id) else {
+                return;
+            };
+            let mut sessions = Vec::new();
+            watched.sessions.retain(|name, device_id| {
+                let keep = device_id != id;
+                if !keep {
+                    sessions.push(name.clone());
+                }
+                keep
+            });
+            (device, sessions)
+        };
+        self.with_notifications(|notifications| {
+            for name in &sessions {
+                let _ = notifications.unregister_session_event(name);
+            }
+            if let Err(err) = notifications.unregister_session_notification(device) {
+                warn!("Failed unregistering session notification of {}: {}", id, err);
+            }
+        });
+    }
+
+    /// Marks `session` as watched, `false` if it already is
+    fn reserve(&self, session: &Session) -> bool {
+        let device_id = session.get_device().get_id().unwrap_or_default();
+        let mut watched = self.lock_watched();
+        if watched.sessions.contains_key(session.get_name()) {
+            return false;
+        }
+        watched.sessions.insert(session.get_name().clone(), device_id);
+        true
+    }
+
+    fn session_added(self: &Arc<Self>, session: &Session, created: bool) {
+        // Reserved before asking, a session is offered once even if the enumeration and its notification both see it
+        if !self.reserve(session) {
+            return;
+        }
+        if !self.handler.session_added(session, created) {
+            self.lock_watched().sessions.remove(session.get_name());
+            return;
+        }
+        if let Err(err) = self.register_session_events(session) {
+            warn!("Failed registering session events for {}: {}", session.get_name(), err);
+        }
+    }
+
+    /// Registers the events of a reserved session, the reservation is dropped if that fails
+    fn register_session_events(self: &Arc<Self>, session: &Session) -> Result<(), NotificationError> {
+        let weak = Arc::downgrade(self);
+        let name = session.get_name().clone();
+        let registered = self.with_notifications(|notifications| {
+            notifications.register_session_event(session, move |event| {
+                if let Some(shared) = weak.upgrade() {
+                    shared.session_event(&name, event);
+                }
+            })
+        });
+        match registered {
+            // Sessions are registered by name, the first session of a name gets the events
+            None | Some(Ok(())) | Some(Err(NotificationError::NotificationAlreadyRegistered)) => Ok(()),
+            Some(Err(err)) => {
+                self.lock_watched().sessions.remove(session.get_name());
+                Err(err)
+            }
+        }
+    }
+
+    fn session_event(&self, name: &str, event: AudioSessionEventArgs) {
+        let ended = match &event {
+            AudioSessionEventArgs::StateChanged(args) => matches!(args.get_state(), SessionState::AudioSessionStateExpired),
+            AudioSessionEventArgs::SessionDisconnected(_) => true,
+            _ => false,
+        };
+        self.handler.session_event(name, event);
+        if ended && self.lock_watched().sessions.remove(name).is_some() {
+            // The callback runs on the dispatcher thread, not inside the COM callback, so it can unregister itself
+            self.with_notifications(|notifications| notifications.unregister_session_event(name));
+        }
+    }
+
+    fn device_event(self: &Arc<Self>, event: DeviceNotificationEventArgs) {
+        let change = match &event {
+            DeviceNotificationEventArgs::DeviceAdded(args) => args.get_device_id().ok().map(|id| (id, None)),
+            DeviceNotificationEventArgs::DeviceRemoved(args) => args.get_device_id().ok().map(|id| (id, Some(false))),
+            DeviceNotificationEventArgs::DeviceStateChanged(args) => {
+                let active = matches!(args.get_state(), DeviceState::Active);
+                args.get_device_id().ok().map(|id| (id, Some(active)))
+            }
+            _ => None,
+        };
+        if let Some((id, active)) = change {
+            // An added device isn't necessarily active, e.g. a disabled one that was installed
+            let device = match active {
+                Some(false) => None,
+                _ => DeviceManager::get_device(&id)
+                    .ok()
+                    .filter(|device| matches!(device.get_state(), Ok(DeviceState::Active))),
+            };
+            match device {
+                Some(device) => {
+                    if let Err(err) = self.watch_device(device) {
+                        warn!("Failed watching sessions of {}: {}", id, err);
+                    }
+                }
+                None => self.unwatch_device(&id),
+            }
+        }
+        self.handler.device_event(event);
+    }
+}
@@ -0,0 +1,74 @@
+//! Session events of a stream's own session, see
+//! [`AudioStreamConfig::watch_session`](crate::audio_stream::AudioStreamConfig::watch_session).
+
+use std::sync::mpsc::{self, Receiver};
+
+use windows::Win32::Media::Audio::{IAudioClient, IAudioSessionControl, IAudioSessionControl2, IAudioSessionEvents};
+use windows_core::Interface;
+
+use crate::audio_client::{AudioClientError, PWSTRWrapper};
+use crate::event_args::AudioSessionEventArgs;
+use crate::notifications::session_event_client;
+
+/// Registration of the session event client, unregistered when dropped
+pub(crate) struct SessionWatch {
+    session_control: IAudioSessionControl,
+    client: IAudioSessionEvents,
+    events: Receiver<AudioSessionEventArgs>,
+}
+
+impl SessionWatch {
+    /// Registers on the session `audio_client` belongs to
+    pub(crate) fn start(audio_client: &IAudioClient) -> Result<Self, AudioClientError> {
+        let session_control: IAudioSessionControl = unsafe { audio_client.GetService() }.map_err(AudioClientError::FailedGettingService)?;
+        // Only used to tell registrations apart in debug output
+        let session_id = session_control
+            .cast::<IAudioSessionControl2>()
+            .and_then(|session| unsafe { session.GetSessionInstanceIdentifier() })
+            .ok()
+            .and_then(|id| unsafe { PWSTRWrapper(id).0.to_string() }.ok())
+            .unwrap_or_default();
+        let (send, events) = mpsc::channel();
+        let client = session_event_client(session_id, move |args| {
+            let _ = send.send(args);
+        });
+        unsafe { session_control.RegisterAudioSessionNotification(&client) }.map_err(AudioClientError::FailedWatchingSession)?;
+        Ok(Self {
+            session_control,
+            client,
+            events,
+        })
+    }
+
+    pub(crate) fn events(&self) -> &Receiver<AudioSessionEventArgs> {
+        &self.events
+    }
+}
+
+impl Drop for SessionWatch {
+    fn drop(&mut self) {
+        let _ = unsafe { self.session_control.UnregisterAudioSessionNotification(&self.client) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use windows::Win32::Media::Audio::ISimpleAudioVolume;
+
+    use crate::audio_client::AudioClient;
+
+    #[test]
+    fn own_session_events() {
+        let (config, _format) = AudioClient::new().start_playback_device(None, |_| 0, |_| {}).unwrap();
+        let audio_stream = config.watch_session(true).start().unwrap();
+        let volume: ISimpleAudioVolume = unsafe { audio_stream.service() }.unwrap();
+        let original = unsafe { volume.GetMasterVolume() }.unwrap();
+        unsafe { volume.SetMasterVolume(original * 0.5, std::ptr::null()) }.unwrap();
+
+        let event = audio_stream.session_events().unwrap().recv_timeout(Duration::from_secs(1));
+        unsafe { volume.SetMasterVolume(original, std::ptr::null()) }.unwrap();
+        assert!(event.is_ok());
+    }
+}
@@ -0,0 +1,327 @@
+//! Single producer, single consumer ring buffer in shared memory for same-machine IPC.
+//!
+//! The capture process creates the ring with [`ShmRingWriter::create`] and writes packets straight from the capture
+//! callback, the consuming process attaches with [`ShmRingReader::open`] using the same name. The writer signals a
+//! named event after every write, so the reader can sleep in [`ShmRingReader::wait`] instead of polling.
+//! The writer never blocks: a packet that doesn't fit into the free space is dropped as a whole and counted.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use thiserror::Error;
+use windows::Win32::{
+    Foundation::{ERROR_ALREADY_EXISTS, GetLastError, INVALID_HANDLE_VALUE, WAIT_OBJECT_0},
+    System::{
+        Memory::{
+            CreateFileMappingW, FILE_MAP_ALL_ACCESS, MEMORY_BASIC_INFORMATION, MEMORY_MAPPED_VIEW_ADDRESS, MapViewOfFile, OpenFileMappingW,
+            PAGE_READWRITE, UnmapViewOfFile, VirtualQuery,
+        },
+        Threading::{CreateEventW, OpenEventW, SYNCHRONIZATION_SYNCHRONIZE, SetEvent, WaitForSingleObject},
+    },
+};
+use windows_core::HSTRING;
+
+use crate::{
+    audio_client::{EventHandleWrapper, get_wait_error},
+    sample_format::SampleFormat,
+    sinks::{HEADER_LEN, decode_header, encode_header},
+};
+
+#[derive(Error, Debug)]
+pub enum ShmError {
+    #[error("Failed creating file mapping: {0}")]
+    FailedCreatingMapping(windows::core::Error),
+    #[error("A file mapping named {0} already exists")]
+    MappingAlreadyExists(String),
+    #[error("Failed opening file mapping: {0}")]
+    FailedOpeningMapping(windows::core::Error),
+    #[error("Failed mapping view of file: {0}")]
+    FailedMappingView(windows::core::Error),
+    #[error("Failed creating event: {0}")]
+    FailedCreatingEvent(windows::core::Error),
+    #[error("Failed opening event: {0}")]
+    FailedOpeningEvent(windows::core::Error),
+    #[error("Failed waiting for data")]
+    WaitFailed,
+    #[error("Shared memory doesn't contain a ring buffer")]
+    InvalidHeader,
+    #[error("Ring capacity must not be zero")]
+    EmptyCapacity,
+}
+
+#[repr(C)]
+struct RingHeader {
+    format: [u8; HEADER_LEN],
+    capacity: u64,
+    write_pos: AtomicU64,
+    read_pos: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Ring data starts on its own cache line
+const DATA_OFFSET: usize = 64;
+
+struct MappedRing {
+    _mapping: EventHandleWrapper,
+    view: MEMORY_MAPPED_VIEW_ADDRESS,
+    event: EventHandleWrapper,
+    /// Kept out of the shared header, which the other process can change at any time
+    capacity: u64,
+}
+
+// The view is only accessed through the atomics in the header and the SPSC protocol
+unsafe impl Send for MappedRing {}
+
+impl MappedRing {
+    fn header(&self) -> &RingHeader {
+        unsafe { &*(self.view.Value as *const RingHeader) }
+    }
+
+    fn data(&self) -> *mut u8 {
+        unsafe { (self.view.Value as *mut u8).add(DATA_OFFSET) }
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Bytes between the write and the read position, at most the capacity whatever the other process wrote
+    fn available(&self) -> u64 {
+        let header = self.header();
+        let written = header
+            .write_pos
+            .load(Ordering::Acquire)
+            .saturating_sub(header.read_pos.load(Ordering::Relaxed));
+        written.min(self.capacity)
+    }
+
+    /// Free bytes as seen by the writer, the reader is done copying out everything before the read position
+    fn free(&self) -> u64 {
+        let header = self.header();
+        let written = header
+            .write_pos
+            .load(Ordering::Relaxed)
+            .saturating_sub(header.read_pos.load(Ordering::Acquire));
+        self.capacity - written.min(self.capacity)
+    }
+
+    /// Size of the mapped view, rounded up to whole pages
+    fn mapped_len(&self) -> usize {
+        let mut info = MEMORY_BASIC_INFORMATION::default();
+        let len = unsafe { VirtualQuery(Some(self.view.Value), &mut info, size_of::<MEMORY_BASIC_INFORMATION>()) };
+        if len == 0 { 0 } else { info.RegionSize }
+    }
+}
+
+impl Drop for MappedRing {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = UnmapViewOfFile(self.view);
+        }
+    }
+}
+
+fn event_name(name: &str) -> HSTRING {
+    HSTRING::from(format!("{}.event", name))
+}
+
+fn map_view(mapping: &EventHandleWrapper, len: usize) -> Result<MEMORY_MAPPED_VIEW_ADDRESS, ShmError> {
+    let view = unsafe { MapViewOfFile(**mapping, FILE_MAP_ALL_ACCESS, 0, 0, len) };
+    if view.Value.is_null() {
+        return Err(ShmError::FailedMappingView(windows::core::Error::from_win32()));
+    }
+    Ok(view)
+}
+
+pub struct ShmRingWriter {
+    ring: MappedRing,
+}
+
+impl ShmRingWriter {
+    /// Creates the ring, `name` is a kernel object name such as `Local\my_capture`
+    pub fn create(name: &str, capacity: usize, format: &SampleFormat) -> Result<Self, ShmError> {
+        if capacity == 0 {
+            return Err(ShmError::EmptyCapacity);
+        }
+        let len = (DATA_OFFSET + capacity) as u64;
+        let mapping = unsafe {
+            CreateFileMappingW(
+                INVALID_HANDLE_VALUE,
+                None,
+                PAGE_READWRITE,
+                (len >> 32) as u32,
+                len as u32,
+                &HSTRING::from(name),
+            )
+        }
+        .map_err(ShmError::FailedCreatingMapping)?;
+        // The mapping of another writer or reader is opened instead of created, its header must not be overwritten
+        let already_exists = unsafe { GetLastError() } == ERROR_ALREADY_EXISTS;
+        let mapping = EventHandleWrapper(mapping);
+        if already_exists {
+            return Err(ShmError::MappingAlreadyExists(name.to_string()));
+        }
+        let view = map_view(&mapping, len as usize)?;
+        unsafe {
+            (view.Value as *mut RingHeader).write(RingHeader {
+                format: encode_header(format),
+                capacity: capacity as u64,
+                write_pos: AtomicU64::new(0),
+                read_pos: AtomicU64::new(0),
+                dropped: AtomicU64::new(0),
+            });
+        }
+        let event = unsafe { CreateEventW(None, false, false, &event_name(name)) }.map_err(ShmError::FailedCreatingEvent)?;
+
+        Ok(Self {
+            ring: MappedRing {
+                _mapping: mapping,
+                view,
+                event: EventHandleWrapper(event),
+                capacity: capacity as u64,
+            },
+        })
+    }
+
+    /// Copies `data` into the ring and wakes the reader
+    /// Returns false if the packet didn't fit and was dropped
+    pub fn write(&mut self, data: &[u8]) -> bool {
+        let header = self.ring.header();
+        let capacity = self.ring.capacity();
+        let write_pos = header.write_pos.load(Ordering::Relaxed);
+        if data.len() as u64 > self.ring.free() {
+            header.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        let offset = (write_pos % capacity) as usize;
+        let first = data.len().min(capacity as usize - offset);
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), self.ring.data().add(offset), first);
+            std::ptr::copy_nonoverlapping(data.as_ptr().add(first), self.ring.data(), data.len() - first);
+        }
+        header.write_pos.store(write_pos + data.len() as u64, Ordering::Release);
+        unsafe {
+            let _ = SetEvent(*self.ring.event);
+        }
+        true
+    }
+
+    /// Number of packets dropped because the reader fell behind
+    pub fn dropped_packets(&self) -> u64 {
+        self.ring.header().dropped.load(Ordering::Relaxed)
+    }
+}
+
+pub struct ShmRingReader {
+    ring: MappedRing,
+    format: SampleFormat,
+}
+
+impl ShmRingReader {
+    /// Attaches to a ring created by [`ShmRingWriter::create`]
+    pub fn open(name: &str) -> Result<Self, ShmError> {
+        let mapping =
+            unsafe { OpenFileMappingW(FILE_MAP_ALL_ACCESS.0, false, &HSTRING::from(name)) }.map_err(ShmError::FailedOpeningMapping)?;
+        let mapping = EventHandleWrapper(mapping);
+        // A length of 0 maps the whole section
+        let view = map_view(&mapping, 0)?;
+        let event = unsafe { OpenEventW(SYNCHRONIZATION_SYNCHRONIZE, false, &event_name(name)) }.map_err(ShmError::FailedOpeningEvent)?;
+        let mut ring = MappedRing {
+            _mapping: mapping,
+            view,
+            event: EventHandleWrapper(event),
+            capacity: 0,
+        };
+        // The header comes from another process, the ring has to fit into what was mapped before it is trusted
+        let mapped = ring.mapped_len();
+        if mapped < DATA_OFFSET {
+            return Err(ShmError::InvalidHeader);
+        }
+        ring.capacity = ring.header().capacity;
+        if ring.capacity == 0 || ring.capacity > (mapped - DATA_OFFSET) as u64 {
+            return Err(ShmError::InvalidHeader);
+        }
+        let format = decode_header(&ring.header().format).ok_or(ShmError::InvalidHeader)?;
+        Ok(Self { ring, format })
+    }
+
+    pub fn format(&self) -> &SampleFormat {
+        &self.format
+    }
+
+    /// Bytes ready to be read
+    pub fn available(&self) -> usize {
+        self.ring.available() as usize
+    }
+
+    /// Reads as many whole frames as fit into `buf` without blocking, returns the number of bytes read
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let header = self.ring.header();
+        let capacity = self.ring.capacity();
+        let block_align = (self.format.block_align() as usize).max(1);
+        let read_pos = header.read_pos.load(Ordering::Relaxed);
+        let available = self.available();
+        let len = available.min(buf.len()) / block_align * block_align;
+
+        let offset = (read_pos % capacity) as usize;
+        let first = len.min(capacity as usize - offset);
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.ring.data().add(offset), buf.as_mut_ptr(), first);
+            std::ptr::copy_nonoverlapping(self.ring.data(), buf.as_mut_ptr().add(first), len - first);
+        }
+        header.read_pos.store(read_pos + len as u64, Ordering::Release);
+        len
+    }
+
+    /// Blocks until the writer signals new data or `timeout` passes, returns false on timeout
+    pub fn wait(&self, timeout: Duration) -> Result<bool, ShmError> {
+        let res = get_wait_error(unsafe { WaitForSingleObject(*self.ring.event, timeout.as_millis() as u32) })
+            .map_err(|_| ShmError::WaitFailed)?;
+        Ok(res == WAIT_OBJECT_0.0)
+    }
+
+    /// Number of packets the writer dropped because the reader fell behind
+    pub fn dropped_packets(&self) -> u64 {
+        self.ring.header().dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_roundtrip() {
+        let name = format!("Local\\win_acapture_rs_test_{}", std::process::id());
+        let format = SampleFormat::default();
+        let mut writer = ShmRingWriter::create(&name, 64, &format).unwrap();
+        let mut reader = ShmRingReader::open(&name).unwrap();
+        assert_eq!(reader.format(), &format);
+
+        let mut buf = [0u8; 64];
+        for i in 0..10u8 {
+            // 40 byte packets force the ring to wrap around
+            let packet = [i; 40];
+            assert!(writer.write(&packet));
+            assert!(reader.wait(Duration::from_millis(100)).unwrap());
+            assert_eq!(reader.read(&mut buf), 40);
+            assert_eq!(&buf[..40], &packet);
+        }
+
+        assert!(writer.write(&[0; 48]));
+        assert!(!writer.write(&[0; 24]));
+        assert_eq!(reader.dropped_packets(), 1);
+
+        assert!(matches!(
+            ShmRingWriter::create(&format!("{}_empty", name), 0, &format),
+            Err(ShmError::EmptyCapacity)
+        ));
+        // A second writer doesn't reset the ring the reader is attached to
+        assert!(matches!(
+            ShmRingWriter::create(&name, 64, &format),
+            Err(ShmError::MappingAlreadyExists(_))
+        ));
+        assert_eq!(reader.available(), 48);
+    }
+}
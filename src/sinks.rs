@@ -0,0 +1,262 @@
+//! Streams raw captured PCM to another process or machine.
+//!
+//! Every connection starts with a [`HEADER_LEN`] byte header describing the [`SampleFormat`], followed by the raw
+//! interleaved samples. Writing happens on a background thread, so pushing from the capture callback never blocks:
+//! if the connection can't keep up, whole packets are dropped and counted. Lost connections are retried, and the
+//! header is sent again on every new connection.
+
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, trace};
+
+use crate::sample_format::SampleFormat;
+
+const HEADER_MAGIC: [u8; 4] = *b"WACP";
+const HEADER_VERSION: u16 = 1;
+pub const HEADER_LEN: usize = 16;
+
+/// Header layout (little endian): magic `WACP`, version u16, format tag u16, channels u16, bits per sample u16, sample rate u32
+pub fn encode_header(format: &SampleFormat) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[0..4].copy_from_slice(&HEADER_MAGIC);
+    header[4..6].copy_from_slice(&HEADER_VERSION.to_le_bytes());
+    header[6..8].copy_from_slice(&format.get_format_tag().to_wave_format_tag().to_le_bytes());
+    header[8..10].copy_from_slice(&format.get_channel().to_le_bytes());
+    header[10..12].copy_from_slice(&format.get_w_bits_per_sample().to_le_bytes());
+    header[12..16].copy_from_slice(&format.get_n_samples_per_sec().to_le_bytes());
+    header
+}
+
+/// Parses a header written by [`encode_header`], returns `None` if it isn't one
+pub fn decode_header(header: &[u8; HEADER_LEN]) -> Option<SampleFormat> {
+    let read_u16 = |at: usize| u16::from_le_bytes([header[at], header[at + 1]]);
+    if header[0..4] != HEADER_MAGIC || read_u16(4) != HEADER_VERSION {
+        return None;
+    }
+    let sample_rate = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+    Some(SampleFormat::new(read_u16(6).into(), read_u16(8), sample_rate, read_u16(10)))
+}
+
+/// Reads the header on the consuming side of a sink connection
+pub fn read_header<R: Read>(reader: &mut R) -> io::Result<SampleFormat> {
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    decode_header(&header).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid sink header"))
+}
+
+#[derive(Debug, Clone)]
+pub struct SinkOptions {
+    /// Number of packets buffered before new packets get dropped
+    pub queue_len: usize,
+    /// Time to wait between connection attempts
+    pub reconnect_delay: Duration,
+}
+
+impl Default for SinkOptions {
+    fn default() -> Self {
+        Self {
+            queue_len: 256,
+            reconnect_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Background writer shared by the sink implementations
+struct SinkWorker {
+    sender: Option<mpsc::SyncSender<Vec<u8>>>,
+    thread: Option<JoinHandle<()>>,
+    dropped: Arc<AtomicU64>,
+    connected: Arc<AtomicBool>,
+}
+
+impl SinkWorker {
+    fn spawn<C, F>(name: &str, mut connect: F, format: &SampleFormat, options: SinkOptions) -> Self
+    where
+        C: Write,
+        F: FnMut() -> io::Result<C> + Send + 'static,
+    {
+        let (sender, recv) = mpsc::sync_channel::<Vec<u8>>(options.queue_len);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let connected = Arc::new(AtomicBool::new(false));
+        let header = encode_header(format);
+
+        let (thread_dropped, thread_connected) = (dropped.clone(), connected.clone());
+        let thread = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                loop {
+                    let mut conn = match connect() {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            trace!("Sink connection failed: {}", err);
+                            // Discard everything captured while there's no one to send it to
+                            let deadline = Instant::now() + options.reconnect_delay;
+                            loop {
+                                match recv.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                                    Ok(_) => {
+                                        thread_dropped.fetch_add(1, Ordering::Relaxed);
+                                    }
+                                    Err(mpsc::RecvTimeoutError::Timeout) => break,
+                                    Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                                }
+                            }
+                            continue;
+                        }
+                    };
+                    if conn.write_all(&header).is_err() {
+                        continue;
+                    }
+                    debug!("Sink connected");
+                    thread_connected.store(true, Ordering::Relaxed);
+                    let result = recv.iter().try_for_each(|packet| conn.write_all(&packet));
+                    thread_connected.store(false, Ordering::Relaxed);
+                    match result {
+                        Ok(()) => {
+                            let _ = conn.flush();
+                            return;
+                        }
+                        Err(err) => debug!("Sink disconnected: {}", err),
+                    }
+                }
+            })
+            .expect("Failed spawning sink thread");
+
+        Self {
+            sender: Some(sender),
+            thread: Some(thread),
+            dropped,
+            connected,
+        }
+    }
+
+    fn send(&self, data: &[u8]) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if sender.try_send(data.to_vec()).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for SinkWorker {
+    fn drop(&mut self) {
+        // Closing the channel lets the writer flush the queue and exit
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Streams PCM over TCP, connecting (and reconnecting) to a listening consumer
+pub struct TcpSink {
+    worker: SinkWorker,
+}
+
+impl TcpSink {
+    pub fn new<A: ToSocketAddrs>(addr: A, format: &SampleFormat) -> io::Result<Self> {
+        Self::with_options(addr, format, SinkOptions::default())
+    }
+
+    pub fn with_options<A: ToSocketAddrs>(addr: A, format: &SampleFormat, options: SinkOptions) -> io::Result<Self> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let connect = move || {
+            let stream = TcpStream::connect(addrs.as_slice())?;
+            stream.set_nodelay(true)?;
+            Ok(stream)
+        };
+        Ok(Self {
+            worker: SinkWorker::spawn("tcp sink", connect, format, options),
+        })
+    }
+
+    /// Queues a packet without blocking, e.g. `sink.send(packet.data())` from the capture callback
+    pub fn send(&self, data: &[u8]) {
+        self.worker.send(data)
+    }
+
+    /// Number of packets dropped because the queue was full or no consumer was connected
+    pub fn dropped_packets(&self) -> u64 {
+        self.worker.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.worker.connected.load(Ordering::Relaxed)
+    }
+}
+
+/// Streams PCM into a named pipe (e.g. `\\.\pipe\audio`) created by the consuming process
+pub struct PipeSink {
+    worker: SinkWorker,
+}
+
+impl PipeSink {
+    pub fn new(pipe_name: impl Into<PathBuf>, format: &SampleFormat) -> Self {
+        Self::with_options(pipe_name, format, SinkOptions::default())
+    }
+
+    pub fn with_options(pipe_name: impl Into<PathBuf>, format: &SampleFormat, options: SinkOptions) -> Self {
+        let pipe_name = pipe_name.into();
+        let connect = move || OpenOptions::new().write(true).open(&pipe_name);
+        Self {
+            worker: SinkWorker::spawn("pipe sink", connect, format, options),
+        }
+    }
+
+    /// Queues a packet without blocking, e.g. `sink.send(packet.data())` from the capture callback
+    pub fn send(&self, data: &[u8]) {
+        self.worker.send(data)
+    }
+
+    /// Number of packets dropped because the queue was full or no consumer was connected
+    pub fn dropped_packets(&self) -> u64 {
+        self.worker.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.worker.connected.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample_format::FormatTag;
+    use std::net::TcpListener;
+
+    #[test]
+    fn header_roundtrip() {
+        let format = SampleFormat::new(FormatTag::WaveFormatPcm, 1, 44100, 16);
+        assert_eq!(decode_header(&encode_header(&format)), Some(format));
+        assert_eq!(decode_header(&[0u8; HEADER_LEN]), None);
+    }
+
+    #[test]
+    fn tcp_sink() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let format = SampleFormat::default();
+        let sink = TcpSink::new(listener.local_addr().unwrap(), &format).unwrap();
+
+        let (mut conn, _) = listener.accept().unwrap();
+        assert_eq!(read_header(&mut conn).unwrap(), format);
+        // Packets sent before the connection is up are dropped, so wait for it
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !sink.is_connected() {
+            assert!(Instant::now() < deadline, "sink never connected");
+            thread::sleep(Duration::from_millis(1));
+        }
+        sink.send(&[1, 2, 3, 4]);
+        drop(sink);
+        let mut data = Vec::new();
+        conn.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [1, 2, 3, 4]);
+    }
+}
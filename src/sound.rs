@@ -0,0 +1,146 @@
+//! Fire and forget playback of short sounds such as alerts.
+//!
+//! [`play_sound`] builds a render stream for the sound, plays it on a background thread and tears the stream down
+//! once the last sample left the device buffer. The audio engine converts the sound to the device format.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use thiserror::Error;
+
+use crate::audio_client::{AudioClient, AudioClientError};
+use crate::manager::Device;
+use crate::sample_format::SampleFormat;
+use crate::stream_category::StreamCategory;
+use crate::wav::{WavError, parse_wav};
+
+#[derive(Error, Debug)]
+pub enum SoundError {
+    #[error("Failed reading sound file: {0}")]
+    FailedReadingFile(io::Error),
+    #[error("Invalid wav data: {0}")]
+    InvalidWav(WavError),
+    #[error("Audio client error: {0}")]
+    AudioClientError(AudioClientError),
+    #[error("Sound thread panicked")]
+    ThreadPanicked,
+}
+
+pub enum SoundSource {
+    /// Raw interleaved samples
+    Pcm {
+        data: Vec<u8>,
+        format: SampleFormat,
+    },
+    /// Contents of a WAV file
+    WavData(Vec<u8>),
+    WavFile(PathBuf),
+}
+
+impl From<PathBuf> for SoundSource {
+    fn from(path: PathBuf) -> Self {
+        SoundSource::WavFile(path)
+    }
+}
+
+impl From<&Path> for SoundSource {
+    fn from(path: &Path) -> Self {
+        SoundSource::WavFile(path.to_path_buf())
+    }
+}
+
+impl SoundSource {
+    fn load(self) -> Result<(SampleFormat, Vec<u8>), SoundError> {
+        let wav = match self {
+            SoundSource::Pcm { data, format } => return Ok((format, data)),
+            SoundSource::WavData(wav) => wav,
+            SoundSource::WavFile(path) => std::fs::read(path).map_err(SoundError::FailedReadingFile)?,
+        };
+        let (format, data) = parse_wav(&wav).map_err(SoundError::InvalidWav)?;
+        Ok((format, data.to_vec()))
+    }
+}
+
+/// Handle to a sound started by [`play_sound`], dropping it lets the sound play to the end
+pub struct PlayingSound {
+    thread: JoinHandle<Result<(), AudioClientError>>,
+}
+
+impl PlayingSound {
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Blocks until the sound finished playing
+    pub fn wait(self) -> Result<(), SoundError> {
+        self.thread
+            .join()
+            .map_err(|_| SoundError::ThreadPanicked)?
+            .map_err(SoundError::AudioClientError)
+    }
+}
+
+/// Plays a sound on the default playback device, see [`play_sound_on`]
+pub fn play_sound(source: impl Into<SoundSource>, category: StreamCategory) -> Result<PlayingSound, SoundError> {
+    play_sound_on(None, source, category)
+}
+
+/// Plays a sound on `dev` with a short lived stream, returns as soon as playback started
+pub fn play_sound_on(dev: Option<&Device>, source: impl Into<SoundSource>, category: StreamCategory) -> Result<PlayingSound, SoundError> {
+    let (format, data) = source.into().load()?;
+    let (done_send, done_recv) = mpsc::channel();
+    let error_send = done_send.clone();
+
+    let mut client = AudioClient::new();
+    client.set_stream_category(category);
+    let mut position = 0;
+    let config = client
+        .start_playback_device_with_format(
+            dev,
+            &format,
+            move |mut request| {
+                let block_align = request.format().block_align().max(1) as usize;
+                let buffer = request.buffer();
+                let len = (data.len() - position).min(buffer.len());
+                buffer[..len].copy_from_slice(&data[position..position + len]);
+                position += len;
+                if position == data.len() {
+                    request.finish();
+                }
+                (len / block_align) as u32
+            },
+            move |err| {
+                let _ = error_send.send(Err(err));
+            },
+        )
+        .map_err(SoundError::AudioClientError)?
+        .on_complete(move |_| {
+            let _ = done_send.send(Ok(()));
+        });
+    let stream = config.start().map_err(SoundError::AudioClientError)?;
+
+    let thread = thread::Builder::new()
+        .name("sound".to_string())
+        .spawn(move || {
+            let res = done_recv.recv().unwrap_or(Ok(()));
+            drop(stream);
+            res
+        })
+        .map_err(|_| SoundError::AudioClientError(AudioClientError::FailedToCreateThread))?;
+    Ok(PlayingSound { thread })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn play_buffer() {
+        let format = SampleFormat::default();
+        let data = vec![0; format.avg_bytes_per_sec() as usize / 20];
+        let sound = play_sound(SoundSource::Pcm { data, format }, StreamCategory::SoundEffects).unwrap();
+        sound.wait().unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+//! Which interleaved channel feeds which speaker, see [`SpeakerLayout`].
+//!
+//! WASAPI describes multichannel formats with the `dwChannelMask` of `WAVEFORMATEXTENSIBLE`: every set bit is a
+//! speaker, and the channels are interleaved in the order of the set bits, lowest bit first.
+
+/// A speaker position, in the order of the channel mask bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Speaker {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    LowFrequency,
+    BackLeft,
+    BackRight,
+    FrontLeftOfCenter,
+    FrontRightOfCenter,
+    BackCenter,
+    SideLeft,
+    SideRight,
+    TopCenter,
+    TopFrontLeft,
+    TopFrontCenter,
+    TopFrontRight,
+    TopBackLeft,
+    TopBackCenter,
+    TopBackRight,
+}
+
+impl Speaker {
+    /// Every speaker, in channel mask bit order
+    pub const ALL: [Speaker; 18] = [
+        Speaker::FrontLeft,
+        Speaker::FrontRight,
+        Speaker::FrontCenter,
+        Speaker::LowFrequency,
+        Speaker::BackLeft,
+        Speaker::BackRight,
+        Speaker::FrontLeftOfCenter,
+        Speaker::FrontRightOfCenter,
+        Speaker::BackCenter,
+        Speaker::SideLeft,
+        Speaker::SideRight,
+        Speaker::TopCenter,
+        Speaker::TopFrontLeft,
+        Speaker::TopFrontCenter,
+        Speaker::TopFrontRight,
+        Speaker::TopBackLeft,
+        Speaker::TopBackCenter,
+        Speaker::TopBackRight,
+    ];
+
+    /// The `SPEAKER_*` bit of this speaker
+    pub fn get_mask(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+const MONO: u32 = 0x4;
+const STEREO: u32 = 0x3;
+const QUAD: u32 = 0x33;
+const SURROUND_5_1: u32 = 0x60F;
+const SURROUND_5_1_BACK: u32 = 0x3F;
+const SURROUND_7_1: u32 = 0x63F;
+
+/// Speaker layout of a multichannel format, the common `KSAUDIO_SPEAKER_*` layouts have their own variant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpeakerLayout {
+    Mono,
+    Stereo,
+    /// Front and back left and right
+    Quad,
+    /// 5.1 with side speakers, the layout Windows uses for 5.1 endpoints
+    Surround5_1,
+    /// 5.1 with back speakers
+    Surround5_1Back,
+    /// 7.1 with side and back speakers
+    Surround7_1,
+    /// Any other channel mask, `0` means the channels aren't assigned to speakers
+    Custom(u32),
+}
+
+impl SpeakerLayout {
+    pub fn from_mask(mask: u32) -> Self {
+        match mask {
+            MONO => SpeakerLayout::Mono,
+            STEREO => SpeakerLayout::Stereo,
+            QUAD => SpeakerLayout::Quad,
+            SURROUND_5_1 => SpeakerLayout::Surround5_1,
+            SURROUND_5_1_BACK => SpeakerLayout::Surround5_1Back,
+            SURROUND_7_1 => SpeakerLayout::Surround7_1,
+            mask => SpeakerLayout::Custom(mask),
+        }
+    }
+
+    /// The layout Windows assumes for a format without a channel mask
+    pub fn default_for_channels(channels: u16) -> Self {
+        match channels {
+            1 => SpeakerLayout::Mono,
+            2 => SpeakerLayout::Stereo,
+            4 => SpeakerLayout::Quad,
+            6 => SpeakerLayout::Surround5_1,
+            8 => SpeakerLayout::Surround7_1,
+            _ => SpeakerLayout::Custom(0),
+        }
+    }
+
+    /// The `dwChannelMask` of the layout
+    pub fn get_mask(&self) -> u32 {
+        match self {
+            SpeakerLayout::Mono => MONO,
+            SpeakerLayout::Stereo => STEREO,
+            SpeakerLayout::Quad => QUAD,
+            SpeakerLayout::Surround5_1 => SURROUND_5_1,
+            SpeakerLayout::Surround5_1Back => SURROUND_5_1_BACK,
+            SpeakerLayout::Surround7_1 => SURROUND_7_1,
+            SpeakerLayout::Custom(mask) => *mask,
+        }
+    }
+
+    /// The speakers of the layout, in the order their channels are interleaved
+    pub fn get_speakers(&self) -> Vec<Speaker> {
+        let mask = self.get_mask();
+        Speaker::ALL.into_iter().filter(|speaker| mask & speaker.get_mask() != 0).collect()
+    }
+
+    /// Speaker of the interleaved channel at `channel`, `None` for channels the mask doesn't cover
+    pub fn get_speaker(&self, channel: usize) -> Option<Speaker> {
+        self.get_speakers().get(channel).copied()
+    }
+
+    /// Interleaved channel index of `speaker`, `None` if the layout doesn't have it
+    pub fn get_channel(&self, speaker: Speaker) -> Option<usize> {
+        self.get_speakers().iter().position(|s| *s == speaker)
+    }
+
+    pub fn get_channel_count(&self) -> u16 {
+        self.get_speakers().len() as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speaker_positions() {
+        let layout = SpeakerLayout::from_mask(0x60F);
+        assert_eq!(layout, SpeakerLayout::Surround5_1);
+        assert_eq!(layout.get_channel_count(), 6);
+        assert_eq!(layout.get_speaker(3), Some(Speaker::LowFrequency));
+        assert_eq!(layout.get_channel(Speaker::SideRight), Some(5));
+        assert_eq!(layout.get_channel(Speaker::BackLeft), None);
+        assert_eq!(SpeakerLayout::default_for_channels(2).get_mask(), 0x3);
+        assert_eq!(SpeakerLayout::from_mask(0x7), SpeakerLayout::Custom(0x7));
+    }
+}
@@ -0,0 +1,238 @@
+//! Identifiers that survive renames and language changes, see [`DeviceKey`] and [`SessionKey`].
+//!
+//! Friendly names are localized and can be changed by the user, so they are unfit for remembering a device or an
+//! application. Both keys convert to and from a string for settings files, and [`DeviceKey::matches`] and
+//! [`SessionKey::matches`] tell whether a stored key still refers to the same thing after e.g. a driver reinstall.
+
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+use windows::Win32::Media::Audio::EndpointFormFactor;
+use windows_core::GUID;
+
+use crate::device_query::{DataFlow, FormFactor};
+
+/// Container id Windows gives every endpoint built into the computer, it doesn't tell them apart
+const BUILT_IN_CONTAINER: GUID = GUID::from_u128(0x00000000_0000_0000_ffff_ffffffffffff);
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum StableKeyError {
+    #[error("Invalid stable key: {0}")]
+    InvalidKey(String),
+}
+
+/// How close a stored key is to a current one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KeyMatch {
+    NoMatch,
+    /// Probably the same thing under a new id, e.g. the same USB headset after its driver was reinstalled
+    Likely,
+    Exact,
+}
+
+/// Stable identity of an endpoint, see [`Device::get_stable_key`](crate::manager::Device::get_stable_key)
+///
+/// The string form is `<render|capture>|<container id>|<form factor>|<endpoint id>`, the container id is empty when
+/// the endpoint doesn't have one and the form factor is the raw `PKEY_AudioEndpoint_FormFactor` value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceKey {
+    pub(crate) flow: DataFlow,
+    pub(crate) container_id: Option<GUID>,
+    pub(crate) form_factor: u32,
+    pub(crate) endpoint_id: String,
+}
+
+impl DeviceKey {
+    pub fn get_flow(&self) -> DataFlow {
+        self.flow
+    }
+
+    /// Id of the physical device the endpoint belongs to, shared by e.g. the speaker and the microphone of a headset
+    pub fn get_container_id(&self) -> Option<GUID> {
+        self.container_id
+    }
+
+    pub fn get_form_factor(&self) -> FormFactor {
+        EndpointFormFactor(self.form_factor as i32).into()
+    }
+
+    pub fn get_endpoint_id(&self) -> &str {
+        &self.endpoint_id
+    }
+
+    /// `Exact` for the same endpoint id, `Likely` for an endpoint with the same direction and form factor on the same
+    /// physical device. Endpoints built into the computer all share one container, they only ever match exactly.
+    pub fn matches(&self, other: &DeviceKey) -> KeyMatch {
+        if self.endpoint_id == other.endpoint_id {
+            return KeyMatch::Exact;
+        }
+        let same_hardware = self.flow == other.flow
+            && self.form_factor == other.form_factor
+            && self.container_id.is_some_and(|id| id != BUILT_IN_CONTAINER)
+            && self.container_id == other.container_id;
+        if same_hardware { KeyMatch::Likely } else { KeyMatch::NoMatch }
+    }
+}
+
+impl fmt::Display for DeviceKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flow = match self.flow {
+            DataFlow::Capture => "capture",
+            _ => "render",
+        };
+        let container_id = self.container_id.map(|id| format!("{:?}", id)).unwrap_or_default();
+        write!(f, "{}|{}|{}|{}", flow, container_id, self.form_factor, self.endpoint_id)
+    }
+}
+
+impl FromStr for DeviceKey {
+    type Err = StableKeyError;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        let invalid = || StableKeyError::InvalidKey(key.to_string());
+        let mut parts = key.splitn(4, '|');
+        let flow = match parts.next() {
+            Some("render") => DataFlow::Render,
+            Some("capture") => DataFlow::Capture,
+            _ => return Err(invalid()),
+        };
+        let container_id = match parts.next().ok_or_else(invalid)? {
+            "" => None,
+            id => Some(GUID::try_from(id).map_err(|_| invalid())?),
+        };
+        let form_factor = parts.next().and_then(|form_factor| form_factor.parse().ok()).ok_or_else(invalid)?;
+        let endpoint_id = parts.next().filter(|id| !id.is_empty()).ok_or_else(invalid)?.to_string();
+        Ok(Self {
+            flow,
+            container_id,
+            form_factor,
+            endpoint_id,
+        })
+    }
+}
+
+/// Stable identity of an application's session on an endpoint, see
+/// [`Session::get_stable_key`](crate::manager::Session::get_stable_key)
+///
+/// Unlike the session name it doesn't contain the process id, so it stays the same across restarts of the
+/// application. The string form is `<endpoint id>|<executable path>|<session guid>`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SessionKey {
+    pub(crate) endpoint_id: String,
+    pub(crate) app: String,
+    pub(crate) session_guid: String,
+}
+
+impl SessionKey {
+    /// Parses the session identifier (`IAudioSessionControl2::GetSessionIdentifier`), which looks like
+    /// `<endpoint id>|<NT executable path>%b<session guid>`
+    pub(crate) fn from_identifier(identifier: &str, app: impl FnOnce(&str) -> String) -> Option<Self> {
+        let (endpoint_id, rest) = identifier.split_once('|')?;
+        let (nt_path, session_guid) = rest.split_once("%b").unwrap_or((rest, ""));
+        Some(Self {
+            endpoint_id: endpoint_id.to_string(),
+            app: app(nt_path),
+            session_guid: session_guid.to_string(),
+        })
+    }
+
+    pub fn get_endpoint_id(&self) -> &str {
+        &self.endpoint_id
+    }
+
+    /// DOS path of the executable, or the NT path if it couldn't be mapped to a drive letter
+    pub fn get_app(&self) -> &str {
+        &self.app
+    }
+
+    /// Grouping guid the application created the session with, all zero for the default session
+    pub fn get_session_guid(&self) -> &str {
+        &self.session_guid
+    }
+
+    /// `Exact` for the same session of the same application on the same endpoint, `Likely` for the same application on
+    /// another endpoint or with another session guid. Paths are compared case insensitively.
+    pub fn matches(&self, other: &SessionKey) -> KeyMatch {
+        if !self.app.eq_ignore_ascii_case(&other.app) {
+            return KeyMatch::NoMatch;
+        }
+        if self.endpoint_id == other.endpoint_id && self.session_guid.eq_ignore_ascii_case(&other.session_guid) {
+            KeyMatch::Exact
+        } else {
+            KeyMatch::Likely
+        }
+    }
+}
+
+impl fmt::Display for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}|{}|{}", self.endpoint_id, self.app, self.session_guid)
+    }
+}
+
+impl FromStr for SessionKey {
+    type Err = StableKeyError;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        let mut parts = key.splitn(3, '|');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(endpoint_id), Some(app), Some(session_guid)) if !endpoint_id.is_empty() && !app.is_empty() => Ok(Self {
+                endpoint_id: endpoint_id.to_string(),
+                app: app.to_string(),
+                session_guid: session_guid.to_string(),
+            }),
+            _ => Err(StableKeyError::InvalidKey(key.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_and_match() {
+        let headset = DeviceKey {
+            flow: DataFlow::Capture,
+            container_id: Some(GUID::from_u128(0x1234)),
+            form_factor: 5,
+            endpoint_id: "{0.0.1.00000000}.{a}".to_string(),
+        };
+        let parsed: DeviceKey = headset.to_string().parse().unwrap();
+        assert_eq!(parsed, headset);
+        assert_eq!(parsed.get_form_factor(), FormFactor::Headset);
+
+        let reinstalled = DeviceKey {
+            endpoint_id: "{0.0.1.00000000}.{b}".to_string(),
+            ..headset.clone()
+        };
+        assert_eq!(headset.matches(&reinstalled), KeyMatch::Likely);
+        let built_in = DeviceKey {
+            container_id: Some(BUILT_IN_CONTAINER),
+            ..headset.clone()
+        };
+        assert_eq!(
+            built_in.matches(&DeviceKey {
+                container_id: Some(BUILT_IN_CONTAINER),
+                ..reinstalled
+            }),
+            KeyMatch::NoMatch
+        );
+        assert!("render||x".parse::<DeviceKey>().is_err());
+
+        let session = SessionKey::from_identifier(
+            "{0.0.0.00000000}.{a}|\\Device\\HarddiskVolume3\\App\\app.exe%b{00000000-0000-0000-0000-000000000000}",
+            |path| path.replace("\\Device\\HarddiskVolume3", "C:"),
+        )
+        .unwrap();
+        assert_eq!(session.get_app(), "C:\\App\\app.exe");
+        assert_eq!(session.to_string().parse::<SessionKey>().unwrap(), session);
+        let moved = SessionKey {
+            endpoint_id: "{0.0.0.00000000}.{b}".to_string(),
+            app: "c:\\app\\APP.exe".to_string(),
+            ..session.clone()
+        };
+        assert_eq!(session.matches(&moved), KeyMatch::Likely);
+    }
+}
@@ -0,0 +1,47 @@
+use windows::Win32::Media::Audio::{
+    AUDIO_STREAM_CATEGORY, AudioCategory_Alerts, AudioCategory_Communications, AudioCategory_FarFieldSpeech,
+    AudioCategory_ForegroundOnlyMedia, AudioCategory_GameChat, AudioCategory_GameEffects, AudioCategory_GameMedia, AudioCategory_Media,
+    AudioCategory_Movie, AudioCategory_Other, AudioCategory_SoundEffects, AudioCategory_Speech, AudioCategory_UniformSpeech,
+    AudioCategory_VoiceTyping,
+};
+
+/// Tells the audio engine what a stream is used for, e.g. to duck media under communications or to route alerts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamCategory {
+    #[default]
+    Other,
+    ForegroundOnlyMedia,
+    Communications,
+    Alerts,
+    SoundEffects,
+    GameEffects,
+    GameMedia,
+    GameChat,
+    Speech,
+    Movie,
+    Media,
+    FarFieldSpeech,
+    UniformSpeech,
+    VoiceTyping,
+}
+
+impl From<StreamCategory> for AUDIO_STREAM_CATEGORY {
+    fn from(category: StreamCategory) -> Self {
+        match category {
+            StreamCategory::Other => AudioCategory_Other,
+            StreamCategory::ForegroundOnlyMedia => AudioCategory_ForegroundOnlyMedia,
+            StreamCategory::Communications => AudioCategory_Communications,
+            StreamCategory::Alerts => AudioCategory_Alerts,
+            StreamCategory::SoundEffects => AudioCategory_SoundEffects,
+            StreamCategory::GameEffects => AudioCategory_GameEffects,
+            StreamCategory::GameMedia => AudioCategory_GameMedia,
+            StreamCategory::GameChat => AudioCategory_GameChat,
+            StreamCategory::Speech => AudioCategory_Speech,
+            StreamCategory::Movie => AudioCategory_Movie,
+            StreamCategory::Media => AudioCategory_Media,
+            StreamCategory::FarFieldSpeech => AudioCategory_FarFieldSpeech,
+            StreamCategory::UniformSpeech => AudioCategory_UniformSpeech,
+            StreamCategory::VoiceTyping => AudioCategory_VoiceTyping,
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Starts and stops several streams together, e.g. for multi-source recordings that have to line up.
+//!
+//! Every stream thread prepares its client first, then all of them wait on a shared gate. Once the last one is
+//! ready the gate opens and the `Start()` calls happen within the same scheduling window, keeping the offsets
+//! between the streams small and consistent.
+
+use std::sync::Arc;
+
+use crate::audio_client::AudioClientError;
+use crate::audio_stream::{AudioStream, AudioStreamConfig, StartGate, StreamId};
+
+#[derive(Default)]
+pub struct StreamGroup {
+    configs: Vec<AudioStreamConfig>,
+}
+
+impl StreamGroup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, config: AudioStreamConfig) -> &mut Self {
+        self.configs.push(config);
+        self
+    }
+
+    pub fn with(mut self, config: AudioStreamConfig) -> Self {
+        self.configs.push(config);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.configs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.configs.is_empty()
+    }
+
+    /// Starts every stream, returns once all of them started their clients
+    /// If a stream thread can't be spawned or a client can't be prepared, none of the streams are started. If a client
+    /// fails to start, the streams that did start are stopped again. Either way the first error is returned.
+    pub fn start(self) -> Result<RunningStreamGroup, AudioClientError> {
+        let gate = Arc::new(StartGate::default());
+        let mut streams = Vec::with_capacity(self.configs.len());
+        for config in self.configs {
+            match config.start_gated(Some(gate.clone())) {
+                Ok(stream) => streams.push(stream),
+                Err(err) => {
+                    gate.open(false);
+                    return Err(err);
+                }
+            }
+        }
+        if let Err(err) = gate.wait_arrived(streams.len()) {
+            gate.open(false);
+            return Err(err);
+        }
+        gate.open(true);
+        // Dropping the group on an error stops the streams that did start
+        let running = RunningStreamGroup { streams };
+        gate.wait_started(running.len())?;
+        Ok(running)
+    }
+}
+
+/// Streams started by [`StreamGroup::start`], dropping it stops all of them
+pub struct RunningStreamGroup {
+    streams: Vec<AudioStream>,
+}
+
+impl RunningStreamGroup {
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// Ids of the streams in the order they were added
+    pub fn ids(&self) -> Vec<StreamId> {
+        self.streams.iter().map(AudioStream::id).collect()
+    }
+
+    // See drop implementation for cleanup
+    pub fn stop(self) {}
+}
+
+impl Drop for RunningStreamGroup {
+    fn drop(&mut self) {
+        // Signal every stream before joining any of them, so they stop together instead of one after the other
+        for stream in &self.streams {
+            stream.signal_stop();
+        }
+        self.streams.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_client::AudioClient;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn start_together() {
+        let (err_sender, err_recv) = channel();
+        let mut group = StreamGroup::new();
+        for _ in 0..2 {
+            let err_sender = err_sender.clone();
+            let (config, _format) = AudioClient::new()
+                .start_playback_device(None, |_| 0, move |err| err_sender.send(err).unwrap())
+                .unwrap();
+            group.add(config);
+        }
+        let running = group.start().unwrap();
+        assert_eq!(running.len(), 2);
+        let ids = running.ids();
+        assert_ne!(ids[0], ids[1]);
+
+        if let Ok(err) = err_recv.recv_timeout(Duration::from_millis(10)) {
+            panic!("Error during group playback: {:?}", err);
+        }
+        running.stop();
+    }
+
+    #[test]
+    fn setup_error_cancels_start() {
+        let gate = Arc::new(StartGate::default());
+        let thread_gate = gate.clone();
+        let thread = std::thread::spawn(move || thread_gate.arrive_and_wait(Err(&AudioClientError::FailedToCreateThread)));
+        assert!(matches!(gate.wait_arrived(1), Err(AudioClientError::FailedToCreateThread)));
+        gate.open(false);
+        assert!(!thread.join().unwrap());
+    }
+}
@@ -1,5 +1,7 @@
 use std::time::Duration;
 
+use crate::clock;
+
 /// Taken from the `cpal` library: `https://github.com/RustAudio/cpal`
 /// Licensed under `Apache-2.0`
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
@@ -41,7 +43,12 @@ impl StreamInstant {
             .and_then(Self::from_nanos_i128)
     }
 
-    fn as_nanos(&self) -> i128 {
+    /// The current performance counter time, the clock capture packet timestamps are taken from, see [`clock`]
+    pub fn now() -> Self {
+        clock::ticks_to_instant(clock::qpc_now())
+    }
+
+    pub(crate) fn as_nanos(&self) -> i128 {
         (self.secs as i128 * 1_000_000_000) + self.nanos as i128
     }
 
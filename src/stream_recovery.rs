@@ -0,0 +1,151 @@
+//! Reopening a stream after its device went away, see [`StreamRecovery`].
+//!
+//! Removing a device, changing its format in the sound settings or restarting the audio service invalidates every
+//! client on it, and the stream fails with `AUDCLNT_E_DEVICE_INVALIDATED`. With a recovery policy the stream thread
+//! opens the same device again with the same format instead, the callbacks carry on after a gap.
+
+use std::time::Duration;
+
+use windows::Win32::{
+    Foundation::{HANDLE, WAIT_OBJECT_0},
+    Media::Audio::{AUDCLNT_E_DEVICE_INVALIDATED, AUDCLNT_E_RESOURCES_INVALIDATED, AUDCLNT_E_SERVICE_NOT_RUNNING, IAudioClient},
+    System::Threading::WaitForSingleObject,
+};
+
+use crate::audio_client::AudioClientError;
+
+pub(crate) type ReopenFn = Box<dyn FnMut() -> Result<IAudioClient, AudioClientError> + Send>;
+type RecoveryFn = Box<dyn FnMut(RecoveryEvent) + Send>;
+
+/// What a stream does when its device is invalidated, see
+/// [`AudioStreamConfig::with_recovery`](crate::audio_stream::AudioStreamConfig::with_recovery)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamRecovery {
+    /// The stream fails with the error
+    #[default]
+    Disabled,
+    /// Reopens the device up to `attempts` times, waiting `backoff` before every attempt
+    Retry { attempts: u32, backoff: Duration },
+}
+
+/// Progress of a recovery, handed to the callback given to
+/// [`AudioStreamConfig::with_recovery`](crate::audio_stream::AudioStreamConfig::with_recovery)
+#[derive(Debug, Clone)]
+pub enum RecoveryEvent {
+    /// The device was invalidated with the error, the recovery starts
+    Lost(AudioClientError),
+    /// Reopening failed in the given attempt
+    AttemptFailed(u32, AudioClientError),
+    /// The stream runs again, after the given number of attempts
+    Recovered(u32),
+    /// Every attempt failed, the stream fails with the last error
+    GaveUp(u32),
+}
+
+/// Errors after which reopening the device can succeed
+fn is_recoverable(err: &AudioClientError) -> bool {
+    matches!(
+        err.hresult(),
+        Some(AUDCLNT_E_DEVICE_INVALIDATED | AUDCLNT_E_RESOURCES_INVALIDATED | AUDCLNT_E_SERVICE_NOT_RUNNING)
+    )
+}
+
+/// Reopens the client of one stream, set up by the method that created the stream
+pub(crate) struct Recoverer {
+    reopen: ReopenFn,
+    policy: StreamRecovery,
+    callback: Option<RecoveryFn>,
+}
+
+impl Recoverer {
+    pub(crate) fn new(reopen: ReopenFn) -> Self {
+        Self {
+            reopen,
+            policy: StreamRecovery::Disabled,
+            callback: None,
+        }
+    }
+
+    pub(crate) fn set_policy(&mut self, policy: StreamRecovery, callback: RecoveryFn) {
+        self.policy = policy;
+        self.callback = Some(callback);
+    }
+
+    fn report(&mut self, event: RecoveryEvent) {
+        if let Some(callback) = &mut self.callback {
+            callback(event);
+        }
+    }
+
+    /// Reopens the client after `err`, waiting on `stop_handle` between the attempts so a stop isn't held up.
+    /// Returns `None` if the stream was stopped while waiting, and `err` back if the policy doesn't cover it.
+    pub(crate) fn recover(&mut self, err: AudioClientError, stop_handle: HANDLE) -> Result<Option<IAudioClient>, AudioClientError> {
+        let StreamRecovery::Retry { attempts, backoff } = self.policy else {
+            return Err(err);
+        };
+        if !is_recoverable(&err) {
+            return Err(err);
+        }
+        self.report(RecoveryEvent::Lost(err.clone()));
+        let mut last_err = err;
+        for attempt in 1..=attempts {
+            let wait_ms = u32::try_from(backoff.as_millis()).unwrap_or(u32::MAX - 1);
+            if unsafe { WaitForSingleObject(stop_handle, wait_ms) } == WAIT_OBJECT_0 {
+                return Ok(None);
+            }
+            match (self.reopen)() {
+                Ok(audio_client) => {
+                    self.report(RecoveryEvent::Recovered(attempt));
+                    return Ok(Some(audio_client));
+                }
+                Err(err) => {
+                    self.report(RecoveryEvent::AttemptFailed(attempt, err.clone()));
+                    last_err = err;
+                }
+            }
+        }
+        self.report(RecoveryEvent::GaveUp(attempts));
+        Err(last_err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use windows::Win32::System::Threading::{CreateEventW, SetEvent};
+
+    use super::*;
+    use crate::audio_client::EventHandleWrapper;
+
+    #[test]
+    fn retry_until_given_up() {
+        let invalidated = || AudioClientError::FailedGettingBuffer(AUDCLNT_E_DEVICE_INVALIDATED.into());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut recoverer = Recoverer::new(Box::new(move || Err(invalidated())));
+        let stop = EventHandleWrapper(unsafe { CreateEventW(None, false, false, None) }.unwrap());
+
+        // Without a policy the error is handed back untouched
+        assert!(recoverer.recover(invalidated(), stop.0).is_err());
+
+        let reported = events.clone();
+        recoverer.set_policy(
+            StreamRecovery::Retry {
+                attempts: 2,
+                backoff: Duration::from_millis(1),
+            },
+            Box::new(move |event| reported.lock().unwrap().push(format!("{:?}", event))),
+        );
+        let err = recoverer.recover(invalidated(), stop.0).unwrap_err();
+        assert_eq!(err.hresult(), Some(AUDCLNT_E_DEVICE_INVALIDATED));
+        let events = events.lock().unwrap().clone();
+        assert_eq!(events.len(), 4);
+        assert!(events[0].starts_with("Lost") && events[1].starts_with("AttemptFailed(1"));
+        assert_eq!(events[3], "GaveUp(2)");
+
+        // Other errors aren't retried, a stop ends the wait
+        assert!(recoverer.recover(AudioClientError::FailedToCreateThread, stop.0).is_err());
+        unsafe { SetEvent(stop.0) }.unwrap();
+        assert!(recoverer.recover(invalidated(), stop.0).unwrap().is_none());
+    }
+}
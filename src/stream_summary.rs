@@ -0,0 +1,245 @@
+//! Quality figures of a whole stream, handed out once it stopped, see [`StreamSummary`].
+//!
+//! Every stream counts its frames, callbacks and xruns as it runs. When it stops, the summary is completed with the
+//! duration the device clock measured and the latency `IAudioClient::GetStreamLatency` reports, read before the client
+//! is reset. Recorders can log it at the end of a session without instrumenting their callbacks.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::warn;
+use windows::Win32::Media::Audio::{AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY, IAudioClient, IAudioClock};
+
+use crate::audio_stream::CurrentStream;
+
+pub(crate) type SummaryFn = Box<dyn FnOnce(StreamSummary) + Send + 'static>;
+
+/// Where a stopped stream leaves its summary for the [`AudioStream`](crate::audio_stream::AudioStream)
+pub(crate) type SummarySlot = Arc<Mutex<Option<StreamSummary>>>;
+
+/// Figures of a stream from start to stop, see
+/// [`AudioStreamConfig::on_summary`](crate::audio_stream::AudioStreamConfig::on_summary)
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamSummary {
+    frames: u64,
+    duration: Duration,
+    callbacks: u64,
+    callback_time: Duration,
+    xruns: u64,
+    discontinuities: u64,
+    latency: Option<Duration>,
+}
+
+impl StreamSummary {
+    /// Frames handed to or written by the data callback
+    pub fn get_frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// How long the stream ran by the device clocks of every client it used, or by its frames if a clock couldn't be
+    /// read
+    pub fn get_duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub fn get_callbacks(&self) -> u64 {
+        self.callbacks
+    }
+
+    pub fn get_average_callback_time(&self) -> Duration {
+        match u32::try_from(self.callbacks) {
+            Ok(0) => Duration::ZERO,
+            Ok(callbacks) => self.callback_time / callbacks,
+            Err(_) => Duration::from_secs_f64(self.callback_time.as_secs_f64() / self.callbacks as f64),
+        }
+    }
+
+    /// Capture packets the audio engine lost data before, or playback periods the device buffer had run empty before
+    pub fn get_xruns(&self) -> u64 {
+        self.xruns
+    }
+
+    /// Device position jumps of capture streams, and moves of either kind of stream to a new client after a recovery,
+    /// failover or target switch
+    pub fn get_discontinuities(&self) -> u64 {
+        self.discontinuities
+    }
+
+    /// Latency reported by the client right before it was stopped, `None` if it couldn't be read
+    pub fn get_latency(&self) -> Option<Duration> {
+        self.latency
+    }
+}
+
+/// Counts what a stream loop did, from the stream thread
+pub(crate) struct StreamCounters {
+    sample_rate: u32,
+    frames: u64,
+    callbacks: u64,
+    callback_time: Duration,
+    xruns: u64,
+    discontinuities: u64,
+    next_position: Option<u64>,
+    /// Clock durations of the clients the stream moved away from, `None` once one couldn't be read
+    previous_clocks: Option<Duration>,
+}
+
+impl StreamCounters {
+    pub(crate) fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            frames: 0,
+            callbacks: 0,
+            callback_time: Duration::ZERO,
+            xruns: 0,
+            discontinuities: 0,
+            next_position: None,
+            previous_clocks: Some(Duration::ZERO),
+        }
+    }
+
+    /// A captured packet with the flags and device position `GetBuffer` returned
+    pub(crate) fn captured(&mut self, flags: u32, position: u64, frames: u32, elapsed: Duration) {
+        self.callback(frames, elapsed);
+        if flags & AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY.0 as u32 != 0 {
+            self.xruns += 1;
+        }
+        if self.next_position.is_some_and(|expected| expected != position) {
+            self.discontinuities += 1;
+        }
+        self.next_position = Some(position + frames as u64);
+    }
+
+    /// A playback period the callback wrote `written` frames of, `underrun` if the device buffer had run empty
+    /// A callback writing less than asked for isn't counted, the device still has the queued frames to play.
+    pub(crate) fn rendered(&mut self, written: u32, underrun: bool, elapsed: Duration) {
+        self.callback(written, elapsed);
+        if underrun {
+            self.xruns += 1;
+        }
+    }
+
+    fn callback(&mut self, frames: u32, elapsed: Duration) {
+        self.frames += frames as u64;
+        self.callbacks += 1;
+        self.callback_time += elapsed;
+    }
+
+    /// The stream moved away from `previous` to another client, whose position and clock start over
+    pub(crate) fn restart(&mut self, previous: &IAudioClient) {
+        self.next_position = None;
+        self.discontinuities += 1;
+        self.previous_clocks = self
+            .previous_clocks
+            .zip(clock_duration(previous))
+            .map(|(before, clock)| before + clock);
+    }
+
+    /// Completes the counts with what `audio_client` reports, before it is reset
+    fn summary(&self, audio_client: &IAudioClient) -> StreamSummary {
+        let clock_duration = self
+            .previous_clocks
+            .zip(clock_duration(audio_client))
+            .map(|(before, clock)| before + clock);
+        let duration = clock_duration.unwrap_or_else(|| match self.sample_rate {
+            0 => Duration::ZERO,
+            rate => Duration::from_secs_f64(self.frames as f64 / rate as f64),
+        });
+        let latency = unsafe { audio_client.GetStreamLatency() }
+            .ok()
+            .map(|latency| Duration::from_nanos(latency.max(0) as u64 * 100));
+        StreamSummary {
+            frames: self.frames,
+            duration,
+            callbacks: self.callbacks,
+            callback_time: self.callback_time,
+            xruns: self.xruns,
+            discontinuities: self.discontinuities,
+            latency,
+        }
+    }
+}
+
+/// How far the device clock of `audio_client` advanced, `None` if it couldn't be read or the client was reset
+fn clock_duration(audio_client: &IAudioClient) -> Option<Duration> {
+    let clock = unsafe { audio_client.GetService::<IAudioClock>() }.ok()?;
+    let frequency = unsafe { clock.GetFrequency() }.ok()?;
+    let mut position = 0;
+    unsafe { clock.GetPosition(&mut position, None) }.ok()?;
+    // A reset client is back at zero
+    (frequency > 0 && position > 0).then(|| Duration::from_secs_f64(position as f64 / frequency as f64))
+}
+
+/// Delivers the summary of a stream once, to the slot and to the callback set with
+/// [`AudioStreamConfig::on_summary`](crate::audio_stream::AudioStreamConfig::on_summary)
+pub(crate) struct SummarySink {
+    slot: SummarySlot,
+    callback: Option<SummaryFn>,
+    delivered: bool,
+}
+
+impl SummarySink {
+    pub(crate) fn new(callback: Option<SummaryFn>) -> Self {
+        Self {
+            slot: SummarySlot::default(),
+            callback,
+            delivered: false,
+        }
+    }
+
+    pub(crate) fn slot(&self) -> SummarySlot {
+        self.slot.clone()
+    }
+
+    /// Summarizes the stream the first time it stops, later calls do nothing
+    pub(crate) fn deliver(&mut self, counters: &StreamCounters, audio_client: &IAudioClient) {
+        if std::mem::replace(&mut self.delivered, true) {
+            return;
+        }
+        let summary = counters.summary(audio_client);
+        *self.slot.lock().unwrap_or_else(|e| e.into_inner()) = Some(summary.clone());
+        if let Some(callback) = self.callback.take()
+            && panic::catch_unwind(AssertUnwindSafe(|| callback(summary))).is_err()
+        {
+            warn!("Summary callback of stream {} panicked", CurrentStream);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::com::com_initialized;
+    use crate::manager::DeviceManager;
+    use windows::Win32::System::Com::CLSCTX_ALL;
+
+    #[test]
+    fn count_xruns_and_discontinuities() {
+        com_initialized();
+        // Never initialized, so its clock can't be read
+        let device = DeviceManager::get_default_playback_device().unwrap();
+        let previous = unsafe { device.inner.Activate::<IAudioClient>(CLSCTX_ALL, None) }.unwrap();
+        let mut counters = StreamCounters::new(1000);
+        let discontinuity = AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY.0 as u32;
+        counters.captured(0, 0, 10, Duration::from_millis(1));
+        counters.captured(discontinuity, 10, 10, Duration::from_millis(3));
+        // Position jumps ahead by 5 frames
+        counters.captured(0, 25, 10, Duration::from_millis(2));
+        counters.restart(&previous);
+        counters.captured(0, 0, 10, Duration::from_millis(2));
+
+        assert_eq!((counters.frames, counters.callbacks), (40, 4));
+        assert_eq!(counters.xruns, 1);
+        assert_eq!(counters.discontinuities, 2);
+        // The duration of the first client is unknown, so the frames give the duration
+        assert_eq!(counters.previous_clocks, None);
+        assert_eq!(counters.summary(&previous).get_duration(), Duration::from_millis(40));
+
+        // Only a buffer that ran empty is an underrun, a short write still left queued frames to play
+        counters.rendered(5, false, Duration::ZERO);
+        counters.rendered(10, true, Duration::ZERO);
+        counters.rendered(10, false, Duration::ZERO);
+        assert_eq!(counters.xruns, 2);
+    }
+}
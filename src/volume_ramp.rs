@@ -0,0 +1,240 @@
+//! Volume fades of sessions, see [`Session::ramp_volume`](crate::manager::Session::ramp_volume).
+//!
+//! Every ramp of the process runs on one scheduler thread, which moves the volume of each active ramp a step closer to
+//! the target every [`RAMP_STEP`]. Starting a ramp on a session that is still ramping cancels the older ramp.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use windows::Win32::Media::Audio::{IAudioSessionControl2, ISimpleAudioVolume};
+use windows_core::Interface;
+
+use crate::com::{ComSend, com_initialized};
+use crate::event_context::EventContext;
+use crate::manager::AudioError;
+
+/// Interval between two volume steps of a ramp
+pub const RAMP_STEP: Duration = Duration::from_millis(10);
+
+static SCHEDULER: Mutex<Option<mpsc::Sender<ActiveRamp>>> = Mutex::new(None);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RampOutcome {
+    /// The volume reached the target
+    Completed,
+    /// The ramp was cancelled or replaced by a newer ramp on the same session, the volume stays where it was
+    Cancelled,
+    /// Setting the volume failed, e.g. because the session expired
+    Failed(windows::core::Error),
+}
+
+type CompleteFn = Box<dyn FnOnce(RampOutcome) + Send + 'static>;
+
+#[derive(Default)]
+struct RampShared {
+    cancelled: AtomicBool,
+    state: Mutex<RampState>,
+    finished: Condvar,
+}
+
+#[derive(Default)]
+struct RampState {
+    outcome: Option<RampOutcome>,
+    on_complete: Option<CompleteFn>,
+}
+
+impl RampShared {
+    fn lock(&self) -> MutexGuard<'_, RampState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn finish(&self, outcome: RampOutcome) {
+        let on_complete = {
+            let mut state = self.lock();
+            state.outcome = Some(outcome.clone());
+            state.on_complete.take()
+        };
+        self.finished.notify_all();
+        if let Some(on_complete) = on_complete {
+            on_complete(outcome);
+        }
+    }
+}
+
+/// Handle to a running ramp, dropping it lets the ramp run to the end
+pub struct VolumeRamp {
+    shared: Arc<RampShared>,
+}
+
+impl VolumeRamp {
+    /// Stops the ramp at the current volume
+    pub fn cancel(&self) {
+        self.shared.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.shared.lock().outcome.is_some()
+    }
+
+    /// `None` while the ramp is still running
+    pub fn get_outcome(&self) -> Option<RampOutcome> {
+        self.shared.lock().outcome.clone()
+    }
+
+    /// Blocks until the ramp finished
+    pub fn wait(&self) -> RampOutcome {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(outcome) = &state.outcome {
+                return outcome.clone();
+            }
+            state = self.shared.finished.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Called on the scheduler thread once the ramp finished, right away if it already did
+    pub fn on_complete<F>(&self, callback: F)
+    where
+        F: FnOnce(RampOutcome) + Send + 'static,
+    {
+        let mut state = self.shared.lock();
+        match state.outcome.clone() {
+            Some(outcome) => {
+                drop(state);
+                callback(outcome);
+            }
+            None => state.on_complete = Some(Box::new(callback)),
+        }
+    }
+}
+
+struct ActiveRamp {
+    session_name: String,
+    session: ComSend<IAudioSessionControl2>,
+    /// Taken from the session on the scheduler thread by the first step, with the volume the ramp starts at
+    volume: Option<(ComSend<ISimpleAudioVolume>, f32)>,
+    target: f32,
+    started: Instant,
+    duration: Duration,
+    context: EventContext,
+    shared: Arc<RampShared>,
+}
+
+impl ActiveRamp {
+    /// Sets the volume for the elapsed time, returns the outcome once the ramp is done
+    fn step(&mut self) -> Option<RampOutcome> {
+        if self.shared.cancelled.load(Ordering::Relaxed) {
+            return Some(RampOutcome::Cancelled);
+        }
+        if self.volume.is_none() {
+            let volume = self.session.get().cast::<ISimpleAudioVolume>();
+            match volume.and_then(|volume| Ok((unsafe { volume.GetMasterVolume() }?, volume))) {
+                Ok((from, volume)) => self.volume = Some((ComSend(volume), from)),
+                Err(err) => return Some(RampOutcome::Failed(err)),
+            }
+        }
+        let (volume, from) = self.volume.as_ref().expect("set above");
+        let progress = ramp_progress(self.started.elapsed(), self.duration);
+        let level = from + (self.target - from) * progress;
+        if let Err(err) = unsafe { volume.get().SetMasterVolume(level, self.context.as_ptr()) } {
+            return Some(RampOutcome::Failed(err));
+        }
+        (progress >= 1.0).then_some(RampOutcome::Completed)
+    }
+}
+
+/// Share of the ramp done after `elapsed`, from `0.0` to `1.0`
+fn ramp_progress(elapsed: Duration, duration: Duration) -> f32 {
+    if duration.is_zero() {
+        return 1.0;
+    }
+    (elapsed.as_secs_f32() / duration.as_secs_f32()).min(1.0)
+}
+
+/// Hands a ramp of the session named `session_name` from its current volume to `target` over to the scheduler
+/// The volume interface is taken and read on the scheduler thread, failing there finishes the ramp with
+/// [`RampOutcome::Failed`].
+pub(crate) fn start(
+    session_name: String,
+    session: IAudioSessionControl2,
+    target: f32,
+    duration: Duration,
+    context: EventContext,
+) -> Result<VolumeRamp, AudioError> {
+    let shared = Arc::new(RampShared::default());
+    let mut ramp = ActiveRamp {
+        session_name,
+        session: ComSend(session),
+        volume: None,
+        target: target.clamp(0.0, 1.0),
+        started: Instant::now(),
+        duration,
+        context,
+        shared: shared.clone(),
+    };
+
+    let mut scheduler = SCHEDULER.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(send) = scheduler.as_ref() {
+        match send.send(ramp) {
+            Ok(()) => return Ok(VolumeRamp { shared }),
+            // The scheduler thread is gone, start a new one below
+            Err(mpsc::SendError(returned)) => ramp = returned,
+        }
+    }
+    let (send, recv) = mpsc::channel();
+    send.send(ramp).expect("the receiver is still in scope");
+    thread::Builder::new()
+        .name("volume ramp".to_string())
+        .spawn(move || scheduler_thread(recv))
+        .map_err(|_| AudioError::FailedToCreateThread)?;
+    *scheduler = Some(send);
+    Ok(VolumeRamp { shared })
+}
+
+fn scheduler_thread(recv: mpsc::Receiver<ActiveRamp>) {
+    com_initialized();
+    let mut active: Vec<ActiveRamp> = Vec::new();
+    loop {
+        if active.is_empty() {
+            match recv.recv() {
+                Ok(ramp) => add_ramp(&mut active, ramp),
+                Err(_) => return,
+            }
+        }
+        while let Ok(ramp) = recv.try_recv() {
+            add_ramp(&mut active, ramp);
+        }
+
+        active.retain_mut(|ramp| match ramp.step() {
+            Some(outcome) => {
+                ramp.shared.finish(outcome);
+                false
+            }
+            None => true,
+        });
+        if !active.is_empty() {
+            thread::sleep(RAMP_STEP);
+        }
+    }
+}
+
+fn add_ramp(active: &mut Vec<ActiveRamp>, ramp: ActiveRamp) {
+    if let Some(index) = active.iter().position(|r| r.session_name == ramp.session_name) {
+        active.remove(index).shared.finish(RampOutcome::Cancelled);
+    }
+    active.push(ramp);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress() {
+        assert_eq!(ramp_progress(Duration::from_millis(50), Duration::from_millis(200)), 0.25);
+        assert_eq!(ramp_progress(Duration::from_millis(300), Duration::from_millis(200)), 1.0);
+        assert_eq!(ramp_progress(Duration::ZERO, Duration::ZERO), 1.0);
+    }
+}
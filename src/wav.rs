@@ -0,0 +1,239 @@
+//! Minimal RIFF/WAVE support for the formats WASAPI deals with: PCM and IEEE float, plain or extensible.
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+use thiserror::Error;
+
+use crate::sample_format::{FormatTag, SampleFormat};
+use crate::speaker_layout::SpeakerLayout;
+
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+/// `cbSize` of a `WAVEFORMATEXTENSIBLE`
+const EXTENSIBLE_SIZE: u16 = 22;
+/// Sub format GUIDs of extensible formats are the format tag followed by these bytes
+const SUB_FORMAT_SUFFIX: [u8; 14] = [0, 0, 0, 0, 0x10, 0, 0x80, 0, 0, 0xAA, 0, 0x38, 0x9B, 0x71];
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum WavError {
+    #[error("Not a RIFF/WAVE file")]
+    NotWave,
+    #[error("Missing fmt chunk")]
+    MissingFormat,
+    #[error("Missing data chunk")]
+    MissingData,
+    #[error("Unsupported wave format tag: {0:#06x}")]
+    UnsupportedFormat(u16),
+}
+
+/// Splits a WAV file into its format and the raw sample data
+/// A truncated data chunk is accepted, only the whole frames that are present are returned
+pub fn parse_wav(data: &[u8]) -> Result<(SampleFormat, &[u8]), WavError> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(WavError::NotWave);
+    }
+    let read_u16 = |chunk: &[u8], at: usize| u16::from_le_bytes([chunk[at], chunk[at + 1]]);
+    let read_u32 = |chunk: &[u8], at: usize| u32::from_le_bytes([chunk[at], chunk[at + 1], chunk[at + 2], chunk[at + 3]]);
+
+    let mut format = None;
+    let mut rest = &data[12..];
+    while rest.len() >= 8 {
+        let id = &rest[0..4];
+        let len = read_u32(rest, 4) as usize;
+        let chunk = &rest[8..(8 + len).min(rest.len())];
+        match id {
+            b"fmt " if chunk.len() >= 16 => {
+                let mut tag = read_u16(chunk, 0);
+                // The actual tag is stored in the first two bytes of the sub format GUID
+                if tag == WAVE_FORMAT_EXTENSIBLE && chunk.len() >= 26 {
+                    tag = read_u16(chunk, 24);
+                }
+                let format_tag = match FormatTag::from(tag) {
+                    tag @ (FormatTag::WaveFormatPcm | FormatTag::WaveFormatIeeeFloat) => tag,
+                    _ => return Err(WavError::UnsupportedFormat(tag)),
+                };
+                let mut parsed = SampleFormat::new(format_tag, read_u16(chunk, 2), read_u32(chunk, 4), read_u16(chunk, 14));
+                if read_u16(chunk, 0) == WAVE_FORMAT_EXTENSIBLE && chunk.len() >= 24 {
+                    parsed = parsed.with_speaker_layout(SpeakerLayout::from_mask(read_u32(chunk, 20)));
+                }
+                format = Some(parsed);
+            }
+            b"data" => {
+                let format = format.ok_or(WavError::MissingFormat)?;
+                let block_align = (format.block_align() as usize).max(1);
+                let len = chunk.len() / block_align * block_align;
+                return Ok((format, &chunk[..len]));
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even length
+        let skip = (8 + len + (len & 1)).min(rest.len());
+        rest = &rest[skip..];
+    }
+    match format {
+        Some(_) => Err(WavError::MissingData),
+        None => Err(WavError::MissingFormat),
+    }
+}
+
+/// Header of a WAV file holding `data_len` bytes of samples in `format`, the sample data follows right after it
+///
+/// Formats a plain fmt chunk can't describe, see [`SampleFormat::needs_extensible`], get a `WAVEFORMATEXTENSIBLE` one
+/// with the channel mask. Float formats also carry `cbSize` and the `fact` chunk every non-PCM file needs. The length
+/// only depends on the format, so the header can be written again once the data length is known.
+pub fn wav_header(format: &SampleFormat, data_len: u32) -> Vec<u8> {
+    let block_align = format.block_align();
+    let format_tag = format.get_format_tag().to_wave_format_tag();
+    let extensible = format.needs_extensible();
+    let float = *format.get_format_tag() == FormatTag::WaveFormatIeeeFloat;
+
+    let mut fmt = Vec::with_capacity(16 + 2 + EXTENSIBLE_SIZE as usize);
+    fmt.extend_from_slice(&if extensible { WAVE_FORMAT_EXTENSIBLE } else { format_tag }.to_le_bytes());
+    fmt.extend_from_slice(&format.get_channel().to_le_bytes());
+    fmt.extend_from_slice(&format.get_n_samples_per_sec().to_le_bytes());
+    fmt.extend_from_slice(&(format.get_n_samples_per_sec() * block_align as u32).to_le_bytes());
+    fmt.extend_from_slice(&block_align.to_le_bytes());
+    fmt.extend_from_slice(&format.get_w_bits_per_sample().to_le_bytes());
+    if extensible {
+        fmt.extend_from_slice(&EXTENSIBLE_SIZE.to_le_bytes());
+        fmt.extend_from_slice(&format.get_w_bits_per_sample().to_le_bytes());
+        fmt.extend_from_slice(&format.get_speaker_layout().get_mask().to_le_bytes());
+        fmt.extend_from_slice(&format_tag.to_le_bytes());
+        fmt.extend_from_slice(&SUB_FORMAT_SUFFIX);
+    } else if float {
+        fmt.extend_from_slice(&0u16.to_le_bytes());
+    }
+
+    let mut header = Vec::with_capacity(12 + 8 + fmt.len() + 12 + 8);
+    header.extend_from_slice(b"RIFF\0\0\0\0WAVEfmt ");
+    header.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+    header.extend_from_slice(&fmt);
+    if float {
+        let frames = data_len / block_align.max(1) as u32;
+        header.extend_from_slice(b"fact");
+        header.extend_from_slice(&4u32.to_le_bytes());
+        header.extend_from_slice(&frames.to_le_bytes());
+    }
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    // The RIFF size covers everything after itself, including the pad byte of an odd data chunk
+    let riff_len = (header.len() as u32 - 8).saturating_add(data_len).saturating_add(data_len & 1);
+    header[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    header
+}
+
+/// Writes samples into a WAV file as they come, the sizes in the header are filled in by [`WavWriter::finish`]
+///
+/// A file that is never finished still has its samples, but claims to be empty. Sizes stop growing at 4 GiB, the
+/// limit of the RIFF format, data written past that is only found by readers that ignore the sizes.
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    format: SampleFormat,
+    data_len: u64,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Writes a header for an empty file, `writer` should be positioned at its start
+    pub fn new(mut writer: W, format: SampleFormat) -> io::Result<Self> {
+        writer.write_all(&wav_header(&format, 0))?;
+        Ok(Self {
+            writer,
+            format,
+            data_len: 0,
+        })
+    }
+
+    /// Appends interleaved samples in the format the writer was created with
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writer.write_all(data)?;
+        self.data_len += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn format(&self) -> &SampleFormat {
+        &self.format
+    }
+
+    /// Number of sample bytes written so far
+    pub fn data_len(&self) -> u64 {
+        self.data_len
+    }
+
+    /// Pads the data chunk and fills in the sizes, returns the underlying writer positioned at the end of the file
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.data_len & 1 == 1 {
+            self.writer.write_all(&[0])?;
+        }
+        let data_len = u32::try_from(self.data_len).unwrap_or(u32::MAX - 1);
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&wav_header(&self.format, data_len))?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF\0\0\0\0WAVE");
+        wav.extend_from_slice(b"LIST\x03\0\0\0abc\0");
+        wav.extend_from_slice(b"fmt \x10\0\0\0");
+        for value in [1u16, 2] {
+            wav.extend_from_slice(&value.to_le_bytes());
+        }
+        wav.extend_from_slice(&44100u32.to_le_bytes());
+        wav.extend_from_slice(&(44100u32 * 4).to_le_bytes());
+        for value in [4u16, 16] {
+            wav.extend_from_slice(&value.to_le_bytes());
+        }
+        wav.extend_from_slice(b"data\x06\0\0\0");
+        wav.extend_from_slice(&[1, 2, 3, 4, 5, 6]);
+
+        let (format, data) = parse_wav(&wav).unwrap();
+        assert_eq!(format, SampleFormat::new(FormatTag::WaveFormatPcm, 2, 44100, 16));
+        assert_eq!(data, [1, 2, 3, 4]);
+        assert_eq!(parse_wav(b"RIFF\0\0\0\0AVI "), Err(WavError::NotWave));
+    }
+
+    #[test]
+    fn write() {
+        let format = SampleFormat::new(FormatTag::WaveFormatIeeeFloat, 2, 48000, 32);
+        let mut writer = WavWriter::new(io::Cursor::new(Vec::new()), format.clone()).unwrap();
+        writer.write(&[1; 8]).unwrap();
+        writer.write(&[2; 8]).unwrap();
+        let wav = writer.finish().unwrap().into_inner();
+
+        // Float is stored as an extensible format with a fact chunk holding the frame count
+        let header_len = wav_header(&format, 0).len();
+        assert_eq!(header_len, 12 + 8 + 40 + 12 + 8);
+        assert_eq!(wav.len(), header_len + 16);
+        assert_eq!(u32::from_le_bytes([wav[4], wav[5], wav[6], wav[7]]) as usize, wav.len() - 8);
+        assert_eq!(&wav[60..64], b"fact");
+        assert_eq!(u32::from_le_bytes([wav[68], wav[69], wav[70], wav[71]]), 2);
+        let (parsed, data) = parse_wav(&wav).unwrap();
+        assert_eq!(parsed, format);
+        assert_eq!(data, [[1; 8], [2; 8]].concat());
+    }
+
+    #[test]
+    fn header_layouts() {
+        let pcm = SampleFormat::new(FormatTag::WaveFormatPcm, 2, 44100, 16);
+        assert_eq!(wav_header(&pcm, 0).len(), 44);
+
+        let float = SampleFormat::new(FormatTag::WaveFormatIeeeFloat, 1, 44100, 16);
+        let header = wav_header(&float, 4);
+        assert_eq!(header.len(), 12 + 8 + 18 + 12 + 8);
+        assert_eq!(u16::from_le_bytes([header[36], header[37]]), 0);
+
+        let surround = SampleFormat::new(FormatTag::WaveFormatPcm, 6, 48000, 24);
+        let header = wav_header(&surround, 0);
+        assert_eq!(header.len(), 12 + 8 + 40 + 8);
+        assert_eq!(u16::from_le_bytes([header[20], header[21]]), WAVE_FORMAT_EXTENSIBLE);
+        let (parsed, _) = parse_wav(&header).unwrap();
+        assert_eq!(parsed, surround);
+    }
+}
@@ -0,0 +1,285 @@
+//! Handles to devices and sessions that know when the object behind them is gone, see [`WeakDevice`] and
+//! [`WeakSession`].
+//!
+//! A [`Device`] or [`Session`] kept after its device was removed still exists, but every call on it fails with an
+//! error that doesn't say why. The handles are backed by one process wide session tracker that marks devices dead
+//! when they are removed or leave the active state, and sessions when they expire, are disconnected or their device
+//! goes away. [`WeakDevice::upgrade`] and [`WeakSession::upgrade`] then return `None` instead of an object that can
+//! only fail.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use log::warn;
+use thiserror::Error;
+
+use crate::device_state::DeviceState;
+use crate::event_args::{AudioSessionEventArgs, DeviceNotificationEventArgs, SessionState};
+use crate::manager::{AudioError, AudioSessionState, Device, DeviceManager, Session};
+use crate::notifications::NotificationError;
+use crate::session_tracker::{SessionHandler, SessionTracker};
+
+#[derive(Error, Debug)]
+pub enum WeakHandleError {
+    #[error("Failed setting up notifications: {0}")]
+    NotificationError(#[source] NotificationError),
+    #[error("Failed reading device: {0}")]
+    DeviceError(#[source] AudioError),
+}
+
+/// Created by the first handle and kept for the rest of the process
+static TRACKER: Mutex<Option<SessionTracker<Liveness>>> = Mutex::new(None);
+
+/// The tracker, started on first use
+fn tracker() -> Result<MutexGuard<'static, Option<SessionTracker<Liveness>>>, WeakHandleError> {
+    let mut tracker = TRACKER.lock().unwrap_or_else(|e| e.into_inner());
+    if tracker.is_none() {
+        *tracker = Some(SessionTracker::start(Liveness::default()).map_err(WeakHandleError::NotificationError)?);
+    }
+    Ok(tracker)
+}
+
+/// Liveness of everything a handle was taken of, updated from the tracker's callbacks
+#[derive(Default)]
+struct Liveness {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Shared by every handle of the device, by device id. Devices with a notification are kept even without a
+    /// handle, a handle taken while the notification arrives uses the newer state.
+    devices: BTreeMap<String, Arc<AtomicBool>>,
+    /// Sessions with a handle by name, removed once they ended
+    sessions: BTreeMap<String, SessionEntry>,
+}
+
+struct SessionEntry {
+    device_id: String,
+    alive: Arc<AtomicBool>,
+}
+
+impl SessionHandler for Liveness {
+    fn session_added(&self, _session: &Session, _created: bool) -> bool {
+        // Only sessions a handle was taken of are watched, see `WeakSession::new`
+        false
+    }
+
+    fn session_event(&self, name: &str, event: AudioSessionEventArgs) {
+        let ended = match event {
+            AudioSessionEventArgs::StateChanged(args) => matches!(args.get_state(), SessionState::AudioSessionStateExpired),
+            AudioSessionEventArgs::SessionDisconnected(_) => true,
+            _ => false,
+        };
+        if ended && let Some(entry) = self.lock_state().sessions.remove(name) {
+            entry.alive.store(false, Ordering::SeqCst);
+        }
+    }
+
+    fn device_event(&self, event: DeviceNotificationEventArgs) {
+        let (id, alive) = match &event {
+            // An added device isn't necessarily active, e.g. a disabled one that was installed
+            DeviceNotificationEventArgs::DeviceAdded(args) => match args.get_device_id() {
+                Ok(id) => {
+                    let active = DeviceManager::get_device(&id).is_ok_and(|device| matches!(device.get_state(), Ok(DeviceState::Active)));
+                    (Ok(id), active)
+                }
+                Err(err) => (Err(err), false),
+            },
+            DeviceNotificationEventArgs::DeviceRemoved(args) => (args.get_device_id(), false),
+            DeviceNotificationEventArgs::DeviceStateChanged(args) => {
+                (args.get_device_id(), matches!(args.get_state(), DeviceState::Active))
+            }
+            _ => return,
+        };
+        match id {
+            Ok(id) => self.set_device_alive(&id, alive),
+            Err(err) => warn!("Failed reading id of changed device: {}", err),
+        }
+    }
+}
+
+impl Liveness {
+    fn lock_state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set_device_alive(&self, id: &str, alive: bool) {
+        let mut state = self.lock_state();
+        state.devices.entry(id.to_string()).or_default().store(alive, Ordering::SeqCst);
+        if !alive {
+            // The session controls of a device don't come back with it, the device has new ones when it returns. The
+            // tracker stops watching them itself.
+            state.sessions.retain(|_, entry| {
+                if entry.device_id != id {
+                    return true;
+                }
+                entry.alive.store(false, Ordering::SeqCst);
+                false
+            });
+        }
+    }
+
+    fn device_alive(&self, id: &str, device: &Device) -> Arc<AtomicBool> {
+        if let Some(alive) = self.lock_state().devices.get(id) {
+            return alive.clone();
+        }
+        // Read without holding the state, a notification arriving meanwhile leaves an entry that wins below
+        let active = matches!(device.get_state(), Ok(DeviceState::Active));
+        self.lock_state()
+            .devices
+            .entry(id.to_string())
+            .or_insert_with(|| Arc::new(AtomicBool::new(active)))
+            .clone()
+    }
+
+    /// The liveness of `session`, `true` alongside it if the session's events have to be watched
+    fn session_alive(&self, session: &Session, device_id: String) -> (Arc<AtomicBool>, bool) {
+        if let Some(entry) = self.lock_state().sessions.get(session.get_name()) {
+            return (entry.alive.clone(), false);
+        }
+        let active = !matches!(session.get_state(), Ok(AudioSessionState::AudioSessionStateExpired) | Err(_));
+        if !active {
+            return (Arc::new(AtomicBool::new(false)), false);
+        }
+        let mut state = self.lock_state();
+        if let Some(entry) = state.sessions.get(session.get_name()) {
+            return (entry.alive.clone(), false);
+        }
+        let alive = Arc::new(AtomicBool::new(true));
+        state.sessions.insert(
+            session.get_name().clone(),
+            SessionEntry {
+                device_id,
+                alive: alive.clone(),
+            },
+        );
+        (alive, true)
+    }
+}
+
+/// A [`Device`] that can be kept around after the device is gone
+///
+/// The device counts as alive while it is active. A device that is disabled or unplugged and comes back is alive
+/// again, its id and the [`Device`] stay valid.
+#[derive(Debug, Clone)]
+pub struct WeakDevice {
+    device: Device,
+    id: String,
+    alive: Arc<AtomicBool>,
+}
+
+impl WeakDevice {
+    pub fn new(device: &Device) -> Result<Self, WeakHandleError> {
+        let id = device.get_id().map_err(WeakHandleError::DeviceError)?;
+        // Watching starts before the state is read, a change in between isn't missed
+        let tracker = tracker()?;
+        let alive = tracker
+            .as_ref()
+            .expect("tracker is started above")
+            .handler()
+            .device_alive(&id, device);
+        Ok(Self {
+            device: device.clone(),
+            id,
+            alive,
+        })
+    }
+
+    /// The device, `None` while it is removed or not active
+    pub fn upgrade(&self) -> Option<Device> {
+        self.is_alive().then(|| self.device.clone())
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst)
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// A [`Session`] that can be kept around after the session is gone
+///
+/// Once a session expired, was disconnected or its device was removed or disabled it stays dead, a new session of the
+/// same application has to be looked up.
+#[derive(Debug, Clone)]
+pub struct WeakSession {
+    session: Session,
+    alive: Arc<AtomicBool>,
+}
+
+impl WeakSession {
+    pub fn new(session: &Session) -> Result<Self, WeakHandleError> {
+        let name = session.get_name().clone();
+        let device_id = session.get_device().get_id().map_err(WeakHandleError::DeviceError)?;
+        let tracker = tracker()?;
+        let tracker = tracker.as_ref().expect("tracker is started above");
+        let (alive, watch) = tracker.handler().session_alive(session, device_id);
+        if watch && let Err(err) = tracker.watch(session) {
+            tracker.handler().lock_state().sessions.remove(&name);
+            return Err(WeakHandleError::NotificationError(err));
+        }
+        Ok(Self {
+            session: session.clone(),
+            alive,
+        })
+    }
+
+    /// The session, `None` once it ended
+    pub fn upgrade(&self) -> Option<Session> {
+        self.is_alive().then(|| self.session.clone())
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst)
+    }
+
+    pub fn get_name(&self) -> &String {
+        self.session.get_name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use windows_core::HSTRING;
+
+    use super::*;
+    use crate::audio_client::AudioClient;
+    use crate::event_args::{DeviceAddedEventArgs, DeviceRemovedEventArgs};
+    use crate::manager::SessionManager;
+
+    #[test]
+    fn dead_after_removal() {
+        let (playback, _format) = AudioClient::new().start_playback_device(None, |_| 0, |_| {}).unwrap();
+        let _playback = playback.start().unwrap();
+        let session = SessionManager::get_sessions()
+            .unwrap()
+            .into_iter()
+            .find(|session| *session.get_pid() == std::process::id())
+            .unwrap();
+        // A local instance, the process wide tracker's state isn't touched
+        let liveness = Liveness::default();
+        let id = session.get_device().get_id().unwrap();
+        let device = liveness.device_alive(&id, session.get_device());
+        let (weak, watch) = liveness.session_alive(&session, id.clone());
+        assert!(watch);
+        assert!(device.load(Ordering::SeqCst));
+        assert!(weak.load(Ordering::SeqCst));
+
+        // Removal as the device notification reports it, the session goes with its device
+        let id = HSTRING::from(id);
+        liveness.device_event(DeviceNotificationEventArgs::DeviceRemoved(DeviceRemovedEventArgs {
+            pwstrDeviceId: id.clone(),
+        }));
+        assert!(!device.load(Ordering::SeqCst));
+        assert!(!weak.load(Ordering::SeqCst));
+
+        // The device is still active, so it is alive again once added
+        liveness.device_event(DeviceNotificationEventArgs::DeviceAdded(DeviceAddedEventArgs { pwstrDeviceId: id }));
+        assert!(device.load(Ordering::SeqCst));
+        assert!(!weak.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,112 @@
+//! Bridge from `Windows.Media.Devices.MediaDevice` events to [`DeviceNotificationEventArgs`].
+//!
+//! Some default device changes, e.g. the communications default as seen by modern apps, are only surfaced through
+//! WinRT. The bridge translates them into [`DeviceNotificationEventArgs::DefaultDeviceChanged`] with MMDevice ids,
+//! so they can be handled by the same callback as [`Notifications::register_device_notification`](crate::notifications::Notifications::register_device_notification).
+
+use std::sync::Arc;
+
+use windows::Foundation::TypedEventHandler;
+use windows::Media::Devices::{
+    AudioDeviceRole, DefaultAudioCaptureDeviceChangedEventArgs, DefaultAudioRenderDeviceChangedEventArgs, MediaDevice,
+};
+use windows::Win32::Media::Audio::{EDataFlow, ERole, eCapture, eCommunications, eConsole, eRender};
+use windows_core::{HSTRING, IInspectable};
+
+use crate::event_args::{DefaultDeviceChangedEventArgs, DeviceNotificationEventArgs};
+use crate::notifications::NotificationError;
+
+/// Keeps the WinRT event handlers registered until dropped
+pub(crate) struct WinRtRegistration {
+    render_token: i64,
+    capture_token: i64,
+}
+
+impl WinRtRegistration {
+    pub(crate) fn register(
+        callback_fn: Arc<dyn Fn(DeviceNotificationEventArgs) + Send + Sync + 'static>,
+    ) -> Result<Self, NotificationError> {
+        let render_callback = callback_fn.clone();
+        let render_handler = TypedEventHandler::<IInspectable, DefaultAudioRenderDeviceChangedEventArgs>::new(move |_, args| {
+            if let Some(args) = args {
+                render_callback(default_device_changed(eRender, args.Role()?, &args.Id()?));
+            }
+            Ok(())
+        });
+        let capture_handler = TypedEventHandler::<IInspectable, DefaultAudioCaptureDeviceChangedEventArgs>::new(move |_, args| {
+            if let Some(args) = args {
+                callback_fn(default_device_changed(eCapture, args.Role()?, &args.Id()?));
+            }
+            Ok(())
+        });
+
+        let render_token =
+            MediaDevice::DefaultAudioRenderDeviceChanged(&render_handler).map_err(NotificationError::NotificationRegisterError)?;
+        let capture_token = match MediaDevice::DefaultAudioCaptureDeviceChanged(&capture_handler) {
+            Ok(token) => token,
+            Err(err) => {
+                let _ = MediaDevice::RemoveDefaultAudioRenderDeviceChanged(render_token);
+                return Err(NotificationError::NotificationRegisterError(err));
+            }
+        };
+        Ok(Self {
+            render_token,
+            capture_token,
+        })
+    }
+
+    pub(crate) fn unregister(self) -> Result<(), NotificationError> {
+        let registration = std::mem::ManuallyDrop::new(self);
+        registration.remove()
+    }
+
+    fn remove(&self) -> Result<(), NotificationError> {
+        let render = MediaDevice::RemoveDefaultAudioRenderDeviceChanged(self.render_token);
+        let capture = MediaDevice::RemoveDefaultAudioCaptureDeviceChanged(self.capture_token);
+        render.and(capture).map_err(NotificationError::NotificationUnregisterError)
+    }
+}
+
+impl Drop for WinRtRegistration {
+    fn drop(&mut self) {
+        let _ = self.remove();
+    }
+}
+
+fn default_device_changed(flow: EDataFlow, role: AudioDeviceRole, interface_id: &HSTRING) -> DeviceNotificationEventArgs {
+    // WinRT's default role covers both the console and multimedia roles
+    let role: ERole = if role == AudioDeviceRole::Communications {
+        eCommunications
+    } else {
+        eConsole
+    };
+    DeviceNotificationEventArgs::DefaultDeviceChanged(DefaultDeviceChangedEventArgs {
+        flow,
+        role,
+        defaultdevice: mmdevice_id(interface_id),
+    })
+}
+
+/// WinRT reports device interface paths (`\\?\SWD#MMDEVAPI#{0.0.0.00000000}.{guid}#{interface guid}`),
+/// the MMDevice id is the part between `MMDEVAPI#` and the interface class
+fn mmdevice_id(interface_id: &HSTRING) -> HSTRING {
+    let path = interface_id.to_string_lossy();
+    match path.split_once("MMDEVAPI#").and_then(|(_, rest)| rest.split('#').next()) {
+        Some(id) => HSTRING::from(id),
+        None => interface_id.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interface_path_to_mmdevice_id() {
+        let path = HSTRING::from(
+            r"\\?\SWD#MMDEVAPI#{0.0.0.00000000}.{8a1b2c3d-0000-1111-2222-333344445555}#{e6327cad-dcec-4949-ae8a-991e976a79d2}",
+        );
+        assert_eq!(mmdevice_id(&path), "{0.0.0.00000000}.{8a1b2c3d-0000-1111-2222-333344445555}");
+        assert_eq!(mmdevice_id(&HSTRING::new()), "");
+    }
+}